@@ -1,27 +1,88 @@
-use std::{env, process::Command};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
 
 // Necessary because of this issue: https://github.com/rust-lang/cargo/issues/9641
 fn main() -> anyhow::Result<()> {
-    let _out_dir = env::var("OUT_DIR").unwrap();
-    // let out_file = out_dir + "/../../web_includes.rs";
-    // WSL paths are too bork, giving up
-    let out_file = "web_includes.rs";
+    // host builds are only for the lib's tests: no web assets, no ESP-IDF
+    let board = env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("espidf");
 
-    // cursed paths are cursed
-    // let mut cmd = Command::new("../color-mixer-ws/target/debug/pack.exe");
+    if board {
+        // `pack` gzips the UI's `dist` next to the originals; the include
+        // file it writes only suits the legacy server, so both backends
+        // use the table from `write_assets` instead
+        let dist = "../color-mixer-ws/mixer-dioxus/dist/";
+        let _output = Command::new("pack")
+            .args(["web_includes.rs", dist])
+            .output()?;
+        // WSL paths are too bork for OUT_DIR, so this lands next to Cargo.toml
+        write_assets(Path::new(dist), "web_assets.rs")?;
+    }
 
-    let mut cmd = Command::new("pack");
-    let cmd = cmd.args([&out_file, "../color-mixer-ws/mixer-dioxus/dist/"]);
-
-    let argstr: Vec<String> = cmd
-        .get_args()
-        .map(|arg| arg.to_string_lossy().to_string())
-        .collect();
-    let argstr = argstr.join("\n");
-    let _cmd_and_args = cmd.get_program().to_string_lossy().to_string() + &argstr;
-
-    let _output = cmd.output().unwrap();
+    // reported by `GET /status`
+    let build_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=HARLOT_BUILD_HASH={build_hash}");
 
+    if !board {
+        return Ok(());
+    }
     embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
     embuild::build::LinkArgs::output_propagated("ESP_IDF")
 }
+
+/// Writes the gzipped files under the UI's `dist` as a table of
+/// `(path, content type, body)` that `src/server.rs` includes, so every
+/// server backend can serve them.
+fn write_assets(dist: &Path, out_file: &str) -> anyhow::Result<()> {
+    let dist = dist.canonicalize()?;
+    let mut files = vec![];
+    gzipped_files(&dist, &mut files)?;
+    files.sort();
+
+    let mut table = String::from("&[\n");
+    for file in files {
+        let name = file
+            .strip_prefix(&dist)?
+            .to_string_lossy()
+            .replace('\\', "/");
+        let name = name.trim_end_matches(".gz");
+        let content_type = match name.rsplit('.').next() {
+            Some("html") => "text/html",
+            Some("js") => "application/javascript",
+            Some("wasm") => "application/wasm",
+            Some("css") => "text/css",
+            Some("svg") => "image/svg+xml",
+            Some("ico") => "image/x-icon",
+            Some("png") => "image/png",
+            _ => "application/octet-stream",
+        };
+        let body = format!("include_bytes!({:?}).as_slice()", file);
+        if name == "index.html" {
+            table += &format!("    (\"/\", {content_type:?}, {body}),\n");
+        }
+        table += &format!("    (\"/{name}\", {content_type:?}, {body}),\n");
+    }
+    table += "]\n";
+    fs::write(out_file, table)?;
+    Ok(())
+}
+
+fn gzipped_files(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            gzipped_files(&path, files)?;
+        } else if path.extension().and_then(|ext| ext.to_str()) == Some("gz") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
@@ -9,4 +9,4 @@ HARLOT_BOARD="http://192.168.71.1/" trunk build
 cp public/style.css dist
 popd
 #cargo espflash --release --monitor --speed 800000
-cargo espflash --monitor --release --speed 800000 /dev/cu.SLAB_USBtoUART
+cargo espflash --monitor --release --speed 800000 --partition-table partitions.csv /dev/cu.SLAB_USBtoUART
@@ -0,0 +1,6 @@
+#!/bin/bash
+
+# Runs the lib's tests on this machine instead of the board; the ESP-IDF
+# modules are left out there, see src/lib.rs.
+host=$(rustc -vV | sed -n 's/^host: //p')
+cargo test --lib --target "$host" "$@"
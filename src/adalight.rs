@@ -0,0 +1,392 @@
+//! Adalight over the USB-UART, as sent by Prismatik, Hyperion and other
+//! ambient light grabbers.
+//!
+//! A frame is `Ada`, the LED count minus one (big endian), a checksum of
+//! those two bytes XOR 0x55, then RGB per LED. The decoder looks for that
+//! header anywhere in the stream, pixel data included, so it finds its feet
+//! again after line noise or a host that stopped mid-frame. The cut-off
+//! frame is dropped, unless the new header lands on its last five bytes:
+//! then it goes out with the header's first bytes as pixels, and the frame
+//! after it is found all the same. The log still goes out on the same
+//! UART; hosts don't read it.
+
+#[cfg(target_os = "espidf")]
+use std::{
+    ptr::null_mut,
+    sync::{Arc, Mutex},
+    thread,
+};
+use std::time::Instant;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::{
+    esp, uart_config_t, uart_driver_install, uart_param_config, uart_parity_t_UART_PARITY_DISABLE,
+    uart_port_t, uart_read_bytes, uart_stop_bits_t_UART_STOP_BITS_1,
+    uart_word_length_t_UART_DATA_8_BITS,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::realtime::{Filter, Frame, Shaping};
+
+const MAGIC: &[u8; 3] = b"Ada";
+const HEADER_LEN: usize = 6;
+/// the console UART, wired to the USB-UART bridge
+#[cfg(target_os = "espidf")]
+const UART: uart_port_t = 0;
+#[cfg(target_os = "espidf")]
+const RX_BUFFER: usize = 2048;
+/// 100 ms at the default 100 Hz tick
+#[cfg(target_os = "espidf")]
+const READ_TICKS: u32 = 10;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct AdalightSettings {
+    pub enabled: bool,
+    /// also the console's
+    pub baud: u32,
+    /// LED the frame's first pixel lands on
+    pub led_offset: usize,
+    pub led_count: usize,
+    #[serde(flatten)]
+    pub shaping: Shaping,
+}
+
+impl Default for AdalightSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            baud: 115_200,
+            led_offset: 0,
+            led_count: 512,
+            shaping: Shaping::default(),
+        }
+    }
+}
+
+impl AdalightSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(9600..=2_000_000).contains(&self.baud) {
+            anyhow::bail!("adalight baud must be 9600..=2000000");
+        }
+        if !(1..=4096).contains(&self.led_count) {
+            anyhow::bail!("adalight led_count must be 1..=4096");
+        }
+        self.shaping.validate("adalight")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Pixel {
+        index: usize,
+        rgb: [u8; 3],
+    },
+    /// a frame of `leds` pixels is complete
+    End {
+        leds: usize,
+    },
+}
+
+/// Incremental decoder for the Adalight byte stream.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    header: [u8; HEADER_LEN],
+    header_len: usize,
+    /// LEDs in the current frame and how many arrived, once in a frame
+    frame: Option<(usize, usize)>,
+    partial: [u8; 3],
+    partial_len: usize,
+    /// the current frame's last bytes, in case a new header shows up in it
+    recent: [u8; HEADER_LEN],
+    recent_len: usize,
+}
+
+/// Whether `header` could be the start of a valid header.
+fn header_prefix(header: &[u8]) -> bool {
+    let magic = header.len().min(MAGIC.len());
+    header[..magic] == MAGIC[..magic]
+        && (header.len() < HEADER_LEN || header[3] ^ header[4] ^ 0x55 == header[5])
+}
+
+/// The LED count of a complete, valid header.
+fn header_leds(header: &[u8; HEADER_LEN]) -> Option<usize> {
+    header_prefix(header).then(|| u16::from_be_bytes([header[3], header[4]]) as usize + 1)
+}
+
+impl Decoder {
+    /// Decodes `input`, continuing where the last call stopped.
+    pub fn feed(&mut self, input: &[u8], mut out: impl FnMut(Event)) {
+        for &byte in input {
+            let Some((leds, index)) = &mut self.frame else {
+                self.header[self.header_len] = byte;
+                self.header_len += 1;
+                self.trim_header();
+                if self.header_len == HEADER_LEN {
+                    self.header_len = 0;
+                    self.start(header_leds(&self.header));
+                }
+                continue;
+            };
+
+            // a host that restarts mid-frame sends a header where we expect
+            // pixels
+            if self.recent_len == HEADER_LEN {
+                self.recent.copy_within(1.., 0);
+                self.recent_len -= 1;
+            }
+            self.recent[self.recent_len] = byte;
+            self.recent_len += 1;
+            if self.recent_len == HEADER_LEN {
+                if let Some(leds) = header_leds(&self.recent) {
+                    self.start(Some(leds));
+                    continue;
+                }
+            }
+
+            self.partial[self.partial_len] = byte;
+            self.partial_len += 1;
+            if self.partial_len < 3 {
+                continue;
+            }
+            self.partial_len = 0;
+            out(Event::Pixel {
+                index: *index,
+                rgb: self.partial,
+            });
+            *index += 1;
+            if *index == *leds {
+                out(Event::End { leds: *leds });
+                self.frame = None;
+                // the frame's tail may be the start of the next header
+                self.header = self.recent;
+                self.header_len = self.recent_len;
+                self.trim_header();
+            }
+        }
+    }
+
+    /// Drops bytes from the front until what's left could be a header.
+    fn trim_header(&mut self) {
+        while !header_prefix(&self.header[..self.header_len]) {
+            self.header.copy_within(1..self.header_len, 0);
+            self.header_len -= 1;
+        }
+    }
+
+    fn start(&mut self, leds: Option<usize>) {
+        self.frame = leds.map(|leds| (leds, 0));
+        self.partial_len = 0;
+        self.recent_len = 0;
+    }
+}
+
+/// The decoder plus the frame being assembled.
+pub struct Receiver {
+    settings: AdalightSettings,
+    decoder: Decoder,
+    pixels: Vec<[u8; 3]>,
+    filter: Filter,
+}
+
+impl Receiver {
+    pub fn new(settings: AdalightSettings) -> Self {
+        Self {
+            decoder: Decoder::default(),
+            pixels: vec![[0; 3]; settings.led_count],
+            filter: Filter::new(&settings.shaping),
+            settings,
+        }
+    }
+
+    /// Feeds received bytes, returns how many frames went on the strip.
+    pub fn handle(&mut self, buf: &[u8], now: Instant, frame: &mut Frame) -> usize {
+        let Self {
+            settings,
+            decoder,
+            pixels,
+            filter,
+        } = self;
+        let mut frames = 0;
+        decoder.feed(buf, |event| match event {
+            Event::Pixel { index, rgb } => {
+                if let Some(pixel) = pixels.get_mut(index) {
+                    *pixel = rgb;
+                }
+            }
+            Event::End { .. } => {
+                let mut shown = pixels.clone();
+                filter.apply(&mut shown, now);
+                for (i, rgb) in shown.into_iter().enumerate() {
+                    frame.set(settings.led_offset + i, rgb);
+                }
+                frame.commit("adalight", now);
+                frames += 1;
+            }
+        });
+        frames
+    }
+}
+
+/// Takes over the console UART's receive side and reads it on its own
+/// thread.
+#[cfg(target_os = "espidf")]
+pub fn serve(settings: AdalightSettings, frame: Arc<Mutex<Frame>>) -> anyhow::Result<()> {
+    let config = uart_config_t {
+        baud_rate: settings.baud as i32,
+        data_bits: uart_word_length_t_UART_DATA_8_BITS,
+        parity: uart_parity_t_UART_PARITY_DISABLE,
+        stop_bits: uart_stop_bits_t_UART_STOP_BITS_1,
+        ..Default::default()
+    };
+    esp!(unsafe { uart_param_config(UART, &config) })?;
+    esp!(unsafe { uart_driver_install(UART, RX_BUFFER as i32, 0, 0, null_mut(), 0) })?;
+    let mut receiver = Receiver::new(settings);
+
+    thread::Builder::new().stack_size(6144).spawn(move || {
+        let mut buf = [0u8; 256];
+        loop {
+            let n = unsafe {
+                uart_read_bytes(
+                    UART,
+                    buf.as_mut_ptr() as *mut _,
+                    buf.len() as u32,
+                    READ_TICKS,
+                )
+            };
+            if n > 0 {
+                let now = Instant::now();
+                receiver.handle(&buf[..n as usize], now, &mut frame.lock().unwrap());
+            }
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(pixels: &[[u8; 3]]) -> Vec<u8> {
+        let [hi, lo] = (pixels.len() as u16 - 1).to_be_bytes();
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&[hi, lo, hi ^ lo ^ 0x55]);
+        out.extend(pixels.iter().flatten());
+        out
+    }
+
+    fn decode(chunks: &[&[u8]]) -> Vec<Event> {
+        let mut decoder = Decoder::default();
+        let mut events = vec![];
+        for chunk in chunks {
+            decoder.feed(chunk, |event| events.push(event));
+        }
+        events
+    }
+
+    fn frames(events: &[Event]) -> Vec<Vec<[u8; 3]>> {
+        let mut out = vec![vec![]];
+        for event in events {
+            match *event {
+                Event::Pixel { index, rgb } => {
+                    // a frame starting over drops what came before
+                    if index == 0 {
+                        out.last_mut().unwrap().clear();
+                    }
+                    assert_eq!(index, out.last().unwrap().len());
+                    out.last_mut().unwrap().push(rgb);
+                }
+                Event::End { leds } => {
+                    assert_eq!(leds, out.last().unwrap().len());
+                    out.push(vec![]);
+                }
+            }
+        }
+        out.pop();
+        out
+    }
+
+    #[test]
+    fn decodes_frames_split_anywhere() {
+        let mut stream = frame(&[[1, 2, 3], [4, 5, 6]]);
+        stream.extend(frame(&[[7, 8, 9]]));
+        let whole = decode(&[&stream]);
+        assert_eq!(frames(&whole), [vec![[1, 2, 3], [4, 5, 6]], vec![[7, 8, 9]]]);
+        assert_eq!(whole.last(), Some(&Event::End { leds: 1 }));
+
+        for at in 0..=stream.len() {
+            let (a, b) = stream.split_at(at);
+            assert_eq!(decode(&[a, b]), whole, "split at {at}");
+        }
+        let bytes: Vec<&[u8]> = stream.chunks(1).collect();
+        assert_eq!(decode(&bytes), whole);
+    }
+
+    #[test]
+    fn resyncs_after_garbage() {
+        let mut stream = b"\x00garbage AdAda\x00\x00\x00 Ad".to_vec();
+        stream.extend(frame(&[[1, 1, 1]]));
+        // a header with a bad checksum is more garbage
+        stream.extend_from_slice(b"Ada\x00\x01\x00");
+        stream.extend_from_slice(b"AAda");
+        stream.extend(frame(&[[2, 2, 2]]));
+        assert_eq!(frames(&decode(&[&stream])), [vec![[1; 3]], vec![[2; 3]]]);
+    }
+
+    /// A frame of `leds` cut off after `sent` pixel bytes, then two whole ones.
+    fn restart(leds: usize, sent: usize) -> Vec<u8> {
+        let mut stream = frame(&vec![[9; 3]; leds]);
+        stream.truncate(HEADER_LEN + sent);
+        stream.extend(frame(&[[1; 3], [2; 3], [3; 3]]));
+        stream.extend(frame(&[[4; 3]]));
+        stream
+    }
+
+    #[test]
+    fn drops_a_frame_cut_off_by_a_new_header() {
+        for sent in 0..=6 {
+            let frames = frames(&decode(&[&restart(4, sent)]));
+            assert_eq!(frames, [vec![[1; 3], [2; 3], [3; 3]], vec![[4; 3]]], "{sent}");
+        }
+
+        let mut receiver = Receiver::new(AdalightSettings {
+            led_offset: 1,
+            led_count: 3,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let mut strip = Frame::new(4);
+        let stream = restart(3, 2);
+        let shown = receiver.handle(&stream[..stream.len() - 7], now, &mut strip);
+        assert_eq!(shown, 1);
+        assert_eq!(strip.pixels(), [[0; 3], [1; 3], [2; 3], [3; 3]]);
+        let timeout = std::time::Duration::from_secs(1);
+        assert_eq!(strip.live_source(now, timeout), Some("adalight"));
+    }
+
+    #[test]
+    fn finds_a_header_over_the_end_of_a_cut_off_frame() {
+        // the old frame ends on the new header's first bytes, and goes out
+        for sent in 7..12 {
+            let frames = frames(&decode(&[&restart(4, sent)]));
+            assert_eq!(frames.len(), 3, "{sent}");
+            assert_eq!(frames[0][..2], [[9; 3], [9; 3]]);
+            assert_eq!(frames[1..], [vec![[1; 3], [2; 3], [3; 3]], vec![[4; 3]]]);
+        }
+    }
+
+    #[test]
+    fn pixels_past_led_count_are_dropped() {
+        let mut receiver = Receiver::new(AdalightSettings {
+            led_count: 2,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let mut strip = Frame::new(4);
+        let stream = frame(&[[1; 3], [2; 3], [3; 3], [4; 3]]);
+        assert_eq!(receiver.handle(&stream[..10], now, &mut strip), 0);
+        assert_eq!(strip.updated(), None);
+        assert_eq!(receiver.handle(&stream[10..], now, &mut strip), 1);
+        assert_eq!(strip.pixels(), [[1; 3], [2; 3], [0; 3], [0; 3]]);
+    }
+}
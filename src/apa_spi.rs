@@ -3,7 +3,7 @@ use std::{mem::size_of, ptr::null_mut};
 use bytemuck::{Pod, Zeroable};
 //spi_bus_config_t
 use esp_idf_sys::{
-    spi_bus_add_device, spi_bus_config_t, spi_bus_config_t__bindgen_ty_1,
+    esp, spi_bus_add_device, spi_bus_config_t, spi_bus_config_t__bindgen_ty_1,
     spi_bus_config_t__bindgen_ty_2, spi_bus_config_t__bindgen_ty_3, spi_bus_config_t__bindgen_ty_4,
     spi_bus_initialize, spi_common_dma_t_SPI_DMA_CH_AUTO, spi_device_get_trans_result,
     spi_device_handle_t, spi_device_interface_config_t, spi_device_queue_trans, spi_host_device_t,
@@ -183,6 +183,7 @@ impl Default for Config {
 pub struct Apa {
     data: HeapData,
     handle: spi_device_handle_t,
+    errors: u32,
 }
 
 impl Apa {
@@ -236,14 +237,23 @@ impl Apa {
 
         let res =
             unsafe { spi_bus_add_device(config.spi_host, &spi_interface_config, &mut handle as _) };
-        Self { data, handle }
+        Self {
+            data,
+            handle,
+            errors: 0,
+        }
+    }
+
+    /// failed SPI transactions since start
+    pub fn errors(&self) -> u32 {
+        self.errors
     }
 
     pub fn set_pixel(&mut self, idx: usize, pixel: Pixel, log: impl Fn(String) -> ()) {
         self.data.set_pixel(idx, pixel, log);
     }
 
-    pub fn flush(&self) {
+    pub fn flush(&mut self) {
         let mut tx = spi_transaction_t::default();
 
         let txl = (8 * self.data.data().len());
@@ -269,6 +279,10 @@ impl Apa {
                 freeRTOS_magic_copypasta_portMAX_DELAY,
             )
         };
+        if esp!(res).is_err() {
+            self.errors = self.errors.wrapping_add(1);
+            return;
+        }
 
         let mut tx_res = null_mut();
         let res = unsafe {
@@ -278,5 +292,8 @@ impl Apa {
                 freeRTOS_magic_copypasta_portMAX_DELAY,
             )
         };
+        if esp!(res).is_err() {
+            self.errors = self.errors.wrapping_add(1);
+        }
     }
 }
@@ -0,0 +1,74 @@
+use serde::Serialize;
+
+/// Error reply of the HTTP API, rendered as `{"error": "..."}` plus the
+/// JSON pointer of the offending value, if there is one.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: u16,
+    pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(status: u16, error: impl Into<String>) -> Self {
+        Self {
+            status,
+            error: error.into(),
+            path: None,
+        }
+    }
+
+    pub fn bad_request(error: impl Into<String>) -> Self {
+        Self::new(400, error)
+    }
+
+    pub fn invalid(path: impl Into<String>, error: impl Into<String>) -> Self {
+        Self {
+            path: Some(path.into()),
+            ..Self::bad_request(error)
+        }
+    }
+
+    pub fn not_found(error: impl Into<String>) -> Self {
+        Self::new(404, error)
+    }
+
+    pub fn method_not_allowed() -> Self {
+        Self::new(405, "method not allowed")
+    }
+
+    pub fn conflict(error: impl Into<String>) -> Self {
+        Self::new(409, error)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| format!("{{\"error\":\"{}\"}}", self.status))
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::bad_request(e.to_string())
+    }
+}
+
+/// Whatever went wrong on our side, e.g. storage.
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(500, e.to_string())
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.status, self.error)?;
+        if let Some(path) = &self.path {
+            write!(f, " at {path}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ApiError {}
@@ -0,0 +1,560 @@
+//! Art-Net receiver: ArtDmx into the realtime frame, ArtPoll answered with
+//! ArtPollReply so controllers discover the board.
+//!
+//! DMX data is mapped onto the strip by a [`DmxMapping`] over 15 bit
+//! Port-Addresses. Sources aren't merged, the latest packet wins.
+
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::realtime::{in_sequence, DmxMapping, Frame, Node};
+
+pub const PORT: u16 = 0x1936;
+/// ArtDmx header plus 512 slots; ArtPoll and ArtPollReply are smaller
+pub const MAX_PACKET: usize = 530;
+const ID: &[u8; 8] = b"Art-Net\0";
+const PROTOCOL_VERSION: u16 = 14;
+const OP_POLL: u16 = 0x2000;
+const OP_POLL_REPLY: u16 = 0x2100;
+const OP_DMX: u16 = 0x5000;
+const POLL_TARGETED: u8 = 0x20;
+pub const MAX_UNIVERSE: u16 = 0x7fff;
+const POLL_REPLY_LEN: usize = 239;
+/// ports per ArtPollReply
+const REPLY_PORTS: usize = 4;
+/// ESTA manufacturer code reserved for prototypes
+const ESTA_PROTOTYPE: u16 = 0x7ff0;
+const OEM_UNKNOWN: u16 = 0x00ff;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ArtnetSettings {
+    pub enabled: bool,
+    /// universes are 15 bit Port-Addresses, 0..=32767
+    #[serde(flatten)]
+    pub mapping: DmxMapping,
+}
+
+impl Default for ArtnetSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mapping: DmxMapping {
+                universe: 0,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl ArtnetSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.mapping
+            .validate(0..=MAX_UNIVERSE)
+            .map_err(|e| anyhow::anyhow!("artnet {e}"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    TooShort(usize),
+    NotArtNet,
+    UnsupportedOpcode(u16),
+    Malformed(&'static str),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::TooShort(len) => write!(f, "packet too short: {len} bytes"),
+            ParseError::NotArtNet => write!(f, "not an Art-Net packet"),
+            ParseError::UnsupportedOpcode(op) => write!(f, "unsupported opcode {op:#06x}"),
+            ParseError::Malformed(what) => write!(f, "malformed packet: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet<'a> {
+    Poll {
+        /// Port-Addresses the controller wants to hear from, if targeted
+        targets: Option<RangeInclusive<u16>>,
+    },
+    Dmx {
+        /// 0 disables the sequence check
+        sequence: u8,
+        universe: u16,
+        data: &'a [u8],
+    },
+}
+
+pub fn parse(buf: &[u8]) -> Result<Packet<'_>, ParseError> {
+    if buf.len() < 12 {
+        return Err(ParseError::TooShort(buf.len()));
+    }
+    if &buf[..8] != ID {
+        return Err(ParseError::NotArtNet);
+    }
+    let opcode = u16::from_le_bytes([buf[8], buf[9]]);
+    // other nodes' ArtPollReplies end up here too
+    if opcode != OP_POLL && opcode != OP_DMX {
+        return Err(ParseError::UnsupportedOpcode(opcode));
+    }
+    if u16::from_be_bytes([buf[10], buf[11]]) < PROTOCOL_VERSION {
+        return Err(ParseError::Malformed("protocol version"));
+    }
+    match opcode {
+        OP_POLL => {
+            if buf.len() < 14 {
+                return Err(ParseError::TooShort(buf.len()));
+            }
+            // Art-Net 3 controllers send no target range
+            let targets = (buf[12] & POLL_TARGETED != 0 && buf.len() >= 18).then(|| {
+                let top = u16::from_be_bytes([buf[14], buf[15]]);
+                let bottom = u16::from_be_bytes([buf[16], buf[17]]);
+                bottom..=top
+            });
+            Ok(Packet::Poll { targets })
+        }
+        OP_DMX => {
+            if buf.len() < 18 {
+                return Err(ParseError::TooShort(buf.len()));
+            }
+            let len = u16::from_be_bytes([buf[16], buf[17]]) as usize;
+            if !(2..=512).contains(&len) || len % 2 != 0 {
+                return Err(ParseError::Malformed("data length"));
+            }
+            let data = buf
+                .get(18..18 + len)
+                .ok_or(ParseError::TooShort(buf.len()))?;
+            Ok(Packet::Dmx {
+                sequence: buf[12],
+                universe: u16::from_le_bytes([buf[14], buf[15] & 0x7f]),
+                data,
+            })
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn put_str(field: &mut [u8], s: &str) {
+    // keep the terminating NUL
+    let len = s.len().min(field.len() - 1);
+    field[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+/// One ArtPollReply per group of up to four universes sharing a Net and
+/// Sub-Net, as the format demands.
+pub fn poll_replies(node: &Node, mapping: &DmxMapping, report: &str) -> Vec<Vec<u8>> {
+    let universes: Vec<u16> = mapping.universes().collect();
+    let mut groups: Vec<Vec<u16>> = vec![];
+    for universe in universes {
+        match groups.last_mut() {
+            Some(group) if group.len() < REPLY_PORTS && group[0] >> 4 == universe >> 4 => {
+                group.push(universe)
+            }
+            _ => groups.push(vec![universe]),
+        }
+    }
+
+    groups
+        .iter()
+        .enumerate()
+        .map(|(idx, group)| {
+            let mut reply = vec![0u8; POLL_REPLY_LEN];
+            reply[..8].copy_from_slice(ID);
+            reply[8..10].copy_from_slice(&OP_POLL_REPLY.to_le_bytes());
+            reply[10..14].copy_from_slice(&node.ip.octets());
+            reply[14..16].copy_from_slice(&PORT.to_le_bytes());
+            reply[18] = (group[0] >> 8) as u8 & 0x7f;
+            reply[19] = (group[0] >> 4) as u8 & 0x0f;
+            reply[20..22].copy_from_slice(&OEM_UNKNOWN.to_be_bytes());
+            // indicators normal, Port-Address set over the network
+            reply[23] = 0xe0;
+            reply[24..26].copy_from_slice(&ESTA_PROTOTYPE.to_le_bytes());
+            put_str(&mut reply[26..44], &node.short_name);
+            put_str(&mut reply[44..108], &node.long_name);
+            put_str(&mut reply[108..172], report);
+            reply[173] = group.len() as u8;
+            for (port, universe) in group.iter().enumerate() {
+                // DMX512 output
+                reply[174 + port] = 0x80;
+                reply[190 + port] = *universe as u8 & 0x0f;
+            }
+            reply[201..207].copy_from_slice(&node.mac);
+            reply[207..211].copy_from_slice(&node.ip.octets());
+            reply[211] = idx as u8 + 1;
+            // 15 bit Port-Addresses, DHCP capable
+            reply[212] = 0x08 | 0x04;
+            reply
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Handled {
+    /// the packet's pixels went into the frame
+    Applied,
+    /// not ours or out of sequence
+    Ignored,
+    /// a controller asked who's there
+    Poll,
+}
+
+/// Sequence bookkeeping and the universe to LED mapping.
+pub struct Receiver {
+    settings: ArtnetSettings,
+    /// last sequence number per universe
+    sequences: Vec<(u16, u8)>,
+}
+
+impl Receiver {
+    pub fn new(settings: ArtnetSettings) -> Self {
+        Self {
+            settings,
+            sequences: vec![],
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        buf: &[u8],
+        now: Instant,
+        frame: &mut Frame,
+    ) -> Result<Handled, ParseError> {
+        let mapping = &self.settings.mapping;
+        match parse(buf)? {
+            Packet::Poll { targets: None } => Ok(Handled::Poll),
+            Packet::Poll {
+                targets: Some(targets),
+            } => match mapping.universes().any(|u| targets.contains(&u)) {
+                true => Ok(Handled::Poll),
+                false => Ok(Handled::Ignored),
+            },
+            Packet::Dmx {
+                sequence,
+                universe,
+                data,
+            } => {
+                if !mapping.contains(universe) {
+                    return Ok(Handled::Ignored);
+                }
+                if sequence != 0 {
+                    match self.sequences.iter_mut().find(|(u, _)| *u == universe) {
+                        Some((_, last)) if !in_sequence(*last, sequence) => {
+                            return Ok(Handled::Ignored)
+                        }
+                        Some((_, last)) => *last = sequence,
+                        None => self.sequences.push((universe, sequence)),
+                    }
+                }
+                mapping.apply(universe, data, frame);
+                frame.commit("artnet", now);
+                Ok(Handled::Applied)
+            }
+        }
+    }
+}
+
+/// Listens on [`PORT`] on its own thread. `node` is asked for the current
+/// address on every poll.
+pub fn serve<F>(settings: ArtnetSettings, node: F, frame: Arc<Mutex<Frame>>) -> anyhow::Result<()>
+where
+    F: Fn() -> Option<Node> + Send + 'static,
+{
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT))?;
+    let mapping = settings.mapping.clone();
+    let mut receiver = Receiver::new(settings);
+
+    thread::Builder::new().stack_size(6144).spawn(move || {
+        let mut buf = [0u8; MAX_PACKET];
+        let mut polls = 0u32;
+        loop {
+            let (n, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    log::warn!("artnet: {e}");
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+            let res = receiver.handle(&buf[..n], Instant::now(), &mut frame.lock().unwrap());
+            match res {
+                Ok(Handled::Poll) => {
+                    polls = polls.wrapping_add(1);
+                    if let Err(e) = reply(&socket, from, &node, &mapping, polls) {
+                        log::warn!("artnet: poll reply: {e}");
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => log::debug!("artnet: {e}"),
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// Unicast to the controller, as Art-Net 4 has it.
+fn reply<F>(
+    socket: &UdpSocket,
+    from: SocketAddr,
+    node: &F,
+    mapping: &DmxMapping,
+    polls: u32,
+) -> anyhow::Result<()>
+where
+    F: Fn() -> Option<Node>,
+{
+    let node = node().ok_or_else(|| anyhow::anyhow!("no address yet"))?;
+    let report = format!("#0001 [{:04}] ok", polls % 10_000);
+    let to = SocketAddr::new(from.ip(), PORT);
+    for reply in poll_replies(&node, mapping, &report) {
+        socket.send_to(&reply, to)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime::LEDS_PER_UNIVERSE as LEDS;
+
+    fn dmx(sequence: u8, universe: u16, data: &[u8]) -> Vec<u8> {
+        let mut buf = ID.to_vec();
+        buf.extend(OP_DMX.to_le_bytes());
+        buf.extend(PROTOCOL_VERSION.to_be_bytes());
+        buf.extend([sequence, 0]);
+        buf.extend(universe.to_le_bytes());
+        buf.extend((data.len() as u16).to_be_bytes());
+        buf.extend(data);
+        buf
+    }
+
+    fn poll(targets: Option<(u16, u16)>) -> Vec<u8> {
+        let mut buf = ID.to_vec();
+        buf.extend(OP_POLL.to_le_bytes());
+        buf.extend(PROTOCOL_VERSION.to_be_bytes());
+        match targets {
+            Some((bottom, top)) => {
+                buf.extend([POLL_TARGETED, 0]);
+                buf.extend(top.to_be_bytes());
+                buf.extend(bottom.to_be_bytes());
+            }
+            None => buf.extend([0, 0]),
+        }
+        buf
+    }
+
+    fn node() -> Node {
+        Node {
+            ip: Ipv4Addr::new(192, 168, 1, 42),
+            mac: [0x02, 0x11, 0x22, 0x33, 0x44, 0x55],
+            short_name: "harlot".to_string(),
+            long_name: "harlot board".to_string(),
+        }
+    }
+
+    fn mapping(universe: u16, led_count: usize) -> DmxMapping {
+        DmxMapping {
+            universe,
+            start_address: 1,
+            led_offset: 0,
+            led_count,
+        }
+    }
+
+    #[test]
+    fn parses_dmx() {
+        let buf = dmx(7, 0x0123, &[1, 2, 3, 4]);
+        let expected = Packet::Dmx {
+            sequence: 7,
+            universe: 0x0123,
+            data: &[1, 2, 3, 4],
+        };
+        assert_eq!(parse(&buf), Ok(expected));
+
+        // only 15 bits are a Port-Address, the rest of a longer packet is ignored
+        let mut buf = dmx(0, 0xffff, &[9; 512]);
+        buf.extend([0xaa; 4]);
+        match parse(&buf) {
+            Ok(Packet::Dmx { universe, data, .. }) => {
+                assert_eq!(universe, MAX_UNIVERSE);
+                assert_eq!(data.len(), 512);
+            }
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_polls() {
+        assert_eq!(parse(&poll(None)), Ok(Packet::Poll { targets: None }));
+        let targets = Some(0x10..=0x1f);
+        assert_eq!(
+            parse(&poll(Some((0x10, 0x1f)))),
+            Ok(Packet::Poll { targets })
+        );
+        // the flag without the range, as some Art-Net 3 controllers send it
+        let mut buf = poll(None);
+        buf[12] = POLL_TARGETED;
+        assert_eq!(parse(&buf), Ok(Packet::Poll { targets: None }));
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        assert_eq!(parse(&[0; 11]), Err(ParseError::TooShort(11)));
+        let mut buf = dmx(0, 1, &[0; 6]);
+        buf[..8].copy_from_slice(b"Art-Net!");
+        assert_eq!(parse(&buf), Err(ParseError::NotArtNet));
+
+        let reply = &poll_replies(&node(), &mapping(1, 10), "")[0];
+        assert_eq!(
+            parse(reply),
+            Err(ParseError::UnsupportedOpcode(OP_POLL_REPLY))
+        );
+
+        let mut buf = dmx(0, 1, &[0; 6]);
+        buf[11] = 13;
+        assert_eq!(parse(&buf), Err(ParseError::Malformed("protocol version")));
+
+        assert_eq!(
+            parse(&dmx(0, 1, &[0; 5])),
+            Err(ParseError::Malformed("data length"))
+        );
+        assert_eq!(
+            parse(&dmx(0, 1, &[])),
+            Err(ParseError::Malformed("data length"))
+        );
+        let mut buf = dmx(0, 1, &[0; 6]);
+        buf.truncate(20);
+        assert_eq!(parse(&buf), Err(ParseError::TooShort(20)));
+        assert_eq!(parse(&buf[..13]), Err(ParseError::TooShort(13)));
+    }
+
+    #[test]
+    fn poll_reply_is_byte_exact() {
+        let replies = poll_replies(&node(), &mapping(0x0123, 400), "#0001 [0001] ok");
+        assert_eq!(replies.len(), 1);
+
+        let mut expected = vec![0u8; POLL_REPLY_LEN];
+        expected[..8].copy_from_slice(b"Art-Net\0");
+        expected[8..10].copy_from_slice(&[0x00, 0x21]);
+        expected[10..14].copy_from_slice(&[192, 168, 1, 42]);
+        expected[14..16].copy_from_slice(&[0x36, 0x19]);
+        // NetSwitch, SubSwitch
+        expected[18..20].copy_from_slice(&[0x01, 0x02]);
+        expected[20..22].copy_from_slice(&[0x00, 0xff]);
+        expected[23] = 0xe0;
+        expected[24..26].copy_from_slice(&[0xf0, 0x7f]);
+        expected[26..32].copy_from_slice(b"harlot");
+        expected[44..56].copy_from_slice(b"harlot board");
+        expected[108..123].copy_from_slice(b"#0001 [0001] ok");
+        // NumPortsLo
+        expected[173] = 3;
+        expected[174..177].copy_from_slice(&[0x80; 3]);
+        // SwOut
+        expected[190..193].copy_from_slice(&[0x3, 0x4, 0x5]);
+        // Style StNode
+        expected[200] = 0x00;
+        expected[201..207].copy_from_slice(&[0x02, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        expected[207..211].copy_from_slice(&[192, 168, 1, 42]);
+        // BindIndex, Status2
+        expected[211] = 1;
+        expected[212] = 0x0c;
+        assert_eq!(replies[0], expected);
+    }
+
+    #[test]
+    fn poll_replies_split_at_four_ports_and_sub_nets() {
+        let mut node = node();
+        node.short_name = "x".repeat(40);
+        // 0x0e..=0x14: a Sub-Net boundary after two universes, then five more
+        let replies = poll_replies(&node, &mapping(0x0e, 7 * LEDS), "");
+        let ports: Vec<_> = replies
+            .iter()
+            .map(|r| (r[18], r[19], r[173], r[190..194].to_vec(), r[211]))
+            .collect();
+        assert_eq!(
+            ports,
+            [
+                (0, 0, 2, vec![0xe, 0xf, 0, 0], 1),
+                (0, 1, 4, vec![0x0, 0x1, 0x2, 0x3], 2),
+                (0, 1, 1, vec![0x4, 0, 0, 0], 3),
+            ]
+        );
+        // names keep their terminating NUL
+        assert_eq!(&replies[0][26..44], [b"x".repeat(17), vec![0]].concat());
+    }
+
+    #[test]
+    fn maps_dmx_and_drops_late_packets() {
+        let mut receiver = Receiver::new(ArtnetSettings {
+            enabled: true,
+            mapping: mapping(2, 200),
+        });
+        let mut frame = Frame::new(200);
+        let now = Instant::now();
+        let mut handle = |buf: &[u8], frame: &mut Frame| receiver.handle(buf, now, frame);
+
+        assert_eq!(
+            handle(&dmx(1, 1, &[1; 6]), &mut frame),
+            Ok(Handled::Ignored)
+        );
+        assert_eq!(frame.updated(), None);
+        assert_eq!(
+            handle(&dmx(10, 2, &[1, 2, 3, 4, 5, 6]), &mut frame),
+            Ok(Handled::Applied)
+        );
+        assert_eq!(frame.pixels()[..3], [[1, 2, 3], [4, 5, 6], [0; 3]]);
+        assert_eq!(
+            frame.live_source(now, Duration::from_secs(1)),
+            Some("artnet")
+        );
+        // second universe starts with LED 170
+        assert_eq!(
+            handle(&dmx(10, 3, &[7, 8, 9, 0]), &mut frame),
+            Ok(Handled::Applied)
+        );
+        assert_eq!(frame.pixels()[LEDS], [7, 8, 9]);
+
+        // a universe's sequence is its own
+        assert_eq!(
+            handle(&dmx(9, 2, &[0; 6]), &mut frame),
+            Ok(Handled::Ignored)
+        );
+        assert_eq!(frame.pixels()[0], [1, 2, 3]);
+        assert_eq!(
+            handle(&dmx(11, 3, &[0; 6]), &mut frame),
+            Ok(Handled::Applied)
+        );
+        // 0 turns the check off
+        assert_eq!(
+            handle(&dmx(0, 2, &[5; 6]), &mut frame),
+            Ok(Handled::Applied)
+        );
+        assert_eq!(frame.pixels()[0], [5, 5, 5]);
+    }
+
+    #[test]
+    fn answers_polls_aimed_at_us() {
+        let mut receiver = Receiver::new(ArtnetSettings {
+            enabled: true,
+            mapping: mapping(0x20, 10),
+        });
+        let mut frame = Frame::new(10);
+        let now = Instant::now();
+        let mut handle = |buf: &[u8]| receiver.handle(buf, now, &mut frame);
+        assert_eq!(handle(&poll(None)), Ok(Handled::Poll));
+        assert_eq!(handle(&poll(Some((0x10, 0x2f)))), Ok(Handled::Poll));
+        assert_eq!(handle(&poll(Some((0x21, 0x2f)))), Ok(Handled::Ignored));
+    }
+}
@@ -0,0 +1,347 @@
+//! Optional API authentication.
+//!
+//! Until an admin password is set everything is open, so a fresh board can
+//! be provisioned. Afterwards every API route but `POST /auth/login` needs a
+//! bearer token: reads take any token, writes an admin one. Tokens are only
+//! kept as SHA-256 hashes, the password as a salted, stretched one. If the
+//! stored state can't be read the API stays locked, rather than open.
+
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    api::ApiError,
+    router::{Layer, Request},
+};
+
+pub const AUTH_FILE: &'static str = "auth.json";
+/// oldest tokens are dropped beyond this
+pub const MAX_TOKENS: usize = 8;
+const HASH_ROUNDS: u32 = 2048;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    ReadOnly,
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PasswordHash {
+    salt: String,
+    rounds: u32,
+    hash: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TokenRecord {
+    hash: String,
+    pub role: Role,
+    #[serde(default)]
+    pub label: String,
+}
+
+/// Body of `POST /auth/login`.
+#[derive(Deserialize, Debug)]
+pub struct Login {
+    pub password: String,
+    #[serde(default = "admin")]
+    pub role: Role,
+    /// shows up nowhere but NVS, handy to tell display tokens apart
+    #[serde(default)]
+    pub label: String,
+}
+
+fn admin() -> Role {
+    Role::Admin
+}
+
+/// Body of `POST /auth/password`.
+#[derive(Deserialize, Debug)]
+pub struct NewPassword {
+    pub password: String,
+}
+
+/// Persisted as JSON in NVS, like the settings.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Auth {
+    password: Option<PasswordHash>,
+    tokens: Vec<TokenRecord>,
+    /// `AUTH_FILE` couldn't be read, so we can't tell whether a password is set
+    #[serde(skip)]
+    locked: bool,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn stretch(salt: &str, password: &str, rounds: u32) -> String {
+    let mut hash = Sha256::new()
+        .chain_update(salt.as_bytes())
+        .chain_update(password.as_bytes())
+        .finalize();
+    for _ in 0..rounds {
+        hash = Sha256::new()
+            .chain_update(hash)
+            .chain_update(password.as_bytes())
+            .finalize();
+    }
+    hex(&hash)
+}
+
+fn token_hash(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// compares without bailing out at the first difference
+fn ct_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// The token of an `Authorization: Bearer <token>` header.
+pub fn bearer(header: &str) -> Option<&str> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim())
+        .filter(|token| !token.is_empty())
+}
+
+/// What a route needs; `None` for routes open to everyone.
+pub fn required_role(path: &str, write: bool) -> Option<Role> {
+    match path {
+        "/auth/login" => None,
+        // only redirects, the stream checks the token itself
+        "/events" => None,
+        // read-only tokens may drop themselves
+        "/auth/logout" => Some(Role::ReadOnly),
+        _ if write => Some(Role::Admin),
+        _ => Some(Role::ReadOnly),
+    }
+}
+
+impl Auth {
+    /// Refuses every request, for when the stored state is unreadable.
+    pub fn locked() -> Self {
+        Self {
+            locked: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.locked || self.password.is_some()
+    }
+
+    /// Sets a new admin password; all issued tokens are revoked.
+    pub fn set_password(&mut self, password: &str, salt: [u8; 16]) -> Result<(), ApiError> {
+        if !(8..=64).contains(&password.len()) {
+            return Err(ApiError::invalid(
+                "/password",
+                "password must be 8..=64 bytes",
+            ));
+        }
+        let salt = hex(&salt);
+        self.password = Some(PasswordHash {
+            hash: stretch(&salt, password, HASH_ROUNDS),
+            salt,
+            rounds: HASH_ROUNDS,
+        });
+        self.tokens.clear();
+        Ok(())
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        match &self.password {
+            Some(p) => ct_eq(&stretch(&p.salt, password, p.rounds), &p.hash),
+            None => false,
+        }
+    }
+
+    /// Stores a token made from `secret` and hands it out; only its hash is kept.
+    pub fn issue(&mut self, role: Role, label: &str, secret: [u8; 32]) -> String {
+        let token = hex(&secret);
+        if self.tokens.len() >= MAX_TOKENS {
+            self.tokens.remove(0);
+        }
+        self.tokens.push(TokenRecord {
+            hash: token_hash(&token),
+            role,
+            label: label.to_string(),
+        });
+        token
+    }
+
+    pub fn revoke(&mut self, token: &str) -> bool {
+        let hash = token_hash(token);
+        let before = self.tokens.len();
+        self.tokens.retain(|t| !ct_eq(&t.hash, &hash));
+        self.tokens.len() != before
+    }
+
+    pub fn verify(&self, token: &str) -> Option<Role> {
+        let hash = token_hash(token);
+        self.tokens
+            .iter()
+            .filter(|t| ct_eq(&t.hash, &hash))
+            .map(|t| t.role)
+            .max()
+    }
+
+    /// Checks an `Authorization` header against what a route requires.
+    pub fn authorize(
+        &self,
+        authorization: Option<&str>,
+        required: Option<Role>,
+    ) -> Result<(), ApiError> {
+        self.authorize_token(authorization.and_then(bearer), required)
+    }
+
+    /// [`authorize`](Self::authorize) for a bare token, e.g. the one a `/ws`
+    /// connection authenticated with.
+    pub fn authorize_token(
+        &self,
+        token: Option<&str>,
+        required: Option<Role>,
+    ) -> Result<(), ApiError> {
+        if self.locked {
+            return Err(ApiError::new(
+                503,
+                "auth state unreadable, the API is locked",
+            ));
+        }
+        let required = match required {
+            Some(required) if self.enabled() => required,
+            _ => return Ok(()),
+        };
+        let role = token
+            .and_then(|token| self.verify(token))
+            .ok_or_else(|| ApiError::new(401, "missing or invalid token"))?;
+        if role < required {
+            return Err(ApiError::new(403, "admin token required"));
+        }
+        Ok(())
+    }
+}
+
+/// [`Layer`] checking every route against [`required_role`].
+pub struct Guard(pub Arc<Mutex<Auth>>);
+
+impl Layer for Guard {
+    fn before(&self, route: &str, req: &Request) -> Result<(), ApiError> {
+        let required = required_role(route, req.method.is_write());
+        self.0
+            .lock()
+            .unwrap()
+            .authorize(req.header("Authorization"), required)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_per_route() {
+        let mut auth = Auth::default();
+        assert!(auth.authorize(None, Some(Role::Admin)).is_ok());
+
+        auth.set_password("correct horse", [1; 16]).unwrap();
+        let reader = auth.issue(Role::ReadOnly, "", [2; 32]);
+        let admin = auth.issue(Role::Admin, "", [3; 32]);
+        let status = |header: Option<&str>, required| {
+            auth.authorize(header, required).err().map(|e| e.status)
+        };
+        assert_eq!(status(None, None), None);
+        assert_eq!(status(None, Some(Role::ReadOnly)), Some(401));
+        assert_eq!(status(Some("Bearer nope"), Some(Role::ReadOnly)), Some(401));
+        let reader = format!("Bearer {reader}");
+        assert_eq!(status(Some(&reader), Some(Role::ReadOnly)), None);
+        assert_eq!(status(Some(&reader), Some(Role::Admin)), Some(403));
+        let admin = format!("bearer  {admin} ");
+        assert_eq!(status(Some(&admin), Some(Role::Admin)), None);
+    }
+
+    #[test]
+    fn an_unreadable_state_locks_everything() {
+        let auth = Auth::locked();
+        assert!(auth.enabled());
+        assert!(!auth.check_password(""));
+        for required in [None, Some(Role::ReadOnly), Some(Role::Admin)] {
+            let e = auth.authorize(Some("Bearer x"), required).unwrap_err();
+            assert_eq!(e.status, 503);
+        }
+    }
+
+    #[test]
+    fn a_wrong_password_is_rejected() {
+        let mut auth = Auth::default();
+        assert!(!auth.check_password("correct horse"));
+        assert_eq!(auth.set_password("short", [1; 16]).unwrap_err().status, 400);
+        assert!(!auth.enabled());
+
+        auth.set_password("correct horse", [1; 16]).unwrap();
+        assert!(auth.enabled());
+        assert!(auth.check_password("correct horse"));
+        assert!(!auth.check_password("correct horsE"));
+        assert!(!auth.check_password("correct horse "));
+        assert!(!auth.check_password(""));
+    }
+
+    #[test]
+    fn a_new_password_revokes_every_token() {
+        let mut auth = Auth::default();
+        auth.set_password("correct horse", [1; 16]).unwrap();
+        let reader = auth.issue(Role::ReadOnly, "display", [2; 32]);
+        let admin = auth.issue(Role::Admin, "", [3; 32]);
+        assert_eq!(auth.verify(&reader), Some(Role::ReadOnly));
+        assert_eq!(auth.verify(&admin), Some(Role::Admin));
+
+        auth.set_password("battery staple", [4; 16]).unwrap();
+        assert_eq!(auth.verify(&reader), None);
+        assert_eq!(auth.verify(&admin), None);
+        assert!(!auth.check_password("correct horse"));
+        assert!(auth.check_password("battery staple"));
+        let header = format!("Bearer {admin}");
+        let e = auth
+            .authorize(Some(&header), Some(Role::ReadOnly))
+            .unwrap_err();
+        assert_eq!(e.status, 401);
+    }
+
+    #[test]
+    fn revoked_tokens_stop_working() {
+        let mut auth = Auth::default();
+        auth.set_password("correct horse", [1; 16]).unwrap();
+        let kept = auth.issue(Role::Admin, "", [2; 32]);
+        let dropped = auth.issue(Role::Admin, "", [3; 32]);
+
+        assert!(auth.revoke(&dropped));
+        assert_eq!(auth.verify(&dropped), None);
+        assert!(!auth.revoke(&dropped));
+        assert!(!auth.revoke("not a token"));
+        assert_eq!(auth.verify(&kept), Some(Role::Admin));
+    }
+
+    #[test]
+    fn the_oldest_token_makes_room() {
+        let mut auth = Auth::default();
+        auth.set_password("correct horse", [1; 16]).unwrap();
+        let tokens: Vec<_> = (0..=MAX_TOKENS as u8)
+            .map(|i| auth.issue(Role::ReadOnly, "", [i; 32]))
+            .collect();
+        assert_eq!(auth.tokens.len(), MAX_TOKENS);
+        assert_eq!(auth.verify(&tokens[0]), None);
+        for token in &tokens[1..] {
+            assert_eq!(auth.verify(token), Some(Role::ReadOnly));
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Bounded request body handling: nothing gets buffered or parsed beyond the
+//! configured maximum body size.
+
+use std::io;
+
+use embedded_svc::io::Read;
+use serde::de::DeserializeOwned;
+
+use crate::api::ApiError;
+
+pub const DEFAULT_MAX_BODY: usize = 8 * 1024;
+
+/// Adapts an `embedded_svc` reader to `std::io::Read`, for `serde_json`.
+pub struct StdReader<R>(pub R);
+
+impl<R: Read> io::Read for StdReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut self.0, buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("body read failed: {e:?}")))
+    }
+}
+
+/// Fails the read once more than `limit` bytes came through.
+pub struct Limited<R> {
+    inner: R,
+    remaining: usize,
+    exceeded: bool,
+}
+
+impl<R> Limited<R> {
+    pub fn new(inner: R, limit: usize) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+            exceeded: false,
+        }
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+}
+
+impl<R: io::Read> io::Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // ask for one byte more than allowed, so hitting the limit exactly
+        // is told apart from going over it
+        let max = buf.len().min(self.remaining + 1);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n > self.remaining {
+            self.exceeded = true;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request body too large",
+            ));
+        }
+        self.remaining -= n;
+        Ok(n)
+    }
+}
+
+fn too_large(limit: usize) -> ApiError {
+    ApiError::new(413, format!("request body exceeds {limit} bytes"))
+}
+
+/// Rejects a declared `Content-Length` over the limit before anything is read.
+pub fn check_len(content_len: Option<usize>, limit: usize) -> Result<(), ApiError> {
+    match content_len {
+        Some(len) if len > limit => Err(too_large(limit)),
+        _ => Ok(()),
+    }
+}
+
+/// Parses JSON straight from the reader, without buffering the body.
+pub fn parse_json<T, R>(reader: R, content_len: Option<usize>, limit: usize) -> Result<T, ApiError>
+where
+    T: DeserializeOwned,
+    R: io::Read,
+{
+    check_len(content_len, limit)?;
+    let mut limited = Limited::new(reader, limit);
+    serde_json::from_reader(&mut limited).map_err(|e| {
+        if limited.exceeded() {
+            too_large(limit)
+        } else {
+            ApiError::from(e)
+        }
+    })
+}
+
+/// Reads the whole body, for handlers that need the raw bytes.
+pub fn read_all<R: io::Read>(reader: R, content_len: Option<usize>, limit: usize) -> Result<Vec<u8>, ApiError> {
+    check_len(content_len, limit)?;
+    let mut limited = Limited::new(reader, limit);
+    let mut buf = Vec::with_capacity(content_len.unwrap_or_default());
+    io::Read::read_to_end(&mut limited, &mut buf).map_err(|e| {
+        if limited.exceeded() {
+            too_large(limit)
+        } else {
+            ApiError::bad_request(e.to_string())
+        }
+    })?;
+    Ok(buf)
+}
@@ -0,0 +1,105 @@
+//! CORS policy, applied by the router to every response, and preflights.
+
+use serde::{Deserialize, Serialize};
+
+use crate::router::{Layer, Method, Request, Response};
+
+/// request headers browser apps may send
+pub const ALLOWED_HEADERS: &'static str =
+    "Authorization, Content-Type, If-Match, If-None-Match, X-Firmware-SHA256";
+/// response headers browser apps may read
+pub const EXPOSED_HEADERS: &'static str = "ETag";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Cors {
+    /// origins like `http://192.168.1.20:8080`; `*` allows any
+    pub allowed_origins: Vec<String>,
+    /// how long browsers may cache a preflight, in seconds
+    pub max_age: u32,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec!["*".into()],
+            max_age: 600,
+        }
+    }
+}
+
+impl Cors {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.allowed_origins.len() > 8 {
+            anyhow::bail!("at most 8 allowed origins");
+        }
+        for origin in &self.allowed_origins {
+            if origin == "*" {
+                continue;
+            }
+            let host = origin
+                .strip_prefix("http://")
+                .or_else(|| origin.strip_prefix("https://"))
+                .ok_or_else(|| {
+                    anyhow::anyhow!("origin {origin:?} must start with http:// or https://")
+                })?;
+            if host.is_empty() || host.contains('/') {
+                anyhow::bail!("origin {origin:?} must be scheme://host[:port], without a path");
+            }
+        }
+        if self.max_age > 86400 {
+            anyhow::bail!("max_age must be at most 86400 seconds");
+        }
+        Ok(())
+    }
+
+    fn any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|o| o == "*")
+    }
+
+    /// The `Access-Control-Allow-Origin` value for a request from `origin`,
+    /// if it may read the response.
+    pub fn allow_origin(&self, origin: Option<&str>) -> Option<String> {
+        if self.any_origin() {
+            return Some("*".into());
+        }
+        let origin = origin?;
+        self.allowed_origins
+            .iter()
+            .any(|o| o.eq_ignore_ascii_case(origin))
+            .then(|| origin.to_string())
+    }
+}
+
+impl Layer for Cors {
+    fn after(&self, _route: &str, req: &Request, res: &mut Response) {
+        // the answer depends on the origin unless every origin gets it
+        if !self.any_origin() {
+            res.headers.push(("Vary".into(), "Origin".into()));
+        }
+        let origin = match self.allow_origin(req.header("Origin")) {
+            Some(origin) => origin,
+            None => return,
+        };
+        res.headers
+            .push(("Access-Control-Allow-Origin".into(), origin));
+        res.headers.push((
+            "Access-Control-Expose-Headers".into(),
+            EXPOSED_HEADERS.into(),
+        ));
+
+        let preflight =
+            req.method == Method::Options && req.header("Access-Control-Request-Method").is_some();
+        if preflight {
+            let methods = res.get_header("Allow").unwrap_or_default().to_string();
+            res.headers
+                .push(("Access-Control-Allow-Methods".into(), methods));
+            res.headers.push((
+                "Access-Control-Allow-Headers".into(),
+                ALLOWED_HEADERS.into(),
+            ));
+            res.headers
+                .push(("Access-Control-Max-Age".into(), self.max_age.to_string()));
+        }
+    }
+}
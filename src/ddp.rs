@@ -0,0 +1,480 @@
+//! DDP (Distributed Display Protocol) receiver.
+//!
+//! Packets carry RGB bytes at a byte offset into the display. They are
+//! collected in a buffer of their own and only shown once a packet with the
+//! push flag arrives, so a frame split over several packets shows up whole.
+//! Status and config queries are answered with JSON.
+
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::realtime::{Frame, Node};
+
+pub const PORT: u16 = 4048;
+/// header with timecode plus a full Ethernet frame of data
+pub const MAX_PACKET: usize = 14 + 1440;
+const HEADER_LEN: usize = 10;
+const VERSION_MASK: u8 = 0xc0;
+const VERSION_1: u8 = 0x40;
+const FLAG_TIMECODE: u8 = 0x10;
+const FLAG_STORAGE: u8 = 0x08;
+const FLAG_REPLY: u8 = 0x04;
+const FLAG_QUERY: u8 = 0x02;
+const FLAG_PUSH: u8 = 0x01;
+/// undefined, the legacy RGB code, and RGB with 8 bit channels
+const RGB_TYPES: [u8; 3] = [0x00, 0x01, 0x0b];
+const ID_DISPLAY: u8 = 1;
+const ID_CONFIG: u8 = 250;
+const ID_STATUS: u8 = 251;
+const ID_ALL: u8 = 255;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct DdpSettings {
+    pub enabled: bool,
+    /// LED the stream's offset 0 lands on
+    pub led_offset: usize,
+    pub led_count: usize,
+}
+
+impl Default for DdpSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            led_offset: 0,
+            led_count: 512,
+        }
+    }
+}
+
+impl DdpSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(1..=4096).contains(&self.led_count) {
+            anyhow::bail!("ddp led_count must be 1..=4096");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    TooShort(usize),
+    UnsupportedVersion(u8),
+    Malformed(&'static str),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::TooShort(len) => write!(f, "packet too short: {len} bytes"),
+            ParseError::UnsupportedVersion(v) => write!(f, "unsupported version {v}"),
+            ParseError::Malformed(what) => write!(f, "malformed packet: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet<'a> {
+    pub flags: u8,
+    /// 1..=15, 0 when unused
+    pub sequence: u8,
+    pub data_type: u8,
+    pub id: u8,
+    /// in bytes
+    pub offset: u32,
+    pub data: &'a [u8],
+}
+
+impl Packet<'_> {
+    pub fn push(&self) -> bool {
+        self.flags & FLAG_PUSH != 0
+    }
+
+    pub fn query(&self) -> bool {
+        self.flags & FLAG_QUERY != 0
+    }
+
+    pub fn reply(&self) -> bool {
+        self.flags & FLAG_REPLY != 0
+    }
+}
+
+pub fn parse(buf: &[u8]) -> Result<Packet<'_>, ParseError> {
+    if buf.len() < HEADER_LEN {
+        return Err(ParseError::TooShort(buf.len()));
+    }
+    let flags = buf[0];
+    if flags & VERSION_MASK != VERSION_1 {
+        return Err(ParseError::UnsupportedVersion(flags >> 6));
+    }
+    let header_len = match flags & FLAG_TIMECODE {
+        0 => HEADER_LEN,
+        _ => HEADER_LEN + 4,
+    };
+    let len = u16::from_be_bytes([buf[8], buf[9]]) as usize;
+    let data = buf
+        .get(header_len..header_len + len)
+        .ok_or(ParseError::TooShort(buf.len()))?;
+
+    Ok(Packet {
+        flags,
+        sequence: buf[1] & 0x0f,
+        data_type: buf[2],
+        id: buf[3],
+        offset: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+        data,
+    })
+}
+
+/// A reply from us to a query, `data` being JSON.
+pub fn reply_packet(id: u8, sequence: u8, data: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0u8; HEADER_LEN];
+    packet[0] = VERSION_1 | FLAG_REPLY | FLAG_PUSH;
+    packet[1] = sequence;
+    packet[3] = id;
+    packet[8..10].copy_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+    packet
+}
+
+pub fn status_json(node: &Node) -> serde_json::Value {
+    json!({
+        "status": {
+            "man": "harlot",
+            "mod": node.long_name,
+            "ver": env!("CARGO_PKG_VERSION"),
+            "mac": node.mac.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>().join(":"),
+            "push": true,
+            "ntp": false,
+        }
+    })
+}
+
+pub fn config_json(node: &Node, settings: &DdpSettings) -> serde_json::Value {
+    json!({
+        "config": {
+            "ip": node.ip.to_string(),
+            "ports": [{ "port": 0, "ts": 0, "l": settings.led_count, "ss": 0 }],
+        }
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Handled {
+    /// kept until the next push
+    Buffered,
+    /// the buffer went into the frame
+    Pushed,
+    /// not for us, not RGB or entirely out of range
+    Ignored,
+    /// asks for our status or config, by id
+    Query(u8),
+}
+
+/// The frame being assembled.
+pub struct Receiver {
+    settings: DdpSettings,
+    pixels: Vec<u8>,
+}
+
+impl Receiver {
+    pub fn new(settings: DdpSettings) -> Self {
+        Self {
+            pixels: vec![0; settings.led_count * 3],
+            settings,
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        buf: &[u8],
+        now: Instant,
+        frame: &mut Frame,
+    ) -> Result<Handled, ParseError> {
+        let packet = parse(buf)?;
+        // other devices' answers, or writes to their storage
+        if packet.reply() || packet.flags & FLAG_STORAGE != 0 {
+            return Ok(Handled::Ignored);
+        }
+        if packet.query() {
+            return Ok(match packet.id {
+                ID_CONFIG | ID_STATUS => Handled::Query(packet.id),
+                _ => Handled::Ignored,
+            });
+        }
+        if packet.id != ID_DISPLAY && packet.id != ID_ALL {
+            return Ok(Handled::Ignored);
+        }
+        if !RGB_TYPES.contains(&packet.data_type) {
+            return Ok(Handled::Ignored);
+        }
+
+        // whatever sticks out past our LEDs is dropped
+        let offset = packet.offset as usize;
+        let written = match self.pixels.get_mut(offset..) {
+            Some(tail) if !tail.is_empty() || packet.data.is_empty() => {
+                let len = tail.len().min(packet.data.len());
+                tail[..len].copy_from_slice(&packet.data[..len]);
+                true
+            }
+            _ => false,
+        };
+        if !packet.push() {
+            return Ok(if written {
+                Handled::Buffered
+            } else {
+                Handled::Ignored
+            });
+        }
+
+        for (i, rgb) in self.pixels.chunks_exact(3).enumerate() {
+            frame.set(self.settings.led_offset + i, [rgb[0], rgb[1], rgb[2]]);
+        }
+        frame.commit("ddp", now);
+        Ok(Handled::Pushed)
+    }
+}
+
+/// Listens on [`PORT`] on its own thread. `node` is asked for the current
+/// address on every query.
+pub fn serve<F>(settings: DdpSettings, node: F, frame: Arc<Mutex<Frame>>) -> anyhow::Result<()>
+where
+    F: Fn() -> Option<Node> + Send + 'static,
+{
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT))?;
+    let mut receiver = Receiver::new(settings.clone());
+
+    thread::Builder::new().stack_size(6144).spawn(move || {
+        let mut buf = [0u8; MAX_PACKET];
+        loop {
+            let (n, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    log::warn!("ddp: {e}");
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+            let res = receiver.handle(&buf[..n], Instant::now(), &mut frame.lock().unwrap());
+            match res {
+                Ok(Handled::Query(id)) => {
+                    let sequence = buf[1] & 0x0f;
+                    if let Err(e) = reply(&socket, from, id, sequence, &node, &settings) {
+                        log::warn!("ddp: query reply: {e}");
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => log::debug!("ddp: {e}"),
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn reply<F>(
+    socket: &UdpSocket,
+    to: SocketAddr,
+    id: u8,
+    sequence: u8,
+    node: &F,
+    settings: &DdpSettings,
+) -> anyhow::Result<()>
+where
+    F: Fn() -> Option<Node>,
+{
+    let node = node().ok_or_else(|| anyhow::anyhow!("no address yet"))?;
+    let data = match id {
+        ID_CONFIG => config_json(&node, settings),
+        _ => status_json(&node),
+    };
+    let packet = reply_packet(id, sequence, &serde_json::to_vec(&data)?);
+    socket.send_to(&packet, to)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(flags: u8, offset: u32, data: &[u8]) -> Vec<u8> {
+        let mut buf = vec![VERSION_1 | flags, 3, 0x0b, ID_DISPLAY];
+        buf.extend(offset.to_be_bytes());
+        buf.extend((data.len() as u16).to_be_bytes());
+        if flags & FLAG_TIMECODE != 0 {
+            buf.extend([0xaa; 4]);
+        }
+        buf.extend(data);
+        buf
+    }
+
+    fn receiver(led_offset: usize, led_count: usize) -> Receiver {
+        Receiver::new(DdpSettings {
+            enabled: true,
+            led_offset,
+            led_count,
+        })
+    }
+
+    #[test]
+    fn parses_with_and_without_timecode() {
+        let buf = packet(FLAG_PUSH, 0x0102_0304, &[1, 2, 3]);
+        let parsed = parse(&buf).unwrap();
+        assert_eq!(
+            parsed,
+            Packet {
+                flags: VERSION_1 | FLAG_PUSH,
+                sequence: 3,
+                data_type: 0x0b,
+                id: ID_DISPLAY,
+                offset: 0x0102_0304,
+                data: &[1, 2, 3],
+            }
+        );
+        assert!(parsed.push() && !parsed.query() && !parsed.reply());
+
+        let buf = packet(FLAG_TIMECODE, 6, &[4, 5, 6]);
+        assert_eq!(parse(&buf).unwrap().data, &[4, 5, 6]);
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        assert_eq!(parse(&[0x41; 9]), Err(ParseError::TooShort(9)));
+        let mut buf = packet(0, 0, &[1, 2, 3]);
+        buf[0] = 0x80;
+        assert_eq!(parse(&buf), Err(ParseError::UnsupportedVersion(2)));
+        let buf = packet(0, 0, &[1, 2, 3]);
+        assert_eq!(parse(&buf[..12]), Err(ParseError::TooShort(12)));
+        // the timecode takes room from the data
+        let mut buf = packet(0, 0, &[1, 2, 3]);
+        buf[0] |= FLAG_TIMECODE;
+        assert_eq!(parse(&buf), Err(ParseError::TooShort(13)));
+    }
+
+    #[test]
+    fn shows_a_fragmented_frame_on_push_only() {
+        let mut receiver = receiver(2, 4);
+        let mut frame = Frame::new(8);
+        let now = Instant::now();
+
+        let first = packet(0, 0, &[1, 1, 1, 2, 2, 2, 3]);
+        assert_eq!(
+            receiver.handle(&first, now, &mut frame),
+            Ok(Handled::Buffered)
+        );
+        assert_eq!(frame.updated(), None);
+        assert_eq!(frame.pixels(), [[0; 3]; 8]);
+
+        let last = packet(FLAG_PUSH, 7, &[3, 3, 4, 4, 4]);
+        assert_eq!(receiver.handle(&last, now, &mut frame), Ok(Handled::Pushed));
+        assert_eq!(frame.live_source(now, Duration::from_secs(1)), Some("ddp"));
+        let expected = [
+            [0; 3], [0; 3], [1; 3], [2; 3], [3; 3], [4; 3], [0; 3], [0; 3],
+        ];
+        assert_eq!(frame.pixels(), expected);
+
+        // the buffer is kept, a later push only needs the changed part
+        let update = packet(FLAG_PUSH, 3, &[9, 9, 9]);
+        assert_eq!(
+            receiver.handle(&update, now, &mut frame),
+            Ok(Handled::Pushed)
+        );
+        assert_eq!(frame.pixels()[2..6], [[1; 3], [9; 3], [3; 3], [4; 3]]);
+    }
+
+    #[test]
+    fn drops_what_lies_past_the_leds() {
+        let mut receiver = receiver(0, 2);
+        let mut frame = Frame::new(2);
+        let now = Instant::now();
+
+        // straddling the end, the rest is cut off
+        let buf = packet(0, 3, &[5, 5, 5, 6, 6, 6]);
+        assert_eq!(
+            receiver.handle(&buf, now, &mut frame),
+            Ok(Handled::Buffered)
+        );
+        for offset in [6, 1000, u32::MAX] {
+            let buf = packet(0, offset, &[7; 3]);
+            assert_eq!(receiver.handle(&buf, now, &mut frame), Ok(Handled::Ignored));
+        }
+        // a push still shows what was buffered so far
+        let buf = packet(FLAG_PUSH, u32::MAX, &[7; 3]);
+        assert_eq!(receiver.handle(&buf, now, &mut frame), Ok(Handled::Pushed));
+        assert_eq!(frame.pixels(), [[0; 3], [5; 3]]);
+    }
+
+    #[test]
+    fn ignores_what_isnt_rgb_for_us() {
+        let mut receiver = receiver(0, 2);
+        let mut frame = Frame::new(2);
+        let now = Instant::now();
+        let mut handle = |buf: &[u8]| receiver.handle(buf, now, &mut frame);
+
+        let mut other_id = packet(FLAG_PUSH, 0, &[1; 6]);
+        other_id[3] = 2;
+        assert_eq!(handle(&other_id), Ok(Handled::Ignored));
+        let mut everyone = other_id.clone();
+        everyone[3] = ID_ALL;
+        assert_eq!(handle(&everyone), Ok(Handled::Pushed));
+        let mut rgbw = packet(FLAG_PUSH, 0, &[1; 8]);
+        rgbw[2] = 0x1b;
+        assert_eq!(handle(&rgbw), Ok(Handled::Ignored));
+        assert_eq!(
+            handle(&packet(FLAG_REPLY | FLAG_PUSH, 0, &[1; 6])),
+            Ok(Handled::Ignored)
+        );
+        assert_eq!(
+            handle(&packet(FLAG_STORAGE | FLAG_PUSH, 0, &[1; 6])),
+            Ok(Handled::Ignored)
+        );
+    }
+
+    #[test]
+    fn answers_status_and_config_queries() {
+        let mut receiver = receiver(0, 2);
+        let mut frame = Frame::new(2);
+        let now = Instant::now();
+        for (id, handled) in [
+            (ID_STATUS, Handled::Query(ID_STATUS)),
+            (ID_CONFIG, Handled::Query(ID_CONFIG)),
+            (ID_DISPLAY, Handled::Ignored),
+        ] {
+            let mut query = packet(FLAG_QUERY, 0, &[]);
+            query[3] = id;
+            assert_eq!(receiver.handle(&query, now, &mut frame), Ok(handled));
+        }
+
+        let node = Node {
+            ip: Ipv4Addr::new(10, 0, 0, 7),
+            mac: [0xde, 0xad, 0xbe, 0xef, 0x00, 0x01],
+            short_name: "harlot".to_string(),
+            long_name: "harlot board".to_string(),
+        };
+        let json = serde_json::to_vec(&status_json(&node)).unwrap();
+        let reply = reply_packet(ID_STATUS, 5, &json);
+        let parsed = parse(&reply).unwrap();
+        assert!(parsed.reply() && parsed.push());
+        assert_eq!((parsed.id, parsed.sequence), (ID_STATUS, 5));
+        let status: serde_json::Value = serde_json::from_slice(parsed.data).unwrap();
+        assert_eq!(status["status"]["mac"], "de:ad:be:ef:00:01");
+
+        let settings = DdpSettings {
+            enabled: true,
+            led_offset: 0,
+            led_count: 300,
+        };
+        let config = config_json(&node, &settings);
+        assert_eq!(config["config"]["ip"], "10.0.0.7");
+        assert_eq!(config["config"]["ports"][0]["l"], 300);
+    }
+}
@@ -0,0 +1,100 @@
+//! [`status`](crate::status) providers that need to talk to ESP-IDF.
+
+use std::{
+    ptr::null,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use embedded_svc::wifi::{self, ClientConnectionStatus, ClientIpStatus, ClientStatus, Wifi as _};
+use esp_idf_svc::wifi::EspWifi;
+use esp_idf_sys::*;
+use serde_json::{json, Value};
+
+use crate::status::{from_fn, Provider};
+
+pub fn system(sys_start: Instant) -> Box<dyn Provider> {
+    from_fn("system", move || {
+        json!({
+            "uptime_ms": sys_start.elapsed().as_millis() as u64,
+            "reset_reason": reset_reason(),
+        })
+    })
+}
+
+fn reset_reason() -> &'static str {
+    #[allow(non_upper_case_globals)]
+    match unsafe { esp_reset_reason() } {
+        esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        esp_reset_reason_t_ESP_RST_EXT => "external",
+        esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt_watchdog",
+        esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
+
+pub fn heap() -> Box<dyn Provider> {
+    from_fn("heap", || {
+        json!({
+            "free": unsafe { esp_get_free_heap_size() },
+            "min_free": unsafe { esp_get_minimum_free_heap_size() },
+        })
+    })
+}
+
+pub fn nvs() -> Box<dyn Provider> {
+    from_fn("nvs", || {
+        let mut stats = nvs_stats_t::default();
+        // null means the default "nvs" partition
+        if esp!(unsafe { nvs_get_stats(null(), &mut stats) }).is_err() {
+            return Value::Null;
+        }
+        json!({
+            "used_entries": stats.used_entries,
+            "free_entries": stats.free_entries,
+            "total_entries": stats.total_entries,
+            "namespaces": stats.namespace_count,
+        })
+    })
+}
+
+/// Doesn't wait for the Wi-Fi lock, a background connect may hold it for a
+/// while.
+pub fn wifi(wifi: Arc<Mutex<Box<EspWifi>>>) -> Box<dyn Provider> {
+    from_fn("wifi", move || {
+        let wifi = match wifi.try_lock() {
+            Ok(wifi) => wifi,
+            Err(_) => return json!({ "mode": "busy" }),
+        };
+        let mode = match wifi.get_configuration() {
+            Ok(wifi::Configuration::Client(_)) => "station",
+            Ok(wifi::Configuration::AccessPoint(_)) => "access_point",
+            Ok(wifi::Configuration::Mixed(_, _)) => "mixed",
+            _ => "none",
+        };
+        let ip = match wifi.get_status() {
+            wifi::Status(
+                ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(
+                    ip_settings,
+                ))),
+                _,
+            ) => Some(ip_settings.ip.to_string()),
+            _ => None,
+        };
+        let mut ap_info = wifi_ap_record_t::default();
+        let rssi = (ip.is_some() && esp!(unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) }).is_ok())
+            .then(|| ap_info.rssi);
+
+        json!({
+            "mode": mode,
+            "ip": ip,
+            "rssi": rssi,
+        })
+    })
+}
@@ -0,0 +1,498 @@
+//! E1.31 (streaming ACN) receiver.
+//!
+//! DMX data is mapped onto the strip by a [`DmxMapping`]. Per universe the
+//! highest priority source wins; its packets are checked for sequence.
+
+use std::{
+    fmt,
+    net::{Ipv4Addr, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::realtime::{in_sequence, DmxMapping, Frame};
+
+pub const PORT: u16 = 5568;
+/// largest E1.31 data packet: 126 header bytes plus 512 slots
+pub const MAX_PACKET: usize = 638;
+const HEADER_LEN: usize = 126;
+const ACN_PID: &[u8; 12] = b"ASC-E1.17\0\0\0";
+const VECTOR_ROOT_DATA: u32 = 0x0000_0004;
+const VECTOR_FRAMING_DATA: u32 = 0x0000_0002;
+const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+const OPTION_PREVIEW: u8 = 0x80;
+const OPTION_TERMINATED: u8 = 0x40;
+pub const MAX_UNIVERSE: u16 = 63999;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct E131Settings {
+    pub enabled: bool,
+    /// universes are 1..=63999
+    #[serde(flatten)]
+    pub mapping: DmxMapping,
+    /// join the universes' multicast groups; unicast always works
+    pub multicast: bool,
+}
+
+impl Default for E131Settings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mapping: DmxMapping::default(),
+            multicast: true,
+        }
+    }
+}
+
+impl E131Settings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.mapping
+            .validate(1..=MAX_UNIVERSE)
+            .map_err(|e| anyhow::anyhow!("e131 {e}"))
+    }
+}
+
+pub fn multicast_group(universe: u16) -> Ipv4Addr {
+    let [hi, lo] = universe.to_be_bytes();
+    Ipv4Addr::new(239, 255, hi, lo)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    TooShort(usize),
+    NotAcn,
+    /// synchronization and discovery packets aren't supported
+    UnsupportedVector(u32),
+    Malformed(&'static str),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::TooShort(len) => write!(f, "packet too short: {len} bytes"),
+            ParseError::NotAcn => write!(f, "not an ACN packet"),
+            ParseError::UnsupportedVector(v) => write!(f, "unsupported vector {v:#010x}"),
+            ParseError::Malformed(what) => write!(f, "malformed packet: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet<'a> {
+    pub cid: [u8; 16],
+    pub source_name: &'a str,
+    pub priority: u8,
+    pub sequence: u8,
+    pub preview: bool,
+    pub terminated: bool,
+    pub universe: u16,
+    pub start_code: u8,
+    /// DMX slots after the start code
+    pub data: &'a [u8],
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+pub fn parse(buf: &[u8]) -> Result<Packet<'_>, ParseError> {
+    if buf.len() < 22 {
+        return Err(ParseError::TooShort(buf.len()));
+    }
+    if u16_at(buf, 0) != 0x0010 || u16_at(buf, 2) != 0 || &buf[4..16] != ACN_PID {
+        return Err(ParseError::NotAcn);
+    }
+    match u32_at(buf, 18) {
+        VECTOR_ROOT_DATA => {}
+        v => return Err(ParseError::UnsupportedVector(v)),
+    }
+    if buf.len() < HEADER_LEN {
+        return Err(ParseError::TooShort(buf.len()));
+    }
+    if u32_at(buf, 40) != VECTOR_FRAMING_DATA {
+        return Err(ParseError::Malformed("framing vector"));
+    }
+    if buf[117] != VECTOR_DMP_SET_PROPERTY || buf[118] != 0xa1 {
+        return Err(ParseError::Malformed("DMP vector or address type"));
+    }
+    if u16_at(buf, 119) != 0 || u16_at(buf, 121) != 1 {
+        return Err(ParseError::Malformed("DMP addressing"));
+    }
+    let count = u16_at(buf, 123) as usize;
+    if count == 0 || count > 513 || HEADER_LEN - 1 + count > buf.len() {
+        return Err(ParseError::Malformed("property value count"));
+    }
+    let universe = u16_at(buf, 113);
+    if !(1..=MAX_UNIVERSE).contains(&universe) {
+        return Err(ParseError::Malformed("universe"));
+    }
+
+    let name = &buf[44..108];
+    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+    let mut cid = [0; 16];
+    cid.copy_from_slice(&buf[22..38]);
+
+    Ok(Packet {
+        cid,
+        source_name: std::str::from_utf8(name).unwrap_or_default(),
+        priority: buf[108],
+        sequence: buf[111],
+        preview: buf[112] & OPTION_PREVIEW != 0,
+        terminated: buf[112] & OPTION_TERMINATED != 0,
+        universe,
+        start_code: buf[125],
+        data: &buf[HEADER_LEN..HEADER_LEN - 1 + count],
+    })
+}
+
+struct Source {
+    cid: [u8; 16],
+    universe: u16,
+    priority: u8,
+    sequence: u8,
+    seen: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Handled {
+    /// the packet's pixels went into the frame
+    Applied,
+    /// not ours, out of sequence, outranked or not DMX
+    Ignored,
+    /// the last source of the strip went away
+    Released,
+}
+
+/// Source bookkeeping and the universe to LED mapping.
+pub struct Receiver {
+    settings: E131Settings,
+    timeout: Duration,
+    sources: Vec<Source>,
+}
+
+impl Receiver {
+    pub fn new(settings: E131Settings, timeout: Duration) -> Self {
+        Self {
+            settings,
+            timeout,
+            sources: vec![],
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        buf: &[u8],
+        now: Instant,
+        frame: &mut Frame,
+    ) -> Result<Handled, ParseError> {
+        let packet = parse(buf)?;
+        if !self.settings.mapping.contains(packet.universe) {
+            return Ok(Handled::Ignored);
+        }
+        if packet.preview || packet.start_code != 0 {
+            return Ok(Handled::Ignored);
+        }
+
+        let timeout = self.timeout;
+        self.sources
+            .retain(|s| now.saturating_duration_since(s.seen) < timeout);
+
+        let known = self
+            .sources
+            .iter_mut()
+            .find(|s| s.cid == packet.cid && s.universe == packet.universe);
+        match known {
+            Some(source) => {
+                if !in_sequence(source.sequence, packet.sequence) {
+                    return Ok(Handled::Ignored);
+                }
+                source.sequence = packet.sequence;
+                source.priority = packet.priority;
+                source.seen = now;
+            }
+            None => {
+                log::info!(
+                    "e131: source {:?} on universe {}",
+                    packet.source_name,
+                    packet.universe
+                );
+                self.sources.push(Source {
+                    cid: packet.cid,
+                    universe: packet.universe,
+                    priority: packet.priority,
+                    sequence: packet.sequence,
+                    seen: now,
+                })
+            }
+        }
+
+        if packet.terminated {
+            self.sources
+                .retain(|s| !(s.cid == packet.cid && s.universe == packet.universe));
+            if self.sources.is_empty() {
+                frame.release();
+                return Ok(Handled::Released);
+            }
+            return Ok(Handled::Ignored);
+        }
+
+        let top = self
+            .sources
+            .iter()
+            .filter(|s| s.universe == packet.universe)
+            .map(|s| s.priority)
+            .max()
+            .unwrap_or_default();
+        if packet.priority < top {
+            return Ok(Handled::Ignored);
+        }
+
+        self.settings
+            .mapping
+            .apply(packet.universe, packet.data, frame);
+        frame.commit("e131", now);
+        Ok(Handled::Applied)
+    }
+}
+
+/// Listens on [`PORT`] on its own thread.
+pub fn serve(
+    settings: E131Settings,
+    timeout: Duration,
+    frame: Arc<Mutex<Frame>>,
+) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT))?;
+    if settings.multicast {
+        for universe in settings.mapping.universes() {
+            // e.g. no IGMP in access point mode; unicast still works
+            if let Err(e) =
+                socket.join_multicast_v4(&multicast_group(universe), &Ipv4Addr::UNSPECIFIED)
+            {
+                log::warn!("e131: could not join universe {universe}: {e}");
+            }
+        }
+    }
+    let mut receiver = Receiver::new(settings, timeout);
+
+    thread::Builder::new().stack_size(4096).spawn(move || {
+        let mut buf = [0u8; MAX_PACKET];
+        loop {
+            let n = match socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e) => {
+                    log::warn!("e131: {e}");
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+            let res = receiver.handle(&buf[..n], Instant::now(), &mut frame.lock().unwrap());
+            if let Err(e) = res {
+                log::debug!("e131: {e}");
+            }
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CID_A: [u8; 16] = [0xa; 16];
+    const CID_B: [u8; 16] = [0xb; 16];
+
+    /// A data packet laid out the way senders put it on the wire.
+    fn packet(
+        cid: [u8; 16],
+        universe: u16,
+        priority: u8,
+        sequence: u8,
+        options: u8,
+        slots: &[u8],
+    ) -> Vec<u8> {
+        let len = HEADER_LEN + slots.len();
+        let flags = |from: usize| (0x7000 | (len - from) as u16).to_be_bytes();
+        let mut buf = vec![0u8; len];
+        buf[0..2].copy_from_slice(&0x0010u16.to_be_bytes());
+        buf[4..16].copy_from_slice(ACN_PID);
+        buf[16..18].copy_from_slice(&flags(16));
+        buf[18..22].copy_from_slice(&VECTOR_ROOT_DATA.to_be_bytes());
+        buf[22..38].copy_from_slice(&cid);
+        buf[38..40].copy_from_slice(&flags(38));
+        buf[40..44].copy_from_slice(&VECTOR_FRAMING_DATA.to_be_bytes());
+        buf[44..52].copy_from_slice(b"sACNView");
+        buf[108] = priority;
+        buf[111] = sequence;
+        buf[112] = options;
+        buf[113..115].copy_from_slice(&universe.to_be_bytes());
+        buf[115..117].copy_from_slice(&flags(115));
+        buf[117] = VECTOR_DMP_SET_PROPERTY;
+        buf[118] = 0xa1;
+        buf[121..123].copy_from_slice(&1u16.to_be_bytes());
+        buf[123..125].copy_from_slice(&(slots.len() as u16 + 1).to_be_bytes());
+        buf[HEADER_LEN..].copy_from_slice(slots);
+        buf
+    }
+
+    fn receiver() -> Receiver {
+        let settings = E131Settings {
+            enabled: true,
+            mapping: DmxMapping {
+                led_count: 2,
+                ..Default::default()
+            },
+            multicast: false,
+        };
+        Receiver::new(settings, Duration::from_millis(2500))
+    }
+
+    #[test]
+    fn parses_a_data_packet() {
+        let buf = packet(CID_A, 7, 150, 42, 0, &[1, 2, 3, 4, 5, 6]);
+        let parsed = parse(&buf).unwrap();
+        assert_eq!(parsed.cid, CID_A);
+        assert_eq!(parsed.source_name, "sACNView");
+        assert_eq!(parsed.priority, 150);
+        assert_eq!(parsed.sequence, 42);
+        assert_eq!(parsed.universe, 7);
+        assert_eq!(parsed.start_code, 0);
+        assert_eq!(parsed.data, &[1, 2, 3, 4, 5, 6]);
+        assert!(!parsed.preview && !parsed.terminated);
+
+        let buf = packet(
+            CID_A,
+            1,
+            100,
+            0,
+            OPTION_PREVIEW | OPTION_TERMINATED,
+            &[0; 3],
+        );
+        let flagged = parse(&buf).unwrap();
+        assert!(flagged.preview && flagged.terminated);
+    }
+
+    #[test]
+    fn rejects_what_isnt_e131_data() {
+        let good = packet(CID_A, 1, 100, 0, 0, &[0; 3]);
+        assert_eq!(parse(&good[..21]), Err(ParseError::TooShort(21)));
+        assert_eq!(parse(&good[..100]), Err(ParseError::TooShort(100)));
+
+        let mut art = good.clone();
+        art[4..12].copy_from_slice(b"Art-Net\0");
+        assert_eq!(parse(&art), Err(ParseError::NotAcn));
+
+        // universe synchronization
+        let mut sync = good.clone();
+        sync[18..22].copy_from_slice(&0x0000_0008u32.to_be_bytes());
+        assert_eq!(parse(&sync), Err(ParseError::UnsupportedVector(8)));
+
+        let mut count = good.clone();
+        count[123..125].copy_from_slice(&100u16.to_be_bytes());
+        assert_eq!(
+            parse(&count),
+            Err(ParseError::Malformed("property value count"))
+        );
+
+        let mut universe = good;
+        universe[113..115].copy_from_slice(&0u16.to_be_bytes());
+        assert_eq!(parse(&universe), Err(ParseError::Malformed("universe")));
+    }
+
+    #[test]
+    fn maps_slots_onto_the_frame() {
+        let t0 = Instant::now();
+        let mut frame = Frame::new(4);
+        let mut rx = receiver();
+        let buf = packet(CID_A, 1, 100, 1, 0, &[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(rx.handle(&buf, t0, &mut frame), Ok(Handled::Applied));
+        // led_count 2 leaves the third LED alone
+        assert_eq!(frame.pixels(), &[[1, 2, 3], [4, 5, 6], [0; 3], [0; 3]]);
+        assert_eq!(frame.live_source(t0, Duration::from_secs(1)), Some("e131"));
+
+        let other = packet(CID_A, 2, 100, 2, 0, &[9; 3]);
+        assert_eq!(rx.handle(&other, t0, &mut frame), Ok(Handled::Ignored));
+        let preview = packet(CID_A, 1, 100, 3, OPTION_PREVIEW, &[9; 3]);
+        assert_eq!(rx.handle(&preview, t0, &mut frame), Ok(Handled::Ignored));
+        assert_eq!(frame.pixels()[0], [1, 2, 3]);
+    }
+
+    #[test]
+    fn drops_late_packets_but_follows_a_restart() {
+        let t0 = Instant::now();
+        let mut frame = Frame::new(2);
+        let mut rx = receiver();
+        let send = |rx: &mut Receiver, frame: &mut Frame, seq: u8, v: u8| {
+            rx.handle(&packet(CID_A, 1, 100, seq, 0, &[v; 3]), t0, frame)
+                .unwrap()
+        };
+        assert_eq!(send(&mut rx, &mut frame, 250, 1), Handled::Applied);
+        // wraps around
+        assert_eq!(send(&mut rx, &mut frame, 3, 2), Handled::Applied);
+        assert_eq!(send(&mut rx, &mut frame, 2, 3), Handled::Ignored);
+        assert_eq!(send(&mut rx, &mut frame, 3, 3), Handled::Ignored);
+        assert_eq!(frame.pixels()[0], [2; 3]);
+        // far behind: the sender started over
+        assert_eq!(send(&mut rx, &mut frame, 200, 4), Handled::Applied);
+        assert_eq!(frame.pixels()[0], [4; 3]);
+    }
+
+    #[test]
+    fn highest_priority_source_wins_until_it_goes_quiet() {
+        let t0 = Instant::now();
+        let at = |ms| t0 + Duration::from_millis(ms);
+        let mut frame = Frame::new(2);
+        let mut rx = receiver();
+        let high = |seq, v| packet(CID_A, 1, 150, seq, 0, &[v; 3]);
+        let low = |seq, v| packet(CID_B, 1, 100, seq, 0, &[v; 3]);
+
+        assert_eq!(
+            rx.handle(&low(1, 1), at(0), &mut frame),
+            Ok(Handled::Applied)
+        );
+        assert_eq!(
+            rx.handle(&high(1, 2), at(10), &mut frame),
+            Ok(Handled::Applied)
+        );
+        assert_eq!(
+            rx.handle(&low(2, 3), at(20), &mut frame),
+            Ok(Handled::Ignored)
+        );
+        assert_eq!(frame.pixels()[0], [2; 3]);
+
+        // the high one times out, the low one gets the strip back
+        assert_eq!(
+            rx.handle(&low(3, 4), at(2600), &mut frame),
+            Ok(Handled::Applied)
+        );
+        assert_eq!(frame.pixels()[0], [4; 3]);
+    }
+
+    #[test]
+    fn termination_releases_the_strip_once_every_source_left() {
+        let t0 = Instant::now();
+        let mut frame = Frame::new(2);
+        let mut rx = receiver();
+        rx.handle(&packet(CID_A, 1, 100, 1, 0, &[1; 3]), t0, &mut frame)
+            .unwrap();
+        rx.handle(&packet(CID_B, 1, 100, 1, 0, &[2; 3]), t0, &mut frame)
+            .unwrap();
+
+        let bye_a = packet(CID_A, 1, 100, 2, OPTION_TERMINATED, &[0; 3]);
+        assert_eq!(rx.handle(&bye_a, t0, &mut frame), Ok(Handled::Ignored));
+        assert!(frame.updated().is_some());
+
+        let bye_b = packet(CID_B, 1, 100, 2, OPTION_TERMINATED, &[0; 3]);
+        assert_eq!(rx.handle(&bye_b, t0, &mut frame), Ok(Handled::Released));
+        assert_eq!(frame.updated(), None);
+    }
+}
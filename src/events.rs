@@ -0,0 +1,207 @@
+//! Server-Sent Events: formatting and fan-out, independent of whatever
+//! transport ends up carrying the stream.
+//!
+//! There are no preset or playlist events: the board has neither.
+
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+use crate::ws::Sink;
+
+/// how many past events a reconnecting client can catch up on
+const BACKLOG: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub kind: &'static str,
+    /// single line JSON
+    pub data: String,
+}
+
+impl Event {
+    pub fn json(kind: &'static str, data: &impl Serialize) -> Self {
+        Self {
+            kind,
+            data: serde_json::to_string(data).unwrap_or_default(),
+        }
+    }
+
+    pub fn segments(revision: u32, etag: &str) -> Self {
+        #[derive(Serialize)]
+        struct Segments<'a> {
+            revision: u32,
+            etag: &'a str,
+        }
+        Self::json("segments", &Segments { revision, etag })
+    }
+
+    pub fn stats(stats: &Stats) -> Self {
+        Self::json("stats", stats)
+    }
+
+    /// `text/event-stream` framing; `data` never contains newlines since it
+    /// is compact JSON.
+    pub fn format(&self, id: u64) -> String {
+        format!("id: {id}\nevent: {}\ndata: {}\n\n", self.kind, self.data)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub uptime_ms: u32,
+    pub fps: f32,
+    pub free_heap: u32,
+    pub min_free_heap: u32,
+}
+
+/// Fans events out to the connected streams. Streams that fail to take an
+/// event are dropped, the client is expected to reconnect with
+/// `Last-Event-ID`.
+pub struct Bus<S> {
+    next_id: u64,
+    subscribers: Vec<S>,
+    backlog: VecDeque<(u64, String)>,
+    max_subscribers: usize,
+}
+
+impl<S: Sink> Bus<S> {
+    pub fn new(max_subscribers: usize) -> Self {
+        Self {
+            next_id: 1,
+            subscribers: Vec::new(),
+            backlog: VecDeque::with_capacity(BACKLOG),
+            max_subscribers,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.subscribers.len() >= self.max_subscribers
+    }
+
+    pub fn len(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    /// Replays everything after `last_event_id` from the backlog, then
+    /// keeps `sink` for future events.
+    pub fn subscribe(&mut self, mut sink: S, last_event_id: Option<u64>) -> anyhow::Result<()> {
+        if self.is_full() {
+            anyhow::bail!("too many event streams");
+        }
+        if let Some(last) = last_event_id {
+            for (_, text) in self.backlog.iter().filter(|(id, _)| *id > last) {
+                sink.send_text(text)?;
+            }
+        }
+        self.subscribers.push(sink);
+        Ok(())
+    }
+
+    pub fn publish(&mut self, event: &Event) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let text = event.format(id);
+
+        self.subscribers.retain_mut(|sink| sink.send_text(&text).is_ok());
+
+        if self.backlog.len() == BACKLOG {
+            self.backlog.pop_front();
+        }
+        self.backlog.push_back((id, text));
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    /// records what it was sent, or fails every send once `broken`
+    #[derive(Clone, Default)]
+    struct Stream {
+        sent: Rc<RefCell<Vec<String>>>,
+        broken: Rc<RefCell<bool>>,
+    }
+
+    impl Sink for Stream {
+        fn send_text(&mut self, text: &str) -> anyhow::Result<()> {
+            if *self.broken.borrow() {
+                anyhow::bail!("broken pipe");
+            }
+            self.sent.borrow_mut().push(text.to_string());
+            Ok(())
+        }
+    }
+
+    impl Stream {
+        fn ids(&self) -> Vec<u64> {
+            let id = |text: &String| text.strip_prefix("id: ")?.split('\n').next()?.parse().ok();
+            self.sent.borrow().iter().filter_map(id).collect()
+        }
+    }
+
+    #[test]
+    fn formats_event_stream_frames() {
+        let event = Event::segments(3, "\"0000cafe-3\"");
+        assert_eq!(
+            event.format(9),
+            "id: 9\nevent: segments\ndata: {\"revision\":3,\"etag\":\"\\\"0000cafe-3\\\"\"}\n\n"
+        );
+
+        let stats = Stats {
+            uptime_ms: 1500,
+            fps: 60.0,
+            free_heap: 100_000,
+            min_free_heap: 90_000,
+        };
+        let frame = Event::stats(&stats).format(1);
+        assert!(frame.starts_with("id: 1\nevent: stats\ndata: {\"uptime_ms\":1500,"));
+        assert_eq!(frame.matches('\n').count(), 4);
+    }
+
+    #[test]
+    fn numbers_events_and_replays_the_backlog() {
+        let mut bus = Bus::new(4);
+        let early = Stream::default();
+        bus.subscribe(early.clone(), None).unwrap();
+        for revision in 0..20 {
+            bus.publish(&Event::segments(revision, "\"x\""));
+        }
+        assert_eq!(early.ids(), (1..=20).collect::<Vec<_>>());
+
+        // catching up after id 17
+        let late = Stream::default();
+        bus.subscribe(late.clone(), Some(17)).unwrap();
+        assert_eq!(late.ids(), [18, 19, 20]);
+
+        // only the last BACKLOG events are kept
+        let later = Stream::default();
+        bus.subscribe(later.clone(), Some(0)).unwrap();
+        assert_eq!(later.ids(), (5..=20).collect::<Vec<_>>());
+
+        // a fresh client starts with the next event
+        let fresh = Stream::default();
+        bus.subscribe(fresh.clone(), None).unwrap();
+        assert_eq!(bus.publish(&Event::segments(20, "\"x\"")), 21);
+        assert_eq!(fresh.ids(), [21]);
+    }
+
+    #[test]
+    fn drops_failing_streams_and_caps_their_number() {
+        let mut bus = Bus::new(2);
+        let (a, b) = (Stream::default(), Stream::default());
+        bus.subscribe(a.clone(), None).unwrap();
+        bus.subscribe(b.clone(), None).unwrap();
+        assert!(bus.is_full());
+        assert!(bus.subscribe(Stream::default(), None).is_err());
+
+        *a.broken.borrow_mut() = true;
+        bus.publish(&Event::segments(1, "\"x\""));
+        assert_eq!(bus.len(), 1);
+        assert_eq!(b.ids(), [1]);
+        assert!(!bus.is_full());
+    }
+}
@@ -0,0 +1,508 @@
+//! Home Assistant over MQTT: the board as a main light (master switch and
+//! dimmer) plus one RGB light per segment, using HA's JSON light schema.
+//! Commands go through the same translation as the WLED API.
+//!
+//! Topics below the prefix:
+//!
+//! - `status`: `online`, or `offline` as our last will
+//! - `state`, `set`: the main light
+//! - `segment/<uuid>/state`, `segment/<uuid>/set`: a segment
+//!
+//! Presets are out of scope: the board has none, so no `effect` list is
+//! announced and `effect` in a command is ignored like `transition`.
+
+use std::{
+    error::Error,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use embedded_svc::storage::RawStorage;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    mqtt::{self, MqttSettings, Packet},
+    routes,
+    segments::SegmentMap,
+    wled::{self, Colour, SegPatch, SegPatches, StatePatch, Switch},
+};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// how often we look for changes to publish
+const POLL: Duration = Duration::from_millis(200);
+
+/// Topic prefix and names, resolved from the settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topics {
+    pub prefix: String,
+    pub node_id: String,
+    /// empty without discovery
+    pub discovery_prefix: String,
+}
+
+impl Topics {
+    pub fn new(settings: &MqttSettings, hostname: &str) -> Self {
+        let prefix = match settings.topic_prefix.as_str() {
+            "" => format!("harlot/{hostname}"),
+            prefix => prefix.to_string(),
+        };
+        let discovery_prefix = match settings.discovery {
+            true => settings.discovery_prefix.clone(),
+            false => String::new(),
+        };
+        Self {
+            prefix,
+            node_id: hostname.to_string(),
+            discovery_prefix,
+        }
+    }
+
+    pub fn status(&self) -> String {
+        format!("{}/status", self.prefix)
+    }
+
+    pub fn state(&self) -> String {
+        format!("{}/state", self.prefix)
+    }
+
+    pub fn set(&self) -> String {
+        format!("{}/set", self.prefix)
+    }
+
+    pub fn segment_state(&self, id: &str) -> String {
+        format!("{}/segment/{id}/state", self.prefix)
+    }
+
+    pub fn segment_set(&self, id: &str) -> String {
+        format!("{}/segment/{id}/set", self.prefix)
+    }
+
+    /// what we subscribe to
+    pub fn commands(&self) -> [String; 2] {
+        [self.set(), format!("{}/segment/+/set", self.prefix)]
+    }
+
+    fn discovery(&self, object_id: &str) -> String {
+        format!(
+            "{}/light/{}/{object_id}/config",
+            self.discovery_prefix, self.node_id
+        )
+    }
+}
+
+fn on_off(on: bool) -> &'static str {
+    match on {
+        true => "ON",
+        false => "OFF",
+    }
+}
+
+/// Retained state payloads for the main light and every segment, `ids`
+/// being the segment ids in map order.
+pub fn states(topics: &Topics, ids: &[String], state: &wled::State) -> Vec<(String, String)> {
+    let main = json!({ "state": on_off(state.on), "brightness": state.bri });
+    let mut out = vec![(topics.state(), main.to_string())];
+    for (id, seg) in ids.iter().zip(&state.seg) {
+        let [r, g, b] = seg.col.first().copied().unwrap_or_default();
+        let payload = json!({
+            "state": on_off(seg.on),
+            "brightness": seg.bri,
+            "color_mode": "rgb",
+            "color": { "r": r, "g": g, "b": b },
+        });
+        out.push((topics.segment_state(id), payload.to_string()));
+    }
+    out
+}
+
+/// Retained discovery configs. Segments in `gone` get an empty config,
+/// which removes them from Home Assistant.
+pub fn discovery(
+    topics: &Topics,
+    name: &str,
+    ids: &[String],
+    gone: &[String],
+) -> Vec<(String, String)> {
+    let device = json!({
+        "identifiers": [topics.node_id],
+        "name": name,
+        "manufacturer": "harlot",
+        "model": "harlot board",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+    let main = json!({
+        "name": name,
+        "unique_id": topics.node_id,
+        "schema": "json",
+        "state_topic": topics.state(),
+        "command_topic": topics.set(),
+        "availability_topic": topics.status(),
+        "brightness": true,
+        "brightness_scale": 255,
+        "supported_color_modes": ["brightness"],
+        "device": device,
+    });
+    let mut out = vec![(topics.discovery("main"), main.to_string())];
+    for (idx, id) in ids.iter().enumerate() {
+        let config = json!({
+            "name": format!("{name} segment {}", idx + 1),
+            "unique_id": format!("{}_{id}", topics.node_id),
+            "schema": "json",
+            "state_topic": topics.segment_state(id),
+            "command_topic": topics.segment_set(id),
+            "availability_topic": topics.status(),
+            "brightness": true,
+            "brightness_scale": 255,
+            "supported_color_modes": ["rgb"],
+            "device": { "identifiers": [topics.node_id] },
+        });
+        out.push((topics.discovery(id), config.to_string()));
+    }
+    for id in gone {
+        out.push((topics.discovery(id), String::new()));
+    }
+    out
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Rgb {
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+/// HA's JSON light command; other fields (transition, effect) are ignored.
+#[derive(Deserialize, Debug, Default)]
+struct LightCommand {
+    state: Option<String>,
+    brightness: Option<u8>,
+    color: Option<Rgb>,
+}
+
+fn light_command(payload: &[u8]) -> Result<LightCommand, String> {
+    // plain `ON`/`OFF` too, for people poking at it by hand
+    match std::str::from_utf8(payload).map(str::trim) {
+        Ok(state @ ("ON" | "OFF")) => {
+            return Ok(LightCommand {
+                state: Some(state.into()),
+                ..Default::default()
+            })
+        }
+        _ => {}
+    }
+    serde_json::from_slice(payload).map_err(|e| e.to_string())
+}
+
+fn switch(state: Option<String>) -> Result<Option<Switch>, String> {
+    match state.as_deref() {
+        None => Ok(None),
+        Some("ON") => Ok(Some(Switch::Set(true))),
+        Some("OFF") => Ok(Some(Switch::Set(false))),
+        Some(other) => Err(format!("state must be ON or OFF, got {other:?}")),
+    }
+}
+
+/// Turns a message on one of our command topics into a WLED state patch.
+/// `None` for topics that aren't ours.
+pub fn command(
+    topics: &Topics,
+    ids: &[String],
+    topic: &str,
+    payload: &[u8],
+) -> Result<Option<StatePatch>, String> {
+    if topic == topics.set() {
+        let cmd = light_command(payload)?;
+        return Ok(Some(StatePatch {
+            on: switch(cmd.state)?,
+            bri: cmd.brightness,
+            ..Default::default()
+        }));
+    }
+
+    let segment = topic
+        .strip_prefix(&topics.prefix)
+        .and_then(|t| t.strip_prefix("/segment/"))
+        .and_then(|t| t.strip_suffix("/set"));
+    let Some(id) = segment else {
+        return Ok(None);
+    };
+    let idx = ids
+        .iter()
+        .position(|known| known == id)
+        .ok_or_else(|| format!("no segment {id}"))?;
+    let cmd = light_command(payload)?;
+    Ok(Some(StatePatch {
+        seg: Some(SegPatches::Many(vec![SegPatch {
+            id: Some(idx),
+            on: switch(cmd.state)?,
+            bri: cmd.brightness,
+            col: cmd.color.map(|c| vec![Colour::Rgb(vec![c.r, c.g, c.b])]),
+            ..Default::default()
+        }])),
+        ..Default::default()
+    }))
+}
+
+/// What the bridge needs from the rest of the board.
+pub struct Bridge<S> {
+    pub settings: MqttSettings,
+    pub hostname: String,
+    pub instance_name: String,
+    pub segments: Arc<Mutex<SegmentMap>>,
+    pub storage: Arc<Mutex<S>>,
+    pub master: Arc<Mutex<wled::Master>>,
+}
+
+/// Connects on its own thread, and reconnects whenever the session drops.
+pub fn serve<S>(bridge: Bridge<S>) -> anyhow::Result<()>
+where
+    S: RawStorage + Send + 'static,
+    S::Error: Error + Send + Sync + 'static,
+{
+    thread::Builder::new().stack_size(8192).spawn(move || {
+        let topics = Topics::new(&bridge.settings, &bridge.hostname);
+        let client_id = match bridge.settings.client_id.as_str() {
+            "" => bridge.hostname.clone(),
+            id => id.to_string(),
+        };
+        loop {
+            if let Err(e) = bridge.session(&topics, &client_id) {
+                log::warn!("mqtt: {e}");
+            }
+            thread::sleep(RECONNECT_DELAY);
+        }
+    })?;
+    Ok(())
+}
+
+impl<S> Bridge<S>
+where
+    S: RawStorage,
+    S::Error: Error + Send + Sync + 'static,
+{
+    fn snapshot(&self) -> anyhow::Result<(Vec<String>, wled::State, u32)> {
+        let map = self.segments.lock().unwrap();
+        let ids = map.segments().keys().cloned().collect();
+        let values = routes::segment_values(&map)?;
+        let state = wled::state(&self.master.lock().unwrap(), &values);
+        Ok((ids, state, map.revision()))
+    }
+
+    fn session(&self, topics: &Topics, client_id: &str) -> anyhow::Result<()> {
+        let status = topics.status();
+        let will = mqtt::Will {
+            topic: &status,
+            payload: b"offline",
+        };
+        let mut client = mqtt::Client::connect(&self.settings, client_id, KEEP_ALIVE, Some(&will))?;
+        log::info!("mqtt: connected to {}", self.settings.host);
+        client.set_timeout(POLL)?;
+        let [set, segment_set] = topics.commands();
+        client.subscribe(&[&set, &segment_set])?;
+        client.publish(&status, b"online", true)?;
+
+        let mut announced: Vec<String> = vec![];
+        let mut published: Option<(u32, wled::Master)> = None;
+        loop {
+            if let Some(Packet::Publish { topic, payload }) = client.poll()? {
+                let (ids, _, _) = self.snapshot()?;
+                match command(topics, &ids, &topic, &payload) {
+                    Ok(Some(patch)) => {
+                        let res = routes::apply_wled(
+                            &self.segments,
+                            &*self.storage,
+                            &self.master,
+                            &patch,
+                        );
+                        if let Err(e) = res {
+                            log::warn!("mqtt: {topic}: {e}");
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => log::warn!("mqtt: {topic}: {e}"),
+                }
+            }
+
+            let (ids, state, revision) = self.snapshot()?;
+            let master = *self.master.lock().unwrap();
+            if !topics.discovery_prefix.is_empty() && ids != announced {
+                let gone: Vec<String> = announced
+                    .iter()
+                    .filter(|id| !ids.contains(id))
+                    .cloned()
+                    .collect();
+                for (topic, payload) in discovery(topics, &self.instance_name, &ids, &gone) {
+                    client.publish(&topic, payload.as_bytes(), true)?;
+                }
+                announced = ids.clone();
+            }
+            if published != Some((revision, master)) {
+                for (topic, payload) in states(topics, &ids, &state) {
+                    client.publish(&topic, payload.as_bytes(), true)?;
+                }
+                published = Some((revision, master));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_mixer::strip::{Segment, Srgb8};
+    use serde_json::Value;
+
+    use super::*;
+
+    fn topics() -> Topics {
+        Topics::new(&MqttSettings::default(), "harlot-1")
+    }
+
+    fn ids() -> Vec<String> {
+        vec!["aaaa".into(), "bbbb".into()]
+    }
+
+    fn payload(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn topics_follow_the_settings() {
+        let topics = topics();
+        assert_eq!(topics.prefix, "harlot/harlot-1");
+        assert_eq!(topics.status(), "harlot/harlot-1/status");
+        assert_eq!(topics.state(), "harlot/harlot-1/state");
+        assert_eq!(
+            topics.segment_set("aaaa"),
+            "harlot/harlot-1/segment/aaaa/set"
+        );
+        assert_eq!(
+            topics.commands(),
+            ["harlot/harlot-1/set", "harlot/harlot-1/segment/+/set"]
+        );
+        assert_eq!(topics.discovery_prefix, "homeassistant");
+
+        let settings = MqttSettings {
+            topic_prefix: "home/strip".into(),
+            discovery: false,
+            ..Default::default()
+        };
+        let topics = Topics::new(&settings, "harlot-1");
+        assert_eq!(topics.set(), "home/strip/set");
+        assert_eq!(topics.discovery_prefix, "");
+    }
+
+    #[test]
+    fn publishes_the_wled_state() {
+        let red = Srgb8::new(255, 0, 0);
+        let segments = [
+            Segment::new(10, false, red, red, 0, 1, 100),
+            Segment::new(5, false, red, red, 0, 1, 0),
+        ];
+        let values: Vec<_> = segments
+            .iter()
+            .map(|s| serde_json::to_value(s).unwrap())
+            .collect();
+        let state = wled::state(&wled::Master { on: true, bri: 128 }, &values);
+
+        let out = states(&topics(), &ids(), &state);
+        let topics: Vec<_> = out.iter().map(|(t, _)| t.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "harlot/harlot-1/state",
+                "harlot/harlot-1/segment/aaaa/state",
+                "harlot/harlot-1/segment/bbbb/state",
+            ]
+        );
+        assert_eq!(
+            payload(&out[0].1),
+            json!({"state": "ON", "brightness": 128})
+        );
+        assert_eq!(
+            payload(&out[1].1),
+            json!({
+                "state": "ON",
+                "brightness": 255,
+                "color_mode": "rgb",
+                "color": {"r": 255, "g": 0, "b": 0},
+            })
+        );
+        assert_eq!(payload(&out[2].1)["state"], "OFF");
+    }
+
+    #[test]
+    fn announces_and_removes_lights() {
+        let out = discovery(&topics(), "Desk", &ids(), &["cccc".into()]);
+        assert_eq!(out.len(), 4);
+        assert_eq!(out[0].0, "homeassistant/light/harlot-1/main/config");
+        let main = payload(&out[0].1);
+        assert_eq!(main["unique_id"], "harlot-1");
+        assert_eq!(main["command_topic"], "harlot/harlot-1/set");
+        assert_eq!(main["availability_topic"], "harlot/harlot-1/status");
+        assert_eq!(main["supported_color_modes"], json!(["brightness"]));
+        assert!(main.get("effect_list").is_none());
+
+        assert_eq!(out[2].0, "homeassistant/light/harlot-1/bbbb/config");
+        let segment = payload(&out[2].1);
+        assert_eq!(segment["name"], "Desk segment 2");
+        assert_eq!(segment["unique_id"], "harlot-1_bbbb");
+        assert_eq!(segment["state_topic"], "harlot/harlot-1/segment/bbbb/state");
+        assert_eq!(segment["supported_color_modes"], json!(["rgb"]));
+
+        assert_eq!(
+            out[3],
+            (
+                "homeassistant/light/harlot-1/cccc/config".into(),
+                String::new()
+            )
+        );
+    }
+
+    #[test]
+    fn commands_become_wled_patches() {
+        let (topics, ids) = (topics(), ids());
+        let command =
+            |topic: &str, payload: &str| command(&topics, &ids, topic, payload.as_bytes());
+
+        let main = command(
+            "harlot/harlot-1/set",
+            r#"{"state": "OFF", "brightness": 20}"#,
+        );
+        let expected = StatePatch {
+            on: Some(Switch::Set(false)),
+            bri: Some(20),
+            ..Default::default()
+        };
+        assert_eq!(main, Ok(Some(expected)));
+        let plain = command("harlot/harlot-1/set", " ON\n").unwrap().unwrap();
+        assert_eq!(plain.on, Some(Switch::Set(true)));
+
+        // effects and transitions are ignored
+        let segment = command(
+            "harlot/harlot-1/segment/bbbb/set",
+            r#"{"color": {"r": 1, "g": 2, "b": 3}, "effect": "rainbow", "transition": 2}"#,
+        );
+        let expected = StatePatch {
+            seg: Some(SegPatches::Many(vec![SegPatch {
+                id: Some(1),
+                col: Some(vec![Colour::Rgb(vec![1, 2, 3])]),
+                ..Default::default()
+            }])),
+            ..Default::default()
+        };
+        assert_eq!(segment, Ok(Some(expected)));
+
+        assert_eq!(command("harlot/harlot-1/status", "ON"), Ok(None));
+        assert_eq!(command("elsewhere/segment/aaaa/set", "ON"), Ok(None));
+        assert_eq!(
+            command("harlot/harlot-1/segment/cccc/set", "ON"),
+            Err("no segment cccc".into())
+        );
+        assert!(command("harlot/harlot-1/set", r#"{"state": "on"}"#).is_err());
+        assert!(command("harlot/harlot-1/set", r#"{"brightness": 256}"#).is_err());
+        assert!(command("harlot/harlot-1/set", "dim").is_err());
+    }
+}
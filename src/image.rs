@@ -0,0 +1,163 @@
+//! Checks on a firmware image while it streams in, kept apart from the
+//! flash writes in `ota` so they also build on a host.
+
+use sha2::{Digest, Sha256};
+
+use crate::api::ApiError;
+
+/// header carrying the hex encoded SHA-256 of the image
+pub const DIGEST_HEADER: &str = "X-Firmware-SHA256";
+
+pub fn parse_digest(hex: &str) -> Result<[u8; 32], ApiError> {
+    let hex = hex.trim();
+    let invalid = || ApiError::bad_request(format!("{DIGEST_HEADER} must be 64 hex digits"));
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let mut digest = [0u8; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = (nibble(pair[0]) << 4) | nibble(pair[1]);
+    }
+    Ok(digest)
+}
+
+/// value of an ASCII hex digit
+fn nibble(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
+
+/// Tracks length and digest of an image while it is being written.
+pub struct Verifier {
+    expected_len: usize,
+    expected_digest: [u8; 32],
+    received: usize,
+    hasher: Sha256,
+}
+
+impl Verifier {
+    pub fn new(expected_len: usize, expected_digest: [u8; 32]) -> Self {
+        Self {
+            expected_len,
+            expected_digest,
+            received: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) -> Result<(), ApiError> {
+        self.received += chunk.len();
+        if self.received > self.expected_len {
+            return Err(ApiError::bad_request("image is longer than announced"));
+        }
+        self.hasher.update(chunk);
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), ApiError> {
+        if self.received != self.expected_len {
+            return Err(ApiError::bad_request(format!(
+                "image is {} bytes, expected {}",
+                self.received, self.expected_len
+            )));
+        }
+        if self.hasher.finalize().as_slice() != self.expected_digest {
+            return Err(ApiError::bad_request("SHA-256 mismatch"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest_of(image: &[u8]) -> [u8; 32] {
+        Sha256::digest(image).into()
+    }
+
+    fn hex(digest: &[u8]) -> String {
+        digest.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    fn image() -> Vec<u8> {
+        (0..10_000u32).map(|i| (i * 7) as u8).collect()
+    }
+
+    fn status(res: Result<(), ApiError>) -> u16 {
+        res.unwrap_err().status
+    }
+
+    #[test]
+    fn parses_digests() {
+        let digest = digest_of(b"firmware");
+        assert_eq!(parse_digest(&hex(&digest)).unwrap(), digest);
+        let upper = format!(" {} ", hex(&digest).to_uppercase());
+        assert_eq!(parse_digest(&upper).unwrap(), digest);
+    }
+
+    #[test]
+    fn rejects_bad_hex() {
+        let good = hex(&digest_of(b"firmware"));
+        assert!(parse_digest(&good[..62]).is_err());
+        assert!(parse_digest(&format!("{good}00")).is_err());
+        assert!(parse_digest(&format!("g{}", &good[1..])).is_err());
+        // a sign is no digit, though `from_str_radix` would take it
+        assert!(parse_digest(&format!("+f{}", &good[2..])).is_err());
+        // 64 bytes, but not 64 characters: must not panic on a char boundary
+        let accented = format!("é{}", &good[..62]);
+        assert_eq!(accented.len(), 64);
+        assert_eq!(parse_digest(&accented).unwrap_err().status, 400);
+        assert!(parse_digest(&format!("0{}", "€".repeat(21))).is_err());
+    }
+
+    #[test]
+    fn accepts_the_announced_image() {
+        let image = image();
+        let mut verifier = Verifier::new(image.len(), digest_of(&image));
+        for chunk in image.chunks(4096) {
+            verifier.update(chunk).unwrap();
+        }
+        assert_eq!(verifier.received(), image.len());
+        verifier.finish().unwrap();
+    }
+
+    #[test]
+    fn stops_at_the_announced_length() {
+        let image = image();
+        let mut verifier = Verifier::new(image.len() - 1, digest_of(&image));
+        let mut chunks = image.chunks(4096);
+        verifier.update(chunks.next().unwrap()).unwrap();
+        verifier.update(chunks.next().unwrap()).unwrap();
+        assert_eq!(status(verifier.update(chunks.next().unwrap())), 400);
+    }
+
+    #[test]
+    fn rejects_a_truncated_image() {
+        let image = image();
+        let mut verifier = Verifier::new(image.len(), digest_of(&image));
+        verifier.update(&image[..image.len() - 1]).unwrap();
+        let error = verifier.finish().unwrap_err();
+        assert_eq!(error.status, 400);
+        assert!(error.to_string().contains("9999 bytes"), "{error}");
+    }
+
+    #[test]
+    fn rejects_a_digest_mismatch() {
+        let image = image();
+        let mut other = image.clone();
+        other[5000] ^= 1;
+        let mut verifier = Verifier::new(image.len(), digest_of(&image));
+        verifier.update(&other).unwrap();
+        let error = verifier.finish().unwrap_err();
+        assert_eq!(error.status, 400);
+        assert!(error.to_string().contains("SHA-256"), "{error}");
+    }
+}
@@ -0,0 +1,48 @@
+//! Everything but the entry point in `main.rs`.
+//!
+//! Modules that talk to ESP-IDF only build for the board. The rest also
+//! builds on a host, which is where their tests run:
+//! `scripts/test-host.sh`.
+
+#![cfg_attr(target_os = "espidf", feature(generic_const_exprs))]
+
+pub mod adalight;
+#[cfg(target_os = "espidf")]
+pub mod apa_spi;
+pub mod api;
+pub mod artnet;
+pub mod auth;
+pub mod body;
+pub mod cors;
+pub mod ddp;
+#[cfg(target_os = "espidf")]
+pub mod diag;
+pub mod e131;
+pub mod events;
+pub mod ha;
+pub mod image;
+pub mod mdns;
+pub mod metrics;
+pub mod mqtt;
+pub mod opc;
+pub mod osc;
+#[cfg(target_os = "espidf")]
+pub mod ota;
+pub mod realtime;
+pub mod router;
+pub mod routes;
+pub mod segments;
+#[cfg(target_os = "espidf")]
+pub mod server;
+pub mod settings;
+pub mod sse;
+pub mod status;
+pub mod sync;
+#[cfg(not(target_os = "espidf"))]
+pub mod test_server;
+pub mod tpm2;
+pub mod validate;
+#[cfg(target_os = "espidf")]
+pub mod wifi;
+pub mod wled;
+pub mod ws;
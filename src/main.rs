@@ -6,11 +6,14 @@
 
 // Logging macros
 
-use std::sync::{Condvar, Mutex};
+use std::sync::Mutex;
 use std::{collections::HashMap, num::Wrapping};
 
-mod apa_spi;
-mod wifi;
+use harlot_board::{
+    adalight, apa_spi, api, artnet, auth, ddp, diag, e131, events, ha, mdns, metrics, opc, osc,
+    ota, realtime, router, routes, segments, server, settings, sse, status, sync, tpm2, validate,
+    wifi, wled,
+};
 
 use apa_spi::{Apa, Pixel};
 use color_mixer::strip::{Control, Segment, Srgb8, State};
@@ -20,19 +23,17 @@ use esp_idf_svc::nvs_storage::EspNvsStorage;
 use esp_idf_sys as _;
 use indexmap::IndexMap;
 use log::*;
+use router::{Method, Response, Router};
+use segments::SegmentMap;
+use settings::Settings;
 
 use std::{cell::RefCell, env, sync::atomic::*, sync::Arc, thread, time::*};
 
-use embedded_svc::{
-    httpd::{Request, Response},
-    storage::RawStorage,
-    wifi::*,
-};
+use embedded_svc::{storage::RawStorage, wifi::*};
 
 use embedded_svc::io::Write;
 
 use esp_idf_svc::{
-    httpd::{Server, ServerRegistry},
     netif::EspNetifStack,
     nvs::EspDefaultNvs,
     sysloop::EspSysLoopStack,
@@ -50,144 +51,244 @@ pub struct Config {
 }
 
 const FS_NAMESPACE: &'static str = "fs";
+const LED_COUNT: usize = 512;
+const HTTP_PORT: u16 = 80;
 
-struct StdReader<R>(R);
+/// bytes from the hardware RNG, for salts and tokens
+fn fill_random(buf: &mut [u8]) {
+    unsafe { esp_idf_sys::esp_fill_random(buf.as_mut_ptr() as *mut _, buf.len() as _) };
+}
 
-impl<R: Read> std::io::Read for StdReader<R> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let res = Read::read(&mut self.0, buf)
-            .map_err(|_e| std::io::Error::new(std::io::ErrorKind::Other, "oh no"));
-        res
+/// Asked by the realtime receivers whenever a controller wants to know
+/// who we are.
+fn node_info(settings: &Settings) -> impl Fn() -> Option<realtime::Node> + Send + 'static {
+    let short_name = settings.hostname.clone();
+    let long_name = settings.instance_name.clone();
+    move || {
+        let (ip, mac) = wifi::local_address()?;
+        Some(realtime::Node {
+            ip,
+            mac,
+            short_name: short_name.clone(),
+            long_name: long_name.clone(),
+        })
     }
 }
 
-#[cfg(not(feature = "experimental"))]
-fn httpd(
-    mutex: Arc<(Mutex<Option<u32>>, Condvar)>,
-    segments: Arc<Mutex<IndexMap<String, Segment>>>,
-    sys_start: Instant,
-    mut storage: EspNvsStorage,
-) -> anyhow::Result<Server> {
-    use embedded_svc::httpd::{registry::Registry, Body, Handler, Method};
-    let read_data = segments.clone();
-    let write_data = segments.clone();
-
-    let now_f = move |rr| {
-        let dt = Instant::now().duration_since(sys_start).as_millis() as u32;
-        let res = format!("{dt}");
-        Ok(res.into())
-    };
-
-    let read_f = move |rr| {
-        let dat = &*read_data.lock().unwrap();
-        let ser = serde_json::to_string(dat)?;
-
-        Response::new(200)
-            .header("Content-Type", "application/json")
-            .header("Access-Control-Allow-Origin", "*")
-            .body(ser.into())
-            .into()
-    };
-
-    let storage = Arc::new(Mutex::new(storage));
-
-    let write_f = move |req: Request| {
-        let mut req = req;
-        let data = req.as_bytes()?;
-        let de: IndexMap<String, Segment> = serde_json::from_slice(&data)?;
-        let mut dat = write_data.lock().unwrap();
-        *dat = de;
-        drop(dat);
-        storage.lock().unwrap().put_raw(SEGMENTS_FILE, &data);
+/// Routes that need the radio, the flash or the render loop.
+fn device_routes(
+    router: &mut Router,
+    storage: Arc<Mutex<EspNvsStorage>>,
+    settings: Arc<Mutex<Settings>>,
+    wifi: Arc<Mutex<Box<EspWifi>>>,
+    wifi_job: Arc<Mutex<wifi::ConnectJob>>,
+    status: Arc<status::Status>,
+    metrics: Arc<metrics::Metrics>,
+    ota_progress: Arc<Mutex<ota::Progress>>,
+) {
+    let max_body = settings.lock().unwrap().max_body_size;
+
+    // see `sse.rs` for why the stream lives on its own port
+    router.route(Method::Get, "/events", |req| {
+        let host = req.header("Host").unwrap_or_default();
+        let location = sse::location(host, &req.query, req.header("Origin"));
+        Ok(Response::new(307).header("Location", location))
+    });
+
+    router.route(Method::Get, "/status", move |_req| {
+        Ok(Response::ok()
+            .json(&status.report())
+            .header("Cache-Control", "no-cache"))
+    });
+
+    let scrape_metrics = metrics.clone();
+    router.route(Method::Get, "/metrics", move |_req| {
+        let metrics = &scrape_metrics;
+        metrics.free_heap.set(unsafe { esp_idf_sys::esp_get_free_heap_size() } as i32);
+        metrics
+            .min_free_heap
+            .set(unsafe { esp_idf_sys::esp_get_minimum_free_heap_size() } as i32);
+
+        Ok(Response::text(metrics.render()).header("Content-Type", "text/plain; version=0.0.4"))
+    });
+
+    // the image is far larger than `max_body`, but never buffered whole
+    router.route(Method::Post, "/ota", move |req| {
+        let len = req
+            .content_len
+            .ok_or_else(|| api::ApiError::new(411, "Content-Length required"))?;
+        let digest = req
+            .header(ota::DIGEST_HEADER)
+            .ok_or_else(|| api::ApiError::bad_request(format!("{} missing", ota::DIGEST_HEADER)))
+            .and_then(ota::parse_digest)?;
+
+        let mut progress = ota_progress.lock().unwrap();
+        if progress.is_busy() {
+            return Err(api::ApiError::conflict("update already in progress"));
+        }
+        *progress = ota::Progress::Receiving {
+            received: 0,
+            total: len,
+        };
+        drop(progress);
+
+        let res = ota::update(req.body(), len, digest, &ota_progress);
+        match res {
+            Ok(()) => {
+                *ota_progress.lock().unwrap() = ota::Progress::Complete;
+                ota::reboot_soon();
+                Ok(Response::text("rebooting"))
+            }
+            Err(e) => {
+                log::error!("OTA failed: {e}");
+                *ota_progress.lock().unwrap() = ota::Progress::Failed {
+                    error: e.error.clone(),
+                };
+                Err(e)
+            }
+        }
+    });
+
+    let scan_wifi = wifi.clone();
+    router.route(Method::Get, "/wifi/scan", move |_req| {
+        // a connect holds the radio for its own scan and up to 20 s after,
+        // don't stall every other request waiting for it
+        let mut wifi = scan_wifi
+            .try_lock()
+            .map_err(|_| api::ApiError::conflict("connecting, scan again once that's done"))?;
+        let networks = wifi::scan(&mut wifi)?;
+        Ok(Response::ok().json(&networks))
+    });
+
+    let read_job = wifi_job.clone();
+    router.route(Method::Get, "/wifi/connect", move |_req| {
+        Ok(Response::ok().json(&*read_job.lock().unwrap()))
+    });
+
+    // stores the network right away, then connects in the background;
+    // progress is polled via `GET /wifi/connect`
+    router.route(Method::Post, "/wifi/connect", move |req| {
+        let credentials: wifi::Credentials = req.json(max_body)?;
+        if credentials.ssid.is_empty() || credentials.ssid.len() > 32 {
+            return Err(api::ApiError::invalid("/ssid", "ssid must be 1..=32 bytes"));
+        }
+        if !credentials.psk.is_empty() && !(8..=64).contains(&credentials.psk.len()) {
+            return Err(api::ApiError::invalid("/psk", "psk must be 8..=64 bytes"));
+        }
 
-        Ok("ok".into())
-    };
+        let mut job = wifi_job.lock().unwrap();
+        if job.is_busy() {
+            return Err(api::ApiError::conflict("already connecting"));
+        }
+        settings::store_json(
+            &mut *storage.lock().unwrap(),
+            wifi::CREDENTIALS_FILE,
+            &credentials,
+        )?;
+        *job = wifi::ConnectJob::Connecting {
+            ssid: credentials.ssid.clone(),
+        };
+        let res = Response::new(202).json(&*job);
+        drop(job);
+
+        let wifi = wifi.clone();
+        let wifi_job = wifi_job.clone();
+        let metrics = metrics.clone();
+        let (hostname, static_ip) = {
+            let settings = settings.lock().unwrap();
+            (settings.hostname.clone(), settings.static_ip.clone())
+        };
+        thread::Builder::new()
+            .stack_size(8192)
+            .spawn(move || {
+                let res = wifi::connect(
+                    &mut wifi.lock().unwrap(),
+                    &credentials.ssid,
+                    &credentials.psk,
+                    &hostname,
+                    static_ip.as_ref(),
+                    credentials.auth_method(),
+                );
+                metrics.wifi_reconnects.inc();
+                let ssid = credentials.ssid;
+                *wifi_job.lock().unwrap() = match res {
+                    Ok(Some(ip)) => wifi::ConnectJob::Connected {
+                        ssid,
+                        ip: ip.to_string(),
+                    },
+                    Ok(None) => wifi::ConnectJob::Failed {
+                        ssid,
+                        error: "not connected".into(),
+                    },
+                    Err(e) => wifi::ConnectJob::Failed {
+                        ssid,
+                        error: e.to_string(),
+                    },
+                };
+            })
+            .map_err(anyhow::Error::from)?;
+
+        Ok(res)
+    });
+}
 
-    fn resp(data: &'static [u8], content_type: &str) -> Result<Response, anyhow::Error> {
-        let response = Response::new(200)
-            .header("Content-Encoding", "gzip")
-            .header("Content-Type", content_type)
-            .header("Access-Control-Allow-Origin", "*");
-        let body = Body::Read(None, Box::new(data));
-        response.body(body).into()
-    }
+/// Watches shared state and publishes changes plus periodic stats to the
+/// event stream.
+fn publish_events(
+    bus: Arc<Mutex<events::Bus<std::net::TcpStream>>>,
+    segments: Arc<Mutex<SegmentMap>>,
+    wifi_job: Arc<Mutex<wifi::ConnectJob>>,
+    metrics: Arc<metrics::Metrics>,
+    sys_start: Instant,
+) -> anyhow::Result<()> {
+    use events::{Event, Stats};
+
+    thread::Builder::new().stack_size(6144).spawn(move || {
+        let mut revision = None;
+        let mut job = String::new();
+        let mut last_stats = Instant::now();
+        let mut last_frames = 0;
+        loop {
+            thread::sleep(Duration::from_millis(100));
+            let mut pending = vec![];
+
+            let map = segments.lock().unwrap();
+            if revision != Some(map.revision()) {
+                revision = Some(map.revision());
+                pending.push(Event::segments(map.revision(), &map.etag()));
+            }
+            drop(map);
 
-    let mut server = include!("../web_includes.rs");
-    server = server
-        //.handler (Handler :: new ("/" , Method :: Get , | _ | { let data = include_bytes ! ("/mnt/c/Users/ace/Documents/GitHub/color-mixer-ws/mixer-dioxus/dist/index.html.gz") ; resp (data . as_slice () , "text/html") })) ?
-        .handler(Handler::new("/now", Method::Get, now_f))?
-        .handler(Handler::new("/data", Method::Get, read_f))?
-        .handler(Handler::new("/data", Method::Post, write_f))?;
+            let current_job = Event::json("wifi", &*wifi_job.lock().unwrap());
+            if current_job.data != job {
+                job = current_job.data.clone();
+                pending.push(current_job);
+            }
 
-    server.start(&Default::default())
-}
+            let elapsed = last_stats.elapsed();
+            if elapsed >= Duration::from_secs(1) {
+                let frames = metrics.frames.get();
+                let stats = Stats {
+                    uptime_ms: sys_start.elapsed().as_millis() as u32,
+                    fps: frames.wrapping_sub(last_frames) as f32 / elapsed.as_secs_f32(),
+                    free_heap: unsafe { esp_idf_sys::esp_get_free_heap_size() },
+                    min_free_heap: unsafe { esp_idf_sys::esp_get_minimum_free_heap_size() },
+                };
+                last_frames = frames;
+                last_stats = Instant::now();
+                pending.push(Event::stats(&stats));
+            }
 
-#[cfg(feature = "experimental")]
-use esp_idf_svc::http::server::{Configuration, EspHttpServer};
-// http::{
-//         server::{registry::Registry, Response, ServerRegistry},
-//         Method,
-//     },
-
-#[cfg(feature = "experimental")]
-fn httpd(
-    mutex: Arc<(Mutex<Option<u32>>, Condvar)>,
-    segments: Arc<Mutex<IndexMap<String, Segment>>>,
-    sys_start: Instant,
-) -> anyhow::Result<esp_idf_svc::http::server::EspHttpServer> {
-    use embedded_svc::errors::wrap::WrapError;
-    use embedded_svc::http::server::registry::Registry;
-    use embedded_svc::http::server::Response;
-    use embedded_svc::http::SendStatus;
-
-    let mut server = esp_idf_svc::http::server::EspHttpServer::new(&Default::default())?;
-
-    let read_data = segments.clone();
-    let write_data = segments.clone();
-    server
-        .handle_get("/", |_req, mut resp| {
-            resp.set_ok();
-            resp.send_str("Hello from Rust!")?;
-            Ok(())
-        })?
-        .handle_get("/now", move |_req, mut resp| {
-            let dt = Instant::now().duration_since(sys_start).as_millis() as u32;
-            resp.set_ok();
-            resp.send_str(&format!("{dt}"))?;
-            Ok(())
-        })?
-        .handle_get("/data", move |_req, mut resp| {
-            let dat = &*read_data.lock().unwrap();
-            let ser = serde_json::to_string(dat)?;
-            resp.set_ok();
-            resp.send_str(&ser)?;
-            Ok(())
-        })?
-        .handle_post("/data", move |mut req, mut resp| {
-            let reader = req.reader();
-            let de: IndexMap<String, Segment> = serde_json::from_reader(StdReader(reader))?;
-            let mut dat = write_data.lock().unwrap();
-            *dat = de;
-            resp.set_ok();
-            resp.send_str("ok")?;
-            Ok(())
-        })?
-        .handle_get("/foo", |_req, resp| {
-            Result::Err(WrapError("Boo, something happened!").into())
-        })?
-        .handle_get("/bar", |_req, resp| {
-            resp.status(403)
-                .status_message("No permissions")
-                .send_str("You have no permissions to access this page")?;
-
-            Ok(())
-        })?
-        .handle_get("/panic", |_req, _resp| panic!("User requested a panic!"))?;
-
-    Ok(server)
+            let mut bus = bus.lock().unwrap();
+            for event in &pending {
+                bus.publish(event);
+            }
+        }
+    })?;
+    Ok(())
 }
 
-const SEGMENTS_FILE: &'static str = "segments.json";
+use routes::SEGMENTS_FILE;
 fn main() -> anyhow::Result<()> {
     esp_idf_sys::link_patches();
     esp_idf_svc::log::EspLogger::initialize_default();
@@ -266,32 +367,284 @@ fn main() -> anyhow::Result<()> {
         segments.extend(some_segs.into_iter().map(|s| (s.to_uuid_string(), s)));
     }
 
-    let segments = Arc::new(Mutex::new(segments));
+    let epoch = unsafe { esp_idf_sys::esp_random() };
+    let limits = validate::Limits {
+        strip_length: LED_COUNT,
+        led_budget: LED_COUNT,
+    };
+    let segments = Arc::new(Mutex::new(SegmentMap::new(segments, limits, epoch)));
+
+    let settings = Settings::load(&storage).unwrap_or_else(|e| {
+        log::error!("could not load settings: {:?}", e);
+        Default::default()
+    });
+    let storage = Arc::new(Mutex::new(storage));
 
     log::info!("starting wifi");
     let netif_stack = Arc::new(EspNetifStack::new()?);
     let sys_loop_stack = Arc::new(EspSysLoopStack::new()?);
 
     log::info!("starting wifi harder...");
+    let credentials: Option<wifi::Credentials> =
+        settings::load_json(&*storage.lock().unwrap(), wifi::CREDENTIALS_FILE).unwrap_or_else(
+            |e| {
+                log::error!("could not load wifi credentials: {:?}", e);
+                None
+            },
+        );
     // let _wifi = wifi::wifi(
     //     app_config.wifi_ssid,
     //     app_config.wifi_psk,
+    //     &settings.hostname,
+    //     settings.static_ip.as_ref(),
     //     netif_stack.clone(),
     //     sys_loop_stack.clone(),
     //     nvs.clone(),
     // )?;
-    let _wifi = wifi::wifi_ap_only(
-        netif_stack.clone(),
-        sys_loop_stack.clone(),
-        nvs.clone(),
-    )?;
+    let station = match &credentials {
+        Some(credentials) => wifi::wifi(
+            &credentials.ssid,
+            &credentials.psk,
+            &settings.hostname,
+            settings.static_ip.as_ref(),
+            netif_stack.clone(),
+            sys_loop_stack.clone(),
+            nvs.clone(),
+        )
+        .map(Some)
+        .unwrap_or_else(|e| {
+            // stay reachable so the credentials can be fixed over the AP
+            log::error!("could not join {:?}, starting the access point: {e:?}", credentials.ssid);
+            None
+        }),
+        None => None,
+    };
+    let wifi = match station {
+        Some(wifi) => wifi,
+        None => wifi::wifi_ap_only(
+            &settings.hostname,
+            netif_stack.clone(),
+            sys_loop_stack.clone(),
+            nvs.clone(),
+        )?,
+    };
+    let wifi = Arc::new(Mutex::new(wifi));
+    let wifi_job = Arc::new(Mutex::new(wifi::ConnectJob::default()));
     log::info!("ok");
 
-    let mutex = Arc::new((Mutex::new(None), Condvar::new()));
+    let advertisement = mdns::Advertisement::new(&settings, HTTP_PORT, LED_COUNT);
+    let _mdns = mdns::advertise(&advertisement)?;
+
+    let bus = Arc::new(Mutex::new(events::Bus::new(sse::MAX_STREAMS)));
+    let metrics = Arc::new(metrics::Metrics::default());
+    publish_events(
+        bus.clone(),
+        segments.clone(),
+        wifi_job.clone(),
+        metrics.clone(),
+        sys_start,
+    )?;
 
-    let httpd = httpd(mutex.clone(), segments.clone(), sys_start, storage)?;
+    // network pixels; like the other settings, changes apply after a reboot
+    let realtime_settings = settings.realtime.clone();
+    let realtime = Arc::new(Mutex::new(realtime::Arbiter::new(
+        &realtime_settings,
+        LED_COUNT,
+    )));
+    let source = |name| realtime.lock().unwrap().source(name);
+    if settings.e131.enabled {
+        let (_, timeout) = realtime_settings.policy("e131");
+        if let Err(e) = e131::serve(settings.e131.clone(), timeout, source("e131")) {
+            log::error!("could not start the E1.31 receiver: {e:?}");
+        }
+    }
+    if settings.artnet.enabled {
+        let node = node_info(&settings);
+        if let Err(e) = artnet::serve(settings.artnet.clone(), node, source("artnet")) {
+            log::error!("could not start the Art-Net receiver: {e:?}");
+        }
+    }
+    if settings.ddp.enabled {
+        let node = node_info(&settings);
+        if let Err(e) = ddp::serve(settings.ddp.clone(), node, source("ddp")) {
+            log::error!("could not start the DDP receiver: {e:?}");
+        }
+    }
+    if settings.opc.enabled {
+        if let Err(e) = opc::serve(settings.opc.clone(), source("opc")) {
+            log::error!("could not start the OPC server: {e:?}");
+        }
+    }
+    if settings.tpm2.enabled {
+        if let Err(e) = tpm2::serve(settings.tpm2.clone(), source("tpm2")) {
+            log::error!("could not start the TPM2.net receiver: {e:?}");
+        }
+    }
+    if settings.adalight.enabled {
+        if let Err(e) = adalight::serve(settings.adalight.clone(), source("adalight")) {
+            log::error!("could not start the Adalight receiver: {e:?}");
+        }
+    }
+
+    // animation time, the leader's once we follow one; `epoch` tells boots apart
+    let clock = sync::Clock::new(sys_start, settings.sync.clone());
+    if settings.sync.enabled {
+        if let Err(e) = sync::serve(clock.clone(), epoch) {
+            log::error!("could not start clock sync: {e:?}");
+        }
+    }
+
+    let frame_stats = Arc::new(Mutex::new(status::FrameStats::default()));
+    let ota_progress = Arc::new(Mutex::new(ota::Progress::default()));
+    let status = {
+        let frame_stats = frame_stats.clone();
+        let metrics = metrics.clone();
+        let ota_progress = ota_progress.clone();
+        let realtime = realtime.clone();
+        let clock = clock.clone();
+        let protocols = serde_json::json!({
+            "e131": settings.e131.enabled,
+            "artnet": settings.artnet.enabled,
+            "ddp": settings.ddp.enabled,
+            "opc": settings.opc.enabled,
+            "tpm2": settings.tpm2.enabled,
+            "adalight": settings.adalight.enabled,
+        });
+        status::Status::default()
+            .with(status::firmware())
+            .with(diag::system(sys_start))
+            .with(diag::heap())
+            .with(status::from_fn("render", move || {
+                serde_json::to_value(frame_stats.lock().unwrap().report()).unwrap_or_default()
+            }))
+            .with(status::from_fn("spi", move || {
+                serde_json::json!({ "errors": metrics.spi_errors.get() })
+            }))
+            .with(diag::wifi(wifi.clone()))
+            .with(diag::nvs())
+            .with(status::from_fn("ota", move || {
+                serde_json::to_value(&*ota_progress.lock().unwrap()).unwrap_or_default()
+            }))
+            .with(status::from_fn("realtime", move || {
+                let report = realtime.lock().unwrap().report(Instant::now());
+                let mut value = serde_json::to_value(report).unwrap_or_default();
+                value["protocols"] = protocols.clone();
+                value
+            }))
+            .with(status::from_fn("sync", move || {
+                serde_json::to_value(clock.report()).unwrap_or_default()
+            }))
+    };
+    let status = Arc::new(status);
+
+    let settings = Arc::new(Mutex::new(settings));
+    // a missing file means no password yet, an unreadable one could hide one
+    let auth = match settings::load_json(&*storage.lock().unwrap(), auth::AUTH_FILE) {
+        Ok(auth) => auth.unwrap_or_default(),
+        Err(e) => {
+            log::error!("could not load auth, locking the API: {e:?}");
+            auth::Auth::locked()
+        }
+    };
+    if !auth.enabled() {
+        log::warn!("no admin password set, the API is open");
+    }
+    let auth = Arc::new(Mutex::new(auth));
+    let cors = settings.lock().unwrap().cors.clone();
+    sse::serve(sse::SSE_PORT, bus, cors, auth.clone())?;
+
+    let master = Arc::new(Mutex::new(wled::Master::default()));
+    let wled_info = {
+        let name = settings.lock().unwrap().instance_name.clone();
+        let node = node_info(&settings.lock().unwrap());
+        let segments = segments.clone();
+        let realtime = realtime.clone();
+        let frame_stats = frame_stats.clone();
+        move || {
+            let count = segments.lock().unwrap().segments().len();
+            let mut info = wled::Info::new(name.clone(), LED_COUNT, count);
+            let live = realtime.lock().unwrap().report(Instant::now()).source;
+            info.live = live.is_some();
+            info.lm = live.unwrap_or_default().to_string();
+            info.leds.fps = frame_stats.lock().unwrap().report().fps.round() as u32;
+            info.freeheap = unsafe { esp_idf_sys::esp_get_free_heap_size() };
+            info.uptime = sys_start.elapsed().as_secs();
+            if let Some(node) = node() {
+                info.ip = node.ip.to_string();
+                info.mac = node.mac.iter().map(|b| format!("{b:02x}")).collect();
+            }
+            info
+        }
+    };
+
+    let mqtt_settings = settings.lock().unwrap().mqtt.clone();
+    if mqtt_settings.enabled {
+        let (hostname, instance_name) = {
+            let settings = settings.lock().unwrap();
+            (settings.hostname.clone(), settings.instance_name.clone())
+        };
+        ha::serve(ha::Bridge {
+            settings: mqtt_settings,
+            hostname,
+            instance_name,
+            segments: segments.clone(),
+            storage: storage.clone(),
+            master: master.clone(),
+        })?;
+    }
+
+    let osc_settings = settings.lock().unwrap().osc.clone();
+    if osc_settings.enabled {
+        let res = osc::serve(osc_settings, segments.clone(), storage.clone(), master.clone());
+        if let Err(e) = res {
+            log::error!("could not start the OSC server: {e:?}");
+        }
+    }
+
+    let mut router = Router::default();
+    // like the other settings, CORS changes apply after a reboot
+    let cors = settings.lock().unwrap().cors.clone();
+    router
+        .layer(metrics.clone())
+        .layer(auth::Guard(auth.clone()))
+        .layer(cors);
+    routes::Api {
+        segments: segments.clone(),
+        storage: storage.clone(),
+        settings: settings.clone(),
+        auth: auth.clone(),
+        clock: clock.clone(),
+        random: fill_random,
+        master: master.clone(),
+        wled_info: Arc::new(wled_info),
+        realtime: realtime.clone(),
+    }
+    .mount(&mut router);
+    device_routes(
+        &mut router,
+        storage.clone(),
+        settings.clone(),
+        wifi.clone(),
+        wifi_job.clone(),
+        status.clone(),
+        metrics.clone(),
+        ota_progress.clone(),
+    );
+    metrics.register(&router.routes());
+    let httpd = server::start(
+        Arc::new(router),
+        segments.clone(),
+        storage.clone(),
+        clock.clone(),
+        auth.clone(),
+        source("ws"),
+    )?;
+
+    // a new image is only kept once it renders
+    let health_metrics = metrics.clone();
+    ota::health_check(move || health_metrics.frames.get() > 100)?;
     let mut apa_config = apa_spi::Config::default();
-    apa_config.length = 512;
+    apa_config.length = LED_COUNT;
     const LEN: usize = 32;
     let mut apa: Apa = Apa::new(apa_config);
     let moar_chill = 1000;
@@ -299,31 +652,79 @@ fn main() -> anyhow::Result<()> {
         segments
             .lock()
             .unwrap()
+            .segments()
             .iter()
             .map(|(id, seg)| seg)
             .cloned(),
     );
 
+    let mut last_frame = Instant::now();
     loop {
+        let frame_start = Instant::now();
         let mut led_start = 0;
 
         let log_f = |s: String| log::warn!("{s}");
         let log_f = |_s| {};
-        let segments = segments.lock().unwrap().clone();
-
-        for (_id, seg) in segments {
-            let color = seg.color_at(now);
-            let segment_color = Pixel::new(color.red, color.green, color.blue, seg.brightness());
-            for i in led_start..led_start + seg.length() {
-                apa.set_pixel(i, segment_color, log_f);
+        let dimmer = *master.lock().unwrap();
+        // network pixels win over the animation, see `realtime::Arbiter`
+        let output = realtime.lock().unwrap().step(frame_start);
+        let live_brightness = dimmer.scale(realtime_settings.brightness);
+        match output {
+            realtime::Output::Live { pixels, .. } => {
+                for (i, [r, g, b]) in pixels.into_iter().enumerate() {
+                    apa.set_pixel(i, Pixel::new(r, g, b, live_brightness), log_f);
+                }
+                apa.flush();
+            }
+            realtime::Output::Local => {
+                let segments = segments.lock().unwrap().segments().clone();
+
+                for (_id, seg) in segments {
+                    let color = seg.color_at(now);
+                    let brightness = dimmer.scale(seg.brightness());
+                    let segment_color = Pixel::new(color.red, color.green, color.blue, brightness);
+                    for i in led_start..led_start + seg.length() {
+                        apa.set_pixel(i, segment_color, log_f);
+                    }
+                    led_start += seg.length();
+                    apa.flush();
+                }
+            }
+            realtime::Output::Fade { pixels, weight } => {
+                // both sides at their own brightness, mixed at full
+                let live = |i: usize| {
+                    realtime::dim(pixels.get(i).copied().unwrap_or_default(), live_brightness)
+                };
+                let segments = segments.lock().unwrap().segments().clone();
+                for (_id, seg) in segments {
+                    let color = seg.color_at(now);
+                    let local = [color.red, color.green, color.blue];
+                    let local = realtime::dim(local, dimmer.scale(seg.brightness()));
+                    for i in led_start..led_start + seg.length() {
+                        let [r, g, b] = realtime::blend(live(i), local, weight);
+                        apa.set_pixel(i, Pixel::new(r, g, b, 100), log_f);
+                    }
+                    led_start += seg.length();
+                }
+                // past the segments, fade to black
+                for i in led_start..pixels.len() {
+                    let [r, g, b] = realtime::blend(live(i), [0; 3], weight);
+                    apa.set_pixel(i, Pixel::new(r, g, b, 100), log_f);
+                }
+                apa.flush();
             }
-            led_start += seg.length();
-            apa.flush();
         }
+        metrics.frames.inc();
+        metrics.spi_errors.set(apa.errors());
+        metrics.frame_time.observe(frame_start.elapsed());
+        frame_stats
+            .lock()
+            .unwrap()
+            .record(frame_start.elapsed(), frame_start.duration_since(last_frame));
+        last_frame = frame_start;
 
         std::thread::sleep(std::time::Duration::from_millis(10));
 
-        let dt = Instant::now().duration_since(sys_start);
-        now = dt.as_millis() as u32;
+        now = clock.now_ms();
     }
 }
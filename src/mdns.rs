@@ -0,0 +1,98 @@
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::mdns::EspMdns;
+
+use crate::settings::Settings;
+
+pub const SERVICE_TYPE: &'static str = "_harlot";
+pub const SERVICE_PROTO: &'static str = "_tcp";
+/// bump whenever the HTTP API changes incompatibly
+pub const API_VERSION: &'static str = "1";
+
+/// Everything we announce via mDNS/DNS-SD. Kept free of ESP types so the
+/// record set can be built (and checked) anywhere.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Advertisement {
+    pub hostname: String,
+    pub instance_name: String,
+    pub port: u16,
+    pub txt: Vec<(String, String)>,
+}
+
+impl Advertisement {
+    pub fn new(settings: &Settings, port: u16, led_count: usize) -> Self {
+        let txt = vec![
+            ("fw".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+            ("leds".to_string(), led_count.to_string()),
+            ("api".to_string(), API_VERSION.to_string()),
+        ];
+        Self {
+            hostname: settings.hostname.clone(),
+            instance_name: settings.instance_name.clone(),
+            port,
+            txt,
+        }
+    }
+
+    /// fully qualified service name, e.g. `harlot board._harlot._tcp.local`
+    pub fn service_name(&self) -> String {
+        format!("{}.{SERVICE_TYPE}.{SERVICE_PROTO}.local", self.instance_name)
+    }
+
+    pub fn host_name(&self) -> String {
+        format!("{}.local", self.hostname)
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub fn advertise(adv: &Advertisement) -> anyhow::Result<EspMdns> {
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(&adv.hostname)?;
+    mdns.set_instance_name(&adv.instance_name)?;
+
+    let txt: Vec<(&str, &str)> = adv
+        .txt
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    mdns.add_service(
+        Some(&adv.instance_name),
+        SERVICE_TYPE,
+        SERVICE_PROTO,
+        adv.port,
+        &txt,
+    )?;
+    log::info!("mDNS: {} at {}", adv.service_name(), adv.host_name());
+
+    Ok(mdns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announces_the_settings_names_and_txt_records() {
+        let settings = Settings {
+            hostname: "kitchen".into(),
+            instance_name: "kitchen shelf".into(),
+            ..Default::default()
+        };
+        let adv = Advertisement::new(&settings, 80, 512);
+        assert_eq!(adv.host_name(), "kitchen.local");
+        assert_eq!(adv.service_name(), "kitchen shelf._harlot._tcp.local");
+        assert_eq!(adv.port, 80);
+        let txt: Vec<(&str, &str)> = adv
+            .txt
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            txt,
+            [
+                ("fw", env!("CARGO_PKG_VERSION")),
+                ("leds", "512"),
+                ("api", API_VERSION)
+            ]
+        );
+    }
+}
@@ -0,0 +1,384 @@
+//! A tiny metrics registry rendered in the Prometheus text exposition
+//! format by `GET /metrics`.
+//!
+//! Updates are plain atomic ops, so the render loop and the handlers never
+//! wait on each other. The ESP32-C3 only has 32 bit atomics in hardware, so
+//! counters wrap eventually; Prometheus treats that like a counter reset.
+//! Histogram sums would wrap within the hour though, they use 64 bit atomics
+//! that ESP-IDF emulates with a short critical section.
+//!
+//! The board has no presets, so there's no active preset gauge either.
+
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI32, AtomicU32, AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use crate::router::{Layer, Method, Request, Response};
+
+#[derive(Default)]
+pub struct Counter(AtomicU32);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u32) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// for counters mirrored from elsewhere, e.g. [`Apa::errors`](crate::apa_spi::Apa::errors)
+    pub fn set(&self, n: u32) {
+        self.0.store(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI32);
+
+impl Gauge {
+    pub fn set(&self, v: i32) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i32 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Upper bounds in µs; the `+Inf` bucket is implicit.
+pub const FRAME_BUCKETS_US: [u32; 7] = [500, 1_000, 2_000, 5_000, 10_000, 20_000, 50_000];
+
+pub struct Histogram {
+    bounds_us: &'static [u32],
+    /// non-cumulative, one more than `bounds_us` for `+Inf`
+    buckets: Vec<AtomicU32>,
+    sum_us: AtomicU64,
+    count: AtomicU32,
+}
+
+impl Histogram {
+    pub fn new(bounds_us: &'static [u32]) -> Self {
+        Self {
+            bounds_us,
+            buckets: (0..=bounds_us.len()).map(|_| AtomicU32::new(0)).collect(),
+            sum_us: AtomicU64::new(0),
+            count: AtomicU32::new(0),
+        }
+    }
+
+    pub fn observe(&self, d: Duration) {
+        let us = d.as_micros().min(u32::MAX as u128) as u32;
+        let idx = self
+            .bounds_us
+            .iter()
+            .position(|&bound| us <= bound)
+            .unwrap_or(self.bounds_us.len());
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Status codes tracked individually, everything else is `other`.
+const STATUSES: [u16; 16] = [
+    200, 201, 202, 204, 304, 307, 400, 401, 403, 404, 405, 409, 412, 413, 500, 503,
+];
+
+/// counts requests with a method their route doesn't have
+const OTHER_METHOD: &str = "other";
+
+/// Requests of one route and method, by status.
+pub struct RouteCounter {
+    route: String,
+    method: &'static str,
+    /// one more than `STATUSES` for `other`
+    by_status: Vec<AtomicU32>,
+}
+
+impl RouteCounter {
+    fn new(route: &str, method: &'static str) -> Self {
+        Self {
+            route: route.to_string(),
+            method,
+            by_status: (0..=STATUSES.len()).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    pub fn record(&self, status: u16) {
+        let idx = STATUSES
+            .iter()
+            .position(|&s| s == status)
+            .unwrap_or(STATUSES.len());
+        self.by_status[idx].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Metrics {
+    pub frames: Counter,
+    pub frame_time: Histogram,
+    pub spi_errors: Counter,
+    pub free_heap: Gauge,
+    pub min_free_heap: Gauge,
+    pub wifi_reconnects: Counter,
+    /// fixed once the routes are mounted, see [`Metrics::register`]
+    routes: OnceLock<Vec<RouteCounter>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            frames: Default::default(),
+            frame_time: Histogram::new(&FRAME_BUCKETS_US),
+            spi_errors: Default::default(),
+            free_heap: Default::default(),
+            min_free_heap: Default::default(),
+            wifi_reconnects: Default::default(),
+            routes: Default::default(),
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// label values need `\`, `"` and newlines escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn seconds(us: u64) -> String {
+    format!("{}", us as f64 / 1_000_000.0)
+}
+
+impl Metrics {
+    /// Sets up the request counters from
+    /// [`Router::routes`](crate::router::Router::routes), once everything is
+    /// mounted. Only the first call counts.
+    pub fn register(&self, routes: &[(Method, &str)]) {
+        let mut counters: Vec<RouteCounter> = routes
+            .iter()
+            .map(|&(method, route)| RouteCounter::new(route, method.as_str()))
+            .collect();
+        for &(_, route) in routes {
+            if !counters.iter().any(|c| c.route == route && c.method == OTHER_METHOD) {
+                counters.push(RouteCounter::new(route, OTHER_METHOD));
+            }
+        }
+        if self.routes.set(counters).is_err() {
+            log::warn!("metrics: routes are already registered");
+        }
+    }
+
+    /// Lock free, routes are only looked up.
+    pub fn record(&self, method: &str, route: &str, status: u16) {
+        let Some(routes) = self.routes.get() else {
+            return;
+        };
+        let counter = routes
+            .iter()
+            .filter(|r| r.route == route)
+            .find(|r| r.method == method || r.method == OTHER_METHOD);
+        if let Some(counter) = counter {
+            counter.record(status);
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::with_capacity(2048);
+
+        header(&mut out, "harlot_frames_total", "counter", "Frames rendered.");
+        let _ = writeln!(out, "harlot_frames_total {}", self.frames.get());
+
+        let name = "harlot_frame_seconds";
+        header(&mut out, name, "histogram", "Time spent rendering and flushing a frame.");
+        let h = &self.frame_time;
+        let mut cumulative = 0;
+        for (i, bucket) in h.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = match h.bounds_us.get(i) {
+                Some(&bound) => seconds(bound as u64),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_sum {}", seconds(h.sum_us.load(Ordering::Relaxed)));
+        let _ = writeln!(out, "{name}_count {}", h.count.load(Ordering::Relaxed));
+
+        let name = "harlot_http_requests_total";
+        header(&mut out, name, "counter", "HTTP requests by route, method and status.");
+        for route in self.routes.get().into_iter().flatten() {
+            for (i, count) in route.by_status.iter().enumerate() {
+                let count = count.load(Ordering::Relaxed);
+                if count == 0 {
+                    continue;
+                }
+                let status = STATUSES
+                    .get(i)
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|| "other".to_string());
+                let _ = writeln!(
+                    out,
+                    "{name}{{route=\"{}\",method=\"{}\",status=\"{status}\"}} {count}",
+                    escape(&route.route),
+                    route.method,
+                );
+            }
+        }
+
+        header(&mut out, "harlot_spi_errors_total", "counter", "Failed SPI transactions.");
+        let _ = writeln!(out, "harlot_spi_errors_total {}", self.spi_errors.get());
+
+        header(&mut out, "harlot_heap_free_bytes", "gauge", "Free heap.");
+        let _ = writeln!(out, "harlot_heap_free_bytes {}", self.free_heap.get());
+
+        header(&mut out, "harlot_heap_min_free_bytes", "gauge", "Lowest free heap since boot.");
+        let _ = writeln!(out, "harlot_heap_min_free_bytes {}", self.min_free_heap.get());
+
+        header(
+            &mut out,
+            "harlot_wifi_reconnects_total",
+            "counter",
+            "Station (re)connects after boot.",
+        );
+        let _ = writeln!(out, "harlot_wifi_reconnects_total {}", self.wifi_reconnects.get());
+
+        out
+    }
+}
+
+/// Counts responses per route, method and status.
+impl Layer for Metrics {
+    fn after(&self, route: &str, req: &Request, res: &mut Response) {
+        // unmatched paths have no counter
+        if !route.is_empty() {
+            self.record(req.method.as_str(), route, res.status);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample<'a>(out: &'a str, series: &str) -> Option<&'a str> {
+        out.lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+    }
+
+    #[test]
+    fn every_family_has_help_and_type() {
+        let out = Metrics::default().render();
+        let mut families = 0;
+        for line in out.lines().filter(|l| l.starts_with("# TYPE ")) {
+            let mut parts = line["# TYPE ".len()..].split(' ');
+            let (name, kind) = (parts.next().unwrap(), parts.next().unwrap());
+            assert!(["counter", "gauge", "histogram"].contains(&kind), "{line}");
+            assert!(out.contains(&format!("# HELP {name} ")), "{name}");
+            families += 1;
+        }
+        assert_eq!(families, 7);
+        assert!(out.ends_with('\n'));
+        // nothing counted yet, no route series
+        assert!(!out.contains("harlot_http_requests_total{"));
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        for us in [100, 500, 501, 3_000, 60_000] {
+            metrics.frame_time.observe(Duration::from_micros(us));
+        }
+        let out = metrics.render();
+        let bucket = |le: &str| {
+            let series = format!("harlot_frame_seconds_bucket{{le=\"{le}\"}}");
+            sample(&out, &series)
+        };
+        assert_eq!(bucket("0.0005"), Some("2"));
+        assert_eq!(bucket("0.001"), Some("3"));
+        assert_eq!(bucket("0.002"), Some("3"));
+        assert_eq!(bucket("0.005"), Some("4"));
+        assert_eq!(bucket("0.05"), Some("4"));
+        assert_eq!(bucket("+Inf"), Some("5"));
+        assert_eq!(sample(&out, "harlot_frame_seconds_sum"), Some("0.064101"));
+        assert_eq!(sample(&out, "harlot_frame_seconds_count"), Some("5"));
+    }
+
+    #[test]
+    fn histogram_sum_outgrows_32_bits() {
+        let metrics = Metrics::default();
+        // 2^32 µs is about 72 minutes
+        for _ in 0..3 {
+            metrics.frame_time.observe(Duration::from_secs(3000));
+        }
+        let out = metrics.render();
+        assert_eq!(sample(&out, "harlot_frame_seconds_sum"), Some("9000"));
+    }
+
+    #[test]
+    fn counts_requests_by_route_method_and_status() {
+        let metrics = Metrics::default();
+        // not registered yet
+        metrics.record("GET", "/data", 200);
+        assert!(!metrics.render().contains("harlot_http_requests_total{"));
+
+        metrics.register(&[
+            (Method::Get, "/data"),
+            (Method::Post, "/data"),
+            (Method::Get, "/a\"b\\c"),
+            (Method::Options, "/data"),
+        ]);
+        metrics.register(&[(Method::Get, "/late")]);
+        metrics.record("GET", "/data", 200);
+        metrics.record("GET", "/data", 200);
+        metrics.record("POST", "/data", 412);
+        metrics.record("POST", "/data", 418);
+        metrics.record("GET", "/a\"b\\c", 404);
+        metrics.record("DELETE", "/data", 405);
+        metrics.record("PUT", "/data", 405);
+        metrics.record("GET", "/late", 200);
+        metrics.record("GET", "/nowhere", 404);
+        let out = metrics.render();
+        let requests = |labels: &str| {
+            let series = format!("harlot_http_requests_total{{{labels}}}");
+            sample(&out, &series)
+        };
+        assert_eq!(requests(r#"route="/data",method="GET",status="200""#), Some("2"));
+        assert_eq!(requests(r#"route="/data",method="POST",status="412""#), Some("1"));
+        assert_eq!(requests(r#"route="/data",method="POST",status="other""#), Some("1"));
+        assert_eq!(requests(r#"route="/a\"b\\c",method="GET",status="404""#), Some("1"));
+        assert_eq!(requests(r#"route="/data",method="POST",status="200""#), None);
+        assert_eq!(requests(r#"route="/data",method="other",status="405""#), Some("2"));
+        assert_eq!(out.matches("harlot_http_requests_total{").count(), 5);
+    }
+
+    #[test]
+    fn plain_samples() {
+        let metrics = Metrics::default();
+        metrics.frames.add(3);
+        metrics.spi_errors.set(7);
+        metrics.free_heap.set(123_456);
+        metrics.min_free_heap.set(-1);
+        metrics.wifi_reconnects.inc();
+        let out = metrics.render();
+        assert_eq!(sample(&out, "harlot_frames_total"), Some("3"));
+        assert_eq!(sample(&out, "harlot_spi_errors_total"), Some("7"));
+        assert_eq!(sample(&out, "harlot_heap_free_bytes"), Some("123456"));
+        assert_eq!(sample(&out, "harlot_heap_min_free_bytes"), Some("-1"));
+        assert_eq!(sample(&out, "harlot_wifi_reconnects_total"), Some("1"));
+    }
+}
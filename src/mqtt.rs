@@ -0,0 +1,619 @@
+//! Minimal MQTT 3.1.1 client on std sockets: QoS 0 publish and subscribe,
+//! a last will, keep-alive pings. Enough for the Home Assistant bridge in
+//! `ha.rs`, and it runs the same on a host against any broker.
+//!
+//! The tests talk to a scripted broker on loopback; running against a real
+//! one (e.g. mosquitto) is out of scope for the test suite.
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PORT: u16 = 1883;
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+/// largest packet we accept from the broker
+pub const MAX_PACKET: usize = 4096;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct MqttSettings {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    /// empty for anonymous access
+    pub username: String,
+    pub password: String,
+    /// defaults to the hostname
+    pub client_id: String,
+    /// defaults to `harlot/<hostname>`
+    pub topic_prefix: String,
+    /// publish Home Assistant discovery configs
+    pub discovery: bool,
+    pub discovery_prefix: String,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: String::new(),
+            port: DEFAULT_PORT,
+            username: String::new(),
+            password: String::new(),
+            client_id: String::new(),
+            topic_prefix: String::new(),
+            discovery: true,
+            discovery_prefix: "homeassistant".into(),
+        }
+    }
+}
+
+/// Topic level names may not contain wildcards, and we don't want empty ones.
+fn valid_topic(topic: &str) -> bool {
+    !topic.is_empty()
+        && !topic.contains(['+', '#', '\0'])
+        && topic.split('/').all(|level| !level.is_empty())
+}
+
+impl MqttSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.enabled && self.host.is_empty() {
+            anyhow::bail!("mqtt host is required");
+        }
+        if self.host.len() > 253 || self.host.contains(['/', ':', ' ']) {
+            anyhow::bail!("mqtt host must be a hostname or IPv4 address");
+        }
+        if self.port == 0 {
+            anyhow::bail!("mqtt port must be 1..=65535");
+        }
+        if self.username.len() > 64 || self.password.len() > 64 {
+            anyhow::bail!("mqtt username and password must be at most 64 bytes");
+        }
+        if self.client_id.len() > 23 {
+            anyhow::bail!("mqtt client_id must be at most 23 bytes");
+        }
+        for (name, topic) in [
+            ("topic_prefix", &self.topic_prefix),
+            ("discovery_prefix", &self.discovery_prefix),
+        ] {
+            if !topic.is_empty() && (topic.len() > 64 || !valid_topic(topic)) {
+                anyhow::bail!(
+                    "mqtt {name} must be up to 64 bytes of non-empty levels without wildcards"
+                );
+            }
+        }
+        if self.discovery && self.discovery_prefix.is_empty() {
+            anyhow::bail!("mqtt discovery_prefix is required for discovery");
+        }
+        Ok(())
+    }
+}
+
+/// A packet from the broker.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    ConnAck {
+        return_code: u8,
+    },
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+    },
+    SubAck,
+    PingResp,
+    /// anything we don't act on
+    Other(u8),
+}
+
+fn remaining_length(mut len: usize, out: &mut Vec<u8>) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn string(s: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s);
+}
+
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind];
+    remaining_length(body.len(), &mut out);
+    out.extend_from_slice(body);
+    out
+}
+
+/// The last will is published retained by the broker if we vanish.
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+}
+
+pub fn connect_packet(
+    client_id: &str,
+    username: &str,
+    password: &str,
+    keep_alive: Duration,
+    will: Option<&Will>,
+) -> Vec<u8> {
+    let mut body = vec![];
+    string(b"MQTT", &mut body);
+    body.push(4);
+    // clean session
+    let mut flags = 0x02;
+    if will.is_some() {
+        // will flag, retained, QoS 0
+        flags |= 0x04 | 0x20;
+    }
+    if !username.is_empty() {
+        flags |= 0x80;
+        if !password.is_empty() {
+            flags |= 0x40;
+        }
+    }
+    body.push(flags);
+    body.extend_from_slice(&(keep_alive.as_secs().min(u16::MAX as u64) as u16).to_be_bytes());
+    string(client_id.as_bytes(), &mut body);
+    if let Some(will) = will {
+        string(will.topic.as_bytes(), &mut body);
+        string(will.payload, &mut body);
+    }
+    if !username.is_empty() {
+        string(username.as_bytes(), &mut body);
+        if !password.is_empty() {
+            string(password.as_bytes(), &mut body);
+        }
+    }
+    packet(CONNECT, &body)
+}
+
+/// QoS 0
+pub fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = vec![];
+    string(topic.as_bytes(), &mut body);
+    body.extend_from_slice(payload);
+    packet(PUBLISH | retain as u8, &body)
+}
+
+/// QoS 0 for every filter
+pub fn subscribe_packet(id: u16, filters: &[&str]) -> Vec<u8> {
+    let mut body = id.to_be_bytes().to_vec();
+    for filter in filters {
+        string(filter.as_bytes(), &mut body);
+        body.push(0);
+    }
+    packet(SUBSCRIBE, &body)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    TooLarge(usize),
+    Malformed(&'static str),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::TooLarge(len) => write!(f, "packet of {len} bytes is too large"),
+            DecodeError::Malformed(what) => write!(f, "malformed packet: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// The first complete packet in `buf` and its length, `None` if more bytes
+/// are needed.
+pub fn decode(buf: &[u8]) -> Result<Option<(Packet, usize)>, DecodeError> {
+    let Some(&kind) = buf.first() else {
+        return Ok(None);
+    };
+    let mut len = 0usize;
+    let mut header = 1;
+    loop {
+        let Some(&byte) = buf.get(header) else {
+            return Ok(None);
+        };
+        len |= ((byte & 0x7f) as usize) << (7 * (header - 1));
+        header += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if header > 4 {
+            return Err(DecodeError::Malformed("remaining length"));
+        }
+    }
+    if header + len > MAX_PACKET {
+        return Err(DecodeError::TooLarge(header + len));
+    }
+    let Some(body) = buf.get(header..header + len) else {
+        return Ok(None);
+    };
+
+    let packet = match kind & 0xf0 {
+        CONNACK if len == 2 => Packet::ConnAck {
+            return_code: body[1],
+        },
+        PUBLISH => {
+            if len < 2 {
+                return Err(DecodeError::Malformed("publish"));
+            }
+            let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+            let qos = (kind >> 1) & 0x03;
+            // QoS 1 and 2 carry a packet id we don't need
+            let payload_at = 2 + topic_len + if qos > 0 { 2 } else { 0 };
+            if payload_at > len {
+                return Err(DecodeError::Malformed("publish"));
+            }
+            let topic = String::from_utf8(body[2..2 + topic_len].to_vec())
+                .map_err(|_| DecodeError::Malformed("topic"))?;
+            Packet::Publish {
+                topic,
+                payload: body[payload_at..].to_vec(),
+            }
+        }
+        SUBACK => Packet::SubAck,
+        PINGRESP => Packet::PingResp,
+        other => Packet::Other(other),
+    };
+    Ok(Some((packet, header + len)))
+}
+
+/// A connected session.
+pub struct Client {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    keep_alive: Duration,
+    last_sent: Instant,
+    next_id: u16,
+}
+
+impl Client {
+    /// Connects and waits for the broker's CONNACK.
+    pub fn connect(
+        settings: &MqttSettings,
+        client_id: &str,
+        keep_alive: Duration,
+        will: Option<&Will>,
+    ) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect((settings.host.as_str(), settings.port))?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let mut client = Self {
+            stream,
+            buf: vec![],
+            keep_alive,
+            last_sent: Instant::now(),
+            next_id: 1,
+        };
+        let connect = connect_packet(
+            client_id,
+            &settings.username,
+            &settings.password,
+            keep_alive,
+            will,
+        );
+        client.send(&connect)?;
+        match client.poll()? {
+            Some(Packet::ConnAck { return_code: 0 }) => Ok(client),
+            Some(Packet::ConnAck { return_code }) => {
+                anyhow::bail!("broker refused the connection: {return_code}")
+            }
+            _ => anyhow::bail!("no CONNACK from the broker"),
+        }
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.stream.write_all(packet)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    pub fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> io::Result<()> {
+        self.send(&publish_packet(topic, payload, retain))
+    }
+
+    pub fn subscribe(&mut self, filters: &[&str]) -> io::Result<()> {
+        let id = self.next_id;
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        self.send(&subscribe_packet(id, filters))
+    }
+
+    /// Waits up to `timeout` for a packet.
+    pub fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.stream.set_read_timeout(Some(timeout))
+    }
+
+    /// The next packet, or `None` once the read timeout passes. Pings the
+    /// broker when we've been quiet for most of the keep-alive interval.
+    pub fn poll(&mut self) -> anyhow::Result<Option<Packet>> {
+        if self.last_sent.elapsed() > self.keep_alive * 3 / 4 {
+            self.send(&[PINGREQ, 0])?;
+        }
+        loop {
+            if let Some((packet, len)) = decode(&self.buf)? {
+                self.buf.drain(..len);
+                return Ok(Some(packet));
+            }
+            let mut chunk = [0u8; 512];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => anyhow::bail!("broker closed the connection"),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    #[test]
+    fn encodes_connect_with_will_and_credentials() {
+        let will = Will {
+            topic: "t/status",
+            payload: b"offline",
+        };
+        let packet = connect_packet("id", "user", "pw", Duration::from_secs(60), Some(&will));
+        let mut body = vec![0, 4];
+        body.extend_from_slice(b"MQTT");
+        body.extend_from_slice(&[4, 0x80 | 0x40 | 0x20 | 0x04 | 0x02, 0, 60]);
+        for field in [&b"id"[..], b"t/status", b"offline", b"user", b"pw"] {
+            body.extend_from_slice(&(field.len() as u16).to_be_bytes());
+            body.extend_from_slice(field);
+        }
+        assert_eq!(packet[0], CONNECT);
+        assert_eq!(packet[1] as usize, body.len());
+        assert_eq!(&packet[2..], body);
+
+        // no password without a username, anonymous and without a will
+        let packet = connect_packet("id", "", "pw", Duration::from_secs(60), None);
+        assert_eq!(packet[9], 0x02);
+        assert_eq!(packet.len(), 2 + 10 + 2 + 2);
+    }
+
+    #[test]
+    fn encodes_long_remaining_lengths() {
+        let payload = vec![7; 300];
+        let packet = publish_packet("a/b", &payload, true);
+        assert_eq!(&packet[..3], [PUBLISH | 1, 0xb1, 0x02]);
+        assert_eq!(packet.len(), 3 + 5 + 300);
+
+        let (decoded, len) = decode(&packet).unwrap().unwrap();
+        assert_eq!(len, packet.len());
+        let expected = Packet::Publish {
+            topic: "a/b".into(),
+            payload,
+        };
+        assert_eq!(decoded, expected);
+
+        let packet = subscribe_packet(9, &["a/+", "b"]);
+        assert_eq!(
+            packet,
+            [SUBSCRIBE, 12, 0, 9, 0, 3, b'a', b'/', b'+', 0, 0, 1, b'b', 0]
+        );
+    }
+
+    #[test]
+    fn decodes_broker_packets() {
+        assert_eq!(
+            decode(&[CONNACK, 2, 0, 5]),
+            Ok(Some((Packet::ConnAck { return_code: 5 }, 4)))
+        );
+        assert_eq!(decode(&[SUBACK, 3, 0, 1, 0]), Ok(Some((Packet::SubAck, 5))));
+        assert_eq!(
+            decode(&[PINGRESP, 0, 0xff]),
+            Ok(Some((Packet::PingResp, 2)))
+        );
+        assert_eq!(decode(&[0xb0, 2, 0, 1]), Ok(Some((Packet::Other(0xb0), 4))));
+
+        // QoS 1 publish: packet id before the payload
+        let qos1 = [PUBLISH | 0x02, 6, 0, 1, b't', 0, 42, b'x'];
+        let publish = Packet::Publish {
+            topic: "t".into(),
+            payload: b"x".to_vec(),
+        };
+        assert_eq!(decode(&qos1), Ok(Some((publish, 8))));
+
+        // partial packets wait for more bytes
+        let packet = publish_packet("topic", b"payload", false);
+        for end in 0..packet.len() {
+            assert_eq!(decode(&packet[..end]), Ok(None));
+        }
+        assert_eq!(decode(&[PUBLISH, 0x80]), Ok(None));
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        assert_eq!(
+            decode(&[PUBLISH, 0x80, 0x80, 0x80, 0x80, 1]),
+            Err(DecodeError::Malformed("remaining length"))
+        );
+        assert_eq!(
+            decode(&[PUBLISH, 0x80, 0x40]),
+            Err(DecodeError::TooLarge(3 + 8192))
+        );
+        assert_eq!(
+            decode(&[PUBLISH, 1, 0]),
+            Err(DecodeError::Malformed("publish"))
+        );
+        assert_eq!(
+            decode(&[PUBLISH, 3, 0, 5, b'a']),
+            Err(DecodeError::Malformed("publish"))
+        );
+        assert_eq!(
+            decode(&[PUBLISH, 3, 0, 1, 0xff]),
+            Err(DecodeError::Malformed("topic"))
+        );
+    }
+
+    #[test]
+    fn validates_settings() {
+        let valid = MqttSettings {
+            enabled: true,
+            host: "broker.local".into(),
+            ..Default::default()
+        };
+        assert!(valid.validate().is_ok());
+        assert!(MqttSettings::default().validate().is_ok());
+
+        let invalid = [
+            MqttSettings {
+                host: String::new(),
+                ..valid.clone()
+            },
+            MqttSettings {
+                host: "broker:1883".into(),
+                ..valid.clone()
+            },
+            MqttSettings {
+                port: 0,
+                ..valid.clone()
+            },
+            MqttSettings {
+                client_id: "x".repeat(24),
+                ..valid.clone()
+            },
+            MqttSettings {
+                topic_prefix: "home/+/strip".into(),
+                ..valid.clone()
+            },
+            MqttSettings {
+                topic_prefix: "home//strip".into(),
+                ..valid.clone()
+            },
+            MqttSettings {
+                discovery_prefix: String::new(),
+                ..valid.clone()
+            },
+        ];
+        for settings in invalid {
+            assert!(settings.validate().is_err(), "{settings:?}");
+        }
+    }
+
+    /// Reads one whole packet from the client, as a broker would.
+    fn read_packet(stream: &mut TcpStream) -> Vec<u8> {
+        let mut buf = vec![];
+        let mut byte = [0];
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            buf.push(byte[0]);
+            if buf.len() > 1 && buf[buf.len() - 1] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut len = 0;
+        for (i, byte) in buf[1..].iter().enumerate() {
+            len |= ((byte & 0x7f) as usize) << (7 * i);
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).unwrap();
+        buf.extend(body);
+        buf
+    }
+
+    #[test]
+    fn session_against_a_scripted_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let connect = read_packet(&mut stream);
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+            let subscribe = read_packet(&mut stream);
+            stream.write_all(&[SUBACK, 3, 0, 1, 0]).unwrap();
+            let publish = read_packet(&mut stream);
+            // a command split across two writes
+            let command = publish_packet("t/set", b"ON", false);
+            stream.write_all(&command[..3]).unwrap();
+            stream.flush().unwrap();
+            thread::sleep(Duration::from_millis(50));
+            stream.write_all(&command[3..]).unwrap();
+            let ping = read_packet(&mut stream);
+            stream.write_all(&[PINGRESP, 0]).unwrap();
+            (connect, subscribe, publish, ping)
+        });
+
+        let settings = MqttSettings {
+            enabled: true,
+            host: "127.0.0.1".into(),
+            port,
+            ..Default::default()
+        };
+        let keep_alive = Duration::from_millis(400);
+        let mut client = Client::connect(&settings, "board", keep_alive, None).unwrap();
+        client.set_timeout(Duration::from_millis(20)).unwrap();
+        client.subscribe(&["t/set"]).unwrap();
+        assert_eq!(client.poll().unwrap(), Some(Packet::SubAck));
+        client.publish("t/state", b"{}", true).unwrap();
+
+        let mut packets = vec![];
+        let start = Instant::now();
+        while packets.len() < 2 && start.elapsed() < Duration::from_secs(5) {
+            packets.extend(client.poll().unwrap());
+        }
+        let command = Packet::Publish {
+            topic: "t/set".into(),
+            payload: b"ON".to_vec(),
+        };
+        assert_eq!(packets, [command, Packet::PingResp]);
+
+        let (connect, subscribe, publish, ping) = broker.join().unwrap();
+        assert_eq!(connect, connect_packet("board", "", "", keep_alive, None));
+        assert_eq!(subscribe, subscribe_packet(1, &["t/set"]));
+        assert_eq!(publish, publish_packet("t/state", b"{}", true));
+        assert_eq!(ping, [PINGREQ, 0]);
+    }
+
+    #[test]
+    fn refused_connections_fail() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_packet(&mut stream);
+            // not authorized
+            stream.write_all(&[CONNACK, 2, 0, 5]).unwrap();
+        });
+        let settings = MqttSettings {
+            host: "127.0.0.1".into(),
+            port,
+            ..Default::default()
+        };
+        let keep_alive = Duration::from_secs(60);
+        let error = Client::connect(&settings, "board", keep_alive, None)
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "broker refused the connection: 5");
+        broker.join().unwrap();
+    }
+}
@@ -0,0 +1,464 @@
+//! Open Pixel Control server: "set pixel colours" messages over TCP into the
+//! realtime frame, each OPC channel mapped onto a stretch of the strip.
+//!
+//! Messages are decoded as the bytes come in, so a 64k message needs no
+//! 64k buffer and reads may split it anywhere. A message's pixels reach the
+//! frame together once it is complete. Every client gets its own thread.
+
+use std::{
+    io::{self, Read},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::realtime::Frame;
+
+pub const DEFAULT_PORT: u16 = 7890;
+const HEADER_LEN: usize = 4;
+const SET_PIXELS: u8 = 0;
+/// messages to channel 0 go to every channel
+const BROADCAST: u8 = 0;
+/// a client that stays quiet this long is dropped
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+/// clients served at once, more are turned away
+const MAX_CLIENTS: usize = 4;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OpcChannel {
+    /// 1..=255
+    pub channel: u8,
+    /// LED the channel's pixel 0 lands on
+    pub led_offset: usize,
+    pub led_count: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct OpcSettings {
+    pub enabled: bool,
+    pub port: u16,
+    pub channels: Vec<OpcChannel>,
+}
+
+impl Default for OpcSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: DEFAULT_PORT,
+            channels: vec![OpcChannel {
+                channel: 1,
+                led_offset: 0,
+                led_count: 512,
+            }],
+        }
+    }
+}
+
+impl OpcSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.port == 0 {
+            anyhow::bail!("opc port must be 1..=65535");
+        }
+        if !(1..=8).contains(&self.channels.len()) {
+            anyhow::bail!("opc needs 1..=8 channels");
+        }
+        for (idx, channel) in self.channels.iter().enumerate() {
+            if channel.channel == BROADCAST {
+                anyhow::bail!("opc channel 0 is the broadcast channel, use 1..=255");
+            }
+            if !(1..=4096).contains(&channel.led_count) {
+                anyhow::bail!("opc led_count must be 1..=4096");
+            }
+            if self.channels[..idx]
+                .iter()
+                .any(|other| other.channel == channel.channel)
+            {
+                anyhow::bail!("opc channel {} is mapped twice", channel.channel);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// pixel `index` of a "set pixel colours" message
+    Pixel {
+        channel: u8,
+        index: usize,
+        rgb: [u8; 3],
+    },
+    /// a message is complete; other commands are skipped, their data unseen
+    End { channel: u8, command: u8 },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    channel: u8,
+    command: u8,
+    /// data bytes still to come
+    remaining: usize,
+}
+
+/// Incremental decoder for the OPC byte stream.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    header: [u8; HEADER_LEN],
+    header_len: usize,
+    message: Option<Header>,
+    /// pixels of the current message so far
+    index: usize,
+    /// a pixel split across reads
+    partial: [u8; 3],
+    partial_len: usize,
+}
+
+impl Decoder {
+    /// Decodes `input`, continuing where the last call stopped.
+    pub fn feed(&mut self, mut input: &[u8], mut out: impl FnMut(Event)) {
+        loop {
+            let Some(message) = &mut self.message else {
+                let Some((&byte, rest)) = input.split_first() else {
+                    return;
+                };
+                input = rest;
+                self.header[self.header_len] = byte;
+                self.header_len += 1;
+                if self.header_len == HEADER_LEN {
+                    self.start();
+                    self.finish_if_done(&mut out);
+                }
+                continue;
+            };
+
+            if input.is_empty() {
+                return;
+            }
+            let len = message.remaining.min(input.len());
+            let (data, rest) = input.split_at(len);
+            input = rest;
+            message.remaining -= len;
+            if message.command == SET_PIXELS {
+                let channel = message.channel;
+                for &byte in data {
+                    self.partial[self.partial_len] = byte;
+                    self.partial_len += 1;
+                    if self.partial_len == 3 {
+                        out(Event::Pixel {
+                            channel,
+                            index: self.index,
+                            rgb: self.partial,
+                        });
+                        self.index += 1;
+                        self.partial_len = 0;
+                    }
+                }
+            }
+            self.finish_if_done(&mut out);
+        }
+    }
+
+    fn start(&mut self) {
+        let [channel, command, hi, lo] = self.header;
+        self.message = Some(Header {
+            channel,
+            command,
+            remaining: u16::from_be_bytes([hi, lo]) as usize,
+        });
+        self.header_len = 0;
+        self.index = 0;
+        self.partial_len = 0;
+    }
+
+    fn finish_if_done(&mut self, out: &mut impl FnMut(Event)) {
+        if let Some(message) = self.message {
+            if message.remaining == 0 {
+                // a trailing partial pixel is dropped
+                self.message = None;
+                out(Event::End {
+                    channel: message.channel,
+                    command: message.command,
+                });
+            }
+        }
+    }
+}
+
+/// One client's decoder plus the channel to LED mapping.
+pub struct Receiver {
+    channels: Vec<OpcChannel>,
+    decoder: Decoder,
+    /// the current message's pixels, by LED
+    pixels: Vec<[u8; 3]>,
+    /// the LEDs the current message set
+    touched: Vec<bool>,
+}
+
+impl Receiver {
+    pub fn new(settings: &OpcSettings) -> Self {
+        let leds = settings
+            .channels
+            .iter()
+            .map(|mapping| mapping.led_offset + mapping.led_count)
+            .max()
+            .unwrap_or(0);
+        Self {
+            channels: settings.channels.clone(),
+            decoder: Decoder::default(),
+            pixels: vec![[0; 3]; leds],
+            touched: vec![false; leds],
+        }
+    }
+
+    /// Feeds received bytes, returns how many messages made it to the frame.
+    pub fn handle(&mut self, buf: &[u8], now: Instant, frame: &mut Frame) -> usize {
+        let Self {
+            channels,
+            decoder,
+            pixels,
+            touched,
+        } = self;
+        let mut frames = 0;
+        decoder.feed(buf, |event| match event {
+            Event::Pixel {
+                channel,
+                index,
+                rgb,
+            } => {
+                for mapping in channels.iter() {
+                    if (channel == BROADCAST || channel == mapping.channel)
+                        && index < mapping.led_count
+                    {
+                        pixels[mapping.led_offset + index] = rgb;
+                        touched[mapping.led_offset + index] = true;
+                    }
+                }
+            }
+            Event::End { .. } => {
+                if !touched.contains(&true) {
+                    return;
+                }
+                for (led, touched) in touched.iter_mut().enumerate() {
+                    if std::mem::take(touched) {
+                        frame.set(led, pixels[led]);
+                    }
+                }
+                frame.commit("opc", now);
+                frames += 1;
+            }
+        });
+        frames
+    }
+}
+
+/// Accepts clients on `settings.port` on its own thread, and serves each
+/// on a thread of its own, up to [`MAX_CLIENTS`] at once.
+pub fn serve(settings: OpcSettings, frame: Arc<Mutex<Frame>>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, settings.port))?;
+    let settings = Arc::new(settings);
+    let clients = Arc::new(AtomicUsize::new(0));
+
+    thread::Builder::new()
+        .stack_size(6144)
+        .spawn(move || loop {
+            let (stream, from) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("opc: {e}");
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+            if clients.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+                clients.fetch_sub(1, Ordering::SeqCst);
+                log::warn!("opc: turning {from} away, {MAX_CLIENTS} clients connected");
+                continue;
+            }
+            log::info!("opc: client {from}");
+            let (settings, frame, count) = (settings.clone(), frame.clone(), clients.clone());
+            let spawned = thread::Builder::new().stack_size(6144).spawn(move || {
+                if let Err(e) = client(stream, &settings, &frame) {
+                    log::info!("opc: client {from}: {e}");
+                }
+                count.fetch_sub(1, Ordering::SeqCst);
+            });
+            if let Err(e) = spawned {
+                clients.fetch_sub(1, Ordering::SeqCst);
+                log::warn!("opc: client {from}: {e}");
+            }
+        })?;
+    Ok(())
+}
+
+fn client(mut stream: TcpStream, settings: &OpcSettings, frame: &Mutex<Frame>) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut receiver = Receiver::new(settings);
+    let mut buf = [0u8; 1460];
+    loop {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        receiver.handle(&buf[..n], Instant::now(), &mut frame.lock().unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn message(channel: u8, command: u8, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![channel, command];
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn decode(chunks: &[&[u8]]) -> Vec<Event> {
+        let mut decoder = Decoder::default();
+        let mut events = vec![];
+        for chunk in chunks {
+            decoder.feed(chunk, |event| events.push(event));
+        }
+        events
+    }
+
+    fn settings(channels: &[(u8, usize, usize)]) -> OpcSettings {
+        let channels = channels
+            .iter()
+            .map(|&(channel, led_offset, led_count)| OpcChannel {
+                channel,
+                led_offset,
+                led_count,
+            })
+            .collect();
+        OpcSettings {
+            enabled: true,
+            port: DEFAULT_PORT,
+            channels,
+        }
+    }
+
+    #[test]
+    fn decodes_messages() {
+        let mut stream = message(1, SET_PIXELS, &[1, 2, 3, 4, 5, 6, 7]);
+        stream.extend(message(2, 0xff, &[9; 5]));
+        stream.extend(message(3, SET_PIXELS, &[]));
+        let pixel = |index, rgb| Event::Pixel {
+            channel: 1,
+            index,
+            rgb,
+        };
+        let expected = [
+            pixel(0, [1, 2, 3]),
+            pixel(1, [4, 5, 6]),
+            // the trailing byte is dropped
+            Event::End {
+                channel: 1,
+                command: SET_PIXELS,
+            },
+            Event::End {
+                channel: 2,
+                command: 0xff,
+            },
+            Event::End {
+                channel: 3,
+                command: SET_PIXELS,
+            },
+        ];
+        assert_eq!(decode(&[&stream]), expected);
+    }
+
+    #[test]
+    fn reads_may_split_messages_anywhere() {
+        let mut stream = message(1, SET_PIXELS, &[1, 2, 3, 4, 5, 6]);
+        stream.extend(message(0, 0xff, &[1, 2]));
+        stream.extend(message(2, SET_PIXELS, &[7, 8, 9]));
+        let whole = decode(&[&stream]);
+        assert_eq!(whole.len(), 6);
+
+        for at in 0..=stream.len() {
+            let (a, b) = stream.split_at(at);
+            assert_eq!(decode(&[a, b]), whole, "split at {at}");
+        }
+        let bytes: Vec<&[u8]> = stream.chunks(1).collect();
+        assert_eq!(decode(&bytes), whole);
+    }
+
+    #[test]
+    fn whole_messages_reach_the_frame() {
+        let now = Instant::now();
+        let mut frame = Frame::new(8);
+        let mut receiver = Receiver::new(&settings(&[(1, 2, 2), (2, 6, 4)]));
+
+        let msg = message(1, SET_PIXELS, &[1, 1, 1, 2, 2, 2, 3, 3, 3]);
+        assert_eq!(receiver.handle(&msg[..8], now, &mut frame), 0);
+        // nothing of a partial message is shown
+        assert_eq!(frame.pixels(), [[0; 3]; 8]);
+        assert_eq!(frame.updated(), None);
+        assert_eq!(receiver.handle(&msg[8..], now, &mut frame), 1);
+        let mut expected = [[0; 3]; 8];
+        expected[2..4].copy_from_slice(&[[1; 3], [2; 3]]);
+        assert_eq!(frame.pixels(), expected);
+        assert_eq!(frame.live_source(now, Duration::from_secs(1)), Some("opc"));
+
+        // broadcast hits both channels, past the strip's end is ignored;
+        // LEDs a message doesn't reach keep their colour
+        let msg = message(BROADCAST, SET_PIXELS, &[5; 3]);
+        assert_eq!(receiver.handle(&msg, now, &mut frame), 1);
+        expected[2] = [5; 3];
+        expected[6] = [5; 3];
+        assert_eq!(frame.pixels(), expected);
+    }
+
+    #[test]
+    fn unmapped_channels_and_other_commands_are_not_frames() {
+        let now = Instant::now();
+        let mut frame = Frame::new(4);
+        let mut receiver = Receiver::new(&settings(&[(1, 0, 4)]));
+        let mut stream = message(9, SET_PIXELS, &[1; 6]);
+        stream.extend(message(1, 0xff, &[1; 6]));
+        stream.extend(message(1, SET_PIXELS, &[]));
+        assert_eq!(receiver.handle(&stream, now, &mut frame), 0);
+        assert_eq!(frame.updated(), None);
+        assert_eq!(frame.pixels(), [[0; 3]; 4]);
+    }
+
+    #[test]
+    fn serves_clients_at_once() {
+        // a free port, released for `serve` to bind
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let frame = Arc::new(Mutex::new(Frame::new(4)));
+        let settings = OpcSettings {
+            port,
+            ..settings(&[(1, 0, 2), (2, 2, 2)])
+        };
+        serve(settings, frame.clone()).unwrap();
+
+        // the first client stays connected while the second one sends
+        let mut first = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        first.write_all(&message(1, SET_PIXELS, &[1; 3])).unwrap();
+        let mut second = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        second.write_all(&message(2, SET_PIXELS, &[2; 6])).unwrap();
+
+        let expected = [[1; 3], [0; 3], [2; 3], [2; 3]];
+        let start = Instant::now();
+        while frame.lock().unwrap().pixels() != expected {
+            assert!(start.elapsed() < Duration::from_secs(5), "no frame");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
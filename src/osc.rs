@@ -0,0 +1,833 @@
+//! OSC over UDP, for control surfaces like TouchOSC:
+//!
+//! - `/harlot/on`, `/harlot/brightness`: the master switch and dimmer
+//! - `/harlot/segment/<n>/on`, `.../brightness`, `.../color1` to `color3`:
+//!   segment `n`, counting from 1 in map order
+//! - `/harlot/preset`: recognised, but there are no presets
+//!
+//! Addresses may be OSC patterns (`*`, `?`, `[a-z]`, `{a,b}`). Changes go
+//! through the WLED translation; segment changes reach the flash once the
+//! faders have been still for a moment.
+
+use std::{
+    fmt,
+    net::{Ipv4Addr, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use embedded_svc::storage::RawStorage;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    routes::{self, SEGMENTS_FILE},
+    segments::SegmentMap,
+    settings,
+    wled::{self, Colour, SegPatch, SegPatches, StatePatch, Switch},
+};
+
+pub const DEFAULT_PORT: u16 = 8000;
+pub const MAX_PACKET: usize = 1536;
+const ROOT: &str = "/harlot";
+const BUNDLE: &[u8; 8] = b"#bundle\0";
+/// bundles in bundles in ...
+const MAX_DEPTH: usize = 4;
+const COLOURS: usize = 3;
+/// what a pattern part's `{a,b}` groups may spell out, more never match
+const MAX_ALTERNATIVES: usize = 64;
+/// how long segments have to stay unchanged before they're stored
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct OscSettings {
+    pub enabled: bool,
+    pub port: u16,
+}
+
+impl Default for OscSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: DEFAULT_PORT,
+        }
+    }
+}
+
+impl OscSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.port == 0 {
+            anyhow::bail!("osc port must be 1..=65535");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    TooShort,
+    Malformed(&'static str),
+    UnsupportedType(char),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::TooShort => write!(f, "packet too short"),
+            ParseError::Malformed(what) => write!(f, "malformed packet: {what}"),
+            ParseError::UnsupportedType(tag) => write!(f, "unsupported argument type {tag:?}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    Str(String),
+    Blob(Vec<u8>),
+    Bool(bool),
+    /// `r`, RGBA
+    Colour([u8; 4]),
+    Nil,
+    Impulse,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub address: String,
+    pub args: Vec<Arg>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    Message(Message),
+    Bundle { timetag: u64, elements: Vec<Packet> },
+}
+
+impl Packet {
+    /// Every message, bundles unpacked in order.
+    pub fn messages(&self) -> Vec<&Message> {
+        match self {
+            Packet::Message(message) => vec![message],
+            Packet::Bundle { elements, .. } => elements.iter().flat_map(Packet::messages).collect(),
+        }
+    }
+}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ParseError> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(ParseError::TooShort)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ParseError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    /// NUL terminated, padded to four bytes
+    fn string(&mut self) -> Result<&'a str, ParseError> {
+        let rest = self.buf.get(self.pos..).ok_or(ParseError::TooShort)?;
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(ParseError::Malformed("unterminated string"))?;
+        let s = std::str::from_utf8(&rest[..len]).map_err(|_| ParseError::Malformed("string"))?;
+        self.take(padded(len + 1))?;
+        Ok(s)
+    }
+
+    fn blob(&mut self) -> Result<&'a [u8], ParseError> {
+        let len = i32::from_be_bytes(self.array()?);
+        let len = usize::try_from(len).map_err(|_| ParseError::Malformed("blob size"))?;
+        let blob = self.take(len)?;
+        self.take(padded(len) - len)?;
+        Ok(blob)
+    }
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+pub fn parse(buf: &[u8]) -> Result<Packet, ParseError> {
+    parse_at(buf, 0)
+}
+
+fn parse_at(buf: &[u8], depth: usize) -> Result<Packet, ParseError> {
+    if buf.len() % 4 != 0 {
+        return Err(ParseError::Malformed("size not a multiple of 4"));
+    }
+    let mut cursor = Cursor { buf, pos: 0 };
+
+    if buf.starts_with(BUNDLE) {
+        if depth >= MAX_DEPTH {
+            return Err(ParseError::Malformed("bundles nested too deep"));
+        }
+        cursor.take(BUNDLE.len())?;
+        let timetag = u64::from_be_bytes(cursor.array()?);
+        let mut elements = vec![];
+        while cursor.pos < buf.len() {
+            let element = cursor.blob()?;
+            elements.push(parse_at(element, depth + 1)?);
+        }
+        return Ok(Packet::Bundle { timetag, elements });
+    }
+
+    let address = cursor.string()?;
+    if !address.starts_with('/') {
+        return Err(ParseError::Malformed("address"));
+    }
+    // very old senders leave out the type tags
+    let tags = match cursor.pos < buf.len() {
+        true => cursor.string()?,
+        false => ",",
+    };
+    let tags = tags
+        .strip_prefix(',')
+        .ok_or(ParseError::Malformed("type tags"))?;
+
+    let mut args = vec![];
+    for tag in tags.chars() {
+        let arg = match tag {
+            'i' => Arg::Int(i32::from_be_bytes(cursor.array()?)),
+            'h' => Arg::Long(i64::from_be_bytes(cursor.array()?)),
+            'f' => Arg::Float(f32::from_be_bytes(cursor.array()?)),
+            'd' => Arg::Double(f64::from_be_bytes(cursor.array()?)),
+            's' | 'S' => Arg::Str(cursor.string()?.to_string()),
+            'b' => Arg::Blob(cursor.blob()?.to_vec()),
+            'r' => Arg::Colour(cursor.array()?),
+            'T' => Arg::Bool(true),
+            'F' => Arg::Bool(false),
+            'N' => Arg::Nil,
+            'I' => Arg::Impulse,
+            other => return Err(ParseError::UnsupportedType(other)),
+        };
+        args.push(arg);
+    }
+    Ok(Packet::Message(Message {
+        address: address.to_string(),
+        args,
+    }))
+}
+
+/// OSC address pattern matching, part by part.
+pub fn matches(pattern: &str, address: &str) -> bool {
+    let mut patterns = pattern.split('/');
+    let mut parts = address.split('/');
+    loop {
+        match (patterns.next(), parts.next()) {
+            (None, None) => return true,
+            (Some(pattern), Some(part)) if matches_part(pattern.as_bytes(), part.as_bytes()) => {}
+            _ => return false,
+        }
+    }
+}
+
+fn matches_part(pattern: &[u8], name: &[u8]) -> bool {
+    match alternatives(pattern) {
+        Some(patterns) => patterns.iter().any(|pattern| glob(pattern, name)),
+        None => false,
+    }
+}
+
+/// The `{a,b}` groups of `pattern` spelled out, `None` for an unclosed
+/// group or too many alternatives.
+fn alternatives(pattern: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut out = vec![vec![]];
+    let mut i = 0;
+    while i < pattern.len() {
+        let len = match pattern[i] {
+            // braces in a character set are just characters
+            b'[' => pattern[i..]
+                .iter()
+                .position(|&b| b == b']')
+                .map_or(1, |end| end + 1),
+            b'{' => {
+                let end = i + pattern[i..].iter().position(|&b| b == b'}')?;
+                let alts: Vec<&[u8]> = pattern[i + 1..end].split(|&b| b == b',').collect();
+                if out.len() * alts.len() > MAX_ALTERNATIVES {
+                    return None;
+                }
+                out = out
+                    .iter()
+                    .flat_map(|start| alts.iter().map(move |alt| [start, *alt].concat()))
+                    .collect();
+                i = end + 1;
+                continue;
+            }
+            _ => 1,
+        };
+        for start in &mut out {
+            start.extend_from_slice(&pattern[i..i + len]);
+        }
+        i += len;
+    }
+    Some(out)
+}
+
+/// Whether `c` is in the set at the start of `pattern` (`[a-z]`, `[!abc]`),
+/// and the set's length; `None` if it isn't closed.
+fn in_set(pattern: &[u8], c: u8) -> Option<(bool, usize)> {
+    let end = pattern.iter().position(|&b| b == b']')?;
+    let (negated, set) = match &pattern[1..end] {
+        [b'!', set @ ..] => (true, set),
+        set => (false, set),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == b'-' {
+            found |= (set[i]..=set[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= set[i] == c;
+            i += 1;
+        }
+    }
+    Some((found != negated, end + 1))
+}
+
+/// `*`, `?` and `[...]` matching without `{}`. A mismatch goes back to the
+/// last `*` and lets it take one more character, so this is linear in the
+/// name for every `*` instead of exponential.
+fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // pattern position after the last `*`, and the name position it took up to
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                // consecutive stars are one
+                while pattern.get(p) == Some(&b'*') {
+                    p += 1;
+                }
+                star = Some((p, n));
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => match in_set(&pattern[p..], name[n]) {
+                Some((true, len)) => Some(len),
+                _ => None,
+            },
+            Some(&c) if c == name[n] => Some(1),
+            _ => None,
+        };
+        match (step, &mut star) {
+            (Some(len), _) => {
+                p += len;
+                n += 1;
+            }
+            (None, Some((after, taken))) => {
+                *taken += 1;
+                (p, n) = (*after, *taken);
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    On,
+    Brightness,
+    Preset,
+    SegmentOn(usize),
+    SegmentBrightness(usize),
+    SegmentColour(usize, usize),
+}
+
+/// Our address space with `segments` segments.
+fn targets(segments: usize) -> Vec<(String, Target)> {
+    let mut targets = vec![
+        (format!("{ROOT}/on"), Target::On),
+        (format!("{ROOT}/brightness"), Target::Brightness),
+        (format!("{ROOT}/preset"), Target::Preset),
+    ];
+    for idx in 0..segments {
+        let seg = format!("{ROOT}/segment/{}", idx + 1);
+        targets.push((format!("{seg}/on"), Target::SegmentOn(idx)));
+        targets.push((format!("{seg}/brightness"), Target::SegmentBrightness(idx)));
+        for colour in 0..COLOURS {
+            let address = format!("{seg}/color{}", colour + 1);
+            targets.push((address, Target::SegmentColour(idx, colour)));
+        }
+    }
+    targets
+}
+
+fn first(args: &[Arg]) -> Result<&Arg, String> {
+    args.first()
+        .ok_or_else(|| "expected an argument".to_string())
+}
+
+/// floats are 0..=1, integers 0..=255
+fn channel(arg: &Arg) -> Option<u8> {
+    match *arg {
+        Arg::Float(f) => Some((f.clamp(0.0, 1.0) * 255.0 + 0.5) as u8),
+        Arg::Double(d) => Some((d.clamp(0.0, 1.0) * 255.0 + 0.5) as u8),
+        Arg::Int(i) => Some(i.clamp(0, 255) as u8),
+        Arg::Long(l) => Some(l.clamp(0, 255) as u8),
+        _ => None,
+    }
+}
+
+fn level(args: &[Arg]) -> Result<u8, String> {
+    match first(args)? {
+        Arg::Bool(on) => Ok(if *on { 255 } else { 0 }),
+        arg => channel(arg).ok_or_else(|| format!("expected a level, got {arg:?}")),
+    }
+}
+
+fn switch(args: &[Arg]) -> Result<bool, String> {
+    match first(args)? {
+        Arg::Bool(on) => Ok(*on),
+        // buttons send 1 while pressed
+        Arg::Float(f) => Ok(*f >= 0.5),
+        Arg::Double(d) => Ok(*d >= 0.5),
+        Arg::Int(i) => Ok(*i != 0),
+        Arg::Long(l) => Ok(*l != 0),
+        arg => Err(format!("expected on or off, got {arg:?}")),
+    }
+}
+
+fn hex(s: &str) -> Option<[u8; 3]> {
+    let s = s.strip_prefix('#').unwrap_or(s);
+    if s.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(s.get(i..i + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// `r`, three numbers, `"RRGGBB"` or a blob of three bytes
+fn colour(args: &[Arg]) -> Result<[u8; 3], String> {
+    let rgb = match args {
+        [Arg::Colour([r, g, b, _]), ..] => Some([*r, *g, *b]),
+        [Arg::Str(s), ..] => hex(s),
+        [Arg::Blob(b), ..] if b.len() >= 3 => Some([b[0], b[1], b[2]]),
+        [r, g, b, ..] => match (channel(r), channel(g), channel(b)) {
+            (Some(r), Some(g), Some(b)) => Some([r, g, b]),
+            _ => None,
+        },
+        _ => None,
+    };
+    rgb.ok_or_else(|| format!("expected a colour, got {args:?}"))
+}
+
+fn segment(patch: &mut StatePatch, idx: usize) -> &mut SegPatch {
+    if !matches!(patch.seg, Some(SegPatches::Many(_))) {
+        patch.seg = Some(SegPatches::Many(vec![]));
+    }
+    let Some(SegPatches::Many(segs)) = &mut patch.seg else {
+        unreachable!()
+    };
+    match segs.iter().position(|s| s.id == Some(idx)) {
+        Some(pos) => &mut segs[pos],
+        None => {
+            segs.push(SegPatch {
+                id: Some(idx),
+                ..Default::default()
+            });
+            segs.last_mut().unwrap()
+        }
+    }
+}
+
+/// Adds what `message` asks for to `patch`, so a bundle becomes a single
+/// change. `segments` is how many there are.
+pub fn apply(message: &Message, segments: usize, patch: &mut StatePatch) -> Result<(), String> {
+    let args = &message.args;
+    let mut matched = false;
+    for (address, target) in targets(segments) {
+        if !matches(&message.address, &address) {
+            continue;
+        }
+        matched = true;
+        match target {
+            Target::On => patch.on = Some(Switch::Set(switch(args)?)),
+            Target::Brightness => patch.bri = Some(level(args)?),
+            Target::Preset => return Err("there are no presets on this board".into()),
+            Target::SegmentOn(idx) => segment(patch, idx).on = Some(Switch::Set(switch(args)?)),
+            Target::SegmentBrightness(idx) => segment(patch, idx).bri = Some(level(args)?),
+            Target::SegmentColour(idx, n) => {
+                let [r, g, b] = colour(args)?;
+                let cols = segment(patch, idx).col.get_or_insert_with(Vec::new);
+                // empty colours are left alone
+                if cols.len() <= n {
+                    cols.resize(n + 1, Colour::Rgb(vec![]));
+                }
+                cols[n] = Colour::Rgb(vec![r, g, b]);
+            }
+        }
+    }
+    match matched {
+        true => Ok(()),
+        false => Err(format!("nothing at {}", message.address)),
+    }
+}
+
+/// Listens on `settings.port` on its own thread. Bundles are applied as
+/// soon as they arrive, whatever their timetag: the board has no wall clock.
+pub fn serve<S>(
+    settings: OscSettings,
+    segments: Arc<Mutex<SegmentMap>>,
+    storage: Arc<Mutex<S>>,
+    master: Arc<Mutex<wled::Master>>,
+) -> anyhow::Result<()>
+where
+    S: RawStorage + Send + 'static,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, settings.port))?;
+    socket.set_read_timeout(Some(SAVE_DELAY / 4))?;
+
+    thread::Builder::new().stack_size(8192).spawn(move || {
+        let mut buf = [0u8; MAX_PACKET];
+        // when the segments changed without being stored
+        let mut unsaved: Option<Instant> = None;
+        loop {
+            if let Ok(n) = socket.recv(&mut buf) {
+                if handle(&buf[..n], &segments, &master) {
+                    unsaved = Some(Instant::now());
+                }
+            }
+            if unsaved.map_or(false, |at| at.elapsed() >= SAVE_DELAY) {
+                unsaved = None;
+                let map = segments.lock().unwrap();
+                let res = settings::store_json(
+                    &mut *storage.lock().unwrap(),
+                    SEGMENTS_FILE,
+                    map.segments(),
+                );
+                if let Err(e) = res {
+                    log::warn!("osc: could not store segments: {e}");
+                }
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// Whether a segment changed.
+fn handle(buf: &[u8], segments: &Mutex<SegmentMap>, master: &Mutex<wled::Master>) -> bool {
+    let packet = match parse(buf) {
+        Ok(packet) => packet,
+        Err(e) => {
+            log::debug!("osc: {e}");
+            return false;
+        }
+    };
+    // resolve the addresses without holding up the other writers
+    let count = segments.lock().unwrap().segments().len();
+    let mut patch = StatePatch::default();
+    for message in packet.messages() {
+        if let Err(e) = apply(message, count, &mut patch) {
+            log::debug!("osc: {}: {e}", message.address);
+        }
+    }
+    if patch == StatePatch::default() {
+        return false;
+    }
+    // a segment removed in the meantime fails the whole patch
+    let mut map = segments.lock().unwrap();
+    match routes::apply_wled_unsaved(&mut map, master, &patch) {
+        Ok((_, changed)) => changed,
+        Err(e) => {
+            log::debug!("osc: {e}");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::segments::fixture;
+
+    fn string(s: &str, out: &mut Vec<u8>) {
+        out.extend_from_slice(s.as_bytes());
+        out.resize(padded(out.len() + 1), 0);
+    }
+
+    fn message(address: &str, tags: &str, args: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        string(address, &mut out);
+        string(tags, &mut out);
+        out.extend_from_slice(args);
+        out
+    }
+
+    fn bundle(elements: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = BUNDLE.to_vec();
+        out.extend_from_slice(&1u64.to_be_bytes());
+        for element in elements {
+            out.extend_from_slice(&(element.len() as i32).to_be_bytes());
+            out.extend_from_slice(element);
+        }
+        out
+    }
+
+    fn msg(address: &str, args: Vec<Arg>) -> Message {
+        Message {
+            address: address.into(),
+            args,
+        }
+    }
+
+    #[test]
+    fn parses_messages() {
+        let mut args = vec![];
+        args.extend_from_slice(&7i32.to_be_bytes());
+        args.extend_from_slice(&0.5f32.to_be_bytes());
+        string("hi", &mut args);
+        args.extend_from_slice(&3i32.to_be_bytes());
+        args.extend_from_slice(&[1, 2, 3, 0]);
+        args.extend_from_slice(&[9, 8, 7, 255]);
+        args.extend_from_slice(&(-2i64).to_be_bytes());
+        args.extend_from_slice(&0.25f64.to_be_bytes());
+        let buf = message("/a/b", ",ifsbrhdTFNI", &args);
+        let expected = msg(
+            "/a/b",
+            vec![
+                Arg::Int(7),
+                Arg::Float(0.5),
+                Arg::Str("hi".into()),
+                Arg::Blob(vec![1, 2, 3]),
+                Arg::Colour([9, 8, 7, 255]),
+                Arg::Long(-2),
+                Arg::Double(0.25),
+                Arg::Bool(true),
+                Arg::Bool(false),
+                Arg::Nil,
+                Arg::Impulse,
+            ],
+        );
+        assert_eq!(parse(&buf), Ok(Packet::Message(expected)));
+
+        // without type tags
+        let mut old = vec![];
+        string("/abc", &mut old);
+        assert_eq!(parse(&old), Ok(Packet::Message(msg("/abc", vec![]))));
+    }
+
+    #[test]
+    fn parses_bundles_in_order() {
+        let on = message("/a", ",T", &[]);
+        let inner = bundle(&[message("/b", ",i", &1i32.to_be_bytes())]);
+        let packet = parse(&bundle(&[on, inner])).unwrap();
+        let addresses: Vec<_> = packet.messages().iter().map(|m| &m.address).collect();
+        assert_eq!(addresses, ["/a", "/b"]);
+
+        let mut nested = message("/deep", ",", &[]);
+        for _ in 0..MAX_DEPTH {
+            nested = bundle(&[nested]);
+        }
+        assert!(parse(&nested).is_ok());
+        assert_eq!(
+            parse(&bundle(&[nested])),
+            Err(ParseError::Malformed("bundles nested too deep"))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        let cases: [(Vec<u8>, ParseError); 7] = [
+            (
+                b"/ab".to_vec(),
+                ParseError::Malformed("size not a multiple of 4"),
+            ),
+            (
+                b"/abc".to_vec(),
+                ParseError::Malformed("unterminated string"),
+            ),
+            (message("abc", ",", &[]), ParseError::Malformed("address")),
+            (
+                message("/a", "i", &[0; 4]),
+                ParseError::Malformed("type tags"),
+            ),
+            (message("/a", ",i", &[]), ParseError::TooShort),
+            (
+                message("/a", ",c", &[0; 4]),
+                ParseError::UnsupportedType('c'),
+            ),
+            (
+                message("/a", ",b", &[0xff; 4]),
+                ParseError::Malformed("blob size"),
+            ),
+        ];
+        for (buf, error) in cases {
+            assert_eq!(parse(&buf), Err(error), "{buf:?}");
+        }
+        let mut cut = bundle(&[message("/a", ",", &[])]);
+        cut.truncate(cut.len() - 4);
+        assert_eq!(parse(&cut), Err(ParseError::TooShort));
+    }
+
+    #[test]
+    fn matches_patterns() {
+        let yes = [
+            ("/harlot/on", "/harlot/on"),
+            ("/harlot/*", "/harlot/brightness"),
+            ("/harlot/segment/*/color1", "/harlot/segment/12/color1"),
+            ("/harlot/segment/?/on", "/harlot/segment/3/on"),
+            ("/harlot/segment/[1-3]/on", "/harlot/segment/2/on"),
+            ("/harlot/segment/[!1-3]/on", "/harlot/segment/4/on"),
+            ("/harlot/segment/1/color[13]", "/harlot/segment/1/color3"),
+            ("/harlot/{on,brightness}", "/harlot/brightness"),
+            (
+                "/harlot/segment/1/{col,}or{1,2}",
+                "/harlot/segment/1/color2",
+            ),
+            ("/harlot/*ness", "/harlot/brightness"),
+            ("/harlot/b**t*s*", "/harlot/brightness"),
+            ("/harlot/*", "/harlot/"),
+            ("/[{]", "/{"),
+        ];
+        for (pattern, address) in yes {
+            assert!(matches(pattern, address), "{pattern} {address}");
+        }
+        let no = [
+            ("/harlot/on", "/harlot/one"),
+            ("/harlot/*", "/harlot/segment/1/on"),
+            ("/harlot/segment/?/on", "/harlot/segment/12/on"),
+            ("/harlot/segment/[1-3]/on", "/harlot/segment/4/on"),
+            ("/harlot/segment/[1-3/on", "/harlot/segment/1/on"),
+            ("/harlot/{on,bri", "/harlot/on"),
+            ("/harlot/*x", "/harlot/brightness"),
+            ("/harlot/b*t*q", "/harlot/brightness"),
+        ];
+        for (pattern, address) in no {
+            assert!(!matches(pattern, address), "{pattern} {address}");
+        }
+    }
+
+    #[test]
+    fn hostile_patterns_stay_cheap() {
+        // exponential for a backtracking matcher
+        let name = format!("/{}b", "a".repeat(200));
+        let stars = format!("/{}c", "a*".repeat(100));
+        let braces = format!("/{}c", "{a,a}".repeat(20));
+        let start = Instant::now();
+        assert!(!matches(&stars, &name));
+        assert!(!matches(&braces, &name));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // the first six groups are within the limit
+        assert!(matches(&format!("/{}*", "{a,a}".repeat(6)), &name));
+        assert!(!matches(&format!("/{}*", "{a,a}".repeat(7)), &name));
+    }
+
+    #[test]
+    fn messages_become_one_patch() {
+        let messages = [
+            msg("/harlot/on", vec![Arg::Float(1.0)]),
+            msg("/harlot/brightness", vec![Arg::Int(300)]),
+            msg("/harlot/segment/2/color2", vec![Arg::Str("#0a0b0c".into())]),
+            msg("/harlot/segment/*/on", vec![Arg::Bool(false)]),
+            msg("/harlot/segment/1/brightness", vec![Arg::Float(0.5)]),
+        ];
+        let mut patch = StatePatch::default();
+        for message in &messages {
+            apply(message, 2, &mut patch).unwrap();
+        }
+        let off = |id| SegPatch {
+            id: Some(id),
+            on: Some(Switch::Set(false)),
+            ..Default::default()
+        };
+        let expected = StatePatch {
+            on: Some(Switch::Set(true)),
+            bri: Some(255),
+            seg: Some(SegPatches::Many(vec![
+                SegPatch {
+                    col: Some(vec![Colour::Rgb(vec![]), Colour::Rgb(vec![10, 11, 12])]),
+                    ..off(1)
+                },
+                SegPatch {
+                    bri: Some(128),
+                    ..off(0)
+                },
+            ])),
+            ..Default::default()
+        };
+        assert_eq!(patch, expected);
+    }
+
+    #[test]
+    fn colours_and_levels_take_any_form() {
+        let rgb = |args| {
+            let mut patch = StatePatch::default();
+            apply(&msg("/harlot/segment/1/color1", args), 1, &mut patch)?;
+            Ok::<_, String>(segment(&mut patch, 0).col.clone().unwrap())
+        };
+        let red = Ok(vec![Colour::Rgb(vec![255, 0, 0])]);
+        assert_eq!(rgb(vec![Arg::Colour([255, 0, 0, 9])]), red);
+        assert_eq!(rgb(vec![Arg::Str("ff0000".into())]), red);
+        assert_eq!(rgb(vec![Arg::Blob(vec![255, 0, 0])]), red);
+        assert_eq!(
+            rgb(vec![Arg::Float(1.0), Arg::Int(0), Arg::Double(-1.0)]),
+            red
+        );
+        assert!(rgb(vec![Arg::Str("red".into())]).is_err());
+        assert!(rgb(vec![Arg::Int(1), Arg::Int(2)]).is_err());
+
+        let mut patch = StatePatch::default();
+        assert!(apply(&msg("/harlot/on", vec![]), 1, &mut patch).is_err());
+        assert!(apply(&msg("/harlot/on", vec![Arg::Nil]), 1, &mut patch).is_err());
+        assert!(apply(&msg("/harlot/preset", vec![Arg::Int(1)]), 1, &mut patch).is_err());
+        assert!(apply(
+            &msg("/harlot/segment/2/on", vec![Arg::Int(1)]),
+            1,
+            &mut patch
+        )
+        .is_err());
+        assert_eq!(patch, StatePatch::default());
+    }
+
+    #[test]
+    fn handles_packets_against_the_segments() {
+        let segments = Mutex::new(fixture::map(
+            &[fixture::segment(5), fixture::segment(5)],
+            100,
+        ));
+        let master = Mutex::new(wled::Master::default());
+
+        let packet = bundle(&[
+            message("/harlot/segment/2/brightness", ",i", &51i32.to_be_bytes()),
+            message("/harlot/brightness", ",f", &0.5f32.to_be_bytes()),
+            message("/nothing/here", ",", &[]),
+        ]);
+        assert!(handle(&packet, &segments, &master));
+        let brightness: Vec<_> = segments
+            .lock()
+            .unwrap()
+            .segments()
+            .values()
+            .map(|s| serde_json::to_value(s).unwrap()["brightness"].clone())
+            .collect();
+        assert_eq!(brightness, [50, 20]);
+        assert_eq!(*master.lock().unwrap(), wled::Master { on: true, bri: 128 });
+
+        // the master alone isn't a segment change
+        let packet = message("/harlot/on", ",F", &[]);
+        assert!(!handle(&packet, &segments, &master));
+        assert!(!master.lock().unwrap().on);
+        assert!(!handle(b"junk", &segments, &master));
+        assert!(!handle(&packet, &segments, &master));
+    }
+}
@@ -0,0 +1,146 @@
+//! Firmware updates over HTTP.
+//!
+//! `POST /ota` streams the image into the inactive OTA slot while hashing
+//! it, checks length and SHA-256 against what the client announced and only
+//! then switches the boot partition; the checks live in [`crate::image`].
+//! The new image boots unverified; if it doesn't pass [`health_check`] the
+//! bootloader falls back to the old one.
+
+use std::{
+    io,
+    ptr::null,
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use embedded_svc::{
+    io::Write,
+    ota::{Ota, OtaUpdate, SlotState},
+};
+use esp_idf_svc::ota::EspOta;
+use serde::Serialize;
+
+use crate::api::ApiError;
+pub use crate::image::{parse_digest, Verifier, DIGEST_HEADER};
+
+const CHUNK: usize = 4096;
+/// how long a fresh image has to run before it is marked valid
+pub const HEALTH_CHECK_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Progress {
+    #[default]
+    Idle,
+    Receiving {
+        received: usize,
+        total: usize,
+    },
+    /// written and verified, waiting for the reboot
+    Complete,
+    Failed {
+        error: String,
+    },
+}
+
+impl Progress {
+    pub fn is_busy(&self) -> bool {
+        matches!(self, Progress::Receiving { .. } | Progress::Complete)
+    }
+}
+
+/// size of the slot the next update goes to
+pub fn slot_size() -> Option<usize> {
+    let partition = unsafe { esp_idf_sys::esp_ota_get_next_update_partition(null()) };
+    if partition.is_null() {
+        return None;
+    }
+    Some(unsafe { (*partition).size } as usize)
+}
+
+/// Streams `len` bytes from `reader` into the inactive slot. On success the
+/// boot partition has been switched and the caller should reboot.
+pub fn update(
+    reader: &mut dyn io::Read,
+    len: usize,
+    digest: [u8; 32],
+    progress: &Mutex<Progress>,
+) -> Result<(), ApiError> {
+    let internal = |e: anyhow::Error| ApiError::new(500, e.to_string());
+
+    match slot_size() {
+        None => return Err(ApiError::new(500, "no OTA slot to update")),
+        Some(size) if len > size => {
+            return Err(ApiError::new(413, format!("image exceeds the {size} byte OTA slot")))
+        }
+        _ => {}
+    }
+
+    let mut ota = EspOta::new().map_err(|e| internal(e.into()))?;
+    let mut flash = ota.initiate_update().map_err(|e| internal(e.into()))?;
+    let mut verifier = Verifier::new(len, digest);
+    let mut buf = vec![0u8; CHUNK];
+
+    let res = loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => n,
+            Err(e) => break Err(ApiError::bad_request(e.to_string())),
+        };
+        if let Err(e) = verifier.update(&buf[..n]) {
+            break Err(e);
+        }
+        if let Err(e) = flash.write_all(&buf[..n]) {
+            break Err(internal(anyhow::anyhow!("{e:?}")));
+        }
+        *progress.lock().unwrap() = Progress::Receiving {
+            received: verifier.received(),
+            total: len,
+        };
+    };
+
+    match res.and_then(|_| verifier.finish()) {
+        Ok(()) => {
+            flash.complete().map_err(|e| internal(e.into()))?;
+            Ok(())
+        }
+        Err(e) => {
+            let _ = flash.abort();
+            Err(e)
+        }
+    }
+}
+
+/// Marks a freshly updated image valid once `healthy` says so after
+/// [`HEALTH_CHECK_DELAY`], otherwise rolls back to the previous one.
+pub fn health_check(healthy: impl Fn() -> bool + Send + 'static) -> anyhow::Result<()> {
+    let mut ota = EspOta::new()?;
+    if ota.get_running_slot()?.state != SlotState::Unverified {
+        return Ok(());
+    }
+    log::warn!("running an unverified image, checking health in {HEALTH_CHECK_DELAY:?}");
+
+    thread::Builder::new().stack_size(4096).spawn(move || {
+        thread::sleep(HEALTH_CHECK_DELAY);
+        if healthy() {
+            log::info!("new image is healthy, keeping it");
+            if let Err(e) = ota.mark_running_slot_valid() {
+                log::error!("could not mark image valid: {e:?}");
+            }
+        } else {
+            log::error!("new image is unhealthy, rolling back");
+            ota.mark_running_slot_invalid_and_reboot();
+        }
+    })?;
+    Ok(())
+}
+
+/// Gives the HTTP response a moment to go out before restarting.
+pub fn reboot_soon() {
+    let _ = thread::Builder::new().stack_size(2048).spawn(|| {
+        thread::sleep(Duration::from_secs(1));
+        log::warn!("rebooting into the new image");
+        unsafe { esp_idf_sys::esp_restart() };
+    });
+}
@@ -0,0 +1,816 @@
+//! Pixels pushed over the network. While they keep coming they replace the
+//! local animation; once they stop for [`RealtimeSettings::timeout_ms`] the
+//! segments take over again. Every source writes a [`Frame`] of its own, and
+//! the [`Arbiter`] decides which one is shown.
+
+use std::{
+    collections::BTreeMap,
+    net::Ipv4Addr,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::ApiError;
+
+/// Everything that can stream pixels, by the name it commits frames with.
+pub const SOURCES: [&str; 8] = [
+    "e131", "artnet", "ddp", "opc", "tpm2", "adalight", "http", "ws",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RealtimeSettings {
+    /// how long a source may stay silent before it loses the strip
+    pub timeout_ms: u32,
+    /// 0..=100, like segment brightness
+    pub brightness: u8,
+    /// fade back to the segments over this long, 0 to cut
+    pub crossfade_ms: u32,
+    /// ignore every source and keep the segments on the strip
+    pub lock_local: bool,
+    /// per source overrides, by name in [`SOURCES`]
+    pub sources: BTreeMap<String, SourcePolicy>,
+}
+
+impl Default for RealtimeSettings {
+    fn default() -> Self {
+        Self {
+            // the E1.31 network data loss timeout
+            timeout_ms: 2500,
+            brightness: 50,
+            crossfade_ms: 500,
+            lock_local: false,
+            sources: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct SourcePolicy {
+    /// the highest live one wins; among equals the one on the strip keeps
+    /// it, or else the latest
+    pub priority: u8,
+    /// `timeout_ms` if unset
+    pub timeout_ms: Option<u32>,
+}
+
+impl Default for SourcePolicy {
+    fn default() -> Self {
+        Self {
+            priority: 100,
+            timeout_ms: None,
+        }
+    }
+}
+
+fn valid_timeout(ms: u32) -> bool {
+    (100..=60_000).contains(&ms)
+}
+
+impl RealtimeSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !valid_timeout(self.timeout_ms) {
+            anyhow::bail!("realtime timeout_ms must be 100..=60000");
+        }
+        if self.brightness > crate::validate::MAX_BRIGHTNESS {
+            anyhow::bail!("realtime brightness must be 0..=100");
+        }
+        if self.crossfade_ms > 10_000 {
+            anyhow::bail!("realtime crossfade_ms must be 0..=10000");
+        }
+        for (name, policy) in &self.sources {
+            if !SOURCES.contains(&name.as_str()) {
+                anyhow::bail!("realtime source {name:?} must be one of {SOURCES:?}");
+            }
+            if !policy.timeout_ms.map_or(true, valid_timeout) {
+                anyhow::bail!("realtime {name} timeout_ms must be 100..=60000");
+            }
+        }
+        Ok(())
+    }
+
+    /// The priority and timeout for `source`.
+    pub fn policy(&self, source: &str) -> (u8, Duration) {
+        let policy = self.sources.get(source).cloned().unwrap_or_default();
+        let timeout_ms = policy.timeout_ms.unwrap_or(self.timeout_ms);
+        (policy.priority, Duration::from_millis(timeout_ms as u64))
+    }
+}
+
+/// What the board tells controllers about itself on discovery.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub ip: Ipv4Addr,
+    pub mac: [u8; 6],
+    /// the hostname; Art-Net sends at most 17 bytes
+    pub short_name: String,
+    /// the instance name; Art-Net sends at most 63 bytes
+    pub long_name: String,
+}
+
+/// whole RGB LEDs in the 512 channels of a DMX universe
+pub const LEDS_PER_UNIVERSE: usize = 170;
+/// a sequence number this far behind the last one means the sender restarted
+const SEQUENCE_WINDOW: i8 = -20;
+
+/// Whether `next` may follow `last`; sequence numbers wrap around.
+pub fn in_sequence(last: u8, next: u8) -> bool {
+    let delta = next.wrapping_sub(last) as i8;
+    delta > 0 || delta <= SEQUENCE_WINDOW
+}
+
+/// Where the DMX universes of a protocol land on the strip.
+///
+/// `led_count` LEDs from `led_offset` on take three channels each, starting
+/// at `start_address` in `universe`. Further universes are filled with
+/// whole LEDs from channel 1 on, [`LEDS_PER_UNIVERSE`] each.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct DmxMapping {
+    pub universe: u16,
+    /// DMX address of the first LED's red channel in the first universe
+    pub start_address: u16,
+    /// first LED driven
+    pub led_offset: usize,
+    pub led_count: usize,
+}
+
+impl Default for DmxMapping {
+    fn default() -> Self {
+        Self {
+            universe: 1,
+            start_address: 1,
+            led_offset: 0,
+            led_count: LEDS_PER_UNIVERSE,
+        }
+    }
+}
+
+impl DmxMapping {
+    /// `universes` is what the protocol can address.
+    pub fn validate(&self, universes: RangeInclusive<u16>) -> anyhow::Result<()> {
+        let (first, last) = (*universes.start(), *universes.end());
+        if !universes.contains(&self.universe) {
+            anyhow::bail!("universe must be {first}..={last}");
+        }
+        // room for at least one LED in the first universe
+        if !(1..=510).contains(&self.start_address) {
+            anyhow::bail!("start_address must be 1..=510");
+        }
+        if !(1..=4096).contains(&self.led_count) {
+            anyhow::bail!("led_count must be 1..=4096");
+        }
+        if self.universe as usize + self.universe_count() - 1 > last as usize {
+            anyhow::bail!("the LEDs would need universes past {last}");
+        }
+        Ok(())
+    }
+
+    fn first_universe_leds(&self) -> usize {
+        (512 - (self.start_address as usize - 1)) / 3
+    }
+
+    pub fn universe_count(&self) -> usize {
+        let first = self.first_universe_leds();
+        if self.led_count <= first {
+            1
+        } else {
+            1 + (self.led_count - first + LEDS_PER_UNIVERSE - 1) / LEDS_PER_UNIVERSE
+        }
+    }
+
+    pub fn universes(&self) -> impl Iterator<Item = u16> {
+        let first = self.universe;
+        (0..self.universe_count()).map(move |i| first + i as u16)
+    }
+
+    pub fn contains(&self, universe: u16) -> bool {
+        self.layout(universe).is_some()
+    }
+
+    /// First LED (relative to `led_offset`) and first channel index of a
+    /// universe, if it is one of ours.
+    fn layout(&self, universe: u16) -> Option<(usize, usize)> {
+        let idx = universe.checked_sub(self.universe)? as usize;
+        if idx >= self.universe_count() {
+            return None;
+        }
+        Some(match idx {
+            0 => (0, self.start_address as usize - 1),
+            _ => (
+                self.first_universe_leds() + (idx - 1) * LEDS_PER_UNIVERSE,
+                0,
+            ),
+        })
+    }
+
+    /// Copies the DMX slots of `universe` into `frame`, returns whether it
+    /// is one of ours.
+    pub fn apply(&self, universe: u16, data: &[u8], frame: &mut Frame) -> bool {
+        let (first_led, first_channel) = match self.layout(universe) {
+            Some(layout) => layout,
+            None => return false,
+        };
+        let channels = data.get(first_channel..).unwrap_or_default();
+        let leds = self.led_count.saturating_sub(first_led);
+        for (i, rgb) in channels.chunks_exact(3).take(leds).enumerate() {
+            frame.set(self.led_offset + first_led + i, [rgb[0], rgb[1], rgb[2]]);
+        }
+        true
+    }
+}
+
+/// The latest pixels from the network, one RGB triple per LED.
+pub struct Frame {
+    pixels: Vec<[u8; 3]>,
+    source: Option<&'static str>,
+    updated: Option<Instant>,
+}
+
+impl Frame {
+    pub fn new(len: usize) -> Self {
+        Self {
+            pixels: vec![[0; 3]; len],
+            source: None,
+            updated: None,
+        }
+    }
+
+    /// LEDs past the end of the strip are ignored.
+    pub fn set(&mut self, idx: usize, rgb: [u8; 3]) {
+        if let Some(pixel) = self.pixels.get_mut(idx) {
+            *pixel = rgb;
+        }
+    }
+
+    pub fn pixels(&self) -> &[[u8; 3]] {
+        &self.pixels
+    }
+
+    /// Marks the pixels as fresh from `source`.
+    pub fn commit(&mut self, source: &'static str, now: Instant) {
+        self.source = Some(source);
+        self.updated = Some(now);
+    }
+
+    /// Hands the strip back right away, e.g. when a sender says goodbye.
+    pub fn release(&mut self) {
+        self.source = None;
+        self.updated = None;
+    }
+
+    /// When the pixels were last committed, unless released since.
+    pub fn updated(&self) -> Option<Instant> {
+        self.updated
+    }
+
+    /// The source currently driving the strip, if any.
+    pub fn live_source(&self, now: Instant, timeout: Duration) -> Option<&'static str> {
+        let updated = self.updated?;
+        (now.saturating_duration_since(updated) < timeout)
+            .then(|| self.source)
+            .flatten()
+    }
+}
+
+/// Pixels pushed through the API, by `POST /realtime/pixels` or over `/ws`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Pixels {
+    /// LED the first pixel lands on
+    #[serde(default)]
+    pub offset: usize,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl Pixels {
+    /// Writes the pixels to `frame` and commits them as `source`.
+    pub fn show(
+        &self,
+        frame: &mut Frame,
+        source: &'static str,
+        now: Instant,
+    ) -> Result<(), ApiError> {
+        let end = self.offset.saturating_add(self.pixels.len());
+        if end > frame.pixels().len() {
+            let len = frame.pixels().len();
+            return Err(ApiError::bad_request(format!(
+                "pixels past the strip's {len} LEDs"
+            )));
+        }
+        for (i, rgb) in self.pixels.iter().enumerate() {
+            frame.set(self.offset + i, *rgb);
+        }
+        frame.commit(source, now);
+        Ok(())
+    }
+}
+
+struct Source {
+    name: &'static str,
+    priority: u8,
+    timeout: Duration,
+    frame: Arc<Mutex<Frame>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum State {
+    Local,
+    /// with the pixels last shown, to fade out from
+    Live(&'static str, Vec<[u8; 3]>),
+    Fading {
+        from: Vec<[u8; 3]>,
+        started: Instant,
+    },
+}
+
+/// What to put on the strip.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    Local,
+    Live {
+        source: &'static str,
+        pixels: Vec<[u8; 3]>,
+    },
+    /// `pixels` blended into the segments, `weight` (0..=255) going to them
+    Fade {
+        pixels: Vec<[u8; 3]>,
+        weight: u8,
+    },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SourceReport {
+    pub name: &'static str,
+    pub priority: u8,
+    pub live: bool,
+}
+
+/// The arbitration state for `/status` and `/realtime`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Report {
+    /// the source on the strip
+    pub source: Option<&'static str>,
+    /// `local`, `live` or `fading`
+    pub state: &'static str,
+    pub lock_local: bool,
+    pub sources: Vec<SourceReport>,
+}
+
+/// Picks the source that gets the strip: the highest priority one that
+/// isn't past its timeout, unless locked to the local animation. The source
+/// on the strip keeps it until it times out or one with a higher priority
+/// sends, so equals don't take turns frame by frame. Handing the strip back
+/// to the segments fades over `crossfade_ms`.
+pub struct Arbiter {
+    settings: RealtimeSettings,
+    len: usize,
+    sources: Vec<Source>,
+    lock_local: bool,
+    state: State,
+}
+
+impl Arbiter {
+    pub fn new(settings: &RealtimeSettings, len: usize) -> Self {
+        Self {
+            lock_local: settings.lock_local,
+            settings: settings.clone(),
+            len,
+            sources: vec![],
+            state: State::Local,
+        }
+    }
+
+    /// A frame for `name` to write to, with its policy from the settings.
+    pub fn source(&mut self, name: &'static str) -> Arc<Mutex<Frame>> {
+        let (priority, timeout) = self.settings.policy(name);
+        let frame = Arc::new(Mutex::new(Frame::new(self.len)));
+        self.sources.push(Source {
+            name,
+            priority,
+            timeout,
+            frame: frame.clone(),
+        });
+        frame
+    }
+
+    pub fn lock_local(&self) -> bool {
+        self.lock_local
+    }
+
+    /// Takes effect with the next [`Arbiter::step`].
+    pub fn set_lock_local(&mut self, lock: bool) {
+        self.lock_local = lock;
+    }
+
+    /// The live source to show and its pixels.
+    fn winner(&self, now: Instant) -> Option<(&'static str, Vec<[u8; 3]>)> {
+        if self.lock_local {
+            return None;
+        }
+        let holder = match &self.state {
+            State::Live(name, _) => Some(*name),
+            _ => None,
+        };
+        // the holder ranks above its equals, then the latest
+        let mut best: Option<(&Source, (u8, bool, Instant))> = None;
+        for source in &self.sources {
+            let frame = source.frame.lock().unwrap();
+            let (Some(_), Some(updated)) =
+                (frame.live_source(now, source.timeout), frame.updated())
+            else {
+                continue;
+            };
+            let rank = (source.priority, holder == Some(source.name), updated);
+            if best.map_or(true, |(_, best)| rank > best) {
+                best = Some((source, rank));
+            }
+        }
+        let (source, _) = best?;
+        let pixels = source.frame.lock().unwrap().pixels().to_vec();
+        Some((source.name, pixels))
+    }
+
+    /// Moves the state along to `now`.
+    pub fn step(&mut self, now: Instant) -> Output {
+        if let Some((source, pixels)) = self.winner(now) {
+            self.state = State::Live(source, pixels.clone());
+            return Output::Live { source, pixels };
+        }
+
+        let crossfade = Duration::from_millis(self.settings.crossfade_ms as u64);
+        let state = std::mem::replace(&mut self.state, State::Local);
+        self.state = match state {
+            State::Live(_, from) if !crossfade.is_zero() => State::Fading { from, started: now },
+            State::Fading { from, started }
+                if now.saturating_duration_since(started) < crossfade =>
+            {
+                State::Fading { from, started }
+            }
+            _ => State::Local,
+        };
+        match &self.state {
+            State::Fading { from, started } => {
+                let t =
+                    now.saturating_duration_since(*started).as_secs_f32() / crossfade.as_secs_f32();
+                Output::Fade {
+                    pixels: from.clone(),
+                    weight: (t * 255.0) as u8,
+                }
+            }
+            _ => Output::Local,
+        }
+    }
+
+    pub fn report(&self, now: Instant) -> Report {
+        let (source, state) = match &self.state {
+            State::Local => (None, "local"),
+            State::Live(name, _) => (Some(*name), "live"),
+            State::Fading { .. } => (None, "fading"),
+        };
+        let sources = self
+            .sources
+            .iter()
+            .map(|source| SourceReport {
+                name: source.name,
+                priority: source.priority,
+                live: source
+                    .frame
+                    .lock()
+                    .unwrap()
+                    .live_source(now, source.timeout)
+                    .is_some(),
+            })
+            .collect();
+        Report {
+            source,
+            state,
+            lock_local: self.lock_local,
+            sources,
+        }
+    }
+}
+
+/// `rgb` at `brightness` (0..=100).
+pub fn dim(rgb: [u8; 3], brightness: u8) -> [u8; 3] {
+    let brightness = brightness.min(crate::validate::MAX_BRIGHTNESS) as u32;
+    rgb.map(|c| ((c as u32 * brightness + 50) / 100) as u8)
+}
+
+/// `weight` (0..=255) of the way from `from` to `to`.
+pub fn blend(from: [u8; 3], to: [u8; 3], weight: u8) -> [u8; 3] {
+    let w = weight as u32;
+    let mut out = from;
+    for (c, to) in out.iter_mut().zip(to) {
+        *c = ((*c as u32 * (255 - w) + to as u32 * w + 127) / 255) as u8;
+    }
+    out
+}
+
+/// Gamma and smoothing for a source sending finished frames, like an
+/// ambient light grabber.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Shaping {
+    /// 1.0 leaves colours alone
+    pub gamma: f32,
+    /// time constant for easing into each new frame, 0 to show it as is
+    pub smoothing_ms: u32,
+}
+
+impl Default for Shaping {
+    fn default() -> Self {
+        Self {
+            gamma: 1.0,
+            smoothing_ms: 0,
+        }
+    }
+}
+
+impl Shaping {
+    pub fn validate(&self, protocol: &str) -> anyhow::Result<()> {
+        if !(0.5..=4.0).contains(&self.gamma) {
+            anyhow::bail!("{protocol} gamma must be 0.5..=4.0");
+        }
+        if self.smoothing_ms > 2000 {
+            anyhow::bail!("{protocol} smoothing_ms must be 0..=2000");
+        }
+        Ok(())
+    }
+}
+
+/// Applies a [`Shaping`] to successive frames.
+pub struct Filter {
+    table: [u8; 256],
+    smoothing: Duration,
+    /// the previous output, in 1/256ths
+    state: Vec<[u16; 3]>,
+    last: Option<Instant>,
+}
+
+impl Filter {
+    pub fn new(shaping: &Shaping) -> Self {
+        let mut table = [0u8; 256];
+        for (i, out) in table.iter_mut().enumerate() {
+            *out = ((i as f32 / 255.0).powf(shaping.gamma) * 255.0 + 0.5) as u8;
+        }
+        Self {
+            table,
+            smoothing: Duration::from_millis(shaping.smoothing_ms as u64),
+            state: vec![],
+            last: None,
+        }
+    }
+
+    /// Gamma corrects `pixels` in place, then eases them in from the last
+    /// frame. After a pause of a second or more the frame shows right away.
+    pub fn apply(&mut self, pixels: &mut [[u8; 3]], now: Instant) {
+        for pixel in pixels.iter_mut() {
+            *pixel = pixel.map(|c| self.table[c as usize]);
+        }
+        if self.smoothing.is_zero() {
+            return;
+        }
+
+        let elapsed = self
+            .last
+            .map(|last| now.saturating_duration_since(last))
+            .unwrap_or(Duration::MAX);
+        self.last = Some(now);
+        let fresh = elapsed >= Duration::from_secs(1) || self.state.len() != pixels.len();
+        // how far to move towards the new frame, in 1/256ths
+        let step = match fresh {
+            true => 256,
+            false => {
+                let t = elapsed.as_secs_f32() / self.smoothing.as_secs_f32();
+                ((1.0 - (-t).exp()) * 256.0).clamp(1.0, 256.0) as i32
+            }
+        };
+        self.state.resize(pixels.len(), [0; 3]);
+
+        for (pixel, state) in pixels.iter_mut().zip(&mut self.state) {
+            for (c, s) in pixel.iter_mut().zip(state.iter_mut()) {
+                let target = (*c as i32) << 8;
+                let current = *s as i32;
+                *s = (current + (target - current) * step / 256) as u16;
+                *c = ((*s as u32 + 128) >> 8).min(255) as u8;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fake clock: `ms` after a fixed start.
+    struct Clock(Instant);
+
+    impl Clock {
+        fn at(&self, ms: u64) -> Instant {
+            self.0 + Duration::from_millis(ms)
+        }
+    }
+
+    fn settings(sources: &[(&str, u8, Option<u32>)]) -> RealtimeSettings {
+        let sources = sources
+            .iter()
+            .map(|&(name, priority, timeout_ms)| {
+                let policy = SourcePolicy {
+                    priority,
+                    timeout_ms,
+                };
+                (name.to_string(), policy)
+            })
+            .collect();
+        RealtimeSettings {
+            timeout_ms: 1000,
+            crossfade_ms: 500,
+            sources,
+            ..Default::default()
+        }
+    }
+
+    fn send(frame: &Mutex<Frame>, source: &'static str, rgb: u8, now: Instant) {
+        let mut frame = frame.lock().unwrap();
+        frame.set(0, [rgb; 3]);
+        frame.commit(source, now);
+    }
+
+    fn live(source: &'static str, rgb: u8) -> Output {
+        Output::Live {
+            source,
+            pixels: vec![[rgb; 3]],
+        }
+    }
+
+    #[test]
+    fn live_until_the_timeout_then_fades_back() {
+        let clock = Clock(Instant::now());
+        let mut arbiter = Arbiter::new(&settings(&[]), 1);
+        let e131 = arbiter.source("e131");
+        assert_eq!(arbiter.step(clock.at(0)), Output::Local);
+        assert_eq!(arbiter.report(clock.at(0)).state, "local");
+
+        send(&e131, "e131", 7, clock.at(10));
+        assert_eq!(arbiter.step(clock.at(20)), live("e131", 7));
+        assert_eq!(arbiter.step(clock.at(1009)), live("e131", 7));
+        let report = arbiter.report(clock.at(1009));
+        assert_eq!((report.source, report.state), (Some("e131"), "live"));
+
+        // silent for `timeout_ms`: fade from the last pixels
+        let fade = |weight| Output::Fade {
+            pixels: vec![[7; 3]],
+            weight,
+        };
+        assert_eq!(arbiter.step(clock.at(1010)), fade(0));
+        assert_eq!(arbiter.step(clock.at(1260)), fade(127));
+        assert_eq!(arbiter.report(clock.at(1260)).state, "fading");
+        assert_eq!(arbiter.step(clock.at(1510)), Output::Local);
+        assert_eq!(arbiter.step(clock.at(2000)), Output::Local);
+    }
+
+    #[test]
+    fn a_zero_crossfade_cuts() {
+        let clock = Clock(Instant::now());
+        let settings = RealtimeSettings {
+            crossfade_ms: 0,
+            ..settings(&[])
+        };
+        let mut arbiter = Arbiter::new(&settings, 1);
+        let ddp = arbiter.source("ddp");
+        send(&ddp, "ddp", 1, clock.at(0));
+        assert_eq!(arbiter.step(clock.at(0)), live("ddp", 1));
+        assert_eq!(arbiter.step(clock.at(1000)), Output::Local);
+    }
+
+    #[test]
+    fn higher_priorities_take_over_and_hand_back() {
+        let clock = Clock(Instant::now());
+        let mut arbiter = Arbiter::new(&settings(&[("adalight", 150, Some(200))]), 1);
+        let e131 = arbiter.source("e131");
+        let adalight = arbiter.source("adalight");
+
+        send(&e131, "e131", 1, clock.at(0));
+        assert_eq!(arbiter.step(clock.at(0)), live("e131", 1));
+        send(&adalight, "adalight", 2, clock.at(10));
+        assert_eq!(arbiter.step(clock.at(10)), live("adalight", 2));
+        // newer but lower frames don't get through
+        send(&e131, "e131", 3, clock.at(20));
+        assert_eq!(arbiter.step(clock.at(20)), live("adalight", 2));
+
+        // adalight times out after its own 200 ms, e131 is still live
+        assert_eq!(arbiter.step(clock.at(210)), live("e131", 3));
+        let report = arbiter.report(clock.at(210));
+        let live: Vec<_> = report
+            .sources
+            .iter()
+            .map(|s| (s.name, s.priority, s.live))
+            .collect();
+        assert_eq!(live, [("e131", 100, true), ("adalight", 150, false)]);
+    }
+
+    #[test]
+    fn equals_dont_take_turns() {
+        let clock = Clock(Instant::now());
+        let mut arbiter = Arbiter::new(&settings(&[]), 1);
+        let artnet = arbiter.source("artnet");
+        let opc = arbiter.source("opc");
+
+        send(&artnet, "artnet", 1, clock.at(0));
+        assert_eq!(arbiter.step(clock.at(0)), live("artnet", 1));
+        // both keep sending, the one on the strip keeps it
+        for ms in (10..900).step_by(20) {
+            send(&opc, "opc", 2, clock.at(ms));
+            assert_eq!(arbiter.step(clock.at(ms)), live("artnet", 1), "{ms}");
+            send(&artnet, "artnet", 1, clock.at(ms + 10));
+            assert_eq!(arbiter.step(clock.at(ms + 10)), live("artnet", 1), "{ms}");
+        }
+        // until it stops: then the other one, which keeps it in turn
+        send(&opc, "opc", 2, clock.at(1500));
+        assert_eq!(arbiter.step(clock.at(1899)), live("artnet", 1));
+        assert_eq!(arbiter.step(clock.at(1900)), live("opc", 2));
+        send(&artnet, "artnet", 1, clock.at(1910));
+        assert_eq!(arbiter.step(clock.at(1910)), live("opc", 2));
+
+        // between equals that aren't on the strip, the latest wins
+        let mut arbiter = Arbiter::new(&settings(&[]), 1);
+        let (artnet, opc) = (arbiter.source("artnet"), arbiter.source("opc"));
+        send(&opc, "opc", 2, clock.at(0));
+        send(&artnet, "artnet", 1, clock.at(5));
+        assert_eq!(arbiter.step(clock.at(10)), live("artnet", 1));
+    }
+
+    #[test]
+    fn released_frames_and_the_lock_give_the_strip_back() {
+        let clock = Clock(Instant::now());
+        let mut arbiter = Arbiter::new(&settings(&[]), 1);
+        let e131 = arbiter.source("e131");
+        send(&e131, "e131", 4, clock.at(0));
+        assert_eq!(arbiter.step(clock.at(0)), live("e131", 4));
+
+        arbiter.set_lock_local(true);
+        assert!(matches!(
+            arbiter.step(clock.at(10)),
+            Output::Fade { weight: 0, .. }
+        ));
+        assert!(arbiter.report(clock.at(10)).lock_local);
+        send(&e131, "e131", 5, clock.at(20));
+        assert!(matches!(arbiter.step(clock.at(20)), Output::Fade { .. }));
+        arbiter.set_lock_local(false);
+        assert_eq!(arbiter.step(clock.at(30)), live("e131", 5));
+
+        e131.lock().unwrap().release();
+        assert!(matches!(
+            arbiter.step(clock.at(40)),
+            Output::Fade { weight: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn pushed_pixels_land_within_the_strip() {
+        let now = Instant::now();
+        let mut frame = Frame::new(4);
+        let pixels = Pixels {
+            offset: 1,
+            pixels: vec![[1; 3], [2; 3], [3; 3]],
+        };
+        pixels.show(&mut frame, "http", now).unwrap();
+        assert_eq!(frame.pixels(), [[0; 3], [1; 3], [2; 3], [3; 3]]);
+        assert_eq!(frame.live_source(now, Duration::from_secs(1)), Some("http"));
+
+        let past = Pixels {
+            offset: 2,
+            ..pixels
+        };
+        let error = past.show(&mut frame, "http", now).err().unwrap();
+        assert_eq!(
+            (error.status, error.error.as_str()),
+            (400, "pixels past the strip's 4 LEDs")
+        );
+        let huge = Pixels {
+            offset: usize::MAX,
+            pixels: vec![[0; 3]],
+        };
+        assert!(huge.show(&mut frame, "http", now).is_err());
+        assert_eq!(frame.pixels()[3], [3; 3]);
+    }
+
+    #[test]
+    fn every_source_can_be_configured() {
+        for name in SOURCES {
+            let settings = settings(&[(name, 1, Some(100))]);
+            assert!(settings.validate().is_ok(), "{name}");
+            assert_eq!(settings.policy(name), (1, Duration::from_millis(100)));
+        }
+        assert!(settings(&[("midi", 1, None)]).validate().is_err());
+        assert!(settings(&[("ws", 1, Some(99))]).validate().is_err());
+        assert_eq!(
+            settings(&[]).policy("http"),
+            (100, Duration::from_millis(1000))
+        );
+    }
+}
@@ -0,0 +1,336 @@
+//! Backend-agnostic routing.
+//!
+//! Handlers see a [`Request`] and return a [`Response`]; each HTTP server
+//! (the two ESP ones, or [`test_server`](crate::test_server) on a host) only
+//! registers the routes and converts to and from its own types.
+
+use std::{io, sync::Arc};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{api::ApiError, body};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Patch,
+    Delete,
+    Options,
+}
+
+impl Method {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Patch => "PATCH",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "PATCH" => Method::Patch,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            _ => return None,
+        })
+    }
+
+    pub fn is_write(self) -> bool {
+        !matches!(self, Method::Get | Method::Head | Method::Options)
+    }
+}
+
+/// Request headers handlers and layers look at. Not every backend can list
+/// the headers of a request, so they are fetched by name.
+pub const HEADERS: &[&str] = &[
+    "Access-Control-Request-Method",
+    "Authorization",
+    "Host",
+    "If-Match",
+    "If-None-Match",
+    "Origin",
+    "X-Firmware-SHA256",
+];
+
+pub struct Request<'a> {
+    pub method: Method,
+    /// without the query string
+    pub path: String,
+    /// what follows the `?`, if anything
+    pub query: String,
+    /// what the `*` of a wildcard route matched, set by the router
+    pub tail: String,
+    pub content_len: Option<usize>,
+    headers: Vec<(String, String)>,
+    body: &'a mut dyn io::Read,
+}
+
+impl<'a> Request<'a> {
+    pub fn new(
+        method: Method,
+        uri: &str,
+        content_len: Option<usize>,
+        body: &'a mut dyn io::Read,
+    ) -> Self {
+        Self {
+            method,
+            path: uri.split('?').next().unwrap_or_default().to_string(),
+            query: uri
+                .split_once('?')
+                .map(|(_, q)| q)
+                .unwrap_or_default()
+                .to_string(),
+            tail: String::new(),
+            content_len,
+            headers: vec![],
+            body,
+        }
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Header names are case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The raw body, for handlers that stream it.
+    pub fn body(&mut self) -> &mut dyn io::Read {
+        &mut *self.body
+    }
+
+    pub fn json<T: DeserializeOwned>(&mut self, limit: usize) -> Result<T, ApiError> {
+        body::parse_json(&mut *self.body, self.content_len, limit)
+    }
+
+    pub fn bytes(&mut self, limit: usize) -> Result<Vec<u8>, ApiError> {
+        body::read_all(&mut *self.body, self.content_len, limit)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    pub fn ok() -> Self {
+        Self::new(200)
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn text(body: impl Into<String>) -> Self {
+        Self::ok().body(body.into())
+    }
+
+    pub fn json<T: Serialize + ?Sized>(self, value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => self.header("Content-Type", "application/json").body(body),
+            Err(e) => ApiError::new(500, e.to_string()).into(),
+        }
+    }
+}
+
+impl From<ApiError> for Response {
+    fn from(e: ApiError) -> Self {
+        let res = Response::new(e.status)
+            .header("Content-Type", "application/json")
+            .body(e.to_json());
+        if e.status == 401 {
+            res.header("WWW-Authenticate", "Bearer")
+        } else {
+            res
+        }
+    }
+}
+
+pub type Handler = Box<dyn Fn(&mut Request) -> Result<Response, ApiError> + Send + Sync>;
+
+/// Runs around every matched route, e.g. for auth or metrics. `route` is the
+/// pattern the route was registered with.
+pub trait Layer: Send + Sync {
+    /// an error answers the request without running the handler
+    fn before(&self, _route: &str, _req: &Request) -> Result<(), ApiError> {
+        Ok(())
+    }
+
+    fn after(&self, _route: &str, _req: &Request, _res: &mut Response) {}
+}
+
+impl<L: Layer + ?Sized> Layer for Arc<L> {
+    fn before(&self, route: &str, req: &Request) -> Result<(), ApiError> {
+        (**self).before(route, req)
+    }
+
+    fn after(&self, route: &str, req: &Request, res: &mut Response) {
+        (**self).after(route, req, res)
+    }
+}
+
+struct Route {
+    method: Method,
+    pattern: String,
+    handler: Handler,
+}
+
+/// `/segments/*` matches everything below `/segments/`, other patterns only
+/// themselves. Returns what `*` matched.
+fn matches<'p>(pattern: &str, path: &'p str) -> Option<&'p str> {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => path.strip_prefix(prefix),
+        None => (pattern == path).then(|| ""),
+    }
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    layers: Vec<Box<dyn Layer>>,
+}
+
+impl Router {
+    pub fn route<F>(&mut self, method: Method, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&mut Request) -> Result<Response, ApiError> + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            pattern: pattern.to_string(),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Layers run in the order they were added.
+    pub fn layer(&mut self, layer: impl Layer + 'static) -> &mut Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    /// Method and pattern of every route, for backends that register them
+    /// one by one. Every pattern also gets `OPTIONS`, for preflights.
+    pub fn routes(&self) -> Vec<(Method, &str)> {
+        let mut routes: Vec<_> = self
+            .routes
+            .iter()
+            .map(|r| (r.method, r.pattern.as_str()))
+            .collect();
+        for route in &self.routes {
+            let options = (Method::Options, route.pattern.as_str());
+            if !routes.contains(&options) {
+                routes.push(options);
+            }
+        }
+        routes
+    }
+
+    pub fn handle(&self, req: &mut Request) -> Response {
+        let matching: Vec<_> = self
+            .routes
+            .iter()
+            .filter_map(|route| Some((route, matches(&route.pattern, &req.path)?)))
+            .collect();
+        let found = matching
+            .iter()
+            .find(|(route, _)| route.method == req.method);
+        let (route, tail) = match found {
+            Some(&(route, tail)) => (route, tail.to_string()),
+            None => {
+                let allow = allow(&matching);
+                let res = match (matching.first(), req.method) {
+                    // a preflight, unless a route answers OPTIONS itself
+                    (Some(_), Method::Options) => Response::new(204),
+                    (Some(_), _) => ApiError::method_not_allowed().into(),
+                    (None, _) => ApiError::not_found("no such route").into(),
+                };
+                let pattern = matching
+                    .first()
+                    .map(|(route, _)| route.pattern.clone())
+                    .unwrap_or_default();
+                let res = if pattern.is_empty() {
+                    res
+                } else {
+                    res.header("Allow", allow)
+                };
+                return self.after(&pattern, req, res);
+            }
+        };
+        req.tail = tail;
+
+        let before = self
+            .layers
+            .iter()
+            .try_for_each(|l| l.before(&route.pattern, req));
+        let res = match before {
+            Ok(()) => (route.handler)(req).unwrap_or_else(Response::from),
+            Err(e) => e.into(),
+        };
+        self.after(&route.pattern, req, res)
+    }
+
+    /// `route` is empty if nothing matched.
+    fn after(&self, route: &str, req: &Request, mut res: Response) -> Response {
+        for layer in &self.layers {
+            layer.after(route, req, &mut res);
+        }
+        res
+    }
+}
+
+/// The `Allow` header for a path, from the routes matching it.
+fn allow(matching: &[(&Route, &str)]) -> String {
+    let mut methods: Vec<&str> = vec![];
+    for (route, _) in matching {
+        if !methods.contains(&route.method.as_str()) {
+            methods.push(route.method.as_str());
+        }
+    }
+    if !methods.contains(&"OPTIONS") {
+        methods.push("OPTIONS");
+    }
+    methods.join(", ")
+}
@@ -0,0 +1,369 @@
+//! The board's HTTP API, as far as it doesn't need the radio or the flash:
+//! segments, settings and auth. Storage is anything `RawStorage`, so the
+//! same handlers run against NVS on the board and in memory on a host.
+
+use std::{
+    error::Error,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use embedded_svc::storage::RawStorage;
+use indexmap::IndexMap;
+use serde::Deserialize;
+
+use crate::{
+    api::ApiError,
+    auth::{self, Auth},
+    realtime,
+    router::{Method, Response, Router},
+    segments::SegmentMap,
+    settings::{self, Settings},
+    sync, validate, wled,
+};
+
+pub const SEGMENTS_FILE: &'static str = "segments.json";
+
+pub struct Api<S> {
+    pub segments: Arc<Mutex<SegmentMap>>,
+    pub storage: Arc<Mutex<S>>,
+    pub settings: Arc<Mutex<Settings>>,
+    pub auth: Arc<Mutex<Auth>>,
+    /// animation time, shared with other boards when synced
+    pub clock: sync::Clock,
+    /// fills salts and tokens, the hardware RNG on the board
+    pub random: fn(&mut [u8]),
+    pub master: Arc<Mutex<wled::Master>>,
+    /// what `/json/info` reports about the device
+    pub wled_info: Arc<dyn Fn() -> wled::Info + Send + Sync>,
+    pub realtime: Arc<Mutex<realtime::Arbiter>>,
+}
+
+/// `POST /realtime`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RealtimeLock {
+    lock_local: bool,
+}
+
+impl<S> Api<S>
+where
+    S: RawStorage + Send + 'static,
+    S::Error: Error + Send + Sync + 'static,
+{
+    pub fn mount(&self, router: &mut Router) {
+        // like the other settings, a new limit applies after a reboot
+        let max_body = self.settings.lock().unwrap().max_body_size;
+
+        let clock = self.clock.clone();
+        router.route(Method::Get, "/now", move |_req| {
+            Ok(Response::text(format!("{}", clock.now_ms())))
+        });
+
+        let segments = self.segments.clone();
+        router.route(Method::Get, "/data", move |req| {
+            let dat = segments.lock().unwrap();
+            if let Some(tags) = req.header("If-None-Match") {
+                if dat.matches(tags) {
+                    return Ok(Response::new(304).header("ETag", dat.etag()));
+                }
+            }
+            Ok(Response::ok()
+                .json(dat.segments())
+                .header("ETag", dat.etag()))
+        });
+
+        let segments = self.segments.clone();
+        let storage = self.storage.clone();
+        router.route(Method::Post, "/data", move |req| {
+            // keeps the segments in the order they were posted
+            let value: IndexMap<String, serde_json::Value> = req.json(max_body)?;
+            let mut dat = segments.lock().unwrap();
+            let de = validate::segment_map(&value, dat.limits())?;
+            if let Err(e) = dat.check_if_match(req.header("If-Match")) {
+                return Ok(Response::from(e).header("ETag", dat.etag()));
+            }
+            dat.replace_all(de);
+            settings::store_json(&mut *storage.lock().unwrap(), SEGMENTS_FILE, dat.segments())?;
+
+            Ok(Response::text("ok").header("ETag", dat.etag()))
+        });
+
+        for (method, pattern) in [
+            (Method::Get, "/segments"),
+            (Method::Post, "/segments"),
+            (Method::Get, "/segments/*"),
+            (Method::Put, "/segments/*"),
+            (Method::Patch, "/segments/*"),
+            (Method::Delete, "/segments/*"),
+        ] {
+            let segments = self.segments.clone();
+            let storage = self.storage.clone();
+            router.route(method, pattern, move |req| {
+                let body = req.bytes(max_body)?;
+                let mut map = segments.lock().unwrap();
+                let res = match map.handle(method, &req.tail, &body, req.header("If-Match")) {
+                    Ok(reply) => {
+                        if reply.modified {
+                            settings::store_json(
+                                &mut *storage.lock().unwrap(),
+                                SEGMENTS_FILE,
+                                map.segments(),
+                            )?;
+                        }
+                        Response::new(reply.status)
+                            .header("Content-Type", "application/json")
+                            .body(reply.body)
+                    }
+                    Err(e) => e.into(),
+                };
+                Ok(res.header("ETag", map.etag()))
+            });
+        }
+
+        self.mount_wled(router, max_body);
+        self.mount_realtime(router, max_body);
+
+        let settings = self.settings.clone();
+        router.route(Method::Get, "/settings", move |_req| {
+            let shown = settings
+                .lock()
+                .unwrap()
+                .redacted()
+                .map_err(anyhow::Error::from)?;
+            Ok(Response::ok().json(&shown))
+        });
+
+        // hostname, instance name and static address are picked up on the
+        // next boot (or the next `POST /wifi/connect`)
+        let settings = self.settings.clone();
+        let storage = self.storage.clone();
+        router.route(Method::Post, "/settings", move |req| {
+            let value = req.json(max_body)?;
+            let de = Settings::from_post(value, &settings.lock().unwrap())?;
+            de.validate()
+                .map_err(|e| ApiError::bad_request(e.to_string()))?;
+            de.store(&mut *storage.lock().unwrap())?;
+            *settings.lock().unwrap() = de;
+
+            Ok(Response::text("ok"))
+        });
+
+        let auth = self.auth.clone();
+        let storage = self.storage.clone();
+        let random = self.random;
+        router.route(Method::Post, "/auth/login", move |req| {
+            let login: auth::Login = req.json(max_body)?;
+            let mut auth = auth.lock().unwrap();
+            if !auth.enabled() {
+                return Err(ApiError::conflict("no admin password set"));
+            }
+            if !auth.check_password(&login.password) {
+                return Err(ApiError::new(401, "wrong password"));
+            }
+            let mut secret = [0u8; 32];
+            random(&mut secret);
+            let token = auth.issue(login.role, &login.label, secret);
+            settings::store_json(&mut *storage.lock().unwrap(), auth::AUTH_FILE, &*auth)?;
+
+            Ok(Response::ok()
+                .json(&serde_json::json!({
+                    "token": token,
+                    "role": login.role,
+                }))
+                .header("Cache-Control", "no-store"))
+        });
+
+        // open while no password is set, which is how a fresh board gets one
+        let auth = self.auth.clone();
+        let storage = self.storage.clone();
+        router.route(Method::Post, "/auth/password", move |req| {
+            let new: auth::NewPassword = req.json(max_body)?;
+            let mut salt = [0u8; 16];
+            random(&mut salt);
+            let mut auth = auth.lock().unwrap();
+            auth.set_password(&new.password, salt)?;
+            settings::store_json(&mut *storage.lock().unwrap(), auth::AUTH_FILE, &*auth)?;
+
+            Ok(Response::text("ok"))
+        });
+
+        let auth = self.auth.clone();
+        let storage = self.storage.clone();
+        router.route(Method::Post, "/auth/logout", move |req| {
+            let token = req.header("Authorization").and_then(auth::bearer);
+            let mut auth = auth.lock().unwrap();
+            if auth.revoke(token.unwrap_or_default()) {
+                settings::store_json(&mut *storage.lock().unwrap(), auth::AUTH_FILE, &*auth)?;
+            }
+
+            Ok(Response::text("ok"))
+        });
+    }
+
+    /// Arbitration between the pixel sources, and pixels pushed over HTTP.
+    fn mount_realtime(&self, router: &mut Router, max_body: usize) {
+        let arbiter = self.realtime.clone();
+        router.route(Method::Get, "/realtime", move |_req| {
+            let report = arbiter.lock().unwrap().report(Instant::now());
+            Ok(Response::ok()
+                .json(&report)
+                .header("Cache-Control", "no-cache"))
+        });
+
+        // the switch survives a reboot, unlike the rest of the arbitration
+        let arbiter = self.realtime.clone();
+        let settings = self.settings.clone();
+        let storage = self.storage.clone();
+        router.route(Method::Post, "/realtime", move |req| {
+            let lock: RealtimeLock = req.json(max_body)?;
+            let mut settings = settings.lock().unwrap();
+            settings.realtime.lock_local = lock.lock_local;
+            settings.store(&mut *storage.lock().unwrap())?;
+            drop(settings);
+
+            let mut arbiter = arbiter.lock().unwrap();
+            arbiter.set_lock_local(lock.lock_local);
+            Ok(Response::ok().json(&arbiter.report(Instant::now())))
+        });
+
+        let frame = self.realtime.lock().unwrap().source("http");
+        router.route(Method::Post, "/realtime/pixels", move |req| {
+            let pixels: realtime::Pixels = req.json(max_body)?;
+            pixels.show(&mut frame.lock().unwrap(), "http", Instant::now())?;
+            Ok(Response::new(204))
+        });
+    }
+
+    /// The WLED compatible subset, see `wled.rs`.
+    fn mount_wled(&self, router: &mut Router, max_body: usize) {
+        let segments = self.segments.clone();
+        let master = self.master.clone();
+        router.route(Method::Get, "/json/state", move |_req| {
+            let map = segments.lock().unwrap();
+            let values = segment_values(&map)?;
+            Ok(Response::ok().json(&wled::state(&master.lock().unwrap(), &values)))
+        });
+
+        let wled_info = self.wled_info.clone();
+        router.route(Method::Get, "/json/info", move |_req| {
+            Ok(Response::ok().json(&wled_info()))
+        });
+
+        let segments = self.segments.clone();
+        let master = self.master.clone();
+        let wled_info = self.wled_info.clone();
+        router.route(Method::Get, "/json", move |_req| {
+            let map = segments.lock().unwrap();
+            let state = wled::state(&master.lock().unwrap(), &segment_values(&map)?);
+            Ok(Response::ok().json(&wled::everything(&state, &wled_info())))
+        });
+
+        for pattern in ["/json", "/json/state"] {
+            let segments = self.segments.clone();
+            let storage = self.storage.clone();
+            let master = self.master.clone();
+            router.route(Method::Post, pattern, move |req| {
+                let patch: wled::StatePatch = req.json(max_body)?;
+                let state = apply_wled(&segments, &*storage, &master, &patch)?;
+
+                if patch.v {
+                    return Ok(Response::ok().json(&state));
+                }
+                Ok(Response::ok().json(&serde_json::json!({ "success": true })))
+            });
+        }
+    }
+}
+
+/// Applies a WLED state change, persisting the segments if they changed.
+/// Also used by the MQTT bridge.
+pub fn apply_wled<S>(
+    segments: &Mutex<SegmentMap>,
+    storage: &Mutex<S>,
+    master: &Mutex<wled::Master>,
+    patch: &wled::StatePatch,
+) -> Result<wled::State, ApiError>
+where
+    S: RawStorage,
+    S::Error: Error + Send + Sync + 'static,
+{
+    let mut map = segments.lock().unwrap();
+    let (state, changed) = apply_wled_unsaved(&mut map, master, patch)?;
+    if changed {
+        settings::store_json(&mut *storage.lock().unwrap(), SEGMENTS_FILE, map.segments())?;
+    }
+    Ok(state)
+}
+
+/// [`apply_wled`] without touching the flash, for callers that batch
+/// writes. Also says whether a segment changed.
+pub fn apply_wled_unsaved(
+    map: &mut SegmentMap,
+    master: &Mutex<wled::Master>,
+    patch: &wled::StatePatch,
+) -> Result<(wled::State, bool), ApiError> {
+    let before = segment_values(map)?;
+    let mut values = before.clone();
+    let mut next = *master.lock().unwrap();
+    wled::apply(&mut next, &mut values, patch)?;
+
+    // only touch the map if a segment changed
+    let changed = values != before;
+    if changed {
+        let ids = map.segments().keys().cloned();
+        let value: IndexMap<_, _> = ids.zip(values.clone()).collect();
+        let de = validate::segment_map(&value, map.limits())?;
+        map.replace_all(de);
+    }
+    *master.lock().unwrap() = next;
+    Ok((wled::state(&next, &values), changed))
+}
+
+/// Segments in their JSON form and map order, as `wled` works on them.
+pub fn segment_values(map: &SegmentMap) -> Result<Vec<serde_json::Value>, ApiError> {
+    map.segments()
+        .values()
+        .map(|seg| serde_json::to_value(seg).map_err(|e| ApiError::from(anyhow::Error::from(e))))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::segments::fixture;
+
+    #[test]
+    fn wled_writes_keep_the_map_order() {
+        let mut segments: Vec<_> = (1..=4).map(fixture::segment).collect();
+        // map order that isn't uuid order
+        segments.sort_by_key(|s| s.to_uuid_string());
+        let segments = [2, 0, 3, 1].map(|i| segments[i].clone());
+        let order: Vec<_> = segments.iter().map(|s| s.to_uuid_string()).collect();
+        let mut map = fixture::map(&segments, 100);
+        let lengths = |map: &SegmentMap| -> Vec<usize> {
+            map.segments().values().map(|s| s.length()).collect()
+        };
+        let before = lengths(&map);
+
+        let master = Mutex::new(wled::Master::default());
+        let patch = serde_json::from_value(json!({"seg": {"bri": 51}})).unwrap();
+        let (state, changed) = apply_wled_unsaved(&mut map, &master, &patch).unwrap();
+        assert!(changed);
+        assert!(map.segments().keys().eq(order.iter()));
+        assert_eq!(lengths(&map), before);
+        let stops: Vec<_> = state.seg.iter().map(|s| s.stop).collect();
+        assert_eq!(
+            stops,
+            [before[0], before[0] + before[1], 10 - before[3], 10]
+        );
+        let bri = |map: &SegmentMap| segment_values(map).unwrap()[2]["brightness"].clone();
+        assert_eq!(bri(&map), 20);
+
+        // nothing to change, nothing touched
+        let (_, changed) = apply_wled_unsaved(&mut map, &master, &patch).unwrap();
+        assert!(!changed);
+    }
+}
@@ -0,0 +1,399 @@
+//! Per-segment operations on the shared segment map, backing the
+//! `/segments` resources.
+
+use color_mixer::strip::Segment;
+use indexmap::IndexMap;
+use serde_json::Value;
+
+use crate::{
+    api::ApiError,
+    router::Method,
+    validate::{self, Limits},
+};
+
+pub type Segments = IndexMap<String, Segment>;
+
+/// The segment map plus a revision that every mutation bumps, used as the
+/// `ETag` of the map. The epoch (random per boot) keeps a tag from before a
+/// reboot from matching a fresh counter.
+pub struct SegmentMap {
+    segments: Segments,
+    limits: Limits,
+    epoch: u32,
+    revision: u32,
+}
+
+impl SegmentMap {
+    pub fn new(segments: Segments, limits: Limits, epoch: u32) -> Self {
+        Self {
+            segments,
+            limits,
+            epoch,
+            revision: 0,
+        }
+    }
+
+    pub fn segments(&self) -> &Segments {
+        &self.segments
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn revision(&self) -> u32 {
+        self.revision
+    }
+
+    pub fn etag(&self) -> String {
+        format!("\"{:08x}-{}\"", self.epoch, self.revision)
+    }
+
+    /// `If-Match`: absent or `*` always matches, otherwise one of the listed
+    /// tags has to be the current one.
+    pub fn check_if_match(&self, if_match: Option<&str>) -> Result<(), ApiError> {
+        match if_match {
+            None => Ok(()),
+            Some(tags) if self.compare(tags, false) => Ok(()),
+            Some(_) => Err(ApiError::new(
+                412,
+                format!("segments changed, now at {}", self.etag()),
+            )),
+        }
+    }
+
+    /// `If-None-Match`, for cheap polling
+    pub fn matches(&self, tags: &str) -> bool {
+        self.compare(tags, true)
+    }
+
+    /// RFC 9110 8.8.3.2: `If-Match` only accepts strong matches, a `W/` tag
+    /// never does; `If-None-Match` ignores the prefix.
+    fn compare(&self, tags: &str, weak: bool) -> bool {
+        let etag = self.etag();
+        tags.split(',').map(str::trim).any(|tag| {
+            let tag = match tag.strip_prefix("W/") {
+                Some(tag) if weak => tag,
+                _ => tag,
+            };
+            tag == "*" || tag == etag
+        })
+    }
+
+    pub fn replace_all(&mut self, segments: Segments) {
+        self.segments = segments;
+        self.revision = self.revision.wrapping_add(1);
+    }
+
+    /// Merge-patches a single segment, see [`patch`].
+    pub fn patch(&mut self, id: &str, patch: Value) -> Result<&Segment, ApiError> {
+        let mut next = self.segments.clone();
+        self::patch(&mut next, id, patch, &self.limits)?;
+        validate::total(&next, &self.limits)?;
+        self.segments = next;
+        self.revision = self.revision.wrapping_add(1);
+        get(&self.segments, id)
+    }
+
+    /// Runs the free [`handle`] against the map, guarded by `If-Match`.
+    pub fn handle(
+        &mut self,
+        method: Method,
+        id: &str,
+        body: &[u8],
+        if_match: Option<&str>,
+    ) -> Result<Reply, ApiError> {
+        if !matches!(method, Method::Get) {
+            self.check_if_match(if_match)?;
+        }
+        // work on a copy so a change that breaks the LED budget leaves no trace
+        let mut next = self.segments.clone();
+        let reply = handle(&mut next, method, id, body, &self.limits)?;
+        if reply.modified {
+            validate::total(&next, &self.limits)?;
+            self.segments = next;
+            self.revision = self.revision.wrapping_add(1);
+        }
+        Ok(reply)
+    }
+}
+
+/// The segment map the tests across the crate start from.
+#[cfg(test)]
+pub(crate) mod fixture {
+    use color_mixer::strip::{Segment, Srgb8};
+
+    use super::SegmentMap;
+    use crate::validate::Limits;
+
+    /// grey at half brightness
+    pub fn segment(length: usize) -> Segment {
+        let grey = Srgb8::new(10, 10, 10);
+        Segment::new(length, false, grey, grey, 0, 1, 50)
+    }
+
+    /// `segments` in this order, each under its uuid, on a strip of `leds`
+    pub fn map(segments: &[Segment], leds: usize) -> SegmentMap {
+        let segments = segments
+            .iter()
+            .map(|s| (s.to_uuid_string(), s.clone()))
+            .collect();
+        let limits = Limits {
+            strip_length: leds,
+            led_budget: leds,
+        };
+        SegmentMap::new(segments, limits, 0xcafe)
+    }
+}
+
+pub struct Reply {
+    pub status: u16,
+    pub body: String,
+    /// the map changed and needs to be persisted
+    pub modified: bool,
+}
+
+impl Reply {
+    fn ok(body: String) -> Self {
+        Self {
+            status: 200,
+            body,
+            modified: false,
+        }
+    }
+
+    fn modified(status: u16, body: String) -> Self {
+        Self {
+            status,
+            body,
+            modified: true,
+        }
+    }
+}
+
+/// `id` is whatever follows `/segments/`, empty for the collection itself.
+pub fn handle(
+    map: &mut Segments,
+    method: Method,
+    id: &str,
+    body: &[u8],
+    limits: &Limits,
+) -> Result<Reply, ApiError> {
+    match (method, id) {
+        (Method::Get, "") => Ok(Reply::ok(serde_json::to_string(map)?)),
+        (Method::Post, "") => {
+            let seg = validate::segment("", &serde_json::from_slice(body)?, limits)?;
+            let id = create(map, seg)?;
+            Ok(Reply::modified(201, serde_json::to_string(&id)?))
+        }
+        (Method::Put, "order") => {
+            let order: Vec<String> = serde_json::from_slice(body)?;
+            reorder(map, &order)?;
+            Ok(Reply::modified(200, serde_json::to_string(&order)?))
+        }
+        (_, "") | (_, "order") => Err(ApiError::method_not_allowed()),
+        (Method::Get, id) => Ok(Reply::ok(serde_json::to_string(get(map, id)?)?)),
+        (Method::Put, id) => {
+            let seg = validate::segment("", &serde_json::from_slice(body)?, limits)?;
+            replace(map, id, seg)?;
+            Ok(Reply::modified(200, serde_json::to_string(get(map, id)?)?))
+        }
+        (Method::Patch, id) => {
+            let patch: Value = serde_json::from_slice(body)?;
+            let seg = self::patch(map, id, patch, limits)?;
+            Ok(Reply::modified(200, serde_json::to_string(seg)?))
+        }
+        (Method::Delete, id) => {
+            remove(map, id)?;
+            Ok(Reply::modified(204, String::new()))
+        }
+        _ => Err(ApiError::method_not_allowed()),
+    }
+}
+
+fn not_found(id: &str) -> ApiError {
+    ApiError::not_found(format!("no segment {id}"))
+}
+
+pub fn get<'a>(map: &'a Segments, id: &str) -> Result<&'a Segment, ApiError> {
+    map.get(id).ok_or_else(|| not_found(id))
+}
+
+/// Appends `seg`, keyed by its uuid.
+pub fn create(map: &mut Segments, seg: Segment) -> Result<String, ApiError> {
+    let id = seg.to_uuid_string();
+    if map.contains_key(&id) {
+        return Err(ApiError::conflict(format!("segment {id} already exists")));
+    }
+    map.insert(id.clone(), seg);
+    Ok(id)
+}
+
+/// Replaces an existing segment in place, keeping its position.
+pub fn replace(map: &mut Segments, id: &str, seg: Segment) -> Result<(), ApiError> {
+    let slot = map.get_mut(id).ok_or_else(|| not_found(id))?;
+    check_uuid(id, &seg)?;
+    *slot = seg;
+    Ok(())
+}
+
+/// The map is keyed by uuid, a segment stored under another one's id would
+/// split the two apart.
+fn check_uuid(id: &str, seg: &Segment) -> Result<(), ApiError> {
+    let uuid = seg.to_uuid_string();
+    if uuid != id {
+        return Err(ApiError::bad_request(format!(
+            "segment {uuid} can't be stored as {id}"
+        )));
+    }
+    Ok(())
+}
+
+/// Applies a JSON merge patch (RFC 7396) to a single segment.
+pub fn patch<'a>(
+    map: &'a mut Segments,
+    id: &str,
+    patch: Value,
+    limits: &Limits,
+) -> Result<&'a Segment, ApiError> {
+    let slot = map.get_mut(id).ok_or_else(|| not_found(id))?;
+    let mut value = serde_json::to_value(&*slot)?;
+    merge(&mut value, patch);
+    let seg = validate::segment("", &value, limits)?;
+    check_uuid(id, &seg)?;
+    *slot = seg;
+    Ok(slot)
+}
+
+fn merge(target: &mut Value, patch: Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Default::default());
+            }
+            let target = target.as_object_mut().unwrap();
+            for (k, v) in patch {
+                if v.is_null() {
+                    target.remove(&k);
+                } else {
+                    merge(target.entry(k).or_insert(Value::Null), v);
+                }
+            }
+        }
+        patch => *target = patch,
+    }
+}
+
+pub fn remove(map: &mut Segments, id: &str) -> Result<Segment, ApiError> {
+    // `shift_remove` keeps the order of the remaining segments
+    map.shift_remove(id).ok_or_else(|| not_found(id))
+}
+
+/// `order` must name every existing segment exactly once.
+pub fn reorder(map: &mut Segments, order: &[String]) -> Result<(), ApiError> {
+    if order.len() != map.len() {
+        return Err(ApiError::bad_request(format!(
+            "expected {} segment ids, got {}",
+            map.len(),
+            order.len()
+        )));
+    }
+    let mut reordered = Segments::with_capacity(map.len());
+    for id in order {
+        let seg = map.get(id).ok_or_else(|| not_found(id))?;
+        if reordered.insert(id.clone(), seg.clone()).is_some() {
+            return Err(ApiError::bad_request(format!("duplicate segment id {id}")));
+        }
+    }
+    *map = reordered;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fixture::segment, *};
+
+    fn map(segments: &[Segment]) -> SegmentMap {
+        fixture::map(segments, 100)
+    }
+
+    fn body(seg: &Segment) -> Vec<u8> {
+        serde_json::to_vec(seg).unwrap()
+    }
+
+    #[test]
+    fn put_keeps_the_segment_under_its_uuid() {
+        let (a, b) = (segment(10), segment(20));
+        let id = a.to_uuid_string();
+        let mut map = map(&[a.clone(), b.clone()]);
+
+        let mut changed = serde_json::to_value(&a).unwrap();
+        changed["length"] = 30.into();
+        let changed = serde_json::to_vec(&changed).unwrap();
+        let reply = map.handle(Method::Put, &id, &changed, None).unwrap();
+        assert_eq!(reply.status, 200);
+        assert_eq!(map.segments()[&id].length(), 30);
+        assert_eq!(map.segments().get_index_of(&id), Some(0));
+
+        let e = map.handle(Method::Put, &id, &body(&b), None).err().unwrap();
+        assert_eq!(e.status, 400);
+        assert_eq!(map.segments()[&id].length(), 30);
+        assert_eq!(map.revision(), 1);
+    }
+
+    #[test]
+    fn patch_cant_change_the_uuid() {
+        let (a, b) = (segment(10), segment(20));
+        let id = a.to_uuid_string();
+        let mut map = map(&[a]);
+
+        let mut patch = serde_json::to_value(&b).unwrap();
+        patch["length"] = 10.into();
+        let patch = serde_json::to_vec(&patch).unwrap();
+        let e = map.handle(Method::Patch, &id, &patch, None).err().unwrap();
+        assert_eq!(e.status, 400);
+        assert_eq!(map.revision(), 0);
+    }
+
+    #[test]
+    fn unknown_ids_are_not_found() {
+        let mut map = map(&[segment(10)]);
+        let b = segment(20);
+        let id = b.to_uuid_string();
+        let e = map.handle(Method::Put, &id, &body(&b), None).err().unwrap();
+        assert_eq!(e.status, 404);
+    }
+
+    #[test]
+    fn if_match_compares_strongly() {
+        let a = segment(10);
+        let id = a.to_uuid_string();
+        let mut map = map(std::slice::from_ref(&a));
+        let etag = map.etag();
+        let weak = format!("W/{etag}");
+
+        assert!(map.check_if_match(Some(&weak)).is_err());
+        assert!(map.check_if_match(Some("\"nope\"")).is_err());
+        assert!(map
+            .check_if_match(Some(&format!("\"nope\", {etag}")))
+            .is_ok());
+        assert!(map.check_if_match(Some("*")).is_ok());
+        assert!(map.check_if_match(None).is_ok());
+
+        let e = map.handle(Method::Put, &id, &body(&a), Some(&weak));
+        assert_eq!(e.err().unwrap().status, 412);
+        assert!(map.handle(Method::Put, &id, &body(&a), Some(&etag)).is_ok());
+        assert!(map.check_if_match(Some(&etag)).is_err());
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let map = map(&[segment(10)]);
+        let etag = map.etag();
+        assert!(map.matches(&etag));
+        assert!(map.matches(&format!("W/{etag}")));
+        assert!(map.matches(&format!("\"nope\",W/{etag}")));
+        assert!(map.matches("*"));
+        assert!(!map.matches("\"nope\""));
+    }
+}
@@ -0,0 +1,282 @@
+//! Mounts a [`Router`] and the web assets on one of the two ESP-IDF HTTP
+//! servers. The `experimental` one, on by default, adds `/ws`; the legacy
+//! `httpd` one can't do WebSockets.
+
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::nvs_storage::EspNvsStorage;
+
+use crate::{
+    auth::Auth,
+    body::StdReader,
+    realtime::Frame,
+    router::{self, Router, HEADERS},
+    segments::SegmentMap,
+    sync,
+};
+
+/// `(path, content type, gzipped body)` of the web assets, written by
+/// `build.rs`. The page itself is same-origin, so they go out without CORS
+/// headers; only the API answers other origins, through the `Cors` layer.
+const ASSETS: &[(&str, &str, &[u8])] = include!("../web_assets.rs");
+
+#[cfg(not(feature = "experimental"))]
+fn legacy_method(method: router::Method) -> embedded_svc::httpd::Method {
+    use embedded_svc::httpd::Method;
+    match method {
+        router::Method::Get => Method::Get,
+        router::Method::Head => Method::Head,
+        router::Method::Post => Method::Post,
+        router::Method::Put => Method::Put,
+        router::Method::Patch => Method::Patch,
+        router::Method::Delete => Method::Delete,
+        router::Method::Options => Method::Options,
+    }
+}
+
+/// `segments`, `storage`, `clock` and `auth` only feed `/ws`, which this
+/// backend lacks.
+#[cfg(not(feature = "experimental"))]
+pub fn start(
+    router: Arc<Router>,
+    _segments: Arc<Mutex<SegmentMap>>,
+    _storage: Arc<Mutex<EspNvsStorage>>,
+    _clock: sync::Clock,
+    _auth: Arc<Mutex<Auth>>,
+    _live: Arc<Mutex<Frame>>,
+) -> anyhow::Result<esp_idf_svc::httpd::Server> {
+    use embedded_svc::httpd::{registry::Registry, Body, Handler, Method, Request, Response};
+    use esp_idf_svc::httpd::ServerRegistry;
+
+    fn resp(data: &'static [u8], content_type: &str) -> Result<Response, anyhow::Error> {
+        let response = Response::new(200)
+            .header("Content-Encoding", "gzip")
+            .header("Content-Type", content_type);
+        let body = Body::Read(None, Box::new(data));
+        response.body(body).into()
+    }
+
+    let mut server = ServerRegistry::new();
+    for &(path, content_type, data) in ASSETS {
+        server = server.handler(Handler::new(path, Method::Get, move |_| {
+            resp(data, content_type)
+        }))?;
+    }
+
+    for (method, pattern) in router.routes() {
+        let router = router.clone();
+        server = server.handler(Handler::new(
+            pattern,
+            legacy_method(method),
+            move |req: Request| {
+                let mut req = req;
+                let uri = req.url();
+                let content_len = req.content_len();
+                let headers: Vec<_> = HEADERS
+                    .iter()
+                    .filter_map(|&name| Some((name, req.header(name)?.to_string())))
+                    .collect();
+
+                let mut body = StdReader(&mut req);
+                let mut request = router::Request::new(method, &uri, content_len, &mut body);
+                for (name, value) in headers {
+                    request = request.with_header(name, value);
+                }
+                let res = router.handle(&mut request);
+
+                let mut response = Response::new(res.status);
+                for (name, value) in res.headers {
+                    response = response.header(name, value);
+                }
+                response.body(Body::Bytes(res.body)).into()
+            },
+        ))?;
+    }
+
+    server.start(&esp_idf_svc::httpd::Configuration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })
+}
+
+/// Detached sender of a `/ws` connection, used for broadcasts.
+#[cfg(feature = "experimental")]
+struct WsSink(esp_idf_svc::http::server::ws::EspHttpWsDetachedSender);
+
+#[cfg(feature = "experimental")]
+impl crate::ws::Sink for WsSink {
+    fn send_text(&mut self, text: &str) -> anyhow::Result<()> {
+        use embedded_svc::ws::{FrameType, Sender};
+        self.0.send(FrameType::Text(false), text.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// largest `/ws` frame we are willing to buffer
+#[cfg(feature = "experimental")]
+const WS_MAX_FRAME: usize = 4096;
+
+#[cfg(feature = "experimental")]
+fn http_method(method: router::Method) -> embedded_svc::http::Method {
+    use embedded_svc::http::Method;
+    match method {
+        router::Method::Get => Method::Get,
+        router::Method::Head => Method::Head,
+        router::Method::Post => Method::Post,
+        router::Method::Put => Method::Put,
+        router::Method::Patch => Method::Patch,
+        router::Method::Delete => Method::Delete,
+        router::Method::Options => Method::Options,
+    }
+}
+
+#[cfg(feature = "experimental")]
+pub fn start(
+    router: Arc<Router>,
+    segments: Arc<Mutex<SegmentMap>>,
+    storage: Arc<Mutex<EspNvsStorage>>,
+    clock: sync::Clock,
+    auth: Arc<Mutex<Auth>>,
+    live: Arc<Mutex<Frame>>,
+) -> anyhow::Result<esp_idf_svc::http::server::EspHttpServer> {
+    use embedded_svc::http::{
+        server::{registry::Registry, Request, Response},
+        Headers, SendHeaders, SendStatus,
+    };
+    use esp_idf_svc::http::server::{Configuration, EspHttpServer};
+
+    let mut server = EspHttpServer::new(&Configuration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+    ws(&mut server, segments, storage, clock, auth, live)?;
+
+    for &(path, content_type, data) in ASSETS {
+        server.handle(path, http_method(router::Method::Get), move |_req, resp| {
+            resp.status(200)
+                .header("Content-Encoding", "gzip")
+                .header("Content-Type", content_type)
+                .send_bytes(data)?;
+            Ok(())
+        })?;
+    }
+
+    for (method, pattern) in router.routes() {
+        let router = router.clone();
+        server.handle(pattern, http_method(method), move |mut req, resp| {
+            let uri = req.uri().to_string();
+            let content_len = req.content_len();
+            let headers: Vec<_> = HEADERS
+                .iter()
+                .filter_map(|&name| Some((name, req.header(name)?.to_string())))
+                .collect();
+
+            let mut body = StdReader(req.reader());
+            let mut request = router::Request::new(method, &uri, content_len, &mut body);
+            for (name, value) in headers {
+                request = request.with_header(name, value);
+            }
+            let res = router.handle(&mut request);
+
+            let mut resp = resp.status(res.status);
+            for (name, value) in &res.headers {
+                resp = resp.header(name, value);
+            }
+            resp.send_bytes(&res.body)?;
+            Ok(())
+        })?;
+    }
+
+    Ok(server)
+}
+
+#[cfg(feature = "experimental")]
+fn ws(
+    server: &mut esp_idf_svc::http::server::EspHttpServer,
+    segments: Arc<Mutex<SegmentMap>>,
+    storage: Arc<Mutex<EspNvsStorage>>,
+    clock: sync::Clock,
+    auth: Arc<Mutex<Auth>>,
+    live: Arc<Mutex<Frame>>,
+) -> anyhow::Result<()> {
+    use std::{
+        collections::HashMap,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{routes::SEGMENTS_FILE, settings, ws};
+
+    let sync_data = segments.clone();
+    let hub: Arc<Mutex<ws::Hub<WsSink>>> = Default::default();
+    let ws_hub = hub.clone();
+    // the token each session authenticated with, see `ws::handle`
+    let tokens: Mutex<HashMap<i32, Option<String>>> = Default::default();
+
+    server.ws_handler("/ws", move |conn| {
+        use embedded_svc::ws::{FrameType, Receiver, Sender};
+
+        if conn.is_new() {
+            return Ok(());
+        }
+        if conn.is_closed() {
+            ws_hub.lock().unwrap().unsubscribe(conn.session());
+            tokens.lock().unwrap().remove(&conn.session());
+            return Ok(());
+        }
+
+        let (_frame_type, len) = conn.recv(&mut [])?;
+        if len > WS_MAX_FRAME {
+            conn.send(FrameType::Close, &[])?;
+            return Ok(());
+        }
+        let mut buf = vec![0; len];
+        conn.recv(&mut buf)?;
+
+        let now = clock.now_ms();
+        let outcome = {
+            let mut map = segments.lock().unwrap();
+            let revision = map.revision();
+            let mut tokens = tokens.lock().unwrap();
+            let token = tokens.entry(conn.session()).or_default();
+            let outcome = ws::handle(&mut map, &auth.lock().unwrap(), token, &buf, now);
+            // like the HTTP routes, keep what a patch changed across reboots
+            if map.revision() != revision {
+                let mut storage = storage.lock().unwrap();
+                settings::store_json(&mut *storage, SEGMENTS_FILE, map.segments())?;
+            }
+            outcome
+        };
+        let reply = match outcome {
+            ws::Outcome::Subscribe(reply) => {
+                let sink = WsSink(conn.create_detached_sender()?);
+                ws_hub.lock().unwrap().subscribe(conn.session(), sink);
+                reply
+            }
+            ws::Outcome::Reply(reply) => reply,
+            ws::Outcome::Pixels(pixels) => {
+                match pixels.show(&mut live.lock().unwrap(), "ws", Instant::now()) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => ws::ServerMessage::from(e).encode(),
+                }
+            }
+        };
+        conn.send(FrameType::Text(false), reply.as_bytes())?;
+
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    // push every revision of the map, whoever changed it, to all subscribers
+    thread::Builder::new()
+        .stack_size(8192)
+        .spawn(move || loop {
+            thread::sleep(Duration::from_millis(50));
+            // encode under the lock, but don't hold the map while sending
+            let state = hub.lock().unwrap().changes(&sync_data.lock().unwrap());
+            if let Some(state) = state {
+                hub.lock().unwrap().broadcast(&state);
+            }
+        })?;
+
+    Ok(())
+}
@@ -0,0 +1,388 @@
+use std::net::Ipv4Addr;
+
+use embedded_svc::{
+    ipv4::{self, Mask, Subnet},
+    storage::RawStorage,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    adalight::AdalightSettings, artnet::ArtnetSettings, cors::Cors, ddp::DdpSettings,
+    e131::E131Settings, mqtt::MqttSettings, opc::OpcSettings, osc::OscSettings,
+    realtime::RealtimeSettings, sync::SyncSettings, tpm2::Tpm2Settings,
+};
+
+pub const SETTINGS_FILE: &'static str = "settings.json";
+
+/// Runtime settings, persisted as JSON in NVS next to the segments.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    /// DHCP hostname, also advertised as `<hostname>.local`
+    pub hostname: String,
+    /// human readable mDNS service instance name
+    pub instance_name: String,
+    /// station mode address; DHCP if unset
+    pub static_ip: Option<StaticIp>,
+    /// largest request body the HTTP API accepts, in bytes
+    pub max_body_size: usize,
+    pub cors: Cors,
+    /// applies to every realtime protocol
+    pub realtime: RealtimeSettings,
+    pub e131: E131Settings,
+    pub artnet: ArtnetSettings,
+    pub ddp: DdpSettings,
+    pub mqtt: MqttSettings,
+    pub opc: OpcSettings,
+    pub tpm2: Tpm2Settings,
+    pub adalight: AdalightSettings,
+    pub osc: OscSettings,
+    pub sync: SyncSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            hostname: "harharlot".into(),
+            instance_name: "harlot board".into(),
+            static_ip: None,
+            max_body_size: crate::body::DEFAULT_MAX_BODY,
+            cors: Cors::default(),
+            realtime: RealtimeSettings::default(),
+            e131: E131Settings::default(),
+            artnet: ArtnetSettings::default(),
+            ddp: DdpSettings::default(),
+            mqtt: MqttSettings::default(),
+            opc: OpcSettings::default(),
+            tpm2: Tpm2Settings::default(),
+            adalight: AdalightSettings::default(),
+            osc: OscSettings::default(),
+            sync: SyncSettings::default(),
+        }
+    }
+}
+
+pub fn load_json<T, S>(storage: &S, name: &str) -> anyhow::Result<Option<T>>
+where
+    T: DeserializeOwned,
+    S: RawStorage,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let len = match storage.len(name)? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    let mut buf = vec![0u8; len];
+    let (loaded_buf, _) = storage.get_raw(name, &mut buf)?.unwrap_or_default();
+    Ok(Some(serde_json::from_slice(loaded_buf)?))
+}
+
+pub fn store_json<T, S>(storage: &mut S, name: &str, value: &T) -> anyhow::Result<()>
+where
+    T: Serialize,
+    S: RawStorage,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let data = serde_json::to_vec(value)?;
+    storage.put_raw(name, &data)?;
+    Ok(())
+}
+
+impl Settings {
+    pub fn load<S>(storage: &S) -> anyhow::Result<Self>
+    where
+        S: RawStorage,
+        S::Error: std::error::Error + Send + Sync + 'static,
+    {
+        Ok(load_json(storage, SETTINGS_FILE)?.unwrap_or_default())
+    }
+
+    pub fn store<S>(&self, storage: &mut S) -> anyhow::Result<()>
+    where
+        S: RawStorage,
+        S::Error: std::error::Error + Send + Sync + 'static,
+    {
+        store_json(storage, SETTINGS_FILE, self)
+    }
+
+    /// The settings as `GET /settings` shows them, without the MQTT password.
+    pub fn redacted(&self) -> serde_json::Result<serde_json::Value> {
+        let mut value = serde_json::to_value(self)?;
+        if let Some(mqtt) = value.get_mut("mqtt").and_then(|m| m.as_object_mut()) {
+            mqtt.remove("password");
+        }
+        Ok(value)
+    }
+
+    /// Settings from a `POST /settings` body. `GET /settings` never shows the
+    /// MQTT password, so a body without one keeps the `current` password.
+    pub fn from_post(value: serde_json::Value, current: &Settings) -> serde_json::Result<Self> {
+        let keep = value.pointer("/mqtt/password").is_none();
+        let mut settings: Settings = serde_json::from_value(value)?;
+        if keep {
+            settings.mqtt.password = current.mqtt.password.clone();
+        }
+        Ok(settings)
+    }
+
+    /// hostnames end up in DHCP and mDNS, so keep them to plain DNS labels
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let host = &self.hostname;
+        if host.is_empty() || host.len() > 30 {
+            anyhow::bail!("hostname must be 1..=30 characters");
+        }
+        if host.starts_with('-') || host.ends_with('-') {
+            anyhow::bail!("hostname must not start or end with '-'");
+        }
+        if !host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            anyhow::bail!("hostname may only contain a-z, 0-9 and '-'");
+        }
+        if self.instance_name.is_empty() || self.instance_name.len() > 63 {
+            anyhow::bail!("instance_name must be 1..=63 bytes");
+        }
+        if !(256..=64 * 1024).contains(&self.max_body_size) {
+            anyhow::bail!("max_body_size must be 256..=65536 bytes");
+        }
+        if let Some(static_ip) = &self.static_ip {
+            static_ip.validate()?;
+        }
+        self.cors.validate()?;
+        self.realtime.validate()?;
+        self.e131.validate()?;
+        self.artnet.validate()?;
+        self.ddp.validate()?;
+        self.mqtt.validate()?;
+        self.opc.validate()?;
+        self.tpm2.validate()?;
+        self.adalight.validate()?;
+        self.osc.validate()?;
+        self.sync.validate()?;
+        Ok(())
+    }
+}
+
+/// Fixed IPv4 configuration for station mode. Addresses are dotted quads,
+/// the netmask may be given either as `255.255.255.0` or as prefix length
+/// `24`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StaticIp {
+    pub address: Ipv4Addr,
+    pub netmask: Netmask,
+    pub gateway: Ipv4Addr,
+    #[serde(default)]
+    pub dns: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub secondary_dns: Option<Ipv4Addr>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "NetmaskRepr", into = "NetmaskRepr")]
+pub struct Netmask(u8);
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum NetmaskRepr {
+    Prefix(u8),
+    Dotted(String),
+}
+
+impl Netmask {
+    pub fn from_prefix(prefix: u8) -> anyhow::Result<Self> {
+        if prefix > 32 {
+            anyhow::bail!("prefix length must be 0..=32, got {prefix}");
+        }
+        Ok(Self(prefix))
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.0
+    }
+
+    pub fn to_addr(&self) -> Ipv4Addr {
+        let bits = u32::MAX.checked_shl(32 - self.0 as u32).unwrap_or(0);
+        Ipv4Addr::from(bits)
+    }
+}
+
+impl std::str::FromStr for Netmask {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(prefix) = s.parse::<u8>() {
+            return Self::from_prefix(prefix);
+        }
+        let bits = u32::from(s.parse::<Ipv4Addr>()?);
+        // ones followed by zeroes only
+        if bits.leading_ones() + bits.trailing_zeros() != 32 {
+            anyhow::bail!("netmask {s} is not contiguous");
+        }
+        Ok(Self(bits.leading_ones() as u8))
+    }
+}
+
+impl TryFrom<NetmaskRepr> for Netmask {
+    type Error = anyhow::Error;
+
+    fn try_from(repr: NetmaskRepr) -> Result<Self, Self::Error> {
+        match repr {
+            NetmaskRepr::Prefix(prefix) => Self::from_prefix(prefix),
+            NetmaskRepr::Dotted(s) => s.parse(),
+        }
+    }
+}
+
+impl From<Netmask> for NetmaskRepr {
+    fn from(mask: Netmask) -> Self {
+        NetmaskRepr::Dotted(mask.to_addr().to_string())
+    }
+}
+
+fn is_usable_host(addr: Ipv4Addr) -> bool {
+    !(addr.is_unspecified() || addr.is_broadcast() || addr.is_multicast() || addr.is_loopback())
+}
+
+impl StaticIp {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let prefix = self.netmask.prefix();
+        if !(1..=30).contains(&prefix) {
+            anyhow::bail!("netmask /{prefix} leaves no room for hosts");
+        }
+        if !is_usable_host(self.address) {
+            anyhow::bail!("address {} is not a usable host address", self.address);
+        }
+        if !is_usable_host(self.gateway) {
+            anyhow::bail!("gateway {} is not a usable host address", self.gateway);
+        }
+        if self.address == self.gateway {
+            anyhow::bail!("address and gateway must differ");
+        }
+
+        let mask = u32::from(self.netmask.to_addr());
+        let addr = u32::from(self.address);
+        let network = addr & mask;
+        if addr == network || addr == network | !mask {
+            anyhow::bail!(
+                "address {} is the network or broadcast address of its subnet",
+                self.address
+            );
+        }
+        if u32::from(self.gateway) & mask != network {
+            anyhow::bail!(
+                "gateway {} is outside of {}/{prefix}",
+                self.gateway,
+                Ipv4Addr::from(network)
+            );
+        }
+
+        for dns in self.dns.iter().chain(self.secondary_dns.iter()) {
+            if !is_usable_host(*dns) {
+                anyhow::bail!("dns server {dns} is not a usable host address");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_client_settings(&self) -> ipv4::ClientSettings {
+        ipv4::ClientSettings {
+            ip: self.address,
+            subnet: Subnet {
+                gateway: self.gateway,
+                mask: Mask(self.netmask.prefix()),
+            },
+            dns: self.dns,
+            secondary_dns: self.secondary_dns,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn mqtt_password_is_never_shown_and_kept_when_left_out() {
+        let mut current = Settings::default();
+        current.mqtt.username = "harlot".into();
+        current.mqtt.password = "secret".into();
+
+        let shown = current.redacted().unwrap();
+        assert_eq!(shown["mqtt"]["username"], "harlot");
+        assert!(shown["mqtt"].get("password").is_none());
+        assert!(!shown.to_string().contains("secret"));
+
+        // what a client read back, edited and posted
+        let mut posted = shown.clone();
+        posted["hostname"] = json!("other");
+        let next = Settings::from_post(posted, &current).unwrap();
+        assert_eq!(next.hostname, "other");
+        assert_eq!(next.mqtt.password, "secret");
+
+        // no mqtt object at all keeps it too
+        let next = Settings::from_post(json!({}), &current).unwrap();
+        assert_eq!(next.mqtt.password, "secret");
+
+        for password in ["new", ""] {
+            let posted = json!({"mqtt": {"password": password}});
+            let next = Settings::from_post(posted, &current).unwrap();
+            assert_eq!(next.mqtt.password, password);
+        }
+        assert!(Settings::from_post(json!({"mqtt": {"port": "x"}}), &current).is_err());
+    }
+
+    fn static_ip(
+        address: &str,
+        netmask: serde_json::Value,
+        gateway: &str,
+    ) -> anyhow::Result<StaticIp> {
+        let ip: StaticIp = serde_json::from_value(json!({
+            "address": address,
+            "netmask": netmask,
+            "gateway": gateway,
+        }))?;
+        ip.validate()?;
+        Ok(ip)
+    }
+
+    #[test]
+    fn netmask_as_prefix_or_dotted_quad() {
+        for mask in [json!(24), json!("24"), json!("255.255.255.0")] {
+            let ip = static_ip("192.168.1.20", mask, "192.168.1.1").unwrap();
+            assert_eq!(ip.netmask.prefix(), 24);
+        }
+        assert_eq!("0.0.0.0".parse::<Netmask>().unwrap().prefix(), 0);
+        assert_eq!("255.255.255.255".parse::<Netmask>().unwrap().prefix(), 32);
+        assert_eq!(Netmask::from_prefix(20).unwrap().to_addr(), Ipv4Addr::new(255, 255, 240, 0));
+        assert!(Netmask::from_prefix(33).is_err());
+
+        // always written back dotted
+        let ip = static_ip("10.0.0.2", json!(8), "10.0.0.1").unwrap();
+        assert_eq!(serde_json::to_value(&ip).unwrap()["netmask"], "255.0.0.0");
+    }
+
+    #[test]
+    fn rejects_non_contiguous_netmasks() {
+        for mask in ["255.0.255.0", "255.255.255.1", "0.255.255.255", "128.0.0.1"] {
+            assert!(mask.parse::<Netmask>().is_err(), "{mask}");
+        }
+        assert!(static_ip("192.168.1.20", json!("255.255.0.255"), "192.168.1.1").is_err());
+    }
+
+    #[test]
+    fn rejects_network_and_broadcast_addresses() {
+        assert!(static_ip("192.168.1.0", json!(24), "192.168.1.1").is_err());
+        assert!(static_ip("192.168.1.255", json!(24), "192.168.1.1").is_err());
+        // same address, but a host in a wider subnet
+        assert!(static_ip("192.168.1.0", json!(16), "192.168.0.1").is_ok());
+        assert!(static_ip("10.1.2.3", json!(31), "10.1.2.2").is_err());
+    }
+
+    #[test]
+    fn rejects_unusable_gateways() {
+        assert!(static_ip("192.168.1.20", json!(24), "192.168.2.1").is_err());
+        assert!(static_ip("192.168.1.20", json!(24), "192.168.1.20").is_err());
+        assert!(static_ip("192.168.1.20", json!(24), "0.0.0.0").is_err());
+        assert!(static_ip("127.0.0.1", json!(8), "127.0.0.2").is_err());
+    }
+}
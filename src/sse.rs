@@ -0,0 +1,433 @@
+//! Minimal `text/event-stream` server on plain std sockets.
+//!
+//! The IDF http server handles requests on a single task, so a long lived
+//! stream there would block every other request. `GET /events` on the main
+//! server redirects here instead, keeping the query string. `EventSource`
+//! can't set headers, so besides `Authorization: Bearer` the stream takes
+//! its token as `?token=`.
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    api::ApiError,
+    auth::{self, Auth, Role},
+    cors::Cors,
+    events::Bus,
+    ws::Sink,
+};
+
+pub const SSE_PORT: u16 = 8080;
+pub const MAX_STREAMS: usize = 2;
+
+impl Sink for TcpStream {
+    fn send_text(&mut self, text: &str) -> anyhow::Result<()> {
+        self.write_all(text.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Where `GET /events` on the main server sends a request for `host` with
+/// `query`. The query carries the token over; the page's `origin` is added,
+/// since browsers send `Origin: null` after a redirect to another origin.
+pub fn location(host: &str, query: &str, origin: Option<&str>) -> String {
+    let host = host.split(':').next().unwrap_or_default();
+    let mut params: Vec<_> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty() && !pair.starts_with("origin="))
+        .map(str::to_string)
+        .collect();
+    if let Some(origin) = origin {
+        params.push(format!("origin={}", percent_encode(origin)));
+    }
+    let query = if params.is_empty() {
+        String::new()
+    } else {
+        format!("?{}", params.join("&"))
+    };
+    format!("http://{host}:{SSE_PORT}/events{query}")
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        if b != b'%' {
+            bytes.push(b);
+            continue;
+        }
+        let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        rest = &rest[2..];
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// The stream is on another port than the page, so always cross-origin:
+/// `cors` decides who may read it.
+pub fn serve(
+    port: u16,
+    bus: Arc<Mutex<Bus<TcpStream>>>,
+    cors: Cors,
+    auth: Arc<Mutex<Auth>>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    thread::Builder::new().stack_size(6144).spawn(move || {
+        for stream in listener.incoming() {
+            let res = stream
+                .map_err(anyhow::Error::from)
+                .and_then(|stream| accept(stream, &bus, &cors, &auth));
+            if let Err(e) = res {
+                log::warn!("sse: {e}");
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn accept(
+    mut stream: TcpStream,
+    bus: &Mutex<Bus<TcpStream>>,
+    cors: &Cors,
+    auth: &Mutex<Auth>,
+) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    // a stalled client must not hold up the publisher for long
+    stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+
+    let head = read_head(&mut stream)?;
+    let request = match parse_request(&head) {
+        Some(request) if request.path == "/events" => request,
+        _ => {
+            stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")?;
+            return Ok(());
+        }
+    };
+
+    // the same rule as `/data` and `/status` on the main server
+    let authorized = auth
+        .lock()
+        .unwrap()
+        .authorize_token(request.token, Some(Role::ReadOnly));
+    if let Err(e) = authorized {
+        return refuse(&mut stream, &e);
+    }
+
+    let mut bus = bus.lock().unwrap();
+    if bus.is_full() {
+        stream.write_all(
+            b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 10\r\nContent-Length: 0\r\n\r\n",
+        )?;
+        return Ok(());
+    }
+    let allowed = match (request.origin, &request.redirected_from) {
+        // a redirected page reads as `null`, whatever origin it passed on
+        (Some("null"), Some(from)) => cors.allow_origin(Some(from)).map(|allowed| {
+            if allowed == "*" {
+                allowed
+            } else {
+                "null".into()
+            }
+        }),
+        (origin, _) => cors.allow_origin(origin),
+    };
+    let allow_origin = match allowed {
+        Some(origin) => format!("Access-Control-Allow-Origin: {origin}\r\nVary: Origin\r\n"),
+        None => String::new(),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/event-stream\r\n\
+         Cache-Control: no-cache\r\n\
+         {allow_origin}\
+         \r\n\
+         retry: 2000\n\n",
+    )?;
+    bus.subscribe(stream, request.last_event_id)
+}
+
+fn refuse(stream: &mut TcpStream, error: &ApiError) -> anyhow::Result<()> {
+    let body = error.to_json();
+    let reason = match error.status {
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        _ => "Service Unavailable",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {reason}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         \r\n\
+         {body}",
+        error.status,
+        body.len(),
+    )?;
+    Ok(())
+}
+
+fn read_head(stream: &mut TcpStream) -> anyhow::Result<String> {
+    let mut head = Vec::with_capacity(512);
+    let mut buf = [0u8; 256];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > 2048 {
+            anyhow::bail!("request head too large");
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            anyhow::bail!("connection closed");
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StreamRequest<'a> {
+    pub path: &'a str,
+    pub last_event_id: Option<u64>,
+    pub origin: Option<&'a str>,
+    /// from `Authorization: Bearer`, else from `?token=`
+    pub token: Option<&'a str>,
+    /// the page's origin, passed on by the redirect from the main server
+    pub redirected_from: Option<String>,
+}
+
+/// Only `GET` is accepted.
+pub fn parse_request(head: &str) -> Option<StreamRequest<'_>> {
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    if request_line.next()? != "GET" {
+        return None;
+    }
+    let target = request_line.next()?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let headers: Vec<_> = lines.filter_map(|line| line.split_once(':')).collect();
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    };
+
+    Some(StreamRequest {
+        path,
+        last_event_id: header("Last-Event-ID").and_then(|value| value.parse().ok()),
+        origin: header("Origin"),
+        token: header("Authorization")
+            .and_then(auth::bearer)
+            .or_else(|| query_param(query, "token")),
+        redirected_from: query_param(query, "origin").and_then(percent_decode),
+    })
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(n, _)| *n == name)
+        .map(|(_, value)| value)
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_stream_request() {
+        let head = "GET /events?x=1 HTTP/1.1\r\n\
+                    Host: board\r\n\
+                    last-event-id: 42\r\n\
+                    Origin: http://app\r\n\r\n";
+        assert_eq!(
+            parse_request(head),
+            Some(StreamRequest {
+                path: "/events",
+                last_event_id: Some(42),
+                origin: Some("http://app"),
+                token: None,
+                redirected_from: None,
+            })
+        );
+
+        let head = "GET /events HTTP/1.1\r\nLast-Event-ID: soon\r\n\r\n";
+        assert_eq!(
+            parse_request(head),
+            Some(StreamRequest {
+                path: "/events",
+                last_event_id: None,
+                origin: None,
+                token: None,
+                redirected_from: None,
+            })
+        );
+
+        assert_eq!(parse_request("POST /events HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(parse_request(""), None);
+    }
+
+    #[test]
+    fn redirects_keep_the_query() {
+        assert_eq!(location("board:80", "", None), "http://board:8080/events");
+        assert_eq!(
+            location("10.0.0.2", "token=abc", None),
+            "http://10.0.0.2:8080/events?token=abc"
+        );
+        // the page's origin travels along, and can't be smuggled in twice
+        let location = location(
+            "board",
+            "token=abc&origin=http%3A%2F%2Fevil",
+            Some("http://app:8000"),
+        );
+        assert_eq!(
+            location,
+            "http://board:8080/events?token=abc&origin=http%3A%2F%2Fapp%3A8000"
+        );
+        let target = location.strip_prefix("http://board:8080").unwrap();
+        let head = format!("GET {target} HTTP/1.1\r\n\r\n");
+        let request = parse_request(&head).unwrap();
+        assert_eq!(request.path, "/events");
+        assert_eq!(request.redirected_from.as_deref(), Some("http://app:8000"));
+        assert_eq!(request.token, Some("abc"));
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("a%2Fb%2fc").as_deref(), Some("a/b/c"));
+        assert_eq!(percent_decode("plain").as_deref(), Some("plain"));
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%+f"), None);
+        assert_eq!(percent_decode("%C3%A9").as_deref(), Some("é"));
+        assert_eq!(percent_decode("%FF"), None);
+    }
+
+    #[test]
+    fn takes_the_token_from_the_header_or_the_query() {
+        let token = |head| parse_request(head).unwrap().token;
+        assert_eq!(token("GET /events?token=abc HTTP/1.1\r\n\r\n"), Some("abc"));
+        assert_eq!(
+            token("GET /events?x=1&token=abc HTTP/1.1\r\n\r\n"),
+            Some("abc")
+        );
+        let head = "GET /events?token=abc HTTP/1.1\r\nAuthorization: Bearer def\r\n\r\n";
+        assert_eq!(token(head), Some("def"));
+        assert_eq!(token("GET /events?token= HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(token("GET /events?tokens=abc HTTP/1.1\r\n\r\n"), None);
+    }
+
+    /// Sends `head` to [`accept`] over loopback and returns the start of
+    /// the answer.
+    fn stream(head: &str, bus: &Mutex<Bus<TcpStream>>, cors: &Cors, auth: &Mutex<Auth>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.write_all(head.as_bytes()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        accept(server, bus, cors, auth).unwrap();
+
+        let mut answer = vec![0; 1024];
+        let n = client.read(&mut answer).unwrap();
+        String::from_utf8_lossy(&answer[..n]).into_owned()
+    }
+
+    #[test]
+    fn streams_only_with_a_token_once_a_password_is_set() {
+        let bus = Mutex::new(Bus::new(MAX_STREAMS));
+        let cors = Cors::default();
+        let auth = Mutex::new(Auth::default());
+        let anonymous = "GET /events HTTP/1.1\r\n\r\n";
+        assert!(stream(anonymous, &bus, &cors, &auth).starts_with("HTTP/1.1 200 OK"));
+
+        let token = {
+            let mut auth = auth.lock().unwrap();
+            auth.set_password("correct horse", [1; 16]).unwrap();
+            auth.issue(Role::ReadOnly, "display", [2; 32])
+        };
+        let answer = stream(anonymous, &bus, &cors, &auth);
+        assert!(answer.starts_with("HTTP/1.1 401 Unauthorized"), "{answer}");
+        assert!(
+            answer.ends_with(r#"{"error":"missing or invalid token"}"#),
+            "{answer}"
+        );
+        let wrong = "GET /events?token=0000 HTTP/1.1\r\n\r\n";
+        assert!(stream(wrong, &bus, &cors, &auth).starts_with("HTTP/1.1 401"));
+        // only the first stream got through so far
+        assert_eq!(bus.lock().unwrap().len(), 1);
+
+        let query = format!("GET /events?token={token} HTTP/1.1\r\n\r\n");
+        assert!(stream(&query, &bus, &cors, &auth).starts_with("HTTP/1.1 200 OK"));
+        // a valid token still waits for a free slot
+        let header = format!("GET /events HTTP/1.1\r\nAuthorization: Bearer {token}\r\n\r\n");
+        let answer = stream(&header, &bus, &cors, &auth);
+        assert!(answer.starts_with("HTTP/1.1 503"), "{answer}");
+
+        let locked = Mutex::new(Auth::locked());
+        let answer = stream(&query, &Mutex::new(Bus::new(1)), &cors, &locked);
+        assert!(
+            answer.starts_with("HTTP/1.1 503 Service Unavailable"),
+            "{answer}"
+        );
+    }
+
+    #[test]
+    fn lets_redirected_pages_read_the_stream() {
+        let cors = Cors {
+            allowed_origins: vec!["http://app:8000".into()],
+            ..Default::default()
+        };
+        let auth = Mutex::new(Auth::default());
+        let answer = |origin: &str, from: &str| {
+            let bus = Mutex::new(Bus::new(1));
+            let head = format!("GET /events?origin={from} HTTP/1.1\r\nOrigin: {origin}\r\n\r\n");
+            let answer = stream(&head, &bus, &cors, &auth);
+            assert!(answer.starts_with("HTTP/1.1 200 OK"), "{answer}");
+            answer
+                .lines()
+                .find_map(|line| line.strip_prefix("Access-Control-Allow-Origin: "))
+                .map(str::to_string)
+        };
+        let app = "http%3A%2F%2Fapp%3A8000";
+        assert_eq!(answer("null", app).as_deref(), Some("null"));
+        assert_eq!(answer("null", "http%3A%2F%2Fevil").as_deref(), None);
+        // a page that wasn't redirected gets its own origin checked
+        assert_eq!(
+            answer("http://app:8000", "").as_deref(),
+            Some("http://app:8000")
+        );
+        assert_eq!(answer("http://evil", app).as_deref(), None);
+
+        let any = Cors::default();
+        let head = "GET /events HTTP/1.1\r\nOrigin: null\r\n\r\n";
+        let answer = stream(head, &Mutex::new(Bus::new(1)), &any, &auth);
+        assert!(
+            answer.contains("Access-Control-Allow-Origin: *\r\n"),
+            "{answer}"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! `GET /status`: a report assembled from independent providers, each
+//! contributing one top level key.
+
+use std::{collections::VecDeque, time::Duration};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+pub trait Provider: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn report(&self) -> Value;
+}
+
+struct FnProvider<F> {
+    name: &'static str,
+    f: F,
+}
+
+impl<F: Fn() -> Value + Send + Sync> Provider for FnProvider<F> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn report(&self) -> Value {
+        (self.f)()
+    }
+}
+
+/// Wraps a closure into a [`Provider`].
+pub fn from_fn<F>(name: &'static str, f: F) -> Box<dyn Provider>
+where
+    F: Fn() -> Value + Send + Sync + 'static,
+{
+    Box::new(FnProvider { name, f })
+}
+
+#[derive(Default)]
+pub struct Status {
+    providers: Vec<Box<dyn Provider>>,
+}
+
+impl Status {
+    pub fn with(mut self, provider: Box<dyn Provider>) -> Self {
+        self.add(provider);
+        self
+    }
+
+    pub fn add(&mut self, provider: Box<dyn Provider>) {
+        self.providers.push(provider);
+    }
+
+    pub fn report(&self) -> Map<String, Value> {
+        self.providers
+            .iter()
+            .map(|p| (p.name().to_string(), p.report()))
+            .collect()
+    }
+}
+
+pub fn firmware() -> Box<dyn Provider> {
+    from_fn("firmware", || {
+        serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "build": env!("HARLOT_BUILD_HASH"),
+        })
+    })
+}
+
+/// how many frames the render statistics look back
+const FRAME_WINDOW: usize = 128;
+
+/// Render loop timings over the last [`FRAME_WINDOW`] frames.
+#[derive(Default)]
+pub struct FrameStats {
+    /// time spent rendering and flushing, in µs
+    render_us: VecDeque<u32>,
+    /// time from one frame start to the next, in µs
+    interval_us: VecDeque<u32>,
+    frames: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FrameReport {
+    pub frames: u64,
+    pub fps: f32,
+    pub frame_us_p50: u32,
+    pub frame_us_p90: u32,
+    pub frame_us_p99: u32,
+    pub frame_us_max: u32,
+}
+
+impl FrameStats {
+    pub fn record(&mut self, render: Duration, interval: Duration) {
+        for (window, d) in [(&mut self.render_us, render), (&mut self.interval_us, interval)] {
+            if window.len() == FRAME_WINDOW {
+                window.pop_front();
+            }
+            window.push_back(d.as_micros().min(u32::MAX as u128) as u32);
+        }
+        self.frames += 1;
+    }
+
+    pub fn report(&self) -> FrameReport {
+        let mut sorted: Vec<u32> = self.render_us.iter().copied().collect();
+        sorted.sort_unstable();
+
+        let total_us: u64 = self.interval_us.iter().map(|&us| us as u64).sum();
+        let fps = if total_us == 0 {
+            0.0
+        } else {
+            self.interval_us.len() as f32 * 1_000_000.0 / total_us as f32
+        };
+
+        FrameReport {
+            frames: self.frames,
+            fps,
+            frame_us_p50: percentile(&sorted, 50),
+            frame_us_p90: percentile(&sorted, 90),
+            frame_us_p99: percentile(&sorted, 99),
+            frame_us_max: sorted.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// nearest-rank percentile of an ascending slice
+pub fn percentile(sorted: &[u32], p: u32) -> u32 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (p as usize * sorted.len() + 99) / 100;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        assert_eq!(percentile(&[], 50), 0);
+        assert_eq!(percentile(&[7], 0), 7);
+        assert_eq!(percentile(&[7], 99), 7);
+
+        let sorted: Vec<u32> = (1..=100).collect();
+        assert_eq!(percentile(&sorted, 0), 1);
+        assert_eq!(percentile(&sorted, 50), 50);
+        assert_eq!(percentile(&sorted, 90), 90);
+        assert_eq!(percentile(&sorted, 99), 99);
+        assert_eq!(percentile(&sorted, 100), 100);
+
+        // the rank rounds up
+        assert_eq!(percentile(&[10, 20, 30, 40], 50), 20);
+        assert_eq!(percentile(&[10, 20, 30, 40], 51), 30);
+        assert_eq!(percentile(&[10, 20, 30], 90), 30);
+    }
+
+    #[test]
+    fn frame_stats_look_at_the_last_frames_only() {
+        let mut stats = FrameStats::default();
+        assert_eq!(stats.report().fps, 0.0);
+        assert_eq!(stats.report().frame_us_max, 0);
+
+        // a slow start that falls out of the window
+        for _ in 0..10 {
+            stats.record(Duration::from_millis(90), Duration::from_millis(100));
+        }
+        for i in 0..FRAME_WINDOW as u64 {
+            stats.record(Duration::from_micros(1000 + i), Duration::from_millis(20));
+        }
+        let report = stats.report();
+        assert_eq!(report.frames, 10 + FRAME_WINDOW as u64);
+        assert!((report.fps - 50.0).abs() < 0.01, "{}", report.fps);
+        assert_eq!(report.frame_us_p50, 1063);
+        assert_eq!(report.frame_us_p90, 1115);
+        assert_eq!(report.frame_us_p99, 1126);
+        assert_eq!(report.frame_us_max, 1127);
+    }
+
+    #[test]
+    fn frame_times_saturate() {
+        let mut stats = FrameStats::default();
+        stats.record(Duration::from_secs(10_000), Duration::from_secs(10_000));
+        assert_eq!(stats.report().frame_us_max, u32::MAX);
+    }
+
+    #[test]
+    fn report_has_one_key_per_provider() {
+        let status = Status::default()
+            .with(from_fn("wifi", || json!({"rssi": -60})))
+            .with(firmware())
+            .with(from_fn("render", || json!(null)));
+        let report = status.report();
+        let mut keys: Vec<_> = report.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, ["firmware", "render", "wifi"]);
+        assert_eq!(report["wifi"]["rssi"], -60);
+        assert_eq!(report["firmware"]["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(report["firmware"]["build"], env!("HARLOT_BUILD_HASH"));
+        assert!(report["render"].is_null());
+    }
+
+    #[test]
+    fn providers_report_on_every_call() {
+        use std::sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        };
+        let calls = Arc::new(AtomicU32::new(0));
+        let counter = calls.clone();
+        let mut status = Status::default();
+        status.add(from_fn("calls", move || {
+            json!(counter.fetch_add(1, Ordering::Relaxed) + 1)
+        }));
+        assert_eq!(status.report()["calls"], 1);
+        assert_eq!(status.report()["calls"], 2);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+}
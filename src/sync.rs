@@ -0,0 +1,760 @@
+//! Clock sync between boards, so the same segments animate in step.
+//!
+//! One board is the leader and broadcasts an announcement every
+//! `interval_ms`. Followers in the same `group` answer by asking the leader
+//! for its time, NTP style: the request carries the follower's send time,
+//! the reply the leader's receive and send times, and the follower notes
+//! when the reply arrived. From those the [`Estimator`] works out the offset
+//! to the leader's clock and how fast the two drift apart.
+//!
+//! The shared timeline is the leader's time since its boot. Until a
+//! follower has heard from a leader, it runs on its own.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PORT: u16 = 48720;
+const MAGIC: &[u8; 4] = b"HSYN";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 12;
+pub const MAX_PACKET: usize = HEADER_LEN + 24;
+const KIND_ANNOUNCE: u8 = 1;
+const KIND_REQUEST: u8 = 2;
+const KIND_RESPONSE: u8 = 3;
+/// samples the estimator keeps
+const WINDOW: usize = 32;
+/// drift is only fitted over samples at least this far apart
+const MIN_SPAN_US: u64 = 10_000_000;
+/// anything faster is a broken clock or a changed leader, not a crystal
+const MAX_DRIFT: f64 = 500e-6;
+/// delay on top of the best one that still makes a usable sample
+const JITTER_US: u64 = 2_000;
+/// a follower without a leader for this long reports itself unsynced
+const STALE: Duration = Duration::from_secs(10);
+/// corrections back in time up to this are sat out rather than jumped
+const MAX_HOLD_US: u64 = 1_000_000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Leader,
+    Follower,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct SyncSettings {
+    pub enabled: bool,
+    pub role: Role,
+    pub port: u16,
+    /// boards only sync within their group
+    pub group: u16,
+    pub interval_ms: u32,
+}
+
+impl Default for SyncSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            role: Role::Follower,
+            port: DEFAULT_PORT,
+            group: 0,
+            interval_ms: 1000,
+        }
+    }
+}
+
+impl SyncSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.port == 0 {
+            anyhow::bail!("sync port must be 1..=65535");
+        }
+        if !(100..=10_000).contains(&self.interval_ms) {
+            anyhow::bail!("sync interval_ms must be 100..=10000");
+        }
+        Ok(())
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms as u64)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    TooShort(usize),
+    NotSync,
+    UnsupportedVersion(u8),
+    UnsupportedKind(u8),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::TooShort(len) => write!(f, "packet too short: {len} bytes"),
+            ParseError::NotSync => write!(f, "not a sync packet"),
+            ParseError::UnsupportedVersion(v) => write!(f, "unsupported version {v}"),
+            ParseError::UnsupportedKind(k) => write!(f, "unsupported packet kind {k}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Times are microseconds, each on the clock of the board that took them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Message {
+    /// the leader is there, its time for good measure
+    Announce { time: u64 },
+    /// from a follower, `t1` being when it was sent
+    Request { t1: u64 },
+    /// the leader's answer: received at `t2`, sent at `t3`
+    Response { t1: u64, t2: u64, t3: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Packet {
+    pub group: u16,
+    /// changes whenever the sender reboots
+    pub boot_id: u32,
+    pub message: Message,
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, times) = match self.message {
+            Message::Announce { time } => (KIND_ANNOUNCE, vec![time]),
+            Message::Request { t1 } => (KIND_REQUEST, vec![t1]),
+            Message::Response { t1, t2, t3 } => (KIND_RESPONSE, vec![t1, t2, t3]),
+        };
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.push(kind);
+        out.extend_from_slice(&self.group.to_be_bytes());
+        out.extend_from_slice(&self.boot_id.to_be_bytes());
+        for time in times {
+            out.extend_from_slice(&time.to_be_bytes());
+        }
+        out
+    }
+}
+
+pub fn parse(buf: &[u8]) -> Result<Packet, ParseError> {
+    if buf.len() < HEADER_LEN {
+        return Err(ParseError::TooShort(buf.len()));
+    }
+    if &buf[..4] != MAGIC {
+        return Err(ParseError::NotSync);
+    }
+    if buf[4] != VERSION {
+        return Err(ParseError::UnsupportedVersion(buf[4]));
+    }
+    let time = |i: usize| -> Result<u64, ParseError> {
+        let at = HEADER_LEN + i * 8;
+        let bytes = buf.get(at..at + 8).ok_or(ParseError::TooShort(buf.len()))?;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    let message = match buf[5] {
+        KIND_ANNOUNCE => Message::Announce { time: time(0)? },
+        KIND_REQUEST => Message::Request { t1: time(0)? },
+        KIND_RESPONSE => Message::Response {
+            t1: time(0)?,
+            t2: time(1)?,
+            t3: time(2)?,
+        },
+        kind => return Err(ParseError::UnsupportedKind(kind)),
+    };
+    Ok(Packet {
+        group: u16::from_be_bytes([buf[6], buf[7]]),
+        boot_id: u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]),
+        message,
+    })
+}
+
+/// One request/response round trip.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    /// local time halfway through the round trip
+    pub local: u64,
+    /// leader minus local time
+    pub offset: i64,
+    /// round trip time without the leader's turnaround
+    pub delay: u64,
+}
+
+impl Sample {
+    /// `t1` and `t4` are local, `t2` and `t3` the leader's.
+    pub fn new(t1: u64, t2: u64, t3: u64, t4: u64) -> Self {
+        let (t1, t2, t3, t4) = (t1 as i128, t2 as i128, t3 as i128, t4 as i128);
+        Self {
+            local: (t1 + (t4 - t1) / 2) as u64,
+            offset: (((t2 - t1) + (t3 - t4)) / 2) as i64,
+            delay: ((t4 - t1) - (t3 - t2)).max(0) as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Fit {
+    /// local time the fit is anchored at
+    local: u64,
+    offset: f64,
+    /// leader seconds gained per local second
+    drift: f64,
+}
+
+/// Offset and drift from a window of samples. Samples much slower than the
+/// best recent one are left out, a line through the rest gives the drift.
+#[derive(Debug, Default)]
+pub struct Estimator {
+    samples: VecDeque<Sample>,
+    fit: Option<Fit>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Estimate {
+    pub offset_ms: f64,
+    pub drift_ppm: f64,
+    /// the best round trip in the window
+    pub delay_ms: f64,
+    pub samples: usize,
+}
+
+impl Estimator {
+    pub fn add(&mut self, sample: Sample) {
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        self.refit();
+    }
+
+    pub fn reset(&mut self) {
+        self.samples.clear();
+        self.fit = None;
+    }
+
+    fn refit(&mut self) {
+        let Some(best) = self.samples.iter().map(|s| s.delay).min() else {
+            self.fit = None;
+            return;
+        };
+        let good: Vec<&Sample> = self
+            .samples
+            .iter()
+            .filter(|s| s.delay <= best * 2 + JITTER_US)
+            .collect();
+        let first = good[0].local;
+        let span = good[good.len() - 1].local.saturating_sub(first);
+
+        let n = good.len() as f64;
+        let x = |s: &Sample| s.local as f64 - first as f64;
+        let mean_x = good.iter().map(|s| x(s)).sum::<f64>() / n;
+        let mean_y = good.iter().map(|s| s.offset as f64).sum::<f64>() / n;
+        let drift = match span >= MIN_SPAN_US && good.len() >= 4 {
+            true => {
+                let sxy: f64 = good
+                    .iter()
+                    .map(|s| (x(s) - mean_x) * (s.offset as f64 - mean_y))
+                    .sum();
+                let sxx: f64 = good.iter().map(|s| (x(s) - mean_x).powi(2)).sum();
+                (sxy / sxx).clamp(-MAX_DRIFT, MAX_DRIFT)
+            }
+            false => 0.0,
+        };
+        self.fit = Some(Fit {
+            local: (first as f64 + mean_x) as u64,
+            offset: mean_y,
+            drift,
+        });
+    }
+
+    /// Leader time for local time `local`, once there's a sample.
+    pub fn leader_time(&self, local: u64) -> Option<u64> {
+        let fit = self.fit?;
+        let since = local as f64 - fit.local as f64;
+        let offset = fit.offset + fit.drift * since;
+        Some((local as f64 + offset).max(0.0) as u64)
+    }
+
+    /// The estimate as of local time `local`.
+    pub fn estimate(&self, local: u64) -> Option<Estimate> {
+        let fit = self.fit?;
+        let delay = self.samples.iter().map(|s| s.delay).min()?;
+        let since = local as f64 - fit.local as f64;
+        Some(Estimate {
+            offset_ms: (fit.offset + fit.drift * since) / 1000.0,
+            drift_ppm: fit.drift * 1e6,
+            delay_ms: delay as f64 / 1000.0,
+            samples: self.samples.len(),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    estimator: Estimator,
+    leader: Option<(SocketAddr, u32)>,
+    /// local time of the last response
+    heard: Option<u64>,
+    /// latest time handed out
+    last: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Report {
+    pub enabled: bool,
+    pub role: Role,
+    pub group: u16,
+    /// following a leader with a recent sample
+    pub synced: bool,
+    pub leader: Option<String>,
+    pub estimate: Option<Estimate>,
+    pub now_ms: u32,
+}
+
+/// The board's animation time: its own time since boot, or the leader's
+/// once a follower has synced. Cheap to clone.
+#[derive(Clone)]
+pub struct Clock {
+    start: Instant,
+    settings: SyncSettings,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Clock {
+    pub fn new(start: Instant, settings: SyncSettings) -> Self {
+        Self {
+            start,
+            settings,
+            shared: Default::default(),
+        }
+    }
+
+    fn local_us(&self, at: Instant) -> u64 {
+        at.saturating_duration_since(self.start).as_micros() as u64
+    }
+
+    /// Shared time in microseconds at `at`.
+    pub fn time_us(&self, at: Instant) -> u64 {
+        let local = self.local_us(at);
+        let mut shared = self.shared.lock().unwrap();
+        let time = match self.settings.role {
+            Role::Follower => shared.estimator.leader_time(local).unwrap_or(local),
+            Role::Leader => local,
+        };
+        // small corrections mustn't run the animations backwards, a new
+        // leader that booted after us may
+        if time >= shared.last || shared.last - time > MAX_HOLD_US {
+            shared.last = time;
+        }
+        shared.last
+    }
+
+    /// Shared time in milliseconds, what `Segment::color_at` takes.
+    pub fn now_ms(&self) -> u32 {
+        (self.time_us(Instant::now()) / 1000) as u32
+    }
+
+    pub fn report(&self) -> Report {
+        let now = Instant::now();
+        let now_ms = (self.time_us(now) / 1000) as u32;
+        let local = self.local_us(now);
+        let shared = self.shared.lock().unwrap();
+        let fresh = shared
+            .heard
+            .is_some_and(|heard| local.saturating_sub(heard) < STALE.as_micros() as u64);
+        Report {
+            enabled: self.settings.enabled,
+            role: self.settings.role,
+            group: self.settings.group,
+            synced: self.settings.enabled && self.settings.role == Role::Follower && fresh,
+            leader: shared.leader.map(|(addr, _)| addr.ip().to_string()),
+            estimate: shared.estimator.estimate(local),
+            now_ms,
+        }
+    }
+
+    /// Takes a packet from `from`, received at `at`, and returns the reply
+    /// to send back to it, if any.
+    pub fn handle(
+        &self,
+        packet: &Packet,
+        from: SocketAddr,
+        at: Instant,
+        boot_id: u32,
+    ) -> Option<Packet> {
+        if packet.group != self.settings.group || packet.boot_id == boot_id {
+            return None;
+        }
+        let local = self.local_us(at);
+        let reply = |message| Packet {
+            group: self.settings.group,
+            boot_id,
+            message,
+        };
+        match (self.settings.role, packet.message) {
+            (Role::Leader, Message::Request { t1 }) => {
+                let t3 = self.local_us(Instant::now());
+                Some(reply(Message::Response { t1, t2: local, t3 }))
+            }
+            (Role::Follower, Message::Announce { .. }) => {
+                let mut shared = self.shared.lock().unwrap();
+                let leader = Some((from, packet.boot_id));
+                if shared.leader != leader {
+                    // a new leader, or the old one rebooted
+                    log::info!("sync: following {from}");
+                    shared.leader = leader;
+                    shared.estimator.reset();
+                }
+                let t1 = self.local_us(Instant::now());
+                Some(reply(Message::Request { t1 }))
+            }
+            (Role::Follower, Message::Response { t1, t2, t3 }) => {
+                let mut shared = self.shared.lock().unwrap();
+                if shared.leader != Some((from, packet.boot_id)) || t1 > local {
+                    return None;
+                }
+                shared.estimator.add(Sample::new(t1, t2, t3, local));
+                shared.heard = Some(local);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Runs the protocol on its own thread. `boot_id` tells our own broadcasts
+/// apart and lets followers notice a leader reboot.
+pub fn serve(clock: Clock, boot_id: u32) -> anyhow::Result<()> {
+    let settings = clock.settings.clone();
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, settings.port))?;
+    socket.set_broadcast(true)?;
+    socket.set_read_timeout(Some(settings.interval() / 4))?;
+    let broadcast = SocketAddr::from((Ipv4Addr::BROADCAST, settings.port));
+
+    thread::Builder::new().stack_size(6144).spawn(move || {
+        let mut buf = [0u8; MAX_PACKET];
+        let mut announced: Option<Instant> = None;
+        loop {
+            // followers ask on every announcement, so this paces both sides
+            let due = match announced {
+                Some(at) => at.elapsed() >= settings.interval(),
+                None => true,
+            };
+            if settings.role == Role::Leader && due {
+                announced = Some(Instant::now());
+                let announce = Packet {
+                    group: settings.group,
+                    boot_id,
+                    message: Message::Announce {
+                        time: clock.time_us(Instant::now()),
+                    },
+                };
+                if let Err(e) = socket.send_to(&announce.encode(), broadcast) {
+                    log::warn!("sync: {e}");
+                }
+            }
+
+            let (n, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            let at = Instant::now();
+            let packet = match parse(&buf[..n]) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    log::debug!("sync: {e}");
+                    continue;
+                }
+            };
+            if let Some(reply) = clock.handle(&packet, from, at, boot_id) {
+                if let Err(e) = socket.send_to(&reply.encode(), from) {
+                    log::warn!("sync: {e}");
+                }
+            }
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A leader whose clock is `offset_us` ahead of ours and gains `drift`
+    /// seconds per second, reached over a jittery network.
+    struct Network {
+        offset_us: f64,
+        drift: f64,
+        /// xorshift state, for repeatable jitter
+        seed: u64,
+    }
+
+    impl Network {
+        fn new(offset_us: f64, drift: f64) -> Self {
+            Self {
+                offset_us,
+                drift,
+                seed: 0x2545_f491_4f6c_dd1d,
+            }
+        }
+
+        fn leader(&self, local: u64) -> u64 {
+            (local as f64 + self.offset_us + self.drift * local as f64) as u64
+        }
+
+        /// 0..max microseconds
+        fn jitter(&mut self, max: u64) -> u64 {
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 7;
+            self.seed ^= self.seed << 17;
+            self.seed % max
+        }
+
+        /// A round trip sent at local `t1` taking `out` and `back` us.
+        fn round_trip(&self, t1: u64, out: u64, back: u64) -> Sample {
+            let t2 = self.leader(t1 + out);
+            let t3 = self.leader(t1 + out + 100);
+            Sample::new(t1, t2, t3, t1 + out + 100 + back)
+        }
+
+        /// `count` round trips `every` us apart with 1..3 ms each way, every
+        /// `outlier`th one held up for 80 ms on the way out.
+        fn samples(&mut self, count: u64, every: u64, outlier: u64) -> Vec<Sample> {
+            (1..=count)
+                .map(|i| {
+                    let out = 1000 + self.jitter(2000);
+                    let back = 1000 + self.jitter(2000);
+                    let out = if i % outlier == 0 { out + 80_000 } else { out };
+                    self.round_trip(i * every, out, back)
+                })
+                .collect()
+        }
+    }
+
+    fn estimator(samples: &[Sample]) -> Estimator {
+        let mut estimator = Estimator::default();
+        for sample in samples {
+            estimator.add(*sample);
+        }
+        estimator
+    }
+
+    #[test]
+    fn samples_split_the_round_trip() {
+        // 5 ms each way, the leader 1 s ahead and 200 us to answer
+        let sample = Sample::new(10_000, 1_015_000, 1_015_200, 20_200);
+        let expected = Sample {
+            local: 15_100,
+            offset: 1_000_000,
+            delay: 10_000,
+        };
+        assert_eq!(sample, expected);
+        // a slow way out shows up as half of it in the offset
+        let sample = Sample::new(10_000, 1_025_000, 1_025_200, 30_200);
+        assert_eq!((sample.offset, sample.delay), (1_005_000, 20_000));
+        // leaders behind us, and a turnaround longer than the round trip
+        assert_eq!(
+            Sample::new(5_000_000, 1_000, 1_100, 5_000_100).offset,
+            -4_999_000
+        );
+        assert_eq!(Sample::new(0, 0, 500, 100).delay, 0);
+    }
+
+    #[test]
+    fn finds_the_offset_through_jitter() {
+        // a leader 300 ms behind us
+        let mut network = Network::new(-300_000.0, 0.0);
+        let estimator = estimator(&network.samples(WINDOW as u64, 1_000_000, 1000));
+        let local = WINDOW as u64 * 1_000_000;
+        let estimate = estimator.estimate(local).unwrap();
+        assert!((estimate.offset_ms + 300.0).abs() < 1.0, "{estimate:?}");
+        assert!(estimate.drift_ppm.abs() < 30.0, "{estimate:?}");
+        // the fastest round trip, 2..6 ms
+        assert!((2.0..2.5).contains(&estimate.delay_ms), "{estimate:?}");
+        assert_eq!(estimate.samples, WINDOW);
+        let leader = estimator.leader_time(local).unwrap();
+        assert!(leader.abs_diff(network.leader(local)) < 1000);
+    }
+
+    #[test]
+    fn leaves_slow_round_trips_out() {
+        // every third round trip is 80 ms late, which would pull the offset
+        // by 40 ms each
+        let mut network = Network::new(2_000_000.0, 0.0);
+        let samples = network.samples(30, 1_000_000, 3);
+        let mean = samples.iter().map(|s| s.offset as f64).sum::<f64>() / 30.0;
+        assert!(mean - 2_000_000.0 > 10_000.0);
+
+        let estimate = estimator(&samples).estimate(30_000_000).unwrap();
+        assert!((estimate.offset_ms - 2000.0).abs() < 1.0, "{estimate:?}");
+        assert!(estimate.drift_ppm.abs() < 30.0, "{estimate:?}");
+    }
+
+    #[test]
+    fn fits_the_drift() {
+        // a crystal 50 ppm fast, over 31 s of samples
+        let mut network = Network::new(1_000_000.0, 50e-6);
+        let samples = network.samples(WINDOW as u64, 1_000_000, 4);
+        let estimator = estimator(&samples);
+        let estimate = estimator.estimate(WINDOW as u64 * 1_000_000).unwrap();
+        assert!((estimate.drift_ppm - 50.0).abs() < 10.0, "{estimate:?}");
+
+        // and keeps following it ten seconds past the last sample
+        let later = (WINDOW as u64 + 10) * 1_000_000;
+        let leader = estimator.leader_time(later).unwrap();
+        assert!(leader.abs_diff(network.leader(later)) < 1000);
+    }
+
+    #[test]
+    fn clamps_the_drift() {
+        for drift in [2000e-6, -2000e-6] {
+            let mut network = Network::new(0.0, drift);
+            let samples = network.samples(20, 1_000_000, 1000);
+            let estimate = estimator(&samples).estimate(20_000_000).unwrap();
+            assert_eq!(estimate.drift_ppm, drift.signum() * MAX_DRIFT * 1e6);
+        }
+    }
+
+    #[test]
+    fn no_drift_from_a_short_span() {
+        // 50 ppm, but only 3 s of samples
+        let mut network = Network::new(1_000_000.0, 50e-6);
+        let estimator = estimator(&network.samples(30, 100_000, 1000));
+        assert_eq!(estimator.estimate(3_000_000).unwrap().drift_ppm, 0.0);
+
+        // and nothing at all without samples
+        let mut estimator = estimator;
+        estimator.reset();
+        assert_eq!(estimator.estimate(0), None);
+        assert_eq!(estimator.leader_time(0), None);
+    }
+
+    #[test]
+    fn keeps_a_window_of_samples() {
+        // a leader that jumped: the old samples age out
+        let mut network = Network::new(0.0, 0.0);
+        let mut estimator = estimator(&network.samples(WINDOW as u64, 1_000_000, 1000));
+        network.offset_us = 10_000_000.0;
+        let later: Vec<_> = network
+            .samples(2 * WINDOW as u64, 1_000_000, 1000)
+            .into_iter()
+            .skip(WINDOW)
+            .collect();
+        for sample in later {
+            estimator.add(sample);
+        }
+        let estimate = estimator.estimate(2 * WINDOW as u64 * 1_000_000).unwrap();
+        assert_eq!(estimate.samples, WINDOW);
+        assert!((estimate.offset_ms - 10_000.0).abs() < 1.0, "{estimate:?}");
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let messages = [
+            Message::Announce { time: 1 },
+            Message::Request { t1: u64::MAX },
+            Message::Response {
+                t1: 1,
+                t2: 2,
+                t3: 3,
+            },
+        ];
+        for message in messages {
+            let packet = Packet {
+                group: 7,
+                boot_id: 0xdead_beef,
+                message,
+            };
+            let buf = packet.encode();
+            assert!(buf.len() <= MAX_PACKET);
+            assert_eq!(parse(&buf), Ok(packet));
+        }
+        let mut buf = Packet {
+            group: 0,
+            boot_id: 0,
+            message: Message::Response {
+                t1: 1,
+                t2: 2,
+                t3: 3,
+            },
+        }
+        .encode();
+        assert_eq!(
+            parse(&buf[..HEADER_LEN + 16]),
+            Err(ParseError::TooShort(28))
+        );
+        buf[5] = 9;
+        assert_eq!(parse(&buf), Err(ParseError::UnsupportedKind(9)));
+        buf[4] = 2;
+        assert_eq!(parse(&buf), Err(ParseError::UnsupportedVersion(2)));
+        assert_eq!(parse(b"HSYX\x01\x01\0\0\0\0\0\0"), Err(ParseError::NotSync));
+    }
+
+    #[test]
+    fn followers_answer_their_leader_only() {
+        let start = Instant::now();
+        let settings = |role| SyncSettings {
+            enabled: true,
+            role,
+            group: 3,
+            ..Default::default()
+        };
+        let leader = Clock::new(start, settings(Role::Leader));
+        let follower = Clock::new(start, settings(Role::Follower));
+        let (leader_addr, follower_addr) = (
+            "10.0.0.1:48720".parse().unwrap(),
+            "10.0.0.2:48720".parse().unwrap(),
+        );
+        let announce = Packet {
+            group: 3,
+            boot_id: 1,
+            message: Message::Announce { time: 0 },
+        };
+        let at = start + Duration::from_millis(5);
+
+        // other groups and our own broadcasts are ignored
+        let other = Packet {
+            group: 4,
+            ..announce
+        };
+        assert_eq!(follower.handle(&other, leader_addr, at, 2), None);
+        assert_eq!(follower.handle(&announce, leader_addr, at, 1), None);
+
+        let request = follower.handle(&announce, leader_addr, at, 2).unwrap();
+        assert!(matches!(request.message, Message::Request { .. }));
+        let response = leader.handle(&request, follower_addr, at, 1).unwrap();
+        assert!(matches!(response.message, Message::Response { .. }));
+        // a response from someone else doesn't count
+        assert_eq!(
+            follower.handle(&response, follower_addr, Instant::now(), 2),
+            None
+        );
+        assert_eq!(follower.report().estimate, None);
+        assert_eq!(
+            follower.handle(&response, leader_addr, Instant::now(), 2),
+            None
+        );
+        let report = follower.report();
+        assert!(report.synced);
+        assert_eq!(report.leader.as_deref(), Some("10.0.0.1"));
+        assert_eq!(report.estimate.unwrap().samples, 1);
+
+        // the leader rebooted: start over
+        let rebooted = Packet {
+            boot_id: 9,
+            ..announce
+        };
+        assert!(follower.handle(&rebooted, leader_addr, at, 2).is_some());
+        assert_eq!(follower.report().estimate, None);
+    }
+}
@@ -0,0 +1,416 @@
+//! Plain std HTTP/1.1 server for a [`Router`], so the API can be exercised
+//! on a host without a board. One request per connection, no keep-alive.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+};
+
+use crate::{
+    api::ApiError,
+    router::{Method, Request, Response, Router},
+};
+
+pub fn serve(listener: TcpListener, router: Arc<Router>) -> anyhow::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let router = router.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, &router) {
+                log::warn!("test server: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+pub fn handle(mut stream: TcpStream, router: &Router) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut request_line = line.split_whitespace();
+    let method = request_line.next().and_then(Method::parse);
+    let uri = request_line.next().unwrap_or("/").to_string();
+
+    let mut headers = vec![];
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    let content_len = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|(_, value)| value.parse().ok());
+
+    let res = match method {
+        Some(method) => {
+            let mut body = reader.take(content_len.unwrap_or_default() as u64);
+            let mut req = Request::new(method, &uri, content_len, &mut body);
+            for (name, value) in headers {
+                req = req.with_header(name, value);
+            }
+            let res = router.handle(&mut req);
+            // closing with unread input would reset the connection under the
+            // response, e.g. after a 413
+            io::copy(&mut body, &mut io::sink())?;
+            res
+        }
+        None => ApiError::method_not_allowed().into(),
+    };
+    write_response(&mut stream, &res)
+}
+
+pub fn write_response(out: &mut impl Write, res: &Response) -> anyhow::Result<()> {
+    write!(out, "HTTP/1.1 {} \r\n", res.status)?;
+    for (name, value) in &res.headers {
+        write!(out, "{name}: {value}\r\n")?;
+    }
+    write!(
+        out,
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        res.body.len()
+    )?;
+    out.write_all(&res.body)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        convert::Infallible,
+        net::SocketAddr,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use embedded_svc::{
+        errors::Errors,
+        storage::{RawStorage, StorageBase},
+    };
+
+    use super::*;
+    use crate::{
+        auth::{self, Auth},
+        metrics::Metrics,
+        realtime,
+        routes::{self, SEGMENTS_FILE},
+        segments::fixture,
+        settings::Settings,
+        sync, wled,
+    };
+
+    /// NVS stand-in
+    #[derive(Default)]
+    struct Memory(HashMap<String, Vec<u8>>);
+
+    impl Errors for Memory {
+        type Error = Infallible;
+    }
+
+    impl StorageBase for Memory {
+        fn contains(&self, name: &str) -> Result<bool, Infallible> {
+            Ok(self.0.contains_key(name))
+        }
+
+        fn remove(&mut self, name: &str) -> Result<bool, Infallible> {
+            Ok(self.0.remove(name).is_some())
+        }
+    }
+
+    impl RawStorage for Memory {
+        fn len(&self, name: &str) -> Result<Option<usize>, Infallible> {
+            Ok(self.0.get(name).map(Vec::len))
+        }
+
+        fn get_raw<'a>(
+            &self,
+            name: &str,
+            buf: &'a mut [u8],
+        ) -> Result<Option<(&'a [u8], usize)>, Infallible> {
+            Ok(self.0.get(name).map(|data| {
+                buf[..data.len()].copy_from_slice(data);
+                (&buf[..data.len()], data.len())
+            }))
+        }
+
+        fn put_raw(&mut self, name: &str, buf: &[u8]) -> Result<bool, Infallible> {
+            self.0.insert(name.to_string(), buf.to_vec());
+            Ok(true)
+        }
+    }
+
+    struct Board {
+        addr: SocketAddr,
+        storage: Arc<Mutex<Memory>>,
+        metrics: Arc<Metrics>,
+        realtime: Arc<Mutex<realtime::Arbiter>>,
+        id: String,
+    }
+
+    /// The API as `main` mounts it, minus the device routes.
+    fn board() -> Board {
+        let seg = fixture::segment(10);
+        let id = seg.to_uuid_string();
+        let map = fixture::map(&[seg], 60);
+        let settings = Settings::default();
+        let storage = Arc::new(Mutex::new(Memory::default()));
+        let auth = Arc::new(Mutex::new(Auth::default()));
+        let metrics = Arc::new(Metrics::default());
+        let realtime = Arc::new(Mutex::new(realtime::Arbiter::new(&settings.realtime, 60)));
+
+        let mut router = Router::default();
+        router
+            .layer(metrics.clone())
+            .layer(auth::Guard(auth.clone()))
+            .layer(settings.cors.clone());
+        routes::Api {
+            segments: Arc::new(Mutex::new(map)),
+            storage: storage.clone(),
+            realtime: realtime.clone(),
+            clock: sync::Clock::new(Instant::now(), settings.sync.clone()),
+            settings: Arc::new(Mutex::new(settings)),
+            auth,
+            random: |buf| buf.fill(7),
+            master: Default::default(),
+            wled_info: Arc::new(|| wled::Info::new("test".to_string(), 60, 1)),
+        }
+        .mount(&mut router);
+        metrics.register(&router.routes());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Arc::new(router);
+        thread::spawn(move || serve(listener, router));
+        Board {
+            addr,
+            storage,
+            metrics,
+            realtime,
+            id,
+        }
+    }
+
+    struct Reply {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Reply {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+
+        fn json(&self) -> serde_json::Value {
+            serde_json::from_str(&self.body).unwrap()
+        }
+    }
+
+    fn request(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> Reply {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nContent-Length: {}\r\n",
+            body.len()
+        )
+        .unwrap();
+        for (name, value) in headers {
+            write!(stream, "{name}: {value}\r\n").unwrap();
+        }
+        write!(stream, "\r\n{body}").unwrap();
+
+        let mut raw = String::new();
+        stream.read_to_string(&mut raw).unwrap();
+        let (head, body) = raw.split_once("\r\n\r\n").unwrap();
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .unwrap()
+            .split(' ')
+            .nth(1)
+            .unwrap()
+            .parse()
+            .unwrap();
+        let headers = lines
+            .filter_map(|line| line.split_once(": "))
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect();
+        Reply {
+            status,
+            headers,
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn routes_by_path_and_method() {
+        let board = board();
+        let get = |path: &str| request(board.addr, "GET", path, &[], "");
+
+        assert_eq!(get("/nowhere").status, 404);
+        assert_eq!(get("/data?pretty").status, 200);
+
+        let res = request(board.addr, "DELETE", "/data", &[], "");
+        assert_eq!(res.status, 405);
+        assert_eq!(res.header("Allow"), Some("GET, POST, OPTIONS"));
+        assert_eq!(request(board.addr, "OPTIONS", "/data", &[], "").status, 204);
+        assert_eq!(request(board.addr, "BREW", "/data", &[], "").status, 405);
+
+        // `/segments/*` hands the rest of the path to the handler
+        let res = get(&format!("/segments/{}", board.id));
+        assert_eq!((res.status, res.json()["length"].as_u64()), (200, Some(10)));
+        assert_eq!(get("/segments/nope").status, 404);
+
+        let out = board.metrics.render();
+        assert!(out
+            .contains(r#"harlot_http_requests_total{route="/data",method="GET",status="200"} 1"#));
+        assert!(out.contains(
+            r#"harlot_http_requests_total{route="/segments/*",method="GET",status="404"} 1"#
+        ));
+        assert!(!out.contains("/nowhere"));
+    }
+
+    #[test]
+    fn writes_segments_and_persists_them() {
+        let board = board();
+        let res = request(board.addr, "GET", "/data", &[], "");
+        let etag = res.header("ETag").unwrap().to_string();
+        let mut data = res.json();
+        data[&board.id]["length"] = 20.into();
+        let data = data.to_string();
+
+        let stale = request(
+            board.addr,
+            "POST",
+            "/data",
+            &[("If-Match", "\"0000cafe-9\"")],
+            &data,
+        );
+        assert_eq!(stale.status, 412);
+        assert!(!board.storage.lock().unwrap().0.contains_key(SEGMENTS_FILE));
+
+        let res = request(board.addr, "POST", "/data", &[("If-Match", &etag)], &data);
+        assert_eq!(res.status, 200);
+        let new_etag = res.header("ETag").unwrap().to_string();
+        assert_ne!(new_etag, etag);
+        let stored: serde_json::Value =
+            serde_json::from_slice(&board.storage.lock().unwrap().0[SEGMENTS_FILE]).unwrap();
+        assert_eq!(stored[&board.id]["length"], 20);
+
+        let res = request(
+            board.addr,
+            "GET",
+            "/data",
+            &[("If-None-Match", &new_etag)],
+            "",
+        );
+        assert_eq!(res.status, 304);
+
+        let res = request(
+            board.addr,
+            "POST",
+            "/data",
+            &[],
+            "{\"x\": {\"length\": 1000}}",
+        );
+        assert_eq!(res.status, 400);
+        assert_eq!(res.json()["path"], "/x/length");
+
+        let huge = format!("[{}0]", "0,".repeat(8 * 1024));
+        assert_eq!(request(board.addr, "POST", "/data", &[], &huge).status, 413);
+    }
+
+    #[test]
+    fn pushed_pixels_go_through_the_arbiter() {
+        let board = board();
+        let pixels = r#"{"offset": 58, "pixels": [[1, 2, 3], [4, 5, 6]]}"#;
+        let res = request(board.addr, "POST", "/realtime/pixels", &[], pixels);
+        assert_eq!(res.status, 204);
+
+        let res = request(board.addr, "GET", "/realtime", &[], "");
+        let http = &res.json()["sources"][0];
+        assert_eq!(
+            (http["name"].as_str(), http["live"].as_bool()),
+            (Some("http"), Some(true))
+        );
+        let realtime::Output::Live { source, pixels } =
+            board.realtime.lock().unwrap().step(Instant::now())
+        else {
+            panic!("not live");
+        };
+        assert_eq!(source, "http");
+        assert_eq!(pixels[58..], [[1, 2, 3], [4, 5, 6]]);
+
+        let past = r#"{"offset": 59, "pixels": [[1, 2, 3], [4, 5, 6]]}"#;
+        let res = request(board.addr, "POST", "/realtime/pixels", &[], past);
+        assert_eq!(res.status, 400);
+        let res = request(
+            board.addr,
+            "POST",
+            "/realtime/pixels",
+            &[],
+            r#"{"pixels": [1]}"#,
+        );
+        assert_eq!(res.status, 400);
+    }
+
+    #[test]
+    fn guards_routes_once_a_password_is_set() {
+        let board = board();
+        let password = r#"{"password": "correct horse"}"#;
+        assert_eq!(
+            request(board.addr, "POST", "/auth/password", &[], password).status,
+            200
+        );
+
+        let res = request(board.addr, "GET", "/data", &[], "");
+        assert_eq!(res.status, 401);
+        assert_eq!(res.header("WWW-Authenticate"), Some("Bearer"));
+
+        let wrong = r#"{"password": "wrong horse"}"#;
+        assert_eq!(
+            request(board.addr, "POST", "/auth/login", &[], wrong).status,
+            401
+        );
+        let login = r#"{"password": "correct horse", "role": "read_only"}"#;
+        let res = request(board.addr, "POST", "/auth/login", &[], login);
+        assert_eq!(res.status, 200);
+        let bearer = format!("Bearer {}", res.json()["token"].as_str().unwrap());
+
+        let token = [("Authorization", bearer.as_str())];
+        assert_eq!(request(board.addr, "GET", "/data", &token, "").status, 200);
+        assert_eq!(
+            request(board.addr, "POST", "/data", &token, "{}").status,
+            403
+        );
+
+        assert_eq!(
+            request(board.addr, "POST", "/auth/logout", &token, "").status,
+            200
+        );
+        assert_eq!(request(board.addr, "GET", "/data", &token, "").status, 401);
+    }
+}
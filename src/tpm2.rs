@@ -0,0 +1,324 @@
+//! TPM2.net receiver, as sent by Hyperion and friends.
+//!
+//! A frame may be split over several packets, numbered from 1; it goes on
+//! the strip once its last packet arrives.
+
+use std::{
+    fmt,
+    net::{Ipv4Addr, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::realtime::{Filter, Frame, Shaping};
+
+pub const PORT: u16 = 65506;
+/// what fits an Ethernet frame
+pub const MAX_PACKET: usize = 1500;
+const HEADER_LEN: usize = 6;
+const START: u8 = 0x9c;
+const END: u8 = 0x36;
+const TYPE_DATA: u8 = 0xda;
+const TYPE_COMMAND: u8 = 0xc0;
+const TYPE_RESPONSE: u8 = 0xaa;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Tpm2Settings {
+    pub enabled: bool,
+    /// LED the frame's first pixel lands on
+    pub led_offset: usize,
+    pub led_count: usize,
+    #[serde(flatten)]
+    pub shaping: Shaping,
+}
+
+impl Default for Tpm2Settings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            led_offset: 0,
+            led_count: 512,
+            shaping: Shaping::default(),
+        }
+    }
+}
+
+impl Tpm2Settings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(1..=4096).contains(&self.led_count) {
+            anyhow::bail!("tpm2 led_count must be 1..=4096");
+        }
+        self.shaping.validate("tpm2")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    TooShort(usize),
+    NotTpm2,
+    Malformed(&'static str),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::TooShort(len) => write!(f, "packet too short: {len} bytes"),
+            ParseError::NotTpm2 => write!(f, "not a TPM2.net packet"),
+            ParseError::Malformed(what) => write!(f, "malformed packet: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet<'a> {
+    Data {
+        /// 1 based
+        number: u8,
+        total: u8,
+        data: &'a [u8],
+    },
+    /// commands and responses, which we don't act on
+    Other(u8),
+}
+
+pub fn parse(buf: &[u8]) -> Result<Packet<'_>, ParseError> {
+    if buf.len() < HEADER_LEN + 1 {
+        return Err(ParseError::TooShort(buf.len()));
+    }
+    if buf[0] != START {
+        return Err(ParseError::NotTpm2);
+    }
+    let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    let data = buf
+        .get(HEADER_LEN..HEADER_LEN + len)
+        .ok_or(ParseError::TooShort(buf.len()))?;
+    if buf.get(HEADER_LEN + len) != Some(&END) {
+        return Err(ParseError::Malformed("end byte"));
+    }
+    match buf[1] {
+        TYPE_DATA => Ok(Packet::Data {
+            number: buf[4],
+            total: buf[5],
+            data,
+        }),
+        kind @ (TYPE_COMMAND | TYPE_RESPONSE) => Ok(Packet::Other(kind)),
+        _ => Err(ParseError::Malformed("packet type")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Handled {
+    /// kept until the frame's last packet
+    Buffered,
+    /// a whole frame went on the strip
+    Shown,
+    Ignored,
+}
+
+/// The frame being assembled.
+pub struct Receiver {
+    settings: Tpm2Settings,
+    pixels: Vec<[u8; 3]>,
+    filter: Filter,
+    /// the packet we expect next, and the LED it starts at
+    next: (u8, usize),
+}
+
+impl Receiver {
+    pub fn new(settings: Tpm2Settings) -> Self {
+        Self {
+            pixels: vec![[0; 3]; settings.led_count],
+            filter: Filter::new(&settings.shaping),
+            settings,
+            next: (1, 0),
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        buf: &[u8],
+        now: Instant,
+        frame: &mut Frame,
+    ) -> Result<Handled, ParseError> {
+        let Packet::Data {
+            number,
+            total,
+            data,
+        } = parse(buf)?
+        else {
+            return Ok(Handled::Ignored);
+        };
+
+        // packets follow each other, but after a lost one all we can do is
+        // assume they're the same size
+        let number = number.max(1);
+        let leds = data.len() / 3;
+        let start = match self.next {
+            (next, start) if next == number => start,
+            _ => (number as usize - 1) * leds,
+        };
+        for (i, rgb) in data.chunks_exact(3).enumerate() {
+            if let Some(pixel) = self.pixels.get_mut(start + i) {
+                *pixel = [rgb[0], rgb[1], rgb[2]];
+            }
+        }
+        if number < total {
+            self.next = (number + 1, start + leds);
+            return Ok(Handled::Buffered);
+        }
+
+        self.next = (1, 0);
+        let mut pixels = self.pixels.clone();
+        self.filter.apply(&mut pixels, now);
+        for (i, rgb) in pixels.into_iter().enumerate() {
+            frame.set(self.settings.led_offset + i, rgb);
+        }
+        frame.commit("tpm2", now);
+        Ok(Handled::Shown)
+    }
+}
+
+/// Listens on [`PORT`] on its own thread.
+pub fn serve(settings: Tpm2Settings, frame: Arc<Mutex<Frame>>) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT))?;
+    let mut receiver = Receiver::new(settings);
+
+    thread::Builder::new().stack_size(6144).spawn(move || {
+        let mut buf = [0u8; MAX_PACKET];
+        loop {
+            let n = match socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e) => {
+                    log::warn!("tpm2: {e}");
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+            let res = receiver.handle(&buf[..n], Instant::now(), &mut frame.lock().unwrap());
+            if let Err(e) = res {
+                log::debug!("tpm2: {e}");
+            }
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(kind: u8, number: u8, total: u8, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![START, kind];
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        out.extend_from_slice(&[number, total]);
+        out.extend_from_slice(data);
+        out.push(END);
+        out
+    }
+
+    fn receiver(led_count: usize) -> Receiver {
+        Receiver::new(Tpm2Settings {
+            enabled: true,
+            led_offset: 1,
+            led_count,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn parses_packets() {
+        let data = packet(TYPE_DATA, 2, 3, &[1, 2, 3]);
+        let expected = Packet::Data {
+            number: 2,
+            total: 3,
+            data: &[1, 2, 3],
+        };
+        assert_eq!(parse(&data), Ok(expected));
+        // trailing bytes after the end byte are ignored
+        let mut padded = packet(TYPE_DATA, 1, 1, &[]);
+        padded.push(0);
+        assert!(matches!(parse(&padded), Ok(Packet::Data { data: [], .. })));
+        let command = packet(TYPE_COMMAND, 0, 0, &[0x0a]);
+        assert_eq!(parse(&command), Ok(Packet::Other(TYPE_COMMAND)));
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        assert_eq!(parse(&[START, TYPE_DATA]), Err(ParseError::TooShort(2)));
+        assert_eq!(parse(&[0; 7]), Err(ParseError::NotTpm2));
+        let mut short = packet(TYPE_DATA, 1, 1, &[1, 2, 3]);
+        short.truncate(8);
+        assert_eq!(parse(&short), Err(ParseError::TooShort(8)));
+        let mut no_end = packet(TYPE_DATA, 1, 1, &[1, 2, 3]);
+        *no_end.last_mut().unwrap() = 0;
+        assert_eq!(parse(&no_end), Err(ParseError::Malformed("end byte")));
+        assert_eq!(
+            parse(&packet(0x01, 1, 1, &[])),
+            Err(ParseError::Malformed("packet type"))
+        );
+    }
+
+    #[test]
+    fn frames_go_out_with_their_last_packet() {
+        let now = Instant::now();
+        let mut frame = Frame::new(6);
+        let mut receiver = receiver(4);
+
+        let first = packet(TYPE_DATA, 1, 2, &[1, 1, 1, 2, 2, 2]);
+        let res = receiver.handle(&first, now, &mut frame);
+        assert_eq!(res, Ok(Handled::Buffered));
+        assert_eq!(frame.updated(), None);
+        let last = packet(TYPE_DATA, 2, 2, &[3, 3, 3, 4, 4, 4, 5, 5, 5]);
+        assert_eq!(receiver.handle(&last, now, &mut frame), Ok(Handled::Shown));
+        // past `led_count` is dropped
+        assert_eq!(
+            frame.pixels(),
+            [[0; 3], [1; 3], [2; 3], [3; 3], [4; 3], [0; 3]]
+        );
+        assert_eq!(frame.live_source(now, Duration::from_secs(1)), Some("tpm2"));
+
+        // a single packet frame, number 0 taken as 1
+        let whole = packet(TYPE_DATA, 0, 1, &[7; 3]);
+        assert_eq!(receiver.handle(&whole, now, &mut frame), Ok(Handled::Shown));
+        assert_eq!(frame.pixels()[1], [7; 3]);
+    }
+
+    #[test]
+    fn resyncs_after_lost_and_garbage_packets() {
+        let now = Instant::now();
+        let mut frame = Frame::new(4);
+        let mut receiver = receiver(3);
+
+        // packet 1 of 3 is lost: packet 2 lands where a same size one would
+        let second = packet(TYPE_DATA, 2, 3, &[2; 3]);
+        assert_eq!(
+            receiver.handle(&second, now, &mut frame),
+            Ok(Handled::Buffered)
+        );
+        assert_eq!(
+            receiver.handle(b"garbage", now, &mut frame),
+            Err(ParseError::NotTpm2)
+        );
+        let command = packet(TYPE_COMMAND, 0, 0, &[]);
+        assert_eq!(
+            receiver.handle(&command, now, &mut frame),
+            Ok(Handled::Ignored)
+        );
+        let third = packet(TYPE_DATA, 3, 3, &[3; 3]);
+        assert_eq!(receiver.handle(&third, now, &mut frame), Ok(Handled::Shown));
+        assert_eq!(frame.pixels(), [[0; 3], [0; 3], [2; 3], [3; 3]]);
+
+        // and the next frame starts over from LED 0
+        for (number, rgb) in [(1, [4; 3]), (2, [5; 3]), (3, [6; 3])] {
+            let data = packet(TYPE_DATA, number, 3, &rgb);
+            assert!(receiver.handle(&data, now, &mut frame).is_ok());
+        }
+        assert_eq!(frame.pixels(), [[0; 3], [4; 3], [5; 3], [6; 3]]);
+    }
+}
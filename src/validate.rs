@@ -0,0 +1,263 @@
+//! Validation of segment JSON coming in over the API. Errors carry a JSON
+//! pointer into the request body (`/<uuid>/brightness` for `/data`,
+//! `/brightness` for a single segment) to the offending value.
+
+use std::sync::OnceLock;
+
+use color_mixer::strip::{Segment, Srgb8};
+use indexmap::IndexMap;
+use serde_json::{Map, Value};
+
+use crate::{api::ApiError, segments::Segments};
+
+/// brightness is a percentage, see `apa_spi::Pixel::new`
+pub const MAX_BRIGHTNESS: u8 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// LEDs on the physical strip, no single segment may be longer
+    pub strip_length: usize,
+    /// upper bound for the sum of all segment lengths
+    pub led_budget: usize,
+}
+
+/// `~` and `/` need escaping in JSON pointer tokens
+pub fn pointer(tokens: &[&str]) -> String {
+    tokens
+        .iter()
+        .map(|t| format!("/{}", t.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+/// Field names a segment serializes to; anything else is rejected.
+fn known_fields() -> &'static [String] {
+    static KNOWN: OnceLock<Vec<String>> = OnceLock::new();
+    KNOWN.get_or_init(|| {
+        let black = Srgb8::new(0, 0, 0);
+        let reference = Segment::new(1, false, black, black, 0, 1, 0);
+        match serde_json::to_value(reference) {
+            Ok(Value::Object(fields)) => fields.keys().cloned().collect(),
+            _ => vec![],
+        }
+    })
+}
+
+/// Checks a whole segment map, as posted to `/data`. An [`IndexMap`] rather
+/// than a JSON object, since the order of the segments is their order on
+/// the strip.
+pub fn segment_map(obj: &IndexMap<String, Value>, limits: &Limits) -> Result<Segments, ApiError> {
+    let mut segments = Segments::with_capacity(obj.len());
+    for (id, seg) in obj {
+        segments.insert(id.clone(), segment(&pointer(&[id]), seg, limits)?);
+    }
+    total(&segments, limits)?;
+    Ok(segments)
+}
+
+/// Checks a single segment and turns it into a [`Segment`]. `path` points
+/// at the segment within the request body.
+pub fn segment(path: &str, value: &Value, limits: &Limits) -> Result<Segment, ApiError> {
+    let at = |tokens: &[&str]| format!("{path}{}", pointer(tokens));
+    let fields = value
+        .as_object()
+        .ok_or_else(|| ApiError::invalid(path, "expected a segment object"))?;
+
+    let known = known_fields();
+    if let Some(unknown) = fields.keys().find(|k| !known.contains(*k)) {
+        return Err(ApiError::invalid(at(&[unknown.as_str()]), "unknown field"));
+    }
+    for (name, field) in fields {
+        if let Value::Object(channels) = field {
+            colour(&at(&[name.as_str()]), channels)?;
+        }
+    }
+    // checked on the JSON, so a value too large for its type still points
+    // at the field instead of the whole segment
+    if let Some(length) = fields.get("length") {
+        if !matches!(length.as_u64(), Some(n) if (1..=limits.strip_length as u64).contains(&n)) {
+            return Err(ApiError::invalid(
+                at(&["length"]),
+                format!("length must be 1..={}", limits.strip_length),
+            ));
+        }
+    }
+    if let Some(brightness) = fields.get("brightness") {
+        if !matches!(brightness.as_u64(), Some(b) if b <= MAX_BRIGHTNESS as u64) {
+            return Err(ApiError::invalid(
+                at(&["brightness"]),
+                format!("brightness must be 0..={MAX_BRIGHTNESS}"),
+            ));
+        }
+    }
+
+    serde_json::from_value(value.clone()).map_err(|e| ApiError::invalid(path, e.to_string()))
+}
+
+/// Colours are objects of `red`, `green` and `blue` bytes.
+fn colour(path: &str, channels: &Map<String, Value>) -> Result<(), ApiError> {
+    const CHANNELS: [&str; 3] = ["red", "green", "blue"];
+    let at = |channel: &str| format!("{path}{}", pointer(&[channel]));
+    if let Some(unknown) = channels.keys().find(|k| !CHANNELS.contains(&k.as_str())) {
+        return Err(ApiError::invalid(at(unknown), "unknown colour channel"));
+    }
+    for channel in CHANNELS {
+        let Some(v) = channels.get(channel) else {
+            continue;
+        };
+        if !matches!(v.as_u64(), Some(0..=255)) {
+            return Err(ApiError::invalid(
+                at(channel),
+                "colour channels must be integers 0..=255",
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn total(segments: &Segments, limits: &Limits) -> Result<(), ApiError> {
+    let total: usize = segments.values().map(|s| s.length()).sum();
+    if total > limits.led_budget {
+        return Err(ApiError::invalid(
+            "",
+            format!(
+                "segments cover {total} LEDs, the budget is {}",
+                limits.led_budget
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        strip_length: 50,
+        led_budget: 60,
+    };
+
+    fn value(length: usize) -> Value {
+        let c = Srgb8::new(1, 2, 3);
+        serde_json::to_value(Segment::new(length, false, c, c, 0, 1, 40)).unwrap()
+    }
+
+    /// name of one of the colour fields
+    fn colour_field(seg: &Value) -> String {
+        let fields = seg.as_object().unwrap();
+        fields
+            .keys()
+            .find(|k| fields[*k].get("red").is_some())
+            .unwrap()
+            .clone()
+    }
+
+    fn path_of(value: &Value) -> Option<String> {
+        segment("/seg", value, &LIMITS).err()?.path
+    }
+
+    #[test]
+    fn accepts_what_a_segment_serializes_to() {
+        let json = value(10);
+        let seg = segment("", &json, &LIMITS).unwrap();
+        assert_eq!(seg.length(), 10);
+        assert_eq!(seg.brightness(), 40);
+        assert_eq!(serde_json::to_value(&seg).unwrap(), json);
+    }
+
+    #[test]
+    fn points_at_the_offending_field() {
+        let mut seg = value(10);
+        seg["glitter"] = true.into();
+        assert_eq!(path_of(&seg).as_deref(), Some("/seg/glitter"));
+
+        for (length, ok) in [(0, false), (1, true), (50, true), (51, false), (-1, false)] {
+            let mut seg = value(10);
+            seg["length"] = json!(length);
+            assert_eq!(
+                path_of(&seg),
+                (!ok).then(|| "/seg/length".to_string()),
+                "{length}"
+            );
+        }
+        let mut seg = value(10);
+        seg["length"] = json!(1e30);
+        assert_eq!(path_of(&seg).as_deref(), Some("/seg/length"));
+
+        // too big for a u8 as well, still reported at the field
+        for (brightness, ok) in [(100, true), (101, false), (300, false), (-5, false)] {
+            let mut seg = value(10);
+            seg["brightness"] = json!(brightness);
+            assert_eq!(
+                path_of(&seg),
+                (!ok).then(|| "/seg/brightness".to_string()),
+                "{brightness}"
+            );
+        }
+
+        let mut seg = value(10);
+        seg["brightness"] = json!("bright");
+        assert_eq!(path_of(&seg).as_deref(), Some("/seg/brightness"));
+    }
+
+    #[test]
+    fn checks_colours_channel_by_channel() {
+        let seg = value(10);
+        let name = colour_field(&seg);
+
+        let mut bad = seg.clone();
+        bad[&name]["green"] = json!(256);
+        assert_eq!(path_of(&bad), Some(format!("/seg/{name}/green")));
+
+        let mut bad = seg.clone();
+        bad[&name]["alpha"] = json!(255);
+        assert_eq!(path_of(&bad), Some(format!("/seg/{name}/alpha")));
+
+        let mut bad = seg;
+        bad[&name]["red"] = json!(-1);
+        assert_eq!(path_of(&bad), Some(format!("/seg/{name}/red")));
+    }
+
+    #[test]
+    fn keeps_the_posted_order() {
+        let body = format!(
+            r#"{{"zz": {}, "aa": {}, "mm": {}}}"#,
+            value(10),
+            value(20),
+            value(30)
+        );
+        let obj: IndexMap<String, Value> = serde_json::from_str(&body).unwrap();
+        let segments = segment_map(&obj, &LIMITS).unwrap();
+        let ids: Vec<_> = segments.keys().map(String::as_str).collect();
+        assert_eq!(ids, ["zz", "aa", "mm"]);
+        let lengths: Vec<_> = segments.values().map(Segment::length).collect();
+        assert_eq!(lengths, [10, 20, 30]);
+    }
+
+    #[test]
+    fn paths_carry_the_escaped_id() {
+        let mut seg = value(10);
+        seg["brightness"] = json!(300);
+        let obj: IndexMap<String, Value> = [("a/b~c".to_string(), seg)].into_iter().collect();
+        let e = segment_map(&obj, &LIMITS).err().unwrap();
+        assert_eq!(e.path.as_deref(), Some("/a~1b~0c/brightness"));
+    }
+
+    #[test]
+    fn enforces_the_led_budget() {
+        let obj: IndexMap<String, Value> = [("a", 40), ("b", 20)]
+            .into_iter()
+            .map(|(id, length)| (id.to_string(), value(length)))
+            .collect();
+        assert!(segment_map(&obj, &LIMITS).is_ok());
+
+        let obj: IndexMap<String, Value> = [("a", 40), ("b", 21)]
+            .into_iter()
+            .map(|(id, length)| (id.to_string(), value(length)))
+            .collect();
+        let e = segment_map(&obj, &LIMITS).err().unwrap();
+        assert_eq!((e.status, e.path.as_deref()), (400, Some("")));
+    }
+}
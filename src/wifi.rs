@@ -1,18 +1,22 @@
 // based on https://github.com/ivmarkov/rust-esp32-std-demo/blob/main/src/main.rs
 
-use std::{sync::Arc, time::Duration};
+use std::{net::Ipv4Addr, sync::Arc, time::Duration};
 
 use anyhow::bail;
 use embedded_svc::{wifi::{
-    self, AuthMethod, ClientConfiguration, AccessPointConfiguration, ClientConnectionStatus, ClientIpStatus, ClientStatus,
+    self, AccessPointInfo, AuthMethod, ClientConfiguration, AccessPointConfiguration, ClientConnectionStatus, ClientIpStatus, ClientStatus,
     Wifi as _,
 }, ipv4::{self, DHCPClientSettings}};
 use esp_idf_svc::{
     netif::EspNetifStack, nvs::EspDefaultNvs, sysloop::EspSysLoopStack, wifi::EspWifi,
 };
 use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::settings::StaticIp;
 
 pub fn wifi_ap_only(
+    hostname: &str,
     netif_stack: Arc<EspNetifStack>,
     sys_loop_stack: Arc<EspSysLoopStack>,
     default_nvs: Arc<EspDefaultNvs>,
@@ -20,7 +24,7 @@ pub fn wifi_ap_only(
     let mut auth_method = AuthMethod::WPA2Personal;
     let mut wifi = Box::new(EspWifi::new(netif_stack, sys_loop_stack, default_nvs)?);
     info!("setting Wifi configuration");
-    let hostname = Some(heapless::String::from("harharlot"));
+    let hostname = Some(heapless::String::from(hostname));
     let ip_conf = Some(ipv4::ClientConfiguration::DHCP(DHCPClientSettings{hostname}));
     wifi.set_configuration(&wifi::Configuration::AccessPoint (
         AccessPointConfiguration {
@@ -40,6 +44,8 @@ pub fn wifi_ap_only(
 pub fn wifi(
     ssid: &str,
     psk: &str,
+    hostname: &str,
+    static_ip: Option<&StaticIp>,
     netif_stack: Arc<EspNetifStack>,
     sys_loop_stack: Arc<EspSysLoopStack>,
     default_nvs: Arc<EspDefaultNvs>,
@@ -54,6 +60,23 @@ pub fn wifi(
     }
     let mut wifi = Box::new(EspWifi::new(netif_stack, sys_loop_stack, default_nvs)?);
 
+    connect(&mut wifi, ssid, psk, hostname, static_ip, auth_method)?;
+
+    Ok(wifi)
+}
+
+/// (Re)configure the station side and wait for it to settle. Returns the
+/// assigned address if we ended up connected.
+pub fn connect(
+    wifi: &mut EspWifi,
+    ssid: &str,
+    psk: &str,
+    hostname: &str,
+    static_ip: Option<&StaticIp>,
+    auth_method: AuthMethod,
+) -> anyhow::Result<Option<Ipv4Addr>> {
+    ensure_station(wifi)?;
+
     info!("Searching for Wifi network {}", ssid);
 
     let ap_infos = wifi.scan()?;
@@ -75,14 +98,23 @@ pub fn wifi(
     };
 
     info!("setting Wifi configuration");
-    let hostname = Some(heapless::String::from("harharlot"));
-    let ip_conf = Some(ipv4::ClientConfiguration::DHCP(DHCPClientSettings{hostname}));
+    let ip_conf = match static_ip {
+        Some(static_ip) => {
+            info!("using static address {}", static_ip.address);
+            ipv4::ClientConfiguration::Fixed(static_ip.to_client_settings())
+        }
+        None => {
+            let hostname = Some(heapless::String::from(hostname));
+            ipv4::ClientConfiguration::DHCP(DHCPClientSettings{hostname})
+        }
+    };
+    let ip_conf = Some(ip_conf);
     wifi.set_configuration(&wifi::Configuration::Mixed(
         ClientConfiguration {
             ssid: ssid.into(),
             password: psk.into(),
             channel,
-            // auth_method,
+            auth_method,
             ip_conf,
             ..Default::default()
         },
@@ -90,7 +122,8 @@ pub fn wifi(
             ssid: "verboten".into(),
             channel: channel.unwrap_or(1),
             password: "JAWOLL!!!".into(),
-            auth_method,
+            // `auth_method` is the network's, the setup AP stays protected
+            auth_method: AuthMethod::WPA2Personal,
             ..Default::default()
         },
     ))?;
@@ -104,15 +137,117 @@ pub fn wifi(
     let status = wifi.get_status();
 
     if let wifi::Status(
-        ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(_))),
+        ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(ip_settings))),
         _,
     ) = status
     {
         info!("Wifi connected!");
+        Ok(Some(ip_settings.ip))
     } else {
         // bail!("Unexpected Wifi status: {:?}", status);
         warn!("Unexpected Wifi status: {:?}", status);
+        Ok(None)
     }
+}
 
-    Ok(wifi)
+/// Scanning needs the station interface, so an AP-only setup is switched to
+/// mixed mode (with an unconfigured client) first.
+fn ensure_station(wifi: &mut EspWifi) -> anyhow::Result<()> {
+    if let wifi::Configuration::AccessPoint(ap_conf) = wifi.get_configuration()? {
+        wifi.set_configuration(&wifi::Configuration::Mixed(Default::default(), ap_conf))?;
+    }
+    Ok(())
+}
+
+pub fn scan(wifi: &mut EspWifi) -> anyhow::Result<Vec<Network>> {
+    ensure_station(wifi)?;
+    let ap_infos = wifi.scan()?;
+    Ok(ap_infos.iter().map(Network::from).collect())
+}
+
+pub const CREDENTIALS_FILE: &'static str = "wifi.json";
+
+/// Station credentials stored via `POST /wifi/connect`; these take precedence
+/// over the compile time `cfg.toml` values.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Credentials {
+    pub ssid: String,
+    #[serde(default)]
+    pub psk: String,
+}
+
+impl Credentials {
+    pub fn auth_method(&self) -> AuthMethod {
+        if self.psk.is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::WPA2Personal
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Network {
+    pub ssid: String,
+    pub rssi: i8,
+    pub channel: u8,
+    pub auth_method: String,
+}
+
+impl From<&AccessPointInfo> for Network {
+    fn from(info: &AccessPointInfo) -> Self {
+        Self {
+            ssid: info.ssid.to_string(),
+            rssi: info.signal_strength as i8,
+            channel: info.channel,
+            auth_method: format!("{:?}", info.auth_method),
+        }
+    }
+}
+
+/// State of the last `POST /wifi/connect`, polled by the setup UI.
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ConnectJob {
+    #[default]
+    Idle,
+    Connecting {
+        ssid: String,
+    },
+    Connected {
+        ssid: String,
+        ip: String,
+    },
+    Failed {
+        ssid: String,
+        error: String,
+    },
+}
+
+impl ConnectJob {
+    pub fn is_busy(&self) -> bool {
+        matches!(self, ConnectJob::Connecting { .. })
+    }
+}
+
+/// Address and MAC of the station interface, or of the access point when
+/// the station isn't up.
+pub fn local_address() -> Option<(Ipv4Addr, [u8; 6])> {
+    use esp_idf_sys::*;
+
+    for key in [&b"WIFI_STA_DEF\0"[..], &b"WIFI_AP_DEF\0"[..]] {
+        let netif = unsafe { esp_netif_get_handle_from_ifkey(key.as_ptr() as *const _) };
+        if netif.is_null() {
+            continue;
+        }
+        let mut info = esp_netif_ip_info_t::default();
+        if esp!(unsafe { esp_netif_get_ip_info(netif, &mut info) }).is_err() || info.ip.addr == 0 {
+            continue;
+        }
+        let mut mac = [0u8; 6];
+        esp!(unsafe { esp_netif_get_mac(netif, mac.as_mut_ptr()) }).ok()?;
+        // stored in network order
+        return Some((Ipv4Addr::from(info.ip.addr.to_le_bytes()), mac));
+    }
+    None
 }
@@ -0,0 +1,556 @@
+//! WLED compatible `/json` API, enough for apps and the Home Assistant
+//! integration to switch the board, dim it and set segment colours.
+//!
+//! Works on segments in their JSON form: WLED segments are ours in map
+//! order, laid out back to back from LED 0, their colours being a
+//! segment's colour fields in order. Effects and palettes aren't mapped.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{api::ApiError, validate::MAX_BRIGHTNESS};
+
+/// the WLED release whose API we follow
+pub const VERSION: &str = "0.13.3";
+const VERSION_ID: u32 = 2208222;
+/// WLED reports three colours per segment
+const COLOURS: usize = 3;
+
+/// The master switch and dimmer, on top of the segments' own brightness.
+/// Not persisted, a reboot turns the board back on at full brightness.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Master {
+    pub on: bool,
+    /// 0..=255, like WLED's
+    pub bri: u8,
+}
+
+impl Default for Master {
+    fn default() -> Self {
+        Self { on: true, bri: 255 }
+    }
+}
+
+impl Master {
+    /// A segment's brightness (0..=100) after the switch and dimmer.
+    pub fn scale(&self, brightness: u8) -> u8 {
+        if !self.on {
+            return 0;
+        }
+        ((brightness as u32 * self.bri as u32 + 127) / 255) as u8
+    }
+}
+
+fn to_wled(brightness: u8) -> u8 {
+    ((brightness.min(MAX_BRIGHTNESS) as u32 * 255 + 50) / 100) as u8
+}
+
+fn from_wled(bri: u8) -> u8 {
+    ((bri as u32 * MAX_BRIGHTNESS as u32 + 127) / 255) as u8
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct State {
+    pub on: bool,
+    pub bri: u8,
+    pub transition: u8,
+    /// no presets or playlists
+    pub ps: i16,
+    pub pl: i16,
+    pub mainseg: usize,
+    pub seg: Vec<SegState>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SegState {
+    pub id: usize,
+    pub start: usize,
+    pub stop: usize,
+    pub len: usize,
+    pub on: bool,
+    pub bri: u8,
+    pub col: Vec<[u8; 3]>,
+    pub fx: u8,
+    pub sx: u8,
+    pub ix: u8,
+    pub pal: u8,
+    pub sel: bool,
+    pub rev: bool,
+}
+
+fn length(seg: &Value) -> usize {
+    seg["length"].as_u64().unwrap_or_default() as usize
+}
+
+fn brightness(seg: &Value) -> u8 {
+    seg["brightness"].as_u64().unwrap_or_default().min(255) as u8
+}
+
+/// Names of the colour fields of a serialized segment, in the order the
+/// segment declares them; `serde_json`'s `preserve_order` keeps that.
+fn colour_fields(seg: &Value) -> Vec<String> {
+    match seg {
+        Value::Object(fields) => fields
+            .iter()
+            .filter(|(_, v)| v.get("red").is_some())
+            .map(|(k, _)| k.clone())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn rgb(colour: &Value) -> [u8; 3] {
+    ["red", "green", "blue"].map(|c| colour[c].as_u64().unwrap_or_default().min(255) as u8)
+}
+
+pub fn state(master: &Master, segments: &[Value]) -> State {
+    let mut start = 0;
+    let seg = segments
+        .iter()
+        .enumerate()
+        .map(|(id, seg)| {
+            let len = length(seg);
+            let mut col: Vec<_> = colour_fields(seg)
+                .iter()
+                .map(|field| rgb(&seg[field]))
+                .collect();
+            col.resize(col.len().max(COLOURS), [0; 3]);
+            let state = SegState {
+                id,
+                start,
+                stop: start + len,
+                len,
+                on: brightness(seg) > 0,
+                bri: to_wled(brightness(seg)),
+                col,
+                fx: 0,
+                sx: 128,
+                ix: 128,
+                pal: 0,
+                sel: true,
+                rev: false,
+            };
+            start += len;
+            state
+        })
+        .collect();
+
+    State {
+        on: master.on,
+        bri: master.bri,
+        transition: 0,
+        ps: -1,
+        pl: -1,
+        mainseg: 0,
+        seg,
+    }
+}
+
+/// `true`/`false`, or `"t"` to toggle.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Switch {
+    Set(bool),
+    Toggle(String),
+}
+
+impl Switch {
+    fn apply(&self, path: &str, current: bool) -> Result<bool, ApiError> {
+        match self {
+            Switch::Set(on) => Ok(*on),
+            Switch::Toggle(t) if t == "t" => Ok(!current),
+            Switch::Toggle(_) => Err(ApiError::invalid(path, "expected true, false or \"t\"")),
+        }
+    }
+}
+
+/// `[r, g, b]` (a fourth, white channel is ignored) or `"RRGGBB"`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Colour {
+    Rgb(Vec<u8>),
+    Hex(String),
+}
+
+impl Colour {
+    /// `None` for an empty array, which leaves the colour alone.
+    fn rgb(&self, path: &str) -> Result<Option<[u8; 3]>, ApiError> {
+        match self {
+            Colour::Rgb(c) if c.is_empty() => Ok(None),
+            Colour::Rgb(c) if c.len() >= 3 => Ok(Some([c[0], c[1], c[2]])),
+            Colour::Hex(hex) if hex.len() == 6 || hex.len() == 8 => {
+                let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2).unwrap_or("-"), 16);
+                // RRGGBB, or WWRRGGBB
+                let at = hex.len() - 6;
+                match (channel(at), channel(at + 2), channel(at + 4)) {
+                    (Ok(r), Ok(g), Ok(b)) => Ok(Some([r, g, b])),
+                    _ => Err(ApiError::invalid(path, "expected hex RRGGBB")),
+                }
+            }
+            _ => Err(ApiError::invalid(path, "expected [r, g, b] or hex RRGGBB")),
+        }
+    }
+}
+
+/// What clients post to `/json/state`; everything else they send is ignored.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct StatePatch {
+    pub on: Option<Switch>,
+    pub bri: Option<u8>,
+    pub seg: Option<SegPatches>,
+    /// reply with the new state instead of `{"success": true}`
+    #[serde(default)]
+    pub v: bool,
+}
+
+/// A single object applies to every segment, an array element without
+/// an `id` to the segment at its position.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum SegPatches {
+    One(SegPatch),
+    Many(Vec<SegPatch>),
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct SegPatch {
+    pub id: Option<usize>,
+    pub start: Option<usize>,
+    pub stop: Option<usize>,
+    pub len: Option<usize>,
+    pub on: Option<Switch>,
+    pub bri: Option<u8>,
+    pub col: Option<Vec<Colour>>,
+}
+
+/// Applies `patch` to the master and to `segments`, serialized in map
+/// order. The result still has to pass segment validation.
+pub fn apply(
+    master: &mut Master,
+    segments: &mut [Value],
+    patch: &StatePatch,
+) -> Result<(), ApiError> {
+    if let Some(bri) = patch.bri {
+        master.bri = bri;
+    }
+    if let Some(on) = &patch.on {
+        master.on = on.apply("/on", master.on)?;
+    }
+
+    match &patch.seg {
+        None => {}
+        Some(SegPatches::One(seg)) => match seg.id {
+            Some(id) => apply_segment(segments, id, seg, "/seg")?,
+            None => {
+                for id in 0..segments.len() {
+                    apply_segment(segments, id, seg, "/seg")?;
+                }
+            }
+        },
+        Some(SegPatches::Many(segs)) => {
+            for (pos, seg) in segs.iter().enumerate() {
+                let path = format!("/seg/{pos}");
+                apply_segment(segments, seg.id.unwrap_or(pos), seg, &path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn apply_segment(
+    segments: &mut [Value],
+    id: usize,
+    patch: &SegPatch,
+    path: &str,
+) -> Result<(), ApiError> {
+    let count = segments.len();
+    let start: usize = segments.iter().take(id).map(length).sum();
+    let seg = segments.get_mut(id).ok_or_else(|| {
+        ApiError::invalid(
+            format!("{path}/id"),
+            format!("no segment {id}, there are {count}"),
+        )
+    })?;
+
+    if let Some(given) = patch.start {
+        if given != start {
+            return Err(ApiError::invalid(
+                format!("{path}/start"),
+                format!("segments are back to back, segment {id} starts at {start}"),
+            ));
+        }
+    }
+    let len = match (patch.len, patch.stop) {
+        (Some(len), _) => Some((len, "len")),
+        (None, Some(stop)) => Some((stop.saturating_sub(start), "stop")),
+        (None, None) => None,
+    };
+    if let Some((len, field)) = len {
+        if len == 0 {
+            return Err(ApiError::invalid(
+                format!("{path}/{field}"),
+                "segments can't be removed or emptied here",
+            ));
+        }
+        seg["length"] = json!(len);
+    }
+
+    if let Some(bri) = patch.bri {
+        seg["brightness"] = json!(from_wled(bri));
+    }
+    if let Some(on) = &patch.on {
+        let current = brightness(seg);
+        match on.apply(&format!("{path}/on"), current > 0)? {
+            false => seg["brightness"] = json!(0),
+            // back on at what the patch asked for, or full brightness
+            true if brightness(seg) == 0 => {
+                let bri = patch.bri.map(from_wled).filter(|&b| b > 0);
+                seg["brightness"] = json!(bri.unwrap_or(MAX_BRIGHTNESS));
+            }
+            true => {}
+        }
+    }
+
+    if let Some(cols) = &patch.col {
+        let fields = colour_fields(seg);
+        for (i, colour) in cols.iter().enumerate() {
+            let Some(field) = fields.get(i) else {
+                break;
+            };
+            if let Some([r, g, b]) = colour.rgb(&format!("{path}/col/{i}"))? {
+                let target = &mut seg[field.as_str()];
+                target["red"] = json!(r);
+                target["green"] = json!(g);
+                target["blue"] = json!(b);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Leds {
+    pub count: usize,
+    pub fps: u32,
+    pub rgbw: bool,
+    pub wv: bool,
+    pub cct: bool,
+    pub pwr: u32,
+    pub maxpwr: u32,
+    pub maxseg: usize,
+}
+
+/// `/json/info`, as far as it applies.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Info {
+    pub ver: &'static str,
+    pub vid: u32,
+    pub leds: Leds,
+    pub name: String,
+    /// no WLED sync
+    pub udpport: u16,
+    pub live: bool,
+    pub lm: String,
+    pub lip: String,
+    pub ws: i32,
+    pub fxcount: u32,
+    pub palcount: u32,
+    pub arch: &'static str,
+    pub core: &'static str,
+    pub freeheap: u32,
+    pub uptime: u64,
+    pub brand: &'static str,
+    pub product: &'static str,
+    /// lowercase hex without separators
+    pub mac: String,
+    pub ip: String,
+}
+
+impl Info {
+    pub fn new(name: String, leds: usize, segments: usize) -> Self {
+        Self {
+            ver: VERSION,
+            vid: VERSION_ID,
+            leds: Leds {
+                count: leds,
+                fps: 0,
+                rgbw: false,
+                wv: false,
+                cct: false,
+                pwr: 0,
+                maxpwr: 0,
+                maxseg: segments,
+            },
+            name,
+            udpport: 0,
+            live: false,
+            lm: String::new(),
+            lip: String::new(),
+            ws: -1,
+            fxcount: 1,
+            palcount: 1,
+            arch: "esp32",
+            core: "",
+            freeheap: 0,
+            uptime: 0,
+            brand: "harlot",
+            product: "harlot board",
+            mac: String::new(),
+            ip: String::new(),
+        }
+    }
+}
+
+/// `GET /json`: state, info, and the single effect and palette we have.
+pub fn everything(state: &State, info: &Info) -> Value {
+    json!({
+        "state": state,
+        "info": info,
+        "effects": ["Solid"],
+        "palettes": ["Default"],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use color_mixer::strip::{Segment, Srgb8};
+
+    use super::*;
+
+    fn segment(length: usize, first: [u8; 3], second: [u8; 3], brightness: u8) -> Value {
+        let [r, g, b] = first;
+        let first = Srgb8::new(r, g, b);
+        let [r, g, b] = second;
+        let second = Srgb8::new(r, g, b);
+        let seg = Segment::new(length, false, first, second, 0, 1, brightness);
+        serde_json::to_value(seg).unwrap()
+    }
+
+    fn patch(json: Value) -> StatePatch {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn brightness_survives_the_round_trip() {
+        for brightness in 0..=MAX_BRIGHTNESS {
+            assert_eq!(from_wled(to_wled(brightness)), brightness);
+        }
+        assert_eq!((to_wled(0), to_wled(50), to_wled(100)), (0, 128, 255));
+        assert_eq!((from_wled(0), from_wled(1), from_wled(255)), (0, 0, 100));
+
+        let master = Master { on: true, bri: 128 };
+        assert_eq!((master.scale(100), master.scale(0)), (50, 0));
+        assert_eq!(Master::default().scale(73), 73);
+        assert_eq!(
+            Master {
+                on: false,
+                bri: 255
+            }
+            .scale(100),
+            0
+        );
+    }
+
+    #[test]
+    fn segments_become_wled_segments() {
+        let segments = [
+            segment(10, [1, 2, 3], [4, 5, 6], 100),
+            segment(5, [7, 8, 9], [0; 3], 0),
+        ];
+        let state = state(&Master { on: false, bri: 7 }, &segments);
+        assert!(!state.on);
+        assert_eq!(state.bri, 7);
+        let seg: Vec<_> = state
+            .seg
+            .iter()
+            .map(|s| (s.id, s.start, s.stop, s.len, s.on, s.bri))
+            .collect();
+        assert_eq!(seg, [(0, 0, 10, 10, true, 255), (1, 10, 15, 5, false, 0)]);
+        // colours in the segment's own field order, padded to three
+        assert_eq!(state.seg[0].col, [[1, 2, 3], [4, 5, 6], [0; 3]]);
+        assert_eq!(state.seg[1].col, [[7, 8, 9], [0; 3], [0; 3]]);
+    }
+
+    #[test]
+    fn wled_state_applies_back_unchanged() {
+        let segments = [
+            segment(10, [1, 2, 3], [4, 5, 6], 40),
+            segment(5, [7, 8, 9], [10, 11, 12], 0),
+        ];
+        let master = Master { on: true, bri: 99 };
+        let wled = serde_json::to_value(state(&master, &segments)).unwrap();
+
+        let mut next = Master::default();
+        let mut values = segments.to_vec();
+        apply(&mut next, &mut values, &patch(wled)).unwrap();
+        assert_eq!(next, master);
+        assert_eq!(values, segments);
+    }
+
+    #[test]
+    fn wled_patches_land_on_our_fields() {
+        let mut segments = [
+            segment(10, [0; 3], [0; 3], 40),
+            segment(5, [0; 3], [0; 3], 60),
+        ];
+        let mut master = Master::default();
+        let fields = colour_fields(&segments[0]);
+        assert_eq!(fields.len(), 2);
+
+        let p = patch(json!({"on": "t", "bri": 20, "seg": [
+            {"col": [[255, 0, 0, 9], "00ff00"], "len": 12},
+            {"id": 1, "on": false},
+        ]}));
+        apply(&mut master, &mut segments, &p).unwrap();
+        assert_eq!(master, Master { on: false, bri: 20 });
+        assert_eq!(segments[0]["length"], 12);
+        assert_eq!(rgb(&segments[0][&fields[0]]), [255, 0, 0]);
+        assert_eq!(rgb(&segments[0][&fields[1]]), [0, 255, 0]);
+        assert_eq!(brightness(&segments[1]), 0);
+
+        // a single object goes to every segment; `on` comes back at full
+        let p = patch(json!({"seg": {"on": true, "col": [[], "ff000010"]}}));
+        apply(&mut master, &mut segments, &p).unwrap();
+        assert_eq!(brightness(&segments[0]), 40);
+        assert_eq!(brightness(&segments[1]), MAX_BRIGHTNESS);
+        assert_eq!(rgb(&segments[0][&fields[0]]), [255, 0, 0]);
+        assert_eq!(rgb(&segments[1][&fields[1]]), [0, 0, 16]);
+    }
+
+    #[test]
+    fn rejects_what_wled_would_not_do() {
+        let mut segments = [
+            segment(10, [0; 3], [0; 3], 40),
+            segment(5, [0; 3], [0; 3], 40),
+        ];
+        let error = |json: Value| {
+            let mut values = segments.clone();
+            apply(&mut Master::default(), &mut values, &patch(json))
+                .err()?
+                .path
+        };
+        assert_eq!(error(json!({"on": "x"})).as_deref(), Some("/on"));
+        assert_eq!(error(json!({"seg": {"id": 2}})).as_deref(), Some("/seg/id"));
+        assert_eq!(
+            error(json!({"seg": [{}, {"start": 3}]})).as_deref(),
+            Some("/seg/1/start")
+        );
+        assert_eq!(
+            error(json!({"seg": [{"stop": 0}]})).as_deref(),
+            Some("/seg/0/stop")
+        );
+        assert_eq!(
+            error(json!({"seg": [{"col": ["zzzzzz"]}]})).as_deref(),
+            Some("/seg/0/col/0")
+        );
+        assert_eq!(
+            error(json!({"seg": [{"col": [[1, 2]]}]})).as_deref(),
+            Some("/seg/0/col/0")
+        );
+        assert_eq!(error(json!({"seg": [{}, {"start": 10, "stop": 12}]})), None);
+
+        let p = patch(json!({"seg": [{}, {"start": 10, "stop": 12}]}));
+        apply(&mut Master::default(), &mut segments, &p).unwrap();
+        assert_eq!(length(&segments[1]), 2);
+    }
+}
@@ -0,0 +1,375 @@
+//! JSON message protocol of the `/ws` live channel.
+//!
+//! Client to board:
+//! - `{"type": "auth", "token": "..."}`: a token from `POST /auth/login`,
+//!   answered with `authorized`. Once an admin password is set nothing else
+//!   is accepted before it; as over HTTP, `patch` then needs an admin token
+//! - `{"type": "subscribe"}`: answered with a `state` snapshot, afterwards
+//!   every change is pushed
+//! - `{"type": "patch", "id": "<uuid>", "patch": {...}, "revision": 7}`:
+//!   merge-patches one segment; `revision` is optional and works like
+//!   `If-Match`
+//! - `{"type": "ping", "t": 1234}`: answered with `pong`, carrying the
+//!   client's `t` and the board's `now` for clock offset estimation
+//! - `{"type": "pixels", "offset": 0, "pixels": [[255, 0, 0], ...]}`: pixels
+//!   for the strip as the `ws` realtime source, needs an admin token like
+//!   `patch`; only errors are answered
+//!
+//! Board to client: `authorized`, `state`, `segment`, `pong` and `error`,
+//! see [`ServerMessage`].
+
+use color_mixer::strip::Segment;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    api::ApiError,
+    auth::{Auth, Role},
+    realtime::Pixels,
+    segments::{SegmentMap, Segments},
+};
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Auth {
+        token: String,
+    },
+    Subscribe,
+    Patch {
+        id: String,
+        patch: Value,
+        #[serde(default)]
+        revision: Option<u32>,
+    },
+    Ping {
+        t: u64,
+    },
+    Pixels(Pixels),
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage<'a> {
+    /// the connection's token was accepted
+    Authorized {
+        role: Role,
+    },
+    /// the whole map, sent on subscribe and whenever it changed
+    State {
+        revision: u32,
+        segments: &'a Segments,
+    },
+    /// a single segment changed via `patch`
+    Segment {
+        revision: u32,
+        id: &'a str,
+        segment: &'a Segment,
+    },
+    Pong {
+        t: u64,
+        now: u32,
+    },
+    Error {
+        error: String,
+    },
+}
+
+impl<'a> ServerMessage<'a> {
+    pub fn state(map: &'a SegmentMap) -> Self {
+        ServerMessage::State {
+            revision: map.revision(),
+            segments: map.segments(),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+impl From<ApiError> for ServerMessage<'_> {
+    fn from(e: ApiError) -> Self {
+        ServerMessage::Error { error: e.error }
+    }
+}
+
+pub fn decode(text: &[u8]) -> Result<ClientMessage, ApiError> {
+    Ok(serde_json::from_slice(text)?)
+}
+
+/// What the connection should do with an incoming message.
+pub enum Outcome {
+    /// register the sender for broadcasts and send `reply`
+    Subscribe(String),
+    Reply(String),
+    /// put these on the strip, answer only if that fails
+    Pixels(Pixels),
+}
+
+/// Applies one client message to the map. `token` is what the connection
+/// authenticated with so far, `now` the board time in ms.
+pub fn handle(
+    map: &mut SegmentMap,
+    auth: &Auth,
+    token: &mut Option<String>,
+    text: &[u8],
+    now: u32,
+) -> Outcome {
+    let msg = match decode(text) {
+        Ok(msg) => msg,
+        Err(e) => return Outcome::Reply(ServerMessage::from(e).encode()),
+    };
+    // checked on every message, so a revoked token stops working right away
+    let required = match msg {
+        ClientMessage::Auth { .. } => None,
+        ClientMessage::Patch { .. } | ClientMessage::Pixels(_) => Some(Role::Admin),
+        ClientMessage::Subscribe | ClientMessage::Ping { .. } => Some(Role::ReadOnly),
+    };
+    if let Err(e) = auth.authorize_token(token.as_deref(), required) {
+        return Outcome::Reply(ServerMessage::from(e).encode());
+    }
+    match msg {
+        ClientMessage::Auth { token: offered } => {
+            let role = match auth.verify(&offered) {
+                Some(role) => role,
+                // open until a password is set
+                None if !auth.enabled() => Role::Admin,
+                None => {
+                    let e = ApiError::new(401, "invalid token");
+                    return Outcome::Reply(ServerMessage::from(e).encode());
+                }
+            };
+            *token = Some(offered);
+            Outcome::Reply(ServerMessage::Authorized { role }.encode())
+        }
+        ClientMessage::Subscribe => Outcome::Subscribe(ServerMessage::state(map).encode()),
+        ClientMessage::Ping { t } => Outcome::Reply(ServerMessage::Pong { t, now }.encode()),
+        ClientMessage::Pixels(pixels) => Outcome::Pixels(pixels),
+        ClientMessage::Patch {
+            id,
+            patch,
+            revision,
+        } => {
+            if let Some(revision) = revision {
+                if revision != map.revision() {
+                    let e = ApiError::new(412, format!("stale, now at {}", map.revision()));
+                    return Outcome::Reply(ServerMessage::from(e).encode());
+                }
+            }
+            let reply = match map.patch(&id, patch) {
+                Ok(_) => {
+                    let revision = map.revision();
+                    // the patch was applied, so the segment exists
+                    let segment = &map.segments()[id.as_str()];
+                    ServerMessage::Segment {
+                        revision,
+                        id: &id,
+                        segment,
+                    }
+                    .encode()
+                }
+                Err(e) => ServerMessage::from(e).encode(),
+            };
+            Outcome::Reply(reply)
+        }
+    }
+}
+
+/// Something a text frame can be pushed to.
+pub trait Sink {
+    fn send_text(&mut self, text: &str) -> anyhow::Result<()>;
+}
+
+/// Subscribed connections, keyed by session id. Sinks that fail to send are
+/// dropped.
+pub struct Hub<S> {
+    subscribers: Vec<(i32, S)>,
+    revision: Option<u32>,
+}
+
+impl<S> Default for Hub<S> {
+    fn default() -> Self {
+        Self {
+            subscribers: Vec::new(),
+            revision: None,
+        }
+    }
+}
+
+impl<S: Sink> Hub<S> {
+    pub fn subscribe(&mut self, session: i32, sink: S) {
+        self.unsubscribe(session);
+        self.subscribers.push((session, sink));
+    }
+
+    pub fn unsubscribe(&mut self, session: i32) {
+        self.subscribers.retain(|(s, _)| *s != session);
+    }
+
+    pub fn len(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    pub fn broadcast(&mut self, text: &str) {
+        self.subscribers.retain_mut(|(session, sink)| match sink.send_text(text) {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("ws: dropping session {session}: {e}");
+                false
+            }
+        });
+    }
+
+    /// The full state to [`broadcast`](Self::broadcast) if the map moved on
+    /// since the last call. Cheap enough to call with the map locked, the
+    /// sending can then happen without it.
+    pub fn changes(&mut self, map: &SegmentMap) -> Option<String> {
+        if self.revision == Some(map.revision()) {
+            return None;
+        }
+        self.revision = Some(map.revision());
+        if self.subscribers.is_empty() {
+            return None;
+        }
+        Some(ServerMessage::state(map).encode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::segments::fixture;
+
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl Sink for &mut Recorder {
+        fn send_text(&mut self, text: &str) -> anyhow::Result<()> {
+            self.0.push(text.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn hub_hands_out_each_revision_once() {
+        let seg = fixture::segment(10);
+        let id = seg.to_uuid_string();
+        let mut map = fixture::map(&[seg], 10);
+
+        let mut recorder = Recorder::default();
+        let mut hub = Hub::default();
+        // nobody listening, nothing to encode
+        assert_eq!(hub.changes(&map), None);
+        hub.subscribe(7, &mut recorder);
+        assert_eq!(hub.changes(&map), None);
+
+        let patch = serde_json::json!({"type": "patch", "id": id, "patch": {"brightness": 20}});
+        let patch = serde_json::to_vec(&patch).unwrap();
+        let outcome = handle(&mut map, &Auth::default(), &mut None, &patch, 0);
+        assert!(matches!(outcome, Outcome::Reply(_)));
+        let state = hub.changes(&map).unwrap();
+        assert_eq!(hub.changes(&map), None);
+        hub.broadcast(&state);
+        drop(hub);
+
+        let sent: Value = serde_json::from_str(&recorder.0[0]).unwrap();
+        assert_eq!(sent["type"], "state");
+        assert_eq!(sent["revision"], 1);
+        assert_eq!(sent["segments"][&id]["brightness"], 20);
+    }
+
+    fn message(json: Value) -> Vec<u8> {
+        serde_json::to_vec(&json).unwrap()
+    }
+
+    fn reply(outcome: Outcome) -> Value {
+        match outcome {
+            Outcome::Subscribe(text) | Outcome::Reply(text) => serde_json::from_str(&text).unwrap(),
+            Outcome::Pixels(pixels) => panic!("expected a reply, got {pixels:?}"),
+        }
+    }
+
+    #[test]
+    fn needs_a_token_once_a_password_is_set() {
+        let seg = fixture::segment(10);
+        let id = seg.to_uuid_string();
+        let mut map = fixture::map(&[seg], 10);
+        let mut auth = Auth::default();
+        auth.set_password("correct horse", [1; 16]).unwrap();
+        let reader = auth.issue(Role::ReadOnly, "display", [2; 32]);
+        let admin = auth.issue(Role::Admin, "", [3; 32]);
+
+        let subscribe = message(serde_json::json!({"type": "subscribe"}));
+        let patch = message(serde_json::json!({
+            "type": "patch", "id": id, "patch": {"brightness": 20}
+        }));
+        let login = |token: &str| message(serde_json::json!({"type": "auth", "token": token}));
+
+        let mut token = None;
+        let res = reply(handle(&mut map, &auth, &mut token, &subscribe, 0));
+        assert_eq!(res["type"], "error");
+        let res = reply(handle(&mut map, &auth, &mut token, &login("guess"), 0));
+        assert_eq!(res["type"], "error");
+        assert_eq!(token, None);
+
+        let res = reply(handle(&mut map, &auth, &mut token, &login(&reader), 0));
+        assert_eq!(res, serde_json::json!({"type": "authorized", "role": "read_only"}));
+        let outcome = handle(&mut map, &auth, &mut token, &subscribe, 0);
+        assert!(matches!(outcome, Outcome::Subscribe(_)));
+        let res = reply(handle(&mut map, &auth, &mut token, &patch, 0));
+        assert_eq!(res["type"], "error");
+        assert_eq!(map.revision(), 0);
+
+        let res = reply(handle(&mut map, &auth, &mut token, &login(&admin), 0));
+        assert_eq!(res["role"], "admin");
+        let res = reply(handle(&mut map, &auth, &mut token, &patch, 0));
+        assert_eq!(res["type"], "segment");
+        assert_eq!(map.revision(), 1);
+
+        // revoking takes effect on the next message
+        auth.revoke(&admin);
+        let res = reply(handle(&mut map, &auth, &mut token, &patch, 0));
+        assert_eq!(res["type"], "error");
+
+        let res = reply(handle(&mut map, &Auth::locked(), &mut None, &login(&admin), 0));
+        assert_eq!(res["type"], "error");
+    }
+
+    #[test]
+    fn open_without_a_password() {
+        let mut map = fixture::map(&[], 10);
+        let auth = Auth::default();
+        let ping = message(serde_json::json!({"type": "ping", "t": 5}));
+        let res = reply(handle(&mut map, &auth, &mut None, &ping, 9));
+        assert_eq!(res, serde_json::json!({"type": "pong", "t": 5, "now": 9}));
+
+        let login = message(serde_json::json!({"type": "auth", "token": "anything"}));
+        let res = reply(handle(&mut map, &auth, &mut None, &login, 0));
+        assert_eq!(res["role"], "admin");
+    }
+
+    #[test]
+    fn pixels_need_an_admin() {
+        let mut map = fixture::map(&[], 10);
+        let mut auth = Auth::default();
+        let pixels = message(serde_json::json!({
+            "type": "pixels", "offset": 2, "pixels": [[1, 2, 3], [4, 5, 6]]
+        }));
+        let expected = Pixels {
+            offset: 2,
+            pixels: vec![[1, 2, 3], [4, 5, 6]],
+        };
+        match handle(&mut map, &auth, &mut None, &pixels, 0) {
+            Outcome::Pixels(pixels) => assert_eq!(pixels, expected),
+            _ => panic!("expected pixels"),
+        }
+        let bad = message(serde_json::json!({"type": "pixels", "pixels": [[1, 2]]}));
+        assert_eq!(reply(handle(&mut map, &auth, &mut None, &bad, 0))["type"], "error");
+
+        auth.set_password("correct horse", [1; 16]).unwrap();
+        let reader = auth.issue(Role::ReadOnly, "", [2; 32]);
+        let res = reply(handle(&mut map, &auth, &mut Some(reader), &pixels, 0));
+        assert_eq!(res["type"], "error");
+    }
+}